@@ -0,0 +1,86 @@
+//! Runs a circuit file for a stretch of simulated time on the same solver the app uses, without
+//! the UI, and reports how long it took. For measuring the effect of changes to the solver.
+//!
+//! cargo run --release --example bench -- <file.ckt> <seconds> [dt]
+//!
+//! The time step defaults to the one saved in the file, and so do the solver settings.
+
+use std::{collections::HashMap, process::ExitCode, time::Instant};
+
+use cirmcut::circuit_widget::Diagram;
+use cirmcut_sim::solver::{Solver, SolverConfig};
+
+#[derive(serde::Deserialize)]
+struct CircuitFile {
+    diagram: Diagram,
+    cfg: SolverConfig,
+    dt: f64,
+    #[serde(default)]
+    variables: HashMap<String, f64>,
+}
+
+fn run() -> Result<(), String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [path, seconds, rest @ ..] = args.as_slice() else {
+        return Err("Usage: bench <file.ckt> <seconds> [dt]".into());
+    };
+    let seconds: f64 = seconds
+        .parse()
+        .map_err(|e| format!("Invalid duration {seconds}: {e}"))?;
+
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    let mut file: CircuitFile = ron::from_str(&text).map_err(|e| format!("{path}: {e}"))?;
+    let dt = match rest.first() {
+        Some(dt) => dt
+            .parse()
+            .map_err(|e| format!("Invalid time step {dt}: {e}"))?,
+        None => file.dt,
+    };
+    if !dt.is_finite() || dt <= 0.0 {
+        return Err(format!("The time step has to be positive, not {dt}"));
+    }
+
+    file.diagram.assign_ids();
+    file.diagram.apply_variables(&file.variables);
+    let diagram = file.diagram.to_primitive_diagram().primitive;
+    println!("{path}: {:?}", diagram.stats());
+
+    let mut solver = Solver::new(&diagram);
+    let mut steps = 0;
+    let mut iterations = 0;
+    let start = Instant::now();
+    while solver.time() < seconds {
+        solver
+            .step(dt, &diagram, &file.cfg, None)
+            .map_err(|e| format!("Simulation failed at t = {} s: {e}", solver.time()))?;
+        steps += 1;
+        // Linear steps solve once, without recording a residual
+        iterations += solver.last_residuals().len().max(1);
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "Simulated {:.6} s in {steps} steps of {dt:e} s",
+        solver.time()
+    );
+    println!("Wall clock: {elapsed:?}");
+    println!(
+        "Steps per second: {:.1}",
+        steps as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "Newton-Raphson iterations per step: {:.2}",
+        iterations as f64 / steps.max(1) as f64
+    );
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Builds the bundled Colpitts oscillator (src/colpitts2.ckt) in code with `DiagramBuilder`, runs
+//! it next to the diagram loaded from the file, and compares the supply and inductor currents.
+//!
+//! cargo run --example colpitts
+
+use cirmcut::circuit_widget::Diagram;
+use cirmcut_sim::{
+    builder::DiagramBuilder,
+    solver::{Solver, SolverConfig},
+    PrimitiveDiagram,
+};
+
+const STEPS: usize = 2000;
+
+#[derive(serde::Deserialize)]
+struct CircuitFile {
+    diagram: Diagram,
+    cfg: SolverConfig,
+    dt: f64,
+}
+
+/// Returns the diagram, and the indices of the battery and the inductor
+fn build_colpitts() -> (PrimitiveDiagram, usize, usize) {
+    let mut builder = DiagramBuilder::new();
+    let emitter = DiagramBuilder::GROUND;
+    let supply = builder.node();
+    let collector = builder.node();
+    let tank = builder.node();
+    let base = builder.node();
+
+    let battery = builder.battery(emitter, supply, 5.0);
+    builder.resistor(supply, collector, 100.0);
+    builder.resistor(collector, tank, 1000.0);
+    builder.capacitor(emitter, tank, 100e-6);
+    builder.capacitor(emitter, base, 100e-6);
+    let inductor = builder.inductor(tank, base, 0.08);
+    builder.npn(collector, base, emitter, 100.0);
+
+    (builder.build(), battery, inductor)
+}
+
+fn main() {
+    let file: CircuitFile = ron::from_str(include_str!("../src/colpitts2.ckt")).unwrap();
+    let loaded = file.diagram.to_primitive_diagram().primitive;
+    let loaded_battery = find(&loaded, "Battery");
+    let loaded_inductor = find(&loaded, "Inductor");
+
+    let (built, battery, inductor) = build_colpitts();
+    println!("Loaded: {:?}", loaded.stats());
+    println!("Built:  {:?}", built.stats());
+
+    let mut loaded_sim = Solver::new(&loaded);
+    let mut built_sim = Solver::new(&built);
+
+    let mut max_diff: f64 = 0.0;
+    for step in 0..STEPS {
+        step_or_exit(&mut loaded_sim, &loaded, &file.cfg, file.dt);
+        step_or_exit(&mut built_sim, &built, &file.cfg, file.dt);
+
+        let loaded_out = loaded_sim.state(&loaded);
+        let built_out = built_sim.state(&built);
+        let currents = [
+            loaded_out.two_terminal_current[loaded_battery],
+            built_out.two_terminal_current[battery],
+            loaded_out.two_terminal_current[loaded_inductor],
+            built_out.two_terminal_current[inductor],
+        ];
+
+        max_diff = max_diff
+            .max((currents[0] - currents[1]).abs())
+            .max((currents[2] - currents[3]).abs());
+
+        if step % 100 == 0 {
+            let [a, b, c, d] = currents;
+            println!(
+                "t = {:.4} s: supply {a:+.6} A / {b:+.6} A, inductor {c:+.6} A / {d:+.6} A",
+                loaded_sim.time()
+            );
+        }
+    }
+
+    println!("Largest difference in current over {STEPS} steps: {max_diff:e} A");
+}
+
+fn find(diagram: &PrimitiveDiagram, name: &str) -> usize {
+    diagram
+        .two_terminal
+        .iter()
+        .position(|(_, component)| component.name() == name)
+        .unwrap_or_else(|| panic!("No {name} in the loaded diagram"))
+}
+
+fn step_or_exit(sim: &mut Solver, diagram: &PrimitiveDiagram, cfg: &SolverConfig, dt: f64) {
+    if let Err(e) = sim.step(dt, diagram, cfg, None) {
+        eprintln!("Simulation failed at t = {} s: {e}", sim.time());
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,70 @@
+//! Sweeps the series resistor of a diode clamp, once with a solver per value and once as a
+//! single batch, and compares the results and the time taken.
+//!
+//! cargo run --release --example batch_sweep
+
+use std::time::Instant;
+
+use cirmcut_sim::{
+    builder::DiagramBuilder,
+    solver::{Solver, SolverConfig},
+    PrimitiveDiagram,
+};
+
+const VARIANTS: usize = 50;
+const STEPS: usize = 100;
+const DT: f64 = 1e-3;
+
+/// 5 V through `ohms` into a diode. Node 1 is across the diode.
+fn clamp(ohms: f64) -> PrimitiveDiagram {
+    let mut builder = DiagramBuilder::new();
+    let supply = builder.node();
+    let anode = builder.node();
+    builder.battery(DiagramBuilder::GROUND, supply, 5.0);
+    builder.resistor(supply, anode, ohms);
+    builder.diode(anode, DiagramBuilder::GROUND);
+    builder.build()
+}
+
+fn main() {
+    let cfg = SolverConfig::default();
+    let diagrams: Vec<PrimitiveDiagram> = (0..VARIANTS)
+        .map(|i| clamp(100.0 * (1.0 + i as f64)))
+        .collect();
+
+    let start = Instant::now();
+    let mut separate = vec![];
+    for diagram in &diagrams {
+        let mut solver = Solver::new(diagram);
+        for _ in 0..STEPS {
+            solver.step(DT, diagram, &cfg, None).unwrap();
+        }
+        separate.push(solver.state(diagram).voltages[1]);
+    }
+    let separate_time = start.elapsed();
+
+    let start = Instant::now();
+    let mut solver = Solver::new_batch(&diagrams).unwrap();
+    for _ in 0..STEPS {
+        solver.step_batch(DT, &diagrams, &cfg).unwrap();
+    }
+    let batched: Vec<f64> = solver
+        .batch_state(&diagrams)
+        .iter()
+        .map(|state| state.voltages[1])
+        .collect();
+    let batch_time = start.elapsed();
+
+    let worst = separate
+        .iter()
+        .zip(&batched)
+        .map(|(a, b)| (a - b).abs())
+        .fold(0.0, f64::max);
+
+    println!("Separately: {separate_time:?}, batched: {batch_time:?}");
+    println!(
+        "Diode voltage from {:.4} V to {:.4} V, largest difference {worst:.2e} V",
+        batched[VARIANTS - 1],
+        batched[0]
+    );
+}
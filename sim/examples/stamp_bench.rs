@@ -0,0 +1,109 @@
+//! Counts the allocations and times the stamping of a large nonlinear circuit, through the
+//! one-off `stamp()` and through `stamp_dynamic()` reusing a `StampCache` as the solver does,
+//! then counts them for whole Newton-Raphson steps.
+//!
+//! cargo run --release --example stamp_bench
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use cirmcut_sim::{
+    builder::DiagramBuilder,
+    solver::{Solver, SolverConfig},
+    stamp::{stamp, stamp_dynamic, StampCache},
+    IntegrationMethod, PrimitiveDiagram,
+};
+
+const SECTIONS: usize = 300;
+const STAMPS: usize = 2000;
+const STEPS: usize = 20;
+const DT: f64 = 1e-3;
+
+/// Passes everything on to the system allocator, counting allocations and reallocations
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// 1 V into a ladder of series resistors, each rung shunted by a diode and a capacitor
+fn ladder() -> PrimitiveDiagram {
+    let mut builder = DiagramBuilder::new();
+    let mut node = builder.node();
+    builder.battery(DiagramBuilder::GROUND, node, 1.0);
+    for _ in 0..SECTIONS {
+        let next = builder.node();
+        builder.resistor(node, next, 1.0);
+        builder.diode(next, DiagramBuilder::GROUND);
+        builder.capacitor(next, DiagramBuilder::GROUND, 1e-6);
+        node = next;
+    }
+    builder.build()
+}
+
+/// Runs `f` `times` times, and prints the allocations and the time of each run
+fn measure(label: &str, times: usize, mut f: impl FnMut()) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..times {
+        f();
+    }
+    let elapsed = start.elapsed() / times as u32;
+    let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - allocations) as f64 / times as f64;
+    println!("{label:<24} {allocations:>8.1} allocations, {elapsed:.2?} each");
+}
+
+fn main() {
+    let diagram = ladder();
+    println!("{} unknowns", diagram.stats().matrix_size);
+
+    let solver = Solver::new(&diagram);
+    let map = solver.map();
+    let state = vec![0.0; map.vector_size()];
+    let integration = IntegrationMethod::BackwardEuler;
+
+    measure("stamp()", STAMPS, || {
+        std::hint::black_box(stamp(DT, integration, map, &diagram, &state, &state, None));
+    });
+
+    // The first call sizes the cache, as the first iteration of a solver's first step does
+    let mut cache = StampCache::new(map, &diagram);
+    stamp_dynamic(DT, integration, map, &diagram, &mut cache, &state, &state, None);
+    measure("stamp_dynamic(), cached", STAMPS, || {
+        let (matrix, params) = stamp_dynamic(DT, integration, map, &diagram, &mut cache, &state, &state, None);
+        std::hint::black_box((matrix, params));
+    });
+
+    let cfg = SolverConfig {
+        line_search: true,
+        ..Default::default()
+    };
+    let mut solver = Solver::new(&diagram);
+    solver.step(DT, &diagram, &cfg, None).unwrap();
+    let mut iterations = 0;
+    measure("Newton-Raphson step", STEPS, || {
+        solver.step(DT, &diagram, &cfg, None).unwrap();
+        iterations += solver.last_residuals().len();
+    });
+    println!("{:.1} iterations per step", iterations as f64 / STEPS as f64);
+}
@@ -0,0 +1,167 @@
+use std::mem::discriminant;
+
+use crate::{PrimitiveDiagram, SimOutputs, TwoTerminalComponent};
+
+/// Combines diagrams with identical topology into one, so that a single solver steps them all
+/// with one factorization per iteration, see `Solver::new_batch()`.
+///
+/// Each copy keeps its own nodes, except for the reference ground, which they share. Nothing
+/// flows through the reference between copies, since no other node connects them, so the
+/// combined matrix is block diagonal apart from the ordering of its rows and columns. Inductor
+/// cores are renumbered so that copies don't couple to each other.
+pub fn merge(diagrams: &[PrimitiveDiagram]) -> Result<PrimitiveDiagram, String> {
+    let Some(first) = diagrams.first() else {
+        return Err("A batch needs at least one diagram".into());
+    };
+
+    for (idx, diagram) in diagrams.iter().enumerate().skip(1) {
+        if !same_topology(first, diagram) {
+            return Err(format!(
+                "Diagram #{idx} of the batch has a different topology than the first"
+            ));
+        }
+    }
+
+    let mut cores: Vec<u16> = first
+        .two_terminal
+        .iter()
+        .filter_map(|(_, component)| match component {
+            TwoTerminalComponent::Inductor(_, core, ..) => *core,
+            _ => None,
+        })
+        .collect();
+    cores.sort_unstable();
+    cores.dedup();
+    if cores.len() * diagrams.len() > usize::from(u16::MAX) + 1 {
+        return Err("Too many inductor cores in the batch".into());
+    }
+
+    // The last node is the reference, shared by every copy
+    let nodes = first.num_nodes.saturating_sub(1);
+    let reference = nodes * diagrams.len();
+
+    let mut merged = PrimitiveDiagram {
+        num_nodes: reference + 1,
+        two_terminal: vec![],
+        three_terminal: vec![],
+        grounds: vec![],
+        unconnected_reference: first.unconnected_reference,
+        disabled_three_terminal: vec![],
+    };
+
+    for (copy, diagram) in diagrams.iter().enumerate() {
+        let node = |node: usize| {
+            if node == nodes {
+                reference
+            } else {
+                copy * nodes + node
+            }
+        };
+        let core = |core: u16| {
+            let rank = cores.binary_search(&core).unwrap_or_default();
+            (copy * cores.len() + rank) as u16
+        };
+
+        let offset = merged.two_terminal.len();
+        merged
+            .two_terminal
+            .extend(diagram.two_terminal.iter().map(|&(nodes, component)| {
+                let component = match component {
+                    TwoTerminalComponent::Inductor(l, hub, ic, method, r) => {
+                        TwoTerminalComponent::Inductor(l, hub.map(core), ic, method, r)
+                    }
+                    TwoTerminalComponent::CurrentControlledSource(gain, sense) => {
+                        TwoTerminalComponent::CurrentControlledSource(
+                            gain,
+                            sense.map(|idx| offset + idx),
+                        )
+                    }
+                    other => other,
+                };
+                (nodes.map(node), component)
+            }));
+
+        merged.disabled_three_terminal.extend(
+            diagram
+                .disabled_three_terminal
+                .iter()
+                .map(|idx| copy * diagram.three_terminal.len() + idx),
+        );
+        merged.three_terminal.extend(
+            diagram
+                .three_terminal
+                .iter()
+                .map(|&(nodes, component)| (nodes.map(node), component)),
+        );
+
+        merged.grounds.extend(
+            diagram
+                .grounds
+                .iter()
+                .filter(|&&(ground, _)| copy == 0 || ground != nodes)
+                .map(|&(ground, kind)| (node(ground), kind)),
+        );
+    }
+
+    Ok(merged)
+}
+
+/// Splits the outputs of a diagram from `merge()` back into one per copy
+pub fn split(outputs: &SimOutputs, first: &PrimitiveDiagram, count: usize) -> Vec<SimOutputs> {
+    let nodes = first.num_nodes.saturating_sub(1);
+    let two_terminal = first.two_terminal.len();
+    let three_terminal = first.three_terminal.len();
+
+    (0..count)
+        .map(|copy| {
+            let mut voltages = outputs.voltages[copy * nodes..(copy + 1) * nodes].to_vec();
+            // Shared reference
+            voltages.push(0.0);
+
+            SimOutputs {
+                voltages,
+                two_terminal_current: outputs.two_terminal_current
+                    [copy * two_terminal..(copy + 1) * two_terminal]
+                    .to_vec(),
+                three_terminal_current: outputs.three_terminal_current
+                    [copy * three_terminal..(copy + 1) * three_terminal]
+                    .to_vec(),
+            }
+        })
+        .collect()
+}
+
+/// Same nodes, components of the same kinds connected the same way, and the same grounds and
+/// disabled components. Component values may differ.
+pub(crate) fn same_topology(a: &PrimitiveDiagram, b: &PrimitiveDiagram) -> bool {
+    a.num_nodes == b.num_nodes
+        && a.two_terminal.len() == b.two_terminal.len()
+        && a.three_terminal.len() == b.three_terminal.len()
+        && a.two_terminal.iter().zip(&b.two_terminal).all(|(a, b)| {
+            a.0 == b.0
+                && discriminant(&a.1) == discriminant(&b.1)
+                && core(&a.1) == core(&b.1)
+                && sense(&a.1) == sense(&b.1)
+        })
+        && a.three_terminal
+            .iter()
+            .zip(&b.three_terminal)
+            .all(|(a, b)| a.0 == b.0 && discriminant(&a.1) == discriminant(&b.1))
+        && a.grounds == b.grounds
+        && a.unconnected_reference == b.unconnected_reference
+        && a.disabled_three_terminal == b.disabled_three_terminal
+}
+
+fn core(component: &TwoTerminalComponent) -> Option<u16> {
+    match component {
+        TwoTerminalComponent::Inductor(_, core, ..) => *core,
+        _ => None,
+    }
+}
+
+fn sense(component: &TwoTerminalComponent) -> Option<usize> {
+    match component {
+        TwoTerminalComponent::CurrentControlledSource(_, sense) => *sense,
+        _ => None,
+    }
+}
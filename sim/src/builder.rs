@@ -0,0 +1,164 @@
+use crate::{
+    PrimitiveDiagram, ThreeTerminalComponent, TwoTerminalComponent, DEFAULT_ALPHA_REVERSE,
+};
+
+/// Builds a `PrimitiveDiagram` in code, keeping track of the node count and of which node is
+/// the ground.
+///
+/// Nodes from `node()` keep their index in the built diagram. `DiagramBuilder::GROUND` stands in
+/// for the 0 V reference, which becomes the last node when the diagram is built. The component
+/// methods return the index of the new component, for looking up its current in `SimOutputs`.
+#[derive(Clone, Debug, Default)]
+pub struct DiagramBuilder {
+    num_nodes: usize,
+    two_terminal: Vec<([usize; 2], TwoTerminalComponent)>,
+    three_terminal: Vec<([usize; 3], ThreeTerminalComponent)>,
+}
+
+impl DiagramBuilder {
+    /// Placeholder for the reference node, resolved by `build()`
+    pub const GROUND: usize = usize::MAX;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a new node
+    pub fn node(&mut self) -> usize {
+        self.num_nodes += 1;
+        self.num_nodes - 1
+    }
+
+    /// Adds any two-terminal component, from `begin` to `end`
+    pub fn two_terminal(
+        &mut self,
+        begin: usize,
+        end: usize,
+        component: TwoTerminalComponent,
+    ) -> usize {
+        self.two_terminal.push(([begin, end], component));
+        self.two_terminal.len() - 1
+    }
+
+    /// Adds any three-terminal component
+    pub fn three_terminal(
+        &mut self,
+        nodes: [usize; 3],
+        component: ThreeTerminalComponent,
+    ) -> usize {
+        self.three_terminal.push((nodes, component));
+        self.three_terminal.len() - 1
+    }
+
+    pub fn wire(&mut self, a: usize, b: usize) -> usize {
+        self.two_terminal(a, b, TwoTerminalComponent::Wire)
+    }
+
+    pub fn resistor(&mut self, a: usize, b: usize, ohms: f64) -> usize {
+        self.two_terminal(a, b, TwoTerminalComponent::Resistor(ohms))
+    }
+
+    pub fn capacitor(&mut self, a: usize, b: usize, farads: f64) -> usize {
+        self.two_terminal(a, b, TwoTerminalComponent::Capacitor(farads, None, None, 0.0))
+    }
+
+    pub fn inductor(&mut self, a: usize, b: usize, henries: f64) -> usize {
+        self.two_terminal(a, b, TwoTerminalComponent::Inductor(henries, None, None, None, 0.0))
+    }
+
+    /// Conducts from `anode` to `cathode`
+    pub fn diode(&mut self, anode: usize, cathode: usize) -> usize {
+        self.two_terminal(anode, cathode, TwoTerminalComponent::Diode)
+    }
+
+    /// Holds `pos` at `volts` above `neg`
+    pub fn battery(&mut self, neg: usize, pos: usize, volts: f64) -> usize {
+        self.two_terminal(neg, pos, TwoTerminalComponent::Battery(volts, None))
+    }
+
+    /// Like `battery()`, but supplies at most `max_amps`, letting the voltage sag instead
+    pub fn limited_battery(&mut self, neg: usize, pos: usize, volts: f64, max_amps: f64) -> usize {
+        self.two_terminal(
+            neg,
+            pos,
+            TwoTerminalComponent::Battery(volts, Some(max_amps)),
+        )
+    }
+
+    /// Pushes `amps` through itself from `from` to `to`
+    pub fn current_source(&mut self, from: usize, to: usize, amps: f64) -> usize {
+        self.two_terminal(from, to, TwoTerminalComponent::CurrentSource(amps))
+    }
+
+    /// Pushes `gain` times the current through the two-terminal component `sense` through itself,
+    /// from `from` to `to`
+    pub fn current_controlled_source(
+        &mut self,
+        from: usize,
+        to: usize,
+        sense: usize,
+        gain: f64,
+    ) -> usize {
+        self.two_terminal(
+            from,
+            to,
+            TwoTerminalComponent::CurrentControlledSource(gain, Some(sense)),
+        )
+    }
+
+    pub fn switch(&mut self, a: usize, b: usize, open: bool) -> usize {
+        self.two_terminal(a, b, TwoTerminalComponent::Switch(open))
+    }
+
+    pub fn npn(&mut self, collector: usize, base: usize, emitter: usize, beta: f64) -> usize {
+        self.three_terminal(
+            [emitter, base, collector],
+            ThreeTerminalComponent::NTransistor(beta, 0.0, 0.0, DEFAULT_ALPHA_REVERSE),
+        )
+    }
+
+    pub fn pnp(&mut self, collector: usize, base: usize, emitter: usize, beta: f64) -> usize {
+        self.three_terminal(
+            [emitter, base, collector],
+            ThreeTerminalComponent::PTransistor(beta, 0.0, 0.0, DEFAULT_ALPHA_REVERSE),
+        )
+    }
+
+    /// `position` goes from 0, with the wiper at `a`, to 1 with it at `c`
+    pub fn potentiometer(
+        &mut self,
+        a: usize,
+        wiper: usize,
+        c: usize,
+        ohms: f64,
+        position: f64,
+    ) -> usize {
+        self.three_terminal(
+            [a, wiper, c],
+            ThreeTerminalComponent::Potentiometer(ohms, position),
+        )
+    }
+
+    /// Appends the ground as the last node, so that it becomes the 0 V reference
+    pub fn build(self) -> PrimitiveDiagram {
+        let ground = self.num_nodes;
+        let resolve = |node: usize| if node == Self::GROUND { ground } else { node };
+
+        PrimitiveDiagram {
+            num_nodes: self.num_nodes + 1,
+            two_terminal: self
+                .two_terminal
+                .into_iter()
+                .map(|(nodes, component)| (nodes.map(resolve), component))
+                .collect(),
+            three_terminal: self
+                .three_terminal
+                .into_iter()
+                .map(|(nodes, component)| (nodes.map(resolve), component))
+                .collect(),
+            grounds: vec![],
+            unconnected_reference: false,
+            disabled_three_terminal: vec![],
+        }
+    }
+}
@@ -1,6 +1,10 @@
 pub mod solver;
 pub mod map;
 pub mod stamp;
+pub mod builder;
+pub mod monte_carlo;
+pub mod two_port;
+pub mod batch;
 
 /// Represents the simplified topology of the network. This is the input to the simulator.
 /// This is an unsimplified representation, suitable for use with human interfaces.
@@ -9,6 +13,20 @@ pub struct PrimitiveDiagram {
     pub num_nodes: usize,
     pub two_terminal: Vec<([usize; 2], TwoTerminalComponent)>,
     pub three_terminal: Vec<([usize; 3], ThreeTerminalComponent)>,
+    /// Nodes designated as grounds. If this is empty, the last node is the 0 V reference.
+    /// Otherwise exactly one of them must be the reference, and it must be the last node.
+    /// Tied grounds are expected to be connected to the reference by components in
+    /// `two_terminal`; this list only records them.
+    #[serde(default)]
+    pub grounds: Vec<(usize, GroundKind)>,
+    /// A reference ground was placed with nothing connected to it, so it has no node and isn't
+    /// in `grounds`. `check_grounds()` reports it.
+    #[serde(default)]
+    pub unconnected_reference: bool,
+    /// Indices into `three_terminal` of components switched off, which conduct no current at all.
+    /// Two-terminal components can be switched off by replacing them with an open switch.
+    #[serde(default)]
+    pub disabled_three_terminal: Vec<usize>,
 }
 
 /// Output voltage and current, corresponding to the input indices
@@ -20,30 +38,113 @@ pub struct SimOutputs {
     pub three_terminal_current: Vec<[f64; 3]>,
 }
 
+/// Size of the system the solver works on for a `PrimitiveDiagram`, see `PrimitiveDiagram::stats()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiagramStats {
+    pub nodes: usize,
+    pub two_terminal: usize,
+    pub three_terminal: usize,
+    /// Rows (and columns) of the matrix solved on each step or iteration
+    pub matrix_size: usize,
+}
+
 /// Represents a single circuit element.
-#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug)]
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq)]
 pub enum TwoTerminalComponent {
     Wire,
     // Resistance
     Resistor(f64),
-    // Inductance, and hub ID
-    Inductor(f64, Option<u16>),
-    // Capacitance
-    Capacitor(f64),
+    // Inductance, hub ID, initial current, integration method if not the solver's, and series
+    // resistance of the winding (0 for none)
+    Inductor(f64, #[serde(default)] Option<u16>, #[serde(default)] Option<f64>, #[serde(default)] Option<IntegrationMethod>, #[serde(default)] f64),
+    // Capacitance, initial voltage, integration method if not the solver's, and equivalent
+    // series resistance (0 for none)
+    Capacitor(f64, #[serde(default)] Option<f64>, #[serde(default)] Option<IntegrationMethod>, #[serde(default)] f64),
     Diode,
-    Battery(f64),
+    // Voltage, and the most current it supplies before it turns into a current source at that
+    // limit instead
+    Battery(f64, #[serde(default)] Option<f64>),
     Switch(bool),
     CurrentSource(f64),
+    /// Current source pushing the gain times the current through another two-terminal component,
+    /// given by its index in the same list. Doesn't conduct without one.
+    CurrentControlledSource(f64, #[serde(default)] Option<usize>),
+    /// Ammeter. Behaves as a 0 V source, so its current is the current through the branch.
+    CurrentProbe,
+    /// Quartz crystal: motional inductance, capacitance and resistance in series, in parallel
+    /// with the shunt capacitance. The solver has no model for this; it has to be expanded into
+    /// the equivalent circuit on internal nodes when building the `PrimitiveDiagram`.
+    Crystal {
+        ls: f64,
+        cs: f64,
+        rs: f64,
+        cp: f64,
+    },
+    /// Gaussian white noise voltage with an RMS of `amplitude`, positive at the end terminal.
+    /// The solver draws a new value every step from a generator started from `seed`, so the
+    /// noise spreads evenly over frequencies up to 1/(2 dt): the smaller the step, the wider the
+    /// band and the less of it falls within any given one. Behaves as a battery otherwise.
+    NoiseSource {
+        amplitude: f64,
+        seed: u64,
+    },
     /*
     AcSource(Source),
     */
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug)]
+/// How capacitors and inductors are stepped through time. The solver uses the one in
+/// `SolverConfig`, unless a component overrides it.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntegrationMethod {
+    /// Stable whatever the time step, but loses energy: an LC tank rings down even without
+    /// resistance, and the coarser the step the faster. Sudden changes, like a switch closing,
+    /// settle without overshoot.
+    #[default]
+    BackwardEuler,
+    /// Second-order accurate, and conserves the energy of an ideal LC tank, so oscillators keep
+    /// their amplitude. Doesn't damp anything either, so a sudden change can leave the
+    /// component's current (or a capacitor's, its voltage) ringing from step to step.
+    Trapezoidal,
+}
+
+impl IntegrationMethod {
+    pub const ALL: [Self; 2] = [Self::BackwardEuler, Self::Trapezoidal];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::BackwardEuler => "Backward Euler",
+            Self::Trapezoidal => "Trapezoidal",
+        }
+    }
+}
+
+/// Fraction of the emitter junction current that reaches the collector when a transistor runs
+/// backwards, with the roles of emitter and collector swapped. Low, since transistors are built
+/// to work the other way around. The forward fraction follows from beta.
+pub const DEFAULT_ALPHA_REVERSE: f64 = 0.1;
+
+fn default_alpha_reverse() -> f64 {
+    DEFAULT_ALPHA_REVERSE
+}
+
+/// How a ground relates to the 0 V reference
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum GroundKind {
+    /// The 0 V reference
+    Reference,
+    /// Tied to the reference through a resistance, or directly if None
+    Tied(Option<f64>),
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq)]
 pub enum ThreeTerminalComponent {
-    /// Beta
-    PTransistor(f64),
-    NTransistor(f64),
+    /// Beta, base-emitter and base-collector junction capacitance (0 for none), and reverse
+    /// alpha, see `DEFAULT_ALPHA_REVERSE`
+    PTransistor(f64, #[serde(default)] f64, #[serde(default)] f64, #[serde(default = "default_alpha_reverse")] f64),
+    NTransistor(f64, #[serde(default)] f64, #[serde(default)] f64, #[serde(default = "default_alpha_reverse")] f64),
+    /// Total resistance between a and c, and the wiper (b) position, from 0 at a to 1 at c
+    Potentiometer(f64, f64),
 }
 
 impl TwoTerminalComponent {
@@ -51,12 +152,64 @@ impl TwoTerminalComponent {
         match self {
             Self::Wire => "Wire",
             Self::Resistor(_) => "Resistor",
-            Self::Capacitor(_) => "Capacitor",
-            Self::Inductor(_, _) => "Inductor",
-            Self::Battery(_) => "Battery",
+            Self::Capacitor(..) => "Capacitor",
+            Self::Inductor(..) => "Inductor",
+            Self::Battery(..) => "Battery",
             Self::Diode => "Diode",
             Self::Switch(_) => "Switch",
             Self::CurrentSource(_) => "Current Source",
+            Self::CurrentControlledSource(..) => "Current-Controlled Current Source",
+            Self::CurrentProbe => "Current Probe",
+            Self::Crystal { .. } => "Crystal",
+            Self::NoiseSource { .. } => "Noise Source",
+        }
+    }
+
+    /// Multiplies the value which sets the component's behavior, as a manufacturing tolerance
+    /// would. Initial conditions are kept, and components without such a value are unchanged.
+    /// So are crystals, which only reach the solver expanded into their equivalent circuit;
+    /// scale its parts instead.
+    pub fn scaled(self, factor: f64) -> Self {
+        match self {
+            Self::Resistor(r) => Self::Resistor(r * factor),
+            Self::Capacitor(c, ic, method, esr) => Self::Capacitor(c * factor, ic, method, esr),
+            Self::Inductor(l, core, ic, method, r) => Self::Inductor(l * factor, core, ic, method, r),
+            Self::Battery(v, limit) => Self::Battery(v * factor, limit),
+            Self::CurrentSource(i) => Self::CurrentSource(i * factor),
+            Self::CurrentControlledSource(gain, sense) => Self::CurrentControlledSource(gain * factor, sense),
+            Self::NoiseSource { amplitude, seed } => Self::NoiseSource { amplitude: amplitude * factor, seed },
+            Self::Wire | Self::Diode | Self::Switch(_) | Self::CurrentProbe | Self::Crystal { .. } => self,
+        }
+    }
+
+    /// The value which sets the component's behavior, if the component has one. For a crystal,
+    /// this is the motional inductance.
+    pub fn value(&self) -> Option<f64> {
+        match *self {
+            Self::Resistor(r) => Some(r),
+            Self::Capacitor(c, ..) => Some(c),
+            Self::Inductor(l, ..) => Some(l),
+            Self::Battery(v, _) => Some(v),
+            Self::CurrentSource(i) => Some(i),
+            Self::CurrentControlledSource(gain, _) => Some(gain),
+            Self::Crystal { ls, .. } => Some(ls),
+            Self::NoiseSource { amplitude, .. } => Some(amplitude),
+            Self::Wire | Self::Diode | Self::Switch(_) | Self::CurrentProbe => None,
+        }
+    }
+
+    /// Replaces the value returned by `value()`. Components without one are unchanged.
+    pub fn with_value(self, value: f64) -> Self {
+        match self {
+            Self::Resistor(_) => Self::Resistor(value),
+            Self::Capacitor(_, ic, method, esr) => Self::Capacitor(value, ic, method, esr),
+            Self::Inductor(_, core, ic, method, r) => Self::Inductor(value, core, ic, method, r),
+            Self::Battery(_, limit) => Self::Battery(value, limit),
+            Self::CurrentSource(_) => Self::CurrentSource(value),
+            Self::CurrentControlledSource(_, sense) => Self::CurrentControlledSource(value, sense),
+            Self::Crystal { cs, rs, cp, .. } => Self::Crystal { ls: value, cs, rs, cp },
+            Self::NoiseSource { seed, .. } => Self::NoiseSource { amplitude: value, seed },
+            Self::Wire | Self::Diode | Self::Switch(_) | Self::CurrentProbe => self,
         }
     }
 }
@@ -64,24 +217,97 @@ impl TwoTerminalComponent {
 impl ThreeTerminalComponent {
     pub fn name(&self) -> &'static str {
         match self {
-            ThreeTerminalComponent::NTransistor(_) => "N-type Transistor (NPN)",
-            ThreeTerminalComponent::PTransistor(_) => "P-type Transistor (PNP)",
+            ThreeTerminalComponent::NTransistor(..) => "N-type Transistor (NPN)",
+            ThreeTerminalComponent::PTransistor(..) => "P-type Transistor (PNP)",
+            ThreeTerminalComponent::Potentiometer(..) => "Potentiometer",
+        }
+    }
+
+    /// Short labels for the a, b and c terminals, in that order
+    pub fn terminal_labels(&self) -> [&'static str; 3] {
+        match self {
+            ThreeTerminalComponent::NTransistor(..) | ThreeTerminalComponent::PTransistor(..) => ["E", "B", "C"],
+            ThreeTerminalComponent::Potentiometer(..) => ["1", "W", "2"],
         }
     }
 }
 
+impl SimOutputs {
+    /// Net current flowing into each node from the components around it, which Kirchhoff's
+    /// current law says is zero. Anything else means the solver hasn't converged, or a component
+    /// is stamped inconsistently with how its currents are read back.
+    pub fn kcl_residuals(&self, diagram: &PrimitiveDiagram) -> Vec<f64> {
+        let mut net = vec![0.0; diagram.num_nodes];
+
+        // Two-terminal currents flow from begin to end
+        for (&([begin, end], _), current) in diagram.two_terminal.iter().zip(&self.two_terminal_current) {
+            net[begin] -= current;
+            net[end] += current;
+        }
+
+        // The ab branch flows from b into a, and the bc branch from c into b, so a and b read as
+        // the current out of the component, and c as the current into it
+        for (&([a, b, c], _), [ia, ib, ic]) in diagram.three_terminal.iter().zip(&self.three_terminal_current) {
+            net[a] += ia;
+            net[b] += ib;
+            net[c] -= ic;
+        }
+
+        net
+    }
+}
+
 impl PrimitiveDiagram {
+    pub fn stats(&self) -> DiagramStats {
+        DiagramStats {
+            nodes: self.num_nodes,
+            two_terminal: self.two_terminal.len(),
+            three_terminal: self.three_terminal.len(),
+            matrix_size: map::PrimitiveDiagramMapping::new(self).vector_size(),
+        }
+    }
+
     /// Returns (component index, voltage)
     pub fn voltage_sources(&self) -> impl Iterator<Item = (usize, f64)> + '_ {
         self.two_terminal
             .iter()
             .enumerate()
             .filter_map(|(component_idx, &(_, comp))| match comp {
-                crate::TwoTerminalComponent::Battery(v) => Some((component_idx, v)),
+                crate::TwoTerminalComponent::Battery(v, _) => Some((component_idx, v)),
                 _ => None,
             })
     }
 
+    /// Node held at 0 V, which the solver leaves out of its unknowns. This is always the last
+    /// node; `check_grounds()` makes sure that a designated reference ground is there.
+    pub fn reference_node(&self) -> usize {
+        self.num_nodes.saturating_sub(1)
+    }
+
+    /// Checks that the grounds designate exactly one reference, at the last node
+    pub fn check_grounds(&self) -> Result<(), String> {
+        if self.unconnected_reference {
+            return Err("The reference ground isn't connected to anything".into());
+        }
+        if self.grounds.is_empty() {
+            return Ok(());
+        }
+
+        let references: Vec<usize> = self
+            .grounds
+            .iter()
+            .filter(|(_, kind)| *kind == GroundKind::Reference)
+            .map(|(node, _)| *node)
+            .collect();
+
+        match references[..] {
+            [] => Err("No ground is marked as the 0 V reference".into()),
+            [node] if node + 1 == self.num_nodes => Ok(()),
+            [_] => Err("The reference ground must be the last node".into()),
+            _ => Err("More than one ground is marked as the 0 V reference".into()),
+        }
+    }
+
     /// Wire together several indices in bulk
     pub fn solder_blob(&mut self, indices: &[usize]) {
         for i in 0..indices.len() {
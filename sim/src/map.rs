@@ -4,7 +4,7 @@ use crate::PrimitiveDiagram;
 
 /// Maps indices of the state vector (x from Ax = b) to the corresponding component voltages,
 /// currents, etc.
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct PrimitiveDiagramStateVectorMapping {
     pub n_currents: usize,
     pub n_voltage_drops: usize,
@@ -13,7 +13,7 @@ pub struct PrimitiveDiagramStateVectorMapping {
 
 /// Maps indices of the parameters (known values such as input voltage or current or signal).
 /// These are the known variables, or b from Ax = b.
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct PrimitiveDiagramParameterMapping {
     pub n_components: usize,
     pub n_current_laws: usize,
@@ -21,7 +21,7 @@ pub struct PrimitiveDiagramParameterMapping {
 }
 
 /// Represents the mappings needed to work with either the state vector or the parameter map
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct PrimitiveDiagramMapping {
     pub state_map: PrimitiveDiagramStateVectorMapping,
     pub param_map: PrimitiveDiagramParameterMapping,
@@ -39,6 +39,42 @@ impl PrimitiveDiagramMapping {
         debug_assert_eq!(self.state_map.total_len(), self.param_map.total_len());
         self.state_map.total_len()
     }
+
+    /// Describes the unknown at `idx` of the state vector, e.g. "the current through Diode #2"
+    pub fn describe_state(&self, diagram: &PrimitiveDiagram, idx: usize) -> String {
+        let s = &self.state_map;
+        if s.currents().contains(&idx) {
+            format!(
+                "the current through {}",
+                component_name(diagram, idx - s.currents().start)
+            )
+        } else if s.voltage_drops().contains(&idx) {
+            format!(
+                "the voltage across {}",
+                component_name(diagram, idx - s.voltage_drops().start)
+            )
+        } else {
+            format!("the voltage at node {}", idx - s.voltages().start)
+        }
+    }
+
+    /// Describes the equation at `idx` of the parameter vector, e.g. "the current law at node 3"
+    pub fn describe_param(&self, diagram: &PrimitiveDiagram, idx: usize) -> String {
+        let p = &self.param_map;
+        if p.components().contains(&idx) {
+            format!(
+                "the component law of {}",
+                component_name(diagram, idx - p.components().start)
+            )
+        } else if p.current_laws().contains(&idx) {
+            format!("the current law at node {}", idx - p.current_laws().start)
+        } else {
+            format!(
+                "the voltage law of {}",
+                component_name(diagram, idx - p.voltage_laws().start)
+            )
+        }
+    }
 }
 
 /// Vector indices for each of component laws, current laws, voltage laws.
@@ -98,3 +134,15 @@ impl PrimitiveDiagramStateVectorMapping {
         self.n_currents + self.n_voltages + self.n_voltage_drops
     }
 }
+
+/// Name and index of the component owning the `idx`th law, current or voltage drop. Each
+/// three-terminal component owns two, after those of all two-terminal components.
+fn component_name(diagram: &PrimitiveDiagram, idx: usize) -> String {
+    match diagram.two_terminal.get(idx) {
+        Some((_, component)) => format!("{} #{idx}", component.name()),
+        None => {
+            let idx = (idx - diagram.two_terminal.len()) / 2;
+            format!("{} #{idx}", diagram.three_terminal[idx].1.name())
+        }
+    }
+}
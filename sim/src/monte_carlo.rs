@@ -0,0 +1,104 @@
+use crate::{
+    solver::{Solver, SolverConfig, SolverError},
+    PrimitiveDiagram,
+};
+
+/// How to run a Monte Carlo analysis, see `monte_carlo()`
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug)]
+pub struct MonteCarloConfig {
+    pub trials: usize,
+    /// Runs with the same seed draw the same component values
+    pub seed: u64,
+    pub dt: f64,
+    /// Time steps simulated in each trial before reading the output
+    pub steps: usize,
+}
+
+/// Simulates `mc.trials` copies of the diagram, each with the value of every two-terminal
+/// component drawn uniformly within its tolerance (e.g. 0.05 for ±5%), and returns the voltage
+/// of `output_node` at the end of each trial. Components past the end of `tolerances` keep
+/// their exact values.
+pub fn monte_carlo(diagram: &PrimitiveDiagram, tolerances: &[f64], output_node: usize, mc: &MonteCarloConfig, cfg: &SolverConfig) -> Result<Vec<f64>, SolverError> {
+    let mut rng = SplitMix64(mc.seed);
+    let mut outputs = Vec::with_capacity(mc.trials);
+
+    for _ in 0..mc.trials {
+        let mut trial = diagram.clone();
+        for ((_, component), tolerance) in trial.two_terminal.iter_mut().zip(tolerances) {
+            let factor = 1.0 + tolerance * (2.0 * rng.next_f64() - 1.0);
+            *component = component.scaled(factor);
+        }
+
+        let mut solver = Solver::new(&trial);
+        for _ in 0..mc.steps {
+            solver.step(mc.dt, &trial, cfg, None)?;
+        }
+
+        let voltages = solver.state(&trial).voltages;
+        outputs.push(voltages.get(output_node).copied().unwrap_or(f64::NAN));
+    }
+
+    Ok(outputs)
+}
+
+/// Small, seedable random number generator, so that trials are reproducible without pulling in
+/// a dependency. Also drives noise sources, see `Solver`.
+#[derive(Clone)]
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal, by the Box-Muller transform
+    pub(crate) fn next_gaussian(&mut self) -> f64 {
+        // 1 - u is in (0, 1], so the log is finite
+        let radius = (-2.0 * (1.0 - self.next_f64()).ln()).sqrt();
+        radius * (std::f64::consts::TAU * self.next_f64()).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DiagramBuilder;
+    use crate::solver::SolverMode;
+
+    #[test]
+    fn same_seed_draws_same_values() {
+        let mut builder = DiagramBuilder::new();
+        let supply = builder.node();
+        let output = builder.node();
+        builder.battery(DiagramBuilder::GROUND, supply, 10.0);
+        builder.resistor(supply, output, 1e3);
+        builder.resistor(output, DiagramBuilder::GROUND, 1e3);
+        let diagram = builder.build();
+
+        let tolerances = vec![0.05; diagram.two_terminal.len()];
+        let cfg = SolverConfig {
+            mode: SolverMode::Linear,
+            ..Default::default()
+        };
+        let run = |seed| {
+            let mc = MonteCarloConfig { trials: 20, seed, dt: 1e-3, steps: 1 };
+            monte_carlo(&diagram, &tolerances, output, &mc, &cfg).unwrap()
+        };
+
+        let first = run(1);
+        assert_eq!(first, run(1));
+        assert_ne!(first, run(2));
+        // The trials themselves differ, and stay within the tolerances
+        assert!(first.iter().any(|&v| v != first[0]), "{first:?}");
+        assert!(first.iter().all(|v| (v - 5.0).abs() < 5.0 * 0.16), "{first:?}");
+    }
+}
@@ -1,11 +1,92 @@
 
-use rsparse::{data::Trpl, lusol};
+use std::collections::HashMap;
 
-use crate::{map::PrimitiveDiagramMapping, stamp::stamp, PrimitiveDiagram, SimOutputs};
+use rsparse::{data::Sprs, lusol};
 
+use crate::{
+    batch,
+    map::PrimitiveDiagramMapping,
+    monte_carlo::SplitMix64,
+    stamp::{find_empty_equation, stamp_dynamic, StampCache},
+    IntegrationMethod, PrimitiveDiagram, SimOutputs, TwoTerminalComponent,
+};
+
+/// Most times a line search halves the Newton-Raphson update, see `SolverConfig::line_search`
+const MAX_BACKTRACKS: usize = 10;
+
+/// Samples kept per component by default, see `Solver::set_history_capacity()`
+pub const DEFAULT_HISTORY_CAPACITY: usize = 10_000;
+
+/// Why a time step failed
+#[derive(Clone, Debug)]
+pub enum SolverError {
+    /// The matrix couldn't be factored at the start of the step, e.g. a node is floating or
+    /// two ideal sources fight over the same voltage
+    Singular,
+    /// Newton-Raphson diverged partway through the step. A smaller dt may help.
+    NotConverged { iters: usize, residual: f64 },
+    /// The diagram can't be simulated as drawn, e.g. it has no reference ground
+    InvalidTopology(String),
+    /// The step produced an infinite or NaN value, named by the message
+    NonFinite(String),
+}
+
+impl std::fmt::Display for SolverError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Singular => write!(
+                f,
+                "Singular matrix; check for floating nodes or conflicting sources"
+            ),
+            Self::NotConverged { iters, residual } => write!(
+                f,
+                "Failed to converge after {iters} iterations (residual {residual:.3e})"
+            ),
+            Self::InvalidTopology(msg) => write!(f, "{msg}"),
+            Self::NonFinite(what) => write!(
+                f,
+                "Infinite or NaN result for {what}; check for extreme component values"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SolverError {}
+
+#[derive(Clone)]
 pub struct Solver {
     pub map: PrimitiveDiagramMapping,
     pub soln_vector: Vec<f64>,
+    /// Matrix entries which only depend on the topology, see `stamp_static`
+    stamps: StampCache,
+    /// Simulated time, in seconds
+    time: f64,
+    /// Error of each Newton-Raphson iteration in the most recent step
+    residuals: Vec<f64>,
+    /// Largest absolute change of any entry of `soln_vector` in the most recent step
+    last_change: f64,
+    /// Recorded samples of each two-terminal component, see `record_enabled()`. Each may hold up
+    /// to twice `history_capacity` before the oldest are dropped, so that dropping is rare.
+    history: Vec<Vec<HistorySample>>,
+    record: bool,
+    history_capacity: usize,
+    /// Generator of each noise source by component index, with the seed it was started from.
+    /// Starts over with the solver, or when the seed changes.
+    noise: HashMap<usize, (u64, SplitMix64)>,
+    /// First diagram and size of the batch given to `new_batch()`, which every later batch has
+    /// to match, since the static part of the matrix was stamped for it
+    batch: Option<(PrimitiveDiagram, usize)>,
+}
+
+/// A two-terminal component at the end of a step, see `Solver::history()`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HistorySample {
+    /// Simulated time, in seconds
+    pub time: f64,
+    /// Voltage of the end terminal relative to the beginning one
+    pub voltage_drop: f64,
+    /// Current from the beginning terminal to the end one
+    pub current: f64,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -16,6 +97,45 @@ pub enum SolverMode {
     NewtonRaphson,
 }
 
+/// Order in which the LU factorization eliminates unknowns. A fill-reducing ordering keeps the
+/// factors sparser on large circuits, at the cost of working it out again on every solve.
+#[derive(serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum ColumnOrdering {
+    /// As the unknowns are laid out, see `PrimitiveDiagramMapping`
+    #[default]
+    Natural,
+    /// Approximate minimum degree of A + Aᵀ
+    AmdSymmetric,
+    /// Approximate minimum degree of AᵀA, leaving out dense rows
+    AmdLu,
+    /// Approximate minimum degree of AᵀA
+    AmdQr,
+}
+
+impl ColumnOrdering {
+    pub const ALL: [Self; 4] = [Self::Natural, Self::AmdSymmetric, Self::AmdLu, Self::AmdQr];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Natural => "Natural",
+            Self::AmdSymmetric => "AMD (A + Aᵀ)",
+            Self::AmdLu => "AMD (LU)",
+            Self::AmdQr => "AMD (AᵀA)",
+        }
+    }
+
+    /// The `order` argument of `rsparse::lusol()`
+    fn rsparse_order(self) -> i8 {
+        match self {
+            Self::Natural => -1,
+            Self::AmdSymmetric => 0,
+            Self::AmdLu => 1,
+            Self::AmdQr => 2,
+        }
+    }
+}
+
 #[derive(serde::Deserialize, serde::Serialize)]
 #[derive(Clone, Copy, Debug)]
 pub struct SolverConfig {
@@ -28,40 +148,253 @@ pub struct SolverConfig {
     pub mode: SolverMode,
     #[serde(default)]
     pub adaptive_step_size: bool,
+    /// Take full Newton-Raphson updates, halving them until they reduce the residual, instead of
+    /// scaling them by `nr_step_size`
+    #[serde(default)]
+    pub line_search: bool,
+    #[serde(default)]
+    pub ordering: ColumnOrdering,
+    /// Used for capacitors and inductors which don't choose their own
+    #[serde(default)]
+    pub integration: IntegrationMethod,
 }
 
 impl Solver {
     pub fn new(diagram: &PrimitiveDiagram) -> Self {
         let map = PrimitiveDiagramMapping::new(diagram);
 
+        let mut soln_vector = vec![0.0; map.vector_size()];
+
+        // Seed initial conditions. The voltage drop state runs from the end terminal to the
+        // beginning, so it's the negative of the voltage across the component.
+        for (idx, (_, component)) in diagram.two_terminal.iter().enumerate() {
+            match *component {
+                TwoTerminalComponent::Capacitor(_, Some(voltage), ..) => {
+                    soln_vector[map.state_map.voltage_drops().nth(idx).unwrap()] = -voltage;
+                }
+                TwoTerminalComponent::Inductor(_, _, Some(current), ..) => {
+                    soln_vector[map.state_map.currents().nth(idx).unwrap()] = current;
+                }
+                _ => (),
+            }
+        }
+
         Self {
-            soln_vector: vec![0.0; map.vector_size()],
+            soln_vector,
+            stamps: StampCache::new(&map, diagram),
             map,
+            time: 0.0,
+            residuals: vec![],
+            last_change: f64::INFINITY,
+            history: vec![],
+            record: false,
+            history_capacity: DEFAULT_HISTORY_CAPACITY,
+            noise: HashMap::new(),
+            batch: None,
+        }
+    }
+
+    /// Starts or stops recording the voltage drop and current of every two-terminal component
+    /// after each step, see `history()`. Stopping discards what was recorded.
+    pub fn record_enabled(&mut self, enabled: bool) {
+        self.record = enabled;
+        if !enabled {
+            self.history.clear();
+        }
+    }
+
+    /// Most samples `history()` returns per component, the most recent ones. Defaults to
+    /// `DEFAULT_HISTORY_CAPACITY`.
+    pub fn set_history_capacity(&mut self, capacity: usize) {
+        self.history_capacity = capacity;
+        for samples in &mut self.history {
+            let excess = samples.len().saturating_sub(capacity);
+            samples.drain(..excess);
+        }
+    }
+
+    /// Samples of a two-terminal component recorded since recording started, oldest first. Empty
+    /// if nothing was recorded or the index is out of range.
+    pub fn history(&self, component_idx: usize) -> &[HistorySample] {
+        let Some(samples) = self.history.get(component_idx) else {
+            return &[];
+        };
+        &samples[samples.len().saturating_sub(self.history_capacity)..]
+    }
+
+    fn record_history(&mut self, diagram: &PrimitiveDiagram) {
+        let state = self.state(diagram);
+        self.history.resize_with(diagram.two_terminal.len(), Vec::new);
+
+        for (samples, (&([begin, end], _), &current)) in self
+            .history
+            .iter_mut()
+            .zip(diagram.two_terminal.iter().zip(&state.two_terminal_current))
+        {
+            if samples.len() >= 2 * self.history_capacity.max(1) {
+                samples.drain(..samples.len() - self.history_capacity);
+            }
+            samples.push(HistorySample {
+                time: self.time,
+                voltage_drop: state.voltages[end] - state.voltages[begin],
+                current,
+            });
         }
     }
 
+    /// Creates a solver which steps all of `diagrams` together, e.g. the variants of a sweep.
+    /// They must share a topology and differ only in component values, see `batch::merge()`.
+    /// One factorization then covers every variant, rather than one each. Use `step_batch()`
+    /// and `batch_state()` instead of `step()` and `state()`.
+    pub fn new_batch(diagrams: &[PrimitiveDiagram]) -> Result<Self, SolverError> {
+        let merged = batch::merge(diagrams).map_err(SolverError::InvalidTopology)?;
+        Ok(Self {
+            batch: Some((diagrams[0].clone(), diagrams.len())),
+            ..Self::new(&merged)
+        })
+    }
+
+    /// Steps a solver from `new_batch()`. `diagrams` may have new component values, but must
+    /// have the topology and count the solver was created with. The Newton-Raphson tolerance
+    /// applies to the batch as a whole, and an error in any diagram fails the whole step.
+    pub fn step_batch(&mut self, dt: f64, diagrams: &[PrimitiveDiagram], cfg: &SolverConfig) -> Result<(), SolverError> {
+        // merge() only compares the diagrams with each other, so compare the first with the
+        // batch this solver was created for
+        let matches = self.batch.as_ref().is_some_and(|(first, count)| diagrams.len() == *count && diagrams.first().is_some_and(|diagram| batch::same_topology(first, diagram)));
+        if !matches {
+            return Err(SolverError::InvalidTopology(
+                "The batch doesn't match the one this solver was created with".into(),
+            ));
+        }
+        let merged = batch::merge(diagrams).map_err(SolverError::InvalidTopology)?;
+        self.step(dt, &merged, cfg, None)
+    }
+
+    /// Outputs of each diagram of a batch, in order, see `new_batch()`
+    pub fn batch_state(&self, diagrams: &[PrimitiveDiagram]) -> Vec<SimOutputs> {
+        let Some(first) = diagrams.first() else {
+            return vec![];
+        };
+        let Ok(merged) = batch::merge(diagrams) else {
+            return vec![];
+        };
+        batch::split(&self.state(&merged), first, diagrams.len())
+    }
+
+    /// Copies the current solution, to be restored later with `restore()`
+    pub fn snapshot(&self) -> Vec<f64> {
+        self.soln_vector.clone()
+    }
+
+    /// Replaces the current solution with a snapshot. Fails if the snapshot was taken from a
+    /// solver with a different vector size, e.g. before the diagram was changed.
+    pub fn restore(&mut self, snapshot: &[f64]) -> Result<(), String> {
+        if snapshot.len() != self.map.vector_size() {
+            return Err(format!(
+                "Snapshot has {} entries, but the circuit needs {}",
+                snapshot.len(),
+                self.map.vector_size()
+            ));
+        }
+
+        self.soln_vector.copy_from_slice(snapshot);
+        self.last_change = f64::INFINITY;
+        // The recording would otherwise jump to the restored state without a break
+        self.history.clear();
+        Ok(())
+    }
+
+    /// Total simulated time since this solver was created, in seconds
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Error of each Newton-Raphson iteration of the most recent step, including iterations
+    /// rejected by the adaptive step size. Empty after a linear step.
+    pub fn last_residuals(&self) -> &[f64] {
+        &self.residuals
+    }
+
+    /// Largest absolute change of any voltage or current in the most recent step. Infinite before
+    /// the first step.
+    pub fn last_change(&self) -> f64 {
+        self.last_change
+    }
+
+    /// True if no voltage or current changed by more than `tolerance` in the most recent step.
+    /// The change per step shrinks with dt, so the tolerance should take it into account.
+    pub fn is_steady(&self, tolerance: f64) -> bool {
+        self.last_change <= tolerance
+    }
+
     /// Note: Assumes diagram is compatible what a sufficiently large battery (or a battery with very low internal resisith the one this solver was created with!
-    pub fn step(&mut self, dt: f64, diagram: &PrimitiveDiagram, cfg: &SolverConfig, external_params: Option<&[f64]>) -> Result<(), String> {
+    pub fn step(&mut self, dt: f64, diagram: &PrimitiveDiagram, cfg: &SolverConfig, external_params: Option<&[f64]>) -> Result<(), SolverError> {
+        self.residuals.clear();
+        diagram.check_grounds().map_err(SolverError::InvalidTopology)?;
+
+        // Drawn once per step, so that every iteration sees the same noise
+        let noise_params = self.sample_noise(diagram, external_params);
+        let external_params = noise_params.as_deref().or(external_params);
+
+        let before = self.soln_vector.clone();
         match cfg.mode {
             SolverMode::NewtonRaphson => self.nr_step(dt, diagram, cfg, external_params),
             SolverMode::Linear => self.linear_step(dt, diagram, cfg, external_params),
+        }?;
+
+        // Keep the last good solution rather than showing garbage, and say where it went wrong
+        if let Some(idx) = self.soln_vector.iter().position(|x| !x.is_finite()) {
+            let what = self.map.describe_state(diagram, idx);
+            self.soln_vector = before;
+            return Err(SolverError::NonFinite(what));
+        }
+        self.last_change = before.iter().zip(&self.soln_vector).map(|(a, b)| (a - b).abs()).fold(0.0, f64::max);
+
+        self.time += dt;
+
+        if self.record {
+            self.record_history(diagram);
+        }
+
+        Ok(())
+    }
+
+    /// External params with this step's voltage of each noise source filled in, or None if the
+    /// diagram has none
+    fn sample_noise(&mut self, diagram: &PrimitiveDiagram, external_params: Option<&[f64]>) -> Option<Vec<f64>> {
+        let mut params: Option<Vec<f64>> = None;
+        for (idx, &(_, component)) in diagram.two_terminal.iter().enumerate() {
+            let TwoTerminalComponent::NoiseSource { amplitude, seed } = component else {
+                continue;
+            };
+            let (start, rng) = self.noise.entry(idx).or_insert((seed, SplitMix64(seed)));
+            if *start != seed {
+                *start = seed;
+                *rng = SplitMix64(seed);
+            }
+
+            let params = params.get_or_insert_with(|| external_params.map_or_else(|| vec![0.0; self.map.vector_size()], <[f64]>::to_vec));
+            let law_idx = self.map.param_map.components().nth(idx).unwrap();
+            params[law_idx] = amplitude * rng.next_gaussian();
         }
+        params
     }
 
-    fn linear_step(&mut self, dt: f64, diagram: &PrimitiveDiagram, cfg: &SolverConfig, external_params: Option<&[f64]>) -> Result<(), String> {
+    fn linear_step(&mut self, dt: f64, diagram: &PrimitiveDiagram, cfg: &SolverConfig, external_params: Option<&[f64]>) -> Result<(), SolverError> {
         let prev_time_step_soln = &self.soln_vector;
 
-        let (matrix, params) = stamp(dt, &self.map, diagram, &prev_time_step_soln, &prev_time_step_soln, external_params);
+        let (matrix, params) = stamp_dynamic(dt, cfg.integration, &self.map, diagram, &mut self.stamps, &prev_time_step_soln, &prev_time_step_soln, external_params);
 
-        let mut new_soln = params;
-        lusol(&matrix, &mut new_soln, -1, cfg.dx_soln_tolerance).map_err(|e| e.to_string())?;
+        // Solved in place, and kept as the new solution
+        let mut new_soln = params.to_vec();
+        lusol(&matrix, &mut new_soln, cfg.ordering.rsparse_order(), cfg.dx_soln_tolerance).map_err(|_| self.singular(diagram, &matrix))?;
 
         self.soln_vector = new_soln;
 
         Ok(())
     }
 
-    fn nr_step(&mut self, dt: f64, diagram: &PrimitiveDiagram, cfg: &SolverConfig, external_params: Option<&[f64]>) -> Result<(), String> {
+    fn nr_step(&mut self, dt: f64, diagram: &PrimitiveDiagram, cfg: &SolverConfig, external_params: Option<&[f64]>) -> Result<(), SolverError> {
         let prev_time_step_soln = &self.soln_vector;
 
         let mut new_state = prev_time_step_soln.clone();
@@ -69,56 +402,131 @@ impl Solver {
         let mut step_size: f64 = cfg.nr_step_size;
 
         let mut last_err = 9e99;
+        // The state before the last update, the update, and the error there, for backing off
+        // when the update overshoots
+        let mut backup = new_state.clone();
+        let mut last_delta = vec![0.0; new_state.len()];
+        let mut backup_err = f64::INFINITY;
+        // Each state tried by the line search
+        let mut candidate = Vec::with_capacity(new_state.len());
         let mut nr_iters = 0;
+        let mut converged = false;
         for _ in 0..cfg.max_nr_iters {
             // Calculate A(w_n(K)), b(w_n(K))
-            let (matrix, params) = stamp(dt, &self.map, diagram, &new_state, &prev_time_step_soln, external_params);
+            let (matrix, params) = stamp_dynamic(dt, cfg.integration, &self.map, diagram, &mut self.stamps, &new_state, &prev_time_step_soln, external_params);
 
             if params.len() == 0 {
                 return Ok(());
             }
 
-            let mut dense_b = Trpl::new();
-            for (i, val) in params.iter().enumerate() {
-                dense_b.append(i, 0, *val);
+            // Calculate -f(w_n(K)) = b(w_n(K)) - A(w_n(K)) w_n(K)
+            let mut delta = residual(&matrix, params, &new_state);
+            let f_norm = delta.iter().map(|f| f.powi(2)).sum::<f64>();
+
+            // Solve A(w_n(K)) dw = -f for dw
+            if lusol(&matrix, &mut delta, cfg.ordering.rsparse_order(), cfg.dx_soln_tolerance).is_err() {
+                // Failing on the first iteration means the last converged solution doesn't
+                // work either, so blame the circuit rather than the iteration
+                return Err(if nr_iters == 0 {
+                    self.singular(diagram, &matrix)
+                } else {
+                    SolverError::NotConverged {
+                        iters: nr_iters,
+                        residual: last_err,
+                    }
+                });
             }
-            let dense_b = dense_b.to_sprs();
 
+            // dw dot dw
+            // The adaptive step size changes as we go, so measure the full update there.
+            // Otherwise a step shrunk far enough passes the tolerance wherever it is.
+            let scale = if cfg.line_search || cfg.adaptive_step_size { 1.0 } else { step_size };
+            let err = delta.iter().map(|f| (f * scale).powi(2)).sum::<f64>();
+            self.residuals.push(err);
 
-            let mut new_state_sparse = Trpl::new();
-            for (i, val) in new_state.iter().enumerate() {
-                new_state_sparse.append(i, 0, *val);
+            if let Some(idx) = delta.iter().position(|x| !x.is_finite()) {
+                return Err(SolverError::NonFinite(self.map.describe_state(diagram, idx)));
+            }
+            // Every entry is finite, but together they overflow. Blame the largest, or the last
+            // of equally large ones.
+            if !err.is_finite() {
+                let largest = (0..delta.len()).max_by(|&a, &b| delta[a].abs().total_cmp(&delta[b].abs())).unwrap_or(0);
+                return Err(SolverError::NonFinite(self.map.describe_state(diagram, largest)));
             }
-            let new_state_sparse = new_state_sparse.to_sprs();
 
-            // Calculate -f(w_n(K)) = b(w_n(K)) - A(w_n(K)) w_n(K)
-            let ax = &matrix * &new_state_sparse;
-            let f = dense_b - ax;
+            if cfg.line_search {
+                // Backtrack from the full update until it brings the residual down
+                let mut alpha = 1.0;
+                let mut decreased = false;
+                for _ in 0..MAX_BACKTRACKS {
+                    candidate.clear();
+                    candidate.extend(new_state.iter().zip(&delta).map(|(n, delta)| n + delta * alpha));
+                    let (matrix, params) = stamp_dynamic(dt, cfg.integration, &self.map, diagram, &mut self.stamps, &candidate, &prev_time_step_soln, external_params);
+                    if residual(&matrix, params, &candidate).iter().map(|f| f.powi(2)).sum::<f64>() < f_norm {
+                        decreased = true;
+                        break;
+                    }
+                    alpha /= 2.0;
+                }
+                // Near a diode's knee no shorter step may help either, and crawling along with a
+                // tiny one stalls. Take the full update, as the undamped method would.
+                if !decreased {
+                    alpha = 1.0;
+                }
 
-            // Solve A(w_n(K)) dw = -f for dw
-            let mut delta: Vec<f64> = f.to_dense().iter().flatten().copied().collect();
-            lusol(&matrix, &mut delta, -1, cfg.dx_soln_tolerance).map_err(|e| e.to_string())?;
+                new_state.iter_mut().zip(&delta).for_each(|(n, delta)| *n += delta * alpha);
 
-            // dw dot dw
-            let err = delta.iter().map(|f| (f * step_size).powi(2)).sum::<f64>();
+                if err < cfg.nr_tolerance {
+                    converged = true;
+                    break;
+                }
 
-            if err > last_err && cfg.adaptive_step_size {
                 last_err = err;
-                step_size /= 2.0;
+                nr_iters += 1;
                 continue;
             }
 
+            // The last update overshot if this one points back and is larger. Undo it, and take
+            // half of it instead. Growth alone isn't enough: from a cold start, the updates grow
+            // while a diode comes up to its knee, since its tangent below the knee barely conducts.
+            if cfg.adaptive_step_size {
+                let reversed = delta.iter().zip(&last_delta).map(|(a, b)| a * b).sum::<f64>() < 0.0;
+                if err > backup_err && reversed {
+                    step_size /= 2.0;
+                    new_state.copy_from_slice(&backup);
+                    new_state.iter_mut().zip(&last_delta).for_each(|(n, delta)| *n += delta * step_size);
+                    continue;
+                }
+                backup_err = err;
+            }
+
             // w += dw * step size
+            backup.copy_from_slice(&new_state);
             new_state.iter_mut().zip(&delta).for_each(|(n, delta)| *n += delta * step_size);
+            last_delta = delta;
 
             if err < cfg.nr_tolerance {
+                converged = true;
                 break;
             }
 
+            // Let the step grow back after an overshoot, so one doesn't slow the rest of the iteration
+            if cfg.adaptive_step_size {
+                step_size = (step_size * 2.0).min(cfg.nr_step_size);
+            }
+
             last_err = err;
             nr_iters += 1;
         }
 
+        // Out of iterations. The state is wherever the iteration stopped, so keep the last step.
+        if !converged {
+            return Err(SolverError::NotConverged {
+                iters: cfg.max_nr_iters,
+                residual: last_err,
+            });
+        }
+
         self.soln_vector = new_state;
 
         Ok(())
@@ -126,8 +534,7 @@ impl Solver {
 
     pub fn state(&self, diagram: &PrimitiveDiagram) -> SimOutputs {
         let mut voltages = self.soln_vector[self.map.state_map.voltages()].to_vec();
-        // Last node voltage is ground!
-        voltages.push(0.0);
+        voltages.insert(diagram.reference_node().min(voltages.len()), 0.0);
 
         let mut total_idx = 0;
         let mut two_terminal_current = vec![];
@@ -163,6 +570,26 @@ impl Solver {
     pub fn map(&self) -> &PrimitiveDiagramMapping {
         &self.map
     }
+
+    /// Error for a matrix which couldn't be factored, naming the culprit if it's obvious
+    fn singular(&self, diagram: &PrimitiveDiagram, matrix: &Sprs<f64>) -> SolverError {
+        match find_empty_equation(&self.map, diagram, matrix) {
+            Some(msg) => SolverError::InvalidTopology(msg),
+            None => SolverError::Singular,
+        }
+    }
+}
+
+/// b(w) - A(w) w, by how much `state` misses the equations stamped at `state`
+fn residual(matrix: &Sprs<f64>, params: &[f64], state: &[f64]) -> Vec<f64> {
+    // Straight from the compressed columns, rather than through sparse vectors
+    let mut f = params.to_vec();
+    for (col, value) in state.iter().enumerate().take(matrix.n) {
+        for k in matrix.p[col] as usize..matrix.p[col + 1] as usize {
+            f[matrix.i[k]] -= matrix.x[k] * value;
+        }
+    }
+    f
 }
 
 impl Default for SolverConfig {
@@ -174,6 +601,436 @@ impl Default for SolverConfig {
             nr_tolerance: 1e-6,
             nr_step_size: 1e-1,
             max_nr_iters: 2000,
+            line_search: false,
+            ordering: ColumnOrdering::default(),
+            integration: IntegrationMethod::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::builder::DiagramBuilder;
+
+    #[test]
+    fn rc_discharges_from_initial_condition() {
+        let (r, c, v0) = (1e3, 1e-6, 5.0);
+        let mut builder = DiagramBuilder::new();
+        let top = builder.node();
+        builder.two_terminal(DiagramBuilder::GROUND, top, TwoTerminalComponent::Capacitor(c, Some(v0), None, 0.0));
+        builder.resistor(top, DiagramBuilder::GROUND, r);
+        let diagram = builder.build();
+
+        let cfg = SolverConfig {
+            mode: SolverMode::Linear,
+            integration: IntegrationMethod::Trapezoidal,
+            ..Default::default()
+        };
+        let tau = r * c;
+        let steps = 1000;
+        let mut solver = Solver::new(&diagram);
+        for _ in 0..steps {
+            solver.step(tau / steps as f64, &diagram, &cfg, None).unwrap();
+        }
+
+        // One time constant in, the voltage is down to 1/e of where it started
+        let v = solver.state(&diagram).voltages[top];
+        let expected = v0 * (-1.0f64).exp();
+        assert!((v - expected).abs() < 1e-3 * v0, "{v} V after one time constant, expected {expected} V");
+    }
+
+    #[test]
+    fn old_files_without_initial_conditions_load() {
+        let old: Vec<TwoTerminalComponent> = ron::from_str("[Capacitor(0.0001), Inductor(0.01), Capacitor(0.0001, None), Inductor(0.08, None)]").unwrap();
+        assert_eq!(
+            old,
+            [
+                TwoTerminalComponent::Capacitor(1e-4, None, None, 0.0),
+                TwoTerminalComponent::Inductor(0.01, None, None, None, 0.0),
+                TwoTerminalComponent::Capacitor(1e-4, None, None, 0.0),
+                TwoTerminalComponent::Inductor(0.08, None, None, None, 0.0),
+            ]
+        );
+    }
+
+    /// Battery through a resistor into a diode, with the resistor either at the top or at the
+    /// bottom. Both have the same nodes and components.
+    fn divider(r: f64, resistor_on_top: bool) -> PrimitiveDiagram {
+        let mut builder = DiagramBuilder::new();
+        let supply = builder.node();
+        let mid = builder.node();
+        builder.battery(DiagramBuilder::GROUND, supply, 5.0);
+        if resistor_on_top {
+            builder.resistor(supply, mid, r);
+            builder.diode(mid, DiagramBuilder::GROUND);
+        } else {
+            builder.diode(supply, mid);
+            builder.resistor(mid, DiagramBuilder::GROUND, r);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn batch_matches_stepping_alone() {
+        let diagrams: Vec<PrimitiveDiagram> = [100.0, 1e3, 10e3].into_iter().map(|r| divider(r, true)).collect();
+        // The tolerance applies to the whole batch, so iterate well past where either stops
+        let cfg = SolverConfig {
+            nr_tolerance: 1e-20,
+            line_search: true,
+            ..Default::default()
+        };
+        let (dt, steps) = (1e-3, 20);
+
+        let mut batch = Solver::new_batch(&diagrams).unwrap();
+        for _ in 0..steps {
+            batch.step_batch(dt, &diagrams, &cfg).unwrap();
+        }
+
+        for (diagram, batched) in diagrams.iter().zip(batch.batch_state(&diagrams)) {
+            let mut alone = Solver::new(diagram);
+            for _ in 0..steps {
+                alone.step(dt, diagram, &cfg, None).unwrap();
+            }
+            let alone = alone.state(diagram);
+            for (a, b) in alone.voltages.iter().zip(&batched.voltages) {
+                assert!((a - b).abs() < 1e-6, "{:?} alone, {:?} batched", alone.voltages, batched.voltages);
+            }
+            for (a, b) in alone.two_terminal_current.iter().zip(&batched.two_terminal_current) {
+                assert!((a - b).abs() < 1e-9, "{:?} alone, {:?} batched", alone.two_terminal_current, batched.two_terminal_current);
+            }
+        }
+    }
+
+    #[test]
+    fn batch_rejects_rewired_diagrams() {
+        let diagrams = [divider(1e3, true), divider(2e3, true)];
+        let mut batch = Solver::new_batch(&diagrams).unwrap();
+
+        // Same counts, so the same vector size, but connected differently
+        let rewired = [divider(1e3, false), divider(2e3, false)];
+        assert!(matches!(batch.step_batch(1e-3, &rewired, &SolverConfig::default()), Err(SolverError::InvalidTopology(_))));
+        assert!(matches!(batch.step_batch(1e-3, &diagrams[..1], &SolverConfig::default()), Err(SolverError::InvalidTopology(_))));
+        batch.step_batch(1e-3, &diagrams, &SolverConfig::default()).unwrap();
+    }
+
+    #[test]
+    fn current_probe_reads_branch_current() {
+        let mut builder = DiagramBuilder::new();
+        let supply = builder.node();
+        let mid = builder.node();
+        builder.battery(DiagramBuilder::GROUND, supply, 5.0);
+        let resistor = builder.resistor(supply, mid, 1e3);
+        let probe = builder.two_terminal(mid, DiagramBuilder::GROUND, TwoTerminalComponent::CurrentProbe);
+        let diagram = builder.build();
+
+        let cfg = SolverConfig {
+            mode: SolverMode::Linear,
+            ..Default::default()
+        };
+        let mut solver = Solver::new(&diagram);
+        solver.step(1e-3, &diagram, &cfg, None).unwrap();
+
+        let state = solver.state(&diagram);
+        let currents = &state.two_terminal_current;
+        assert!((currents[resistor] - 5e-3).abs() < 1e-12, "{currents:?}");
+        assert_eq!(currents[probe], currents[resistor]);
+        // No voltage across the probe
+        assert_eq!(state.voltages[mid], 0.0);
+    }
+
+    #[test]
+    fn current_mirror_copies_sensed_current() {
+        // The reference branch draws 5 V through 1 kΩ past a probe, and a gain of 1 pushes the
+        // same current through loads of very different sizes
+        for load in [10.0, 1e3, 100e3] {
+            let mut builder = DiagramBuilder::new();
+            let supply = builder.node();
+            let reference = builder.node();
+            let out = builder.node();
+            builder.battery(DiagramBuilder::GROUND, supply, 5.0);
+            builder.resistor(supply, reference, 1e3);
+            let probe = builder.two_terminal(reference, DiagramBuilder::GROUND, TwoTerminalComponent::CurrentProbe);
+            builder.current_controlled_source(DiagramBuilder::GROUND, out, probe, 1.0);
+            let resistor = builder.resistor(out, DiagramBuilder::GROUND, load);
+            let diagram = builder.build();
+
+            let cfg = SolverConfig {
+                mode: SolverMode::Linear,
+                ..Default::default()
+            };
+            let mut solver = Solver::new(&diagram);
+            solver.step(1e-3, &diagram, &cfg, None).unwrap();
+
+            let currents = solver.state(&diagram).two_terminal_current;
+            assert!((currents[probe].abs() - 5e-3).abs() < 1e-12, "{load} Ω load: {currents:?}");
+            assert!((currents[resistor] - currents[probe]).abs() < 1e-9 * currents[probe].abs(), "{load} Ω load: {currents:?}");
+        }
+    }
+
+    #[test]
+    fn limited_battery_holds_current_or_voltage() {
+        let (volts, limit) = (12.0, 0.3);
+
+        // Output voltage and battery current after each step
+        let run = |load: f64| {
+            let mut builder = DiagramBuilder::new();
+            let out = builder.node();
+            let battery = builder.limited_battery(DiagramBuilder::GROUND, out, volts, limit);
+            builder.resistor(out, DiagramBuilder::GROUND, load);
+            let diagram = builder.build();
+
+            let cfg = SolverConfig {
+                mode: SolverMode::Linear,
+                ..Default::default()
+            };
+            let mut solver = Solver::new(&diagram);
+            (0..50)
+                .map(|_| {
+                    solver.step(1e-3, &diagram, &cfg, None).unwrap();
+                    let state = solver.state(&diagram);
+                    (state.voltages[out], state.two_terminal_current[battery])
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // A short is held to the limit
+        let (v, i) = *run(1e-3).last().unwrap();
+        assert!((i.abs() - limit).abs() < 1e-9, "{i} A into a short");
+        assert!((v.abs() - 1e-3 * limit).abs() < 1e-9, "{v} V across a short");
+
+        // A light load gets the full voltage
+        let (v, i) = *run(100.0).last().unwrap();
+        assert!((v.abs() - volts).abs() < 1e-9, "{v} V across 100 Ω");
+        assert!((i.abs() - volts / 100.0).abs() < 1e-9, "{i} A into 100 Ω");
+
+        // Right at the limit, either way gives the same answer, and the battery settles on one of
+        // them. Without `LIMIT_SLACK`, rounding flips this one between them on every step.
+        let samples = run(volts / limit);
+        for &(v, i) in &samples {
+            assert!((v.abs() - volts).abs() < 1e-6 * volts && (i.abs() - limit).abs() < 1e-6 * limit, "{v} V, {i} A at the limit");
         }
+        let changes = samples.windows(2).filter(|pair| pair[0] != pair[1]).count();
+        assert!(changes <= 1, "{changes} changes over {} steps: {samples:?}", samples.len());
+    }
+
+    #[test]
+    fn junction_capacitance_slows_inverter() {
+        let dt = 1e-6;
+
+        // A common-emitter inverter: 5 V through 1 kΩ to the collector, and the input through
+        // 10 kΩ to the base
+        let inverter = |input: f64, capacitance: f64| {
+            let mut builder = DiagramBuilder::new();
+            let supply = builder.node();
+            let collector = builder.node();
+            let drive = builder.node();
+            let base = builder.node();
+            builder.battery(DiagramBuilder::GROUND, supply, 5.0);
+            builder.resistor(supply, collector, 1e3);
+            builder.battery(DiagramBuilder::GROUND, drive, input);
+            builder.resistor(drive, base, 10e3);
+            builder.three_terminal([DiagramBuilder::GROUND, base, collector], crate::ThreeTerminalComponent::NTransistor(100.0, capacitance, capacitance, crate::DEFAULT_ALPHA_REVERSE));
+            (builder.build(), collector)
+        };
+
+        // Steps the input from 0 V to 5 V once the output has settled high, and returns how long
+        // the output then takes to fall below half the supply
+        let fall_time = |capacitance: f64| {
+            let (low, collector) = inverter(0.0, capacitance);
+            let (high, _) = inverter(5.0, capacitance);
+            // The default step size lags well behind the junctions
+            let cfg = SolverConfig {
+                line_search: true,
+                ..Default::default()
+            };
+            let mut solver = Solver::new(&low);
+            for _ in 0..2000 {
+                solver.step(dt, &low, &cfg, None).unwrap();
+            }
+            assert!(solver.state(&low).voltages[collector] > 4.9);
+
+            (1..=1000)
+                .find(|_| {
+                    solver.step(dt, &high, &cfg, None).unwrap();
+                    solver.state(&high).voltages[collector] < 2.5
+                })
+                .map(|steps| steps as f64 * dt)
+        };
+
+        // Without capacitance the output follows the input within a few steps. 10 nF on each
+        // junction, charged through the 10 kΩ base resistor, takes tens of µs.
+        let ideal = fall_time(0.0).unwrap();
+        let slowed = fall_time(10e-9).expect("the output never fell");
+        assert!(ideal <= 5.0 * dt, "fell after {ideal} s without capacitance");
+        assert!(slowed >= 20e-6, "fell after {slowed} s with capacitance, {ideal} s without");
+    }
+
+    #[test]
+    fn isolated_node_is_named() {
+        let mut builder = DiagramBuilder::new();
+        let supply = builder.node();
+        let isolated = builder.node();
+        builder.battery(DiagramBuilder::GROUND, supply, 5.0);
+        builder.resistor(supply, DiagramBuilder::GROUND, 1e3);
+        let diagram = builder.build();
+
+        let mut solver = Solver::new(&diagram);
+        let err = solver.step(1e-3, &diagram, &SolverConfig::default(), None).unwrap_err();
+        assert_eq!(err.to_string(), format!("Nothing constrains the current law at node {isolated}"));
+    }
+
+    #[test]
+    fn dangling_component_is_named() {
+        let mut builder = DiagramBuilder::new();
+        let supply = builder.node();
+        builder.battery(DiagramBuilder::GROUND, supply, 5.0);
+        builder.resistor(supply, DiagramBuilder::GROUND, 1e3);
+        // Both ends on the reference, which has no current law, so nothing sets its current
+        builder.battery(DiagramBuilder::GROUND, DiagramBuilder::GROUND, 1.0);
+        let diagram = builder.build();
+
+        let mut solver = Solver::new(&diagram);
+        let err = solver.step(1e-3, &diagram, &SolverConfig::default(), None).unwrap_err();
+        assert_eq!(err.to_string(), "Nothing determines the current through Battery #2");
+    }
+
+    #[test]
+    fn diode_overflow_is_named() {
+        let mut builder = DiagramBuilder::new();
+        let supply = builder.node();
+        // Small enough to get through the first iteration, after which the diode's current
+        // overflows. The battery carries the same current, but ties go to the later component.
+        builder.battery(DiagramBuilder::GROUND, supply, 1e150);
+        let diode = builder.diode(supply, DiagramBuilder::GROUND);
+        let diagram = builder.build();
+
+        let mut solver = Solver::new(&diagram);
+        let err = solver.step(1e-3, &diagram, &SolverConfig::default(), None).unwrap_err();
+        assert!(matches!(&err, SolverError::NonFinite(what) if *what == format!("the current through Diode #{diode}")), "{err:?}");
+    }
+
+    #[test]
+    fn diode_chain_converges_from_cold_start() {
+        // Nearly a short across three diodes, which overshoot far into their exponential region.
+        // Shrinking the step without undoing the overshoot used to stop well short of the
+        // solution, once the shrunken step passed the tolerance.
+        let mut builder = DiagramBuilder::new();
+        let supply = builder.node();
+        let mut anode = builder.node();
+        builder.battery(DiagramBuilder::GROUND, supply, 2.0);
+        let resistor = builder.resistor(supply, anode, 0.01);
+        for _ in 0..2 {
+            let cathode = builder.node();
+            builder.diode(anode, cathode);
+            anode = cathode;
+        }
+        builder.diode(anode, DiagramBuilder::GROUND);
+        let diagram = builder.build();
+
+        // Full updates, halved until the residual drops, for comparison
+        let reference_cfg = SolverConfig {
+            nr_tolerance: 1e-20,
+            line_search: true,
+            ..Default::default()
+        };
+        let mut reference = Solver::new(&diagram);
+        reference.step(1e-3, &diagram, &reference_cfg, None).unwrap();
+        let expected = reference.state(&diagram).two_terminal_current[resistor];
+
+        let cfg = SolverConfig::default();
+        assert!(cfg.adaptive_step_size);
+        let mut solver = Solver::new(&diagram);
+        solver.step(1e-3, &diagram, &cfg, None).unwrap();
+        let current = solver.state(&diagram).two_terminal_current[resistor];
+        assert!((current - expected).abs() < 1e-3 * expected, "{current} A, expected {expected} A");
+    }
+
+    #[test]
+    fn noise_repeats_with_its_seed() {
+        let amplitude = 0.5;
+        let run = |seed| {
+            let mut builder = DiagramBuilder::new();
+            let node = builder.node();
+            builder.two_terminal(DiagramBuilder::GROUND, node, TwoTerminalComponent::NoiseSource { amplitude, seed });
+            builder.resistor(node, DiagramBuilder::GROUND, 1e3);
+            let diagram = builder.build();
+
+            let cfg = SolverConfig {
+                mode: SolverMode::Linear,
+                ..Default::default()
+            };
+            let mut solver = Solver::new(&diagram);
+            (0..20_000)
+                .map(|_| {
+                    solver.step(1e-6, &diagram, &cfg, None).unwrap();
+                    solver.state(&diagram).voltages[node]
+                })
+                .collect::<Vec<f64>>()
+        };
+
+        let first = run(1);
+        assert_eq!(first, run(1), "a new solver with the same seed drew different noise");
+        assert_ne!(first, run(2), "a different seed drew the same noise");
+
+        let rms = (first.iter().map(|v| v * v).sum::<f64>() / first.len() as f64).sqrt();
+        assert!((rms - amplitude).abs() < 0.03 * amplitude, "RMS {rms} V, set to {amplitude} V");
+    }
+
+    #[test]
+    fn series_resistance_sets_lc_q() {
+        let (l, c, r) = (1e-3, 1e-6, 1.0);
+        let periods = 4;
+
+        // Rings the tank, started with a current in the inductor, and measures Q from how fast
+        // the current dies down: its amplitude falls by exp(-pi/Q) per period
+        let measure_q = |integration, steps_per_period: usize, inductor_r, capacitor_esr| {
+            let mut builder = DiagramBuilder::new();
+            let tank = builder.node();
+            let inductor = builder.two_terminal(tank, DiagramBuilder::GROUND, TwoTerminalComponent::Inductor(l, None, Some(10e-3), None, inductor_r));
+            builder.two_terminal(tank, DiagramBuilder::GROUND, TwoTerminalComponent::Capacitor(c, None, None, capacitor_esr));
+            let diagram = builder.build();
+
+            let dt = 2.0 * PI * (l * c).sqrt() / steps_per_period as f64;
+            let cfg = SolverConfig {
+                integration,
+                mode: SolverMode::Linear,
+                ..Default::default()
+            };
+
+            // Peak current in the first and in the last period
+            let (mut first, mut last) = (0.0f64, 0.0f64);
+            let mut solver = Solver::new(&diagram);
+            for step in 0..steps_per_period * periods {
+                solver.step(dt, &diagram, &cfg, None).unwrap();
+                let current = solver.state(&diagram).two_terminal_current[inductor].abs();
+                if step < steps_per_period {
+                    first = first.max(current);
+                } else if step >= steps_per_period * (periods - 1) {
+                    last = last.max(current);
+                }
+            }
+            PI * (periods - 1) as f64 / (first / last).ln()
+        };
+
+        // Backward Euler damps the tank by itself, as much as a Q of the steps per period over
+        // 2 pi, so it needs finer steps for the resistance to dominate
+        let expected = (l / c).sqrt() / r;
+        for (integration, steps_per_period) in [(IntegrationMethod::Trapezoidal, 200), (IntegrationMethod::BackwardEuler, 10_000)] {
+            for (inductor_r, capacitor_esr) in [(r, 0.0), (0.0, r)] {
+                let q = measure_q(integration, steps_per_period, inductor_r, capacitor_esr);
+                assert!(
+                    (q - expected).abs() < 0.05 * expected,
+                    "{integration:?} with {inductor_r} Ω in the inductor and {capacitor_esr} Ω in the capacitor: Q = {q}, expected {expected}"
+                );
+            }
+        }
+
+        // Without resistance, the trapezoidal rule keeps the tank ringing. The peaks, sampled
+        // once a step, may even come out slightly higher at the end.
+        let q = measure_q(IntegrationMethod::Trapezoidal, 200, 0.0, 0.0);
+        assert!(q.abs() > 100.0 * expected, "Q = {q} without resistance");
     }
 }
@@ -2,20 +2,51 @@ use std::collections::{HashMap, HashSet};
 
 use rsparse::data::{Sprs, Trpl};
 
-use crate::{map::PrimitiveDiagramMapping, PrimitiveDiagram, ThreeTerminalComponent, TwoTerminalComponent};
+use crate::{map::PrimitiveDiagramMapping, IntegrationMethod, PrimitiveDiagram, ThreeTerminalComponent, TwoTerminalComponent};
 
-pub fn stamp(dt: f64, map: &PrimitiveDiagramMapping, diagram: &PrimitiveDiagram, last_iteration: &[f64], last_timestep: &[f64], external_params: Option<&[f64]>) -> (Sprs<f64>, Vec<f64>) {
-    let n = map.vector_size();
+/// Relative slack on a battery's current limit and voltage when choosing which one it holds, so
+/// that a battery sitting right at either doesn't flip back and forth on rounding errors
+pub const LIMIT_SLACK: f64 = 1e-6;
 
-    // (params, state)
-    let mut matrix = Trpl::new();
-    let mut params = vec![0_f64; n];
-    
-    if let Some(ext) = external_params {
-        params.copy_from_slice(ext);
+/// Saturation current of diodes and of both transistor junctions. Stolen from falstad.
+pub const SATURATION_CURRENT: f64 = 171.4352819281e-9;
+
+pub fn stamp(dt: f64, integration: IntegrationMethod, map: &PrimitiveDiagramMapping, diagram: &PrimitiveDiagram, last_iteration: &[f64], last_timestep: &[f64], external_params: Option<&[f64]>) -> (Sprs<f64>, Vec<f64>) {
+    let mut cache = StampCache::new(map, diagram);
+    let (matrix, params) = stamp_dynamic(dt, integration, map, diagram, &mut cache, last_iteration, last_timestep, external_params);
+    (matrix, params.to_vec())
+}
+
+/// The output of `stamp_static`, along with the triplet list, params and core map which
+/// `stamp_dynamic` refills on every call, so that each Newton-Raphson iteration reuses their
+/// allocations
+#[derive(Clone)]
+pub struct StampCache {
+    static_matrix: Trpl<f64>,
+    scratch: Trpl<f64>,
+    params: Vec<f64>,
+    /// Maps core ID -> inductance, two terminal component idx. Cores which are gone keep an
+    /// empty list.
+    cores: HashMap<u16, Vec<(f64, usize)>>,
+}
+
+impl StampCache {
+    pub fn new(map: &PrimitiveDiagramMapping, diagram: &PrimitiveDiagram) -> Self {
+        Self {
+            static_matrix: stamp_static(map, diagram),
+            scratch: Trpl::new(),
+            params: vec![],
+            cores: HashMap::new(),
+        }
     }
+}
 
-    // TODO: Three-terminal components
+/// Stamps the entries which only depend on the topology of the circuit: the current laws, the
+/// voltage laws and wires. These stay the same for as long as the diagram is compatible with the
+/// `Solver`, so they only need to be computed once.
+pub fn stamp_static(map: &PrimitiveDiagramMapping, diagram: &PrimitiveDiagram) -> Trpl<f64> {
+    // (params, state)
+    let mut matrix = Trpl::new();
 
     // Stamp current laws
     let mut total_current_idx = 0;
@@ -143,17 +174,63 @@ pub fn stamp(dt: f64, map: &PrimitiveDiagramMapping, diagram: &PrimitiveDiagram,
         }
     }
 
-    // Maps core ID -> inductance, two terminal component idx
-    let mut cores: HashMap<u16, Vec<(f64, usize)>> = HashMap::new();
+    // Stamp wires
+    for (total_idx, &(node_indices, component)) in diagram.two_terminal.iter().enumerate() {
+        if let TwoTerminalComponent::Wire = component {
+            // Vd = 0
+            //matrix.append(component_idx, voltage_drop_idx, 1.0);
+            let law_idx = map.param_map.components().nth(total_idx).unwrap();
+            let [begin_node_idx, end_node_idx] = node_indices;
+
+            if let Some(voltage_idx) = map.state_map.voltages().nth(end_node_idx) {
+                matrix.append(law_idx, voltage_idx, 1.0);
+            }
+
+            if let Some(voltage_idx) = map.state_map.voltages().nth(begin_node_idx) {
+                matrix.append(law_idx, voltage_idx, -1.0);
+            }
+        }
+    }
+
+    // append() only grows the matrix up to the last entry, so an empty trailing equation would
+    // leave it smaller than the vectors instead of singular
+    matrix.m = map.vector_size();
+    matrix.n = map.vector_size();
+
+    matrix
+}
+
+/// Stamps the entries which depend on component values or on the solution so far, on top of
+/// the output of `stamp_static`.
+/// `integration` is used for capacitors and inductors which don't choose their own. The params
+/// are borrowed from `cache`, and overwritten by the next call.
+pub fn stamp_dynamic<'a>(dt: f64, integration: IntegrationMethod, map: &PrimitiveDiagramMapping, diagram: &PrimitiveDiagram, cache: &'a mut StampCache, last_iteration: &[f64], last_timestep: &[f64], external_params: Option<&[f64]>) -> (Sprs<f64>, &'a [f64]) {
+    let n = map.vector_size();
+
+    // (params, state). Copied field by field, since Vec::clone_from() keeps the capacity from
+    // the last call, while the Trpl as a whole would be cloned into a new allocation.
+    let StampCache { static_matrix, scratch: matrix, params, cores } = cache;
+    matrix.m = static_matrix.m;
+    matrix.n = static_matrix.n;
+    matrix.p.clone_from(&static_matrix.p);
+    matrix.i.clone_from(&static_matrix.i);
+    matrix.x.clone_from(&static_matrix.x);
+    params.clear();
+    params.resize(n, 0.0);
+    if let Some(ext) = external_params {
+        params.copy_from_slice(ext);
+    }
+
+    cores.values_mut().for_each(Vec::clear);
     for (idx, (_, component)) in diagram.two_terminal.iter().enumerate() {
-        if let TwoTerminalComponent::Inductor(value, Some(core_id)) = component {
+        if let TwoTerminalComponent::Inductor(value, Some(core_id), ..) = component {
             cores.entry(*core_id).or_default().push((*value, idx));
         }
     }
 
     // Stamp components
     let mut total_idx = 0;
-    for &(node_indices, component) in &diagram.two_terminal {
+    for &(_, component) in &diagram.two_terminal {
         let law_idx = map.param_map.components().nth(total_idx).unwrap();
 
         let current_idx = map.state_map.currents().nth(total_idx).unwrap();
@@ -165,17 +242,7 @@ pub fn stamp(dt: f64, map: &PrimitiveDiagramMapping, diagram: &PrimitiveDiagram,
                 matrix.append(law_idx, voltage_drop_idx, 1.0);
             }
             TwoTerminalComponent::Wire => {
-                // Vd = 0
-                //matrix.append(component_idx, voltage_drop_idx, 1.0);
-                let [begin_node_idx, end_node_idx] = node_indices;
-
-                if let Some(voltage_idx) = map.state_map.voltages().nth(end_node_idx) {
-                    matrix.append(law_idx, voltage_idx, 1.0);
-                }
-
-                if let Some(voltage_idx) = map.state_map.voltages().nth(begin_node_idx) {
-                    matrix.append(law_idx, voltage_idx, -1.0);
-                }
+                // Stamped by stamp_static()
             }
             TwoTerminalComponent::Switch(is_open) => {
                 // Vd = 0
@@ -201,19 +268,59 @@ pub fn stamp(dt: f64, map: &PrimitiveDiagramMapping, diagram: &PrimitiveDiagram,
                     */
                 }
             }
-            TwoTerminalComponent::Battery(voltage) => {
-                matrix.append(law_idx, voltage_drop_idx, -1.0);
-                params[law_idx] = voltage;
+            TwoTerminalComponent::Battery(voltage, limit) => {
+                // The voltage drop runs from the end terminal to the beginning
+                let current = last_iteration[current_idx];
+                let output = -last_iteration[voltage_drop_idx];
+
+                // Limited once the load draws more than the limit, until it would let the output
+                // rise past the set voltage again
+                let limited = limit.is_some_and(|limit| {
+                    current.abs() >= limit * (1.0 - LIMIT_SLACK)
+                        && output.abs() <= voltage.abs() * (1.0 + LIMIT_SLACK)
+                });
+
+                match limit {
+                    Some(limit) if limited => {
+                        matrix.append(law_idx, current_idx, 1.0);
+                        params[law_idx] = limit.copysign(current);
+                    }
+                    _ => {
+                        matrix.append(law_idx, voltage_drop_idx, -1.0);
+                        params[law_idx] = voltage;
+                    }
+                }
             }
-            TwoTerminalComponent::Capacitor(capacitance) => {
-                matrix.append(law_idx, current_idx, -dt);
-                matrix.append(law_idx, voltage_drop_idx, capacitance);
-                params[law_idx] = last_timestep[voltage_drop_idx] * capacitance;
+            TwoTerminalComponent::Capacitor(capacitance, _, method, esr) => {
+                // The ESR is folded into the branch: the capacitor itself sees Vc = Vd - ESR I
+                let last_vc = last_timestep[voltage_drop_idx] - esr * last_timestep[current_idx];
+                match method.unwrap_or(integration) {
+                    // C (Vc - last Vc) = dt I
+                    IntegrationMethod::BackwardEuler => {
+                        matrix.append(law_idx, current_idx, -dt - capacitance * esr);
+                        matrix.append(law_idx, voltage_drop_idx, capacitance);
+                        params[law_idx] = last_vc * capacitance;
+                    }
+                    // C (Vc - last Vc) = dt/2 (I + last I)
+                    IntegrationMethod::Trapezoidal => {
+                        matrix.append(law_idx, current_idx, -dt / 2.0 - capacitance * esr);
+                        matrix.append(law_idx, voltage_drop_idx, capacitance);
+                        params[law_idx] = last_vc * capacitance + last_timestep[current_idx] * dt / 2.0;
+                    }
+                }
             }
-            TwoTerminalComponent::Inductor(inductance, core_id) => {
-                matrix.append(law_idx, current_idx, -inductance);
+            TwoTerminalComponent::Inductor(inductance, core_id, _, method, resistance) => {
                 params[law_idx] = -last_timestep[current_idx] * inductance;
                 let mut coeff = dt;
+                // L (I - last I) = dt/2 (VL + last VL). Transformers always step with backward
+                // Euler, since the coupling terms are written for it.
+                if core_id.is_none() && method.unwrap_or(integration) == IntegrationMethod::Trapezoidal {
+                    coeff = dt / 2.0;
+                    params[law_idx] -= (last_timestep[voltage_drop_idx] - resistance * last_timestep[current_idx]) * dt / 2.0;
+                }
+                // The series resistance is folded into the branch: the winding itself sees
+                // VL = Vd - R I
+                matrix.append(law_idx, current_idx, -inductance - coeff * resistance);
                 if let Some(others) = core_id.and_then(|id| cores.get(&id)) {
                     for (value, twoterm_idx) in others {
                         if *twoterm_idx != total_idx {
@@ -235,13 +342,34 @@ pub fn stamp(dt: f64, map: &PrimitiveDiagramMapping, diagram: &PrimitiveDiagram,
                 matrix.append(law_idx, current_idx, 1.0);
                 params[law_idx] = current;
             }
+            TwoTerminalComponent::CurrentControlledSource(gain, sense) => {
+                // I - gain * I_sense = 0
+                matrix.append(law_idx, current_idx, 1.0);
+                if let Some(sense) = sense.filter(|&idx| idx < diagram.two_terminal.len()) {
+                    let sense_current_idx = map.state_map.currents().nth(sense).unwrap();
+                    matrix.append(law_idx, sense_current_idx, -gain);
+                }
+            }
+            TwoTerminalComponent::CurrentProbe => {
+                // Same as Battery(0.0)
+                matrix.append(law_idx, voltage_drop_idx, -1.0);
+            }
+            TwoTerminalComponent::NoiseSource { .. } => {
+                // Same as a battery, with the voltage of this step supplied by the solver through
+                // the external params
+                matrix.append(law_idx, voltage_drop_idx, -1.0);
+            }
+            TwoTerminalComponent::Crystal { .. } => {
+                // Should have been expanded into its equivalent circuit. If not, it doesn't conduct.
+                matrix.append(law_idx, current_idx, 1.0);
+            }
             //other => eprintln!("{other:?} is not supported yet!!"),
         }
 
         total_idx += 1;
     }
 
-    for &(_, component) in &diagram.three_terminal {
+    for (component_idx, &(_, component)) in diagram.three_terminal.iter().enumerate() {
         let ab_law_idx = map.param_map.components().nth(total_idx).unwrap();
         let ab_current_idx = map.state_map.currents().nth(total_idx).unwrap();
         let ab_voltage_drop_idx = map.state_map.voltage_drops().nth(total_idx).unwrap();
@@ -252,53 +380,104 @@ pub fn stamp(dt: f64, map: &PrimitiveDiagramMapping, diagram: &PrimitiveDiagram,
         let bc_voltage_drop_idx = map.state_map.voltage_drops().nth(total_idx).unwrap();
         total_idx += 1;
 
+        if diagram.disabled_three_terminal.contains(&component_idx) {
+            // Both branches open
+            matrix.append(ab_law_idx, ab_current_idx, 1.0);
+            matrix.append(bc_law_idx, bc_current_idx, 1.0);
+            continue;
+        }
+
         match component {
-            ThreeTerminalComponent::NTransistor(_) | ThreeTerminalComponent::PTransistor(_) => {
+            ThreeTerminalComponent::NTransistor(beta, c_be, c_bc, ar) | ThreeTerminalComponent::PTransistor(beta, c_be, c_bc, ar) => {
                 let sign = match component {
-                    ThreeTerminalComponent::NTransistor(_) => 1.0,
+                    ThreeTerminalComponent::NTransistor(..) => 1.0,
                     _ => -1.0,
                 };
 
-                let (diode_coeff_ab, mut diode_param_ab) = diode_eq(sign * last_iteration[ab_voltage_drop_idx]);
+                let (diode_coeff_ab, diode_param_ab) = diode_eq(sign * last_iteration[ab_voltage_drop_idx]);
+
+                let (diode_coeff_bc, diode_param_bc) = diode_eq(-sign * last_iteration[bc_voltage_drop_idx]);
 
-                let (diode_coeff_bc, mut diode_param_bc) = diode_eq(-sign * last_iteration[bc_voltage_drop_idx]);
+                // Collector current is af/(1 - af) times the base current, which is beta
+                let beta = beta.max(0.0);
+                let af = beta / (beta + 1.0);
 
-                let af = 0.98;
-                let ar = 0.1;
+                // Each branch carries its diode's current plus a fraction of the other branch's.
+                // Both are linear in the currents, so they go in the matrix, where Newton-Raphson
+                // sees them, rather than lagging an iteration behind in the params.
+                matrix.append(bc_law_idx, ab_current_idx, -af);
+                matrix.append(ab_law_idx, bc_current_idx, -ar);
 
-                diode_param_bc += af * last_iteration[ab_current_idx];
-                diode_param_ab += ar * last_iteration[bc_current_idx];
+                // Junction capacitances sit across the diodes, like a Capacitor, adding
+                // C/dt (Vd - last Vd) to the branch current
+                let (cap_coeff_ab, cap_param_ab) = (c_be / dt, c_be / dt * last_timestep[ab_voltage_drop_idx]);
+                let (cap_coeff_bc, cap_param_bc) = (c_bc / dt, c_bc / dt * last_timestep[bc_voltage_drop_idx]);
 
-                matrix.append(ab_law_idx, ab_voltage_drop_idx, diode_coeff_ab);
+                matrix.append(ab_law_idx, ab_voltage_drop_idx, diode_coeff_ab - cap_coeff_ab);
                 matrix.append(ab_law_idx, ab_current_idx, 1.0);
-                params[ab_law_idx] = diode_param_ab;
+                params[ab_law_idx] = diode_param_ab - cap_param_ab;
 
-                matrix.append(bc_law_idx, bc_voltage_drop_idx, diode_coeff_bc);
+                matrix.append(bc_law_idx, bc_voltage_drop_idx, diode_coeff_bc - cap_coeff_bc);
                 matrix.append(bc_law_idx, bc_current_idx, 1.0);
-                params[bc_law_idx] = diode_param_bc;
+                params[bc_law_idx] = diode_param_bc - cap_param_bc;
+            }
+            ThreeTerminalComponent::Potentiometer(resistance, position) => {
+                // Two resistors in series, split at the wiper
+                let position = position.clamp(0.0, 1.0);
+
+                matrix.append(ab_law_idx, ab_current_idx, -resistance * position);
+                matrix.append(ab_law_idx, ab_voltage_drop_idx, 1.0);
+
+                matrix.append(bc_law_idx, bc_current_idx, -resistance * (1.0 - position));
+                matrix.append(bc_law_idx, bc_voltage_drop_idx, 1.0);
             }
         }
     }
 
-    (matrix.to_sprs(), params)
+    (matrix.to_sprs(), &params[..])
+}
+
+/// Looks for an equation (row) or unknown (column) of the stamped matrix without any nonzero
+/// coefficients, either of which makes it singular. Usually this means a node with nothing
+/// connected to it, or a component with nothing constraining it. Returns a description of the
+/// first one found.
+pub fn find_empty_equation(map: &PrimitiveDiagramMapping, diagram: &PrimitiveDiagram, matrix: &Sprs<f64>) -> Option<String> {
+    let n = map.vector_size();
+    let dense = matrix.to_dense();
+    let nonzero = |row: usize, col: usize| dense.get(row).and_then(|r| r.get(col)).is_some_and(|x| *x != 0.0);
+
+    if let Some(row) = (0..n).find(|&row| !(0..n).any(|col| nonzero(row, col))) {
+        return Some(format!("Nothing constrains {}", map.describe_param(diagram, row)));
+    }
+
+    if let Some(col) = (0..n).find(|&col| !(0..n).any(|row| nonzero(row, col))) {
+        return Some(format!("Nothing determines {}", map.describe_state(diagram, col)));
+    }
+
+    None
 }
 
 // Solves for the backwards difference, using the taylor expansion of 
 // the diode equation about `last_iteration_voltage`.
 fn diode_eq(last_iteration_voltage: f64) -> (f64, f64) {
-    // Stolen from falstad.
-    let sat_current = 171.4352819281e-9;
+    let sat_current = SATURATION_CURRENT;
     let n = 2.0;
     let temperature = 273.15 + 22.0;
     let thermal_voltage = 8.617e-5 * temperature;
     let nvt = n * thermal_voltage;
 
+    // Past this exponent the diode is continued as a straight line instead. exp() overflows
+    // to infinity soon after, which a large forward bias mid-iteration would otherwise reach.
+    let max_exp_arg = 30.0;
+
     let v0 = last_iteration_voltage;
+    let x = (v0 / nvt).min(max_exp_arg);
 
-    let ex = (v0 / nvt).exp();
+    let ex = x.exp();
     let coeff = -(sat_current / nvt) * ex;
 
-    let param = sat_current * (1.0 - ex + v0 * ex / nvt);
+    // Same as the tangent at v0 while x isn't clamped, otherwise the tangent at the clamp
+    let param = sat_current * (1.0 - ex + x * ex);
 
     (coeff, param)
 }
@@ -0,0 +1,163 @@
+use crate::{
+    solver::{Solver, SolverConfig, SolverError, SolverMode},
+    PrimitiveDiagram,
+};
+
+/// Current injected into a port to measure its resistance, see `port_resistance()`. Too much
+/// pulls the operating point along with it. Small enough to stay well clear of that for bias
+/// currents of a microamp or so.
+const TEST_CURRENT: f64 = 1e-9;
+
+/// Newton-Raphson tolerance while measuring. The response to the test current is only a few
+/// microvolts, so the usual tolerance would stop before it has been resolved.
+const NR_TOLERANCE: f64 = 1e-20;
+
+/// A small-signal resistance
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Ohms(pub f64);
+
+impl std::fmt::Display for Ohms {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.4e} Ω", self.0)
+    }
+}
+
+/// Input and output resistance of an amplifier stage, see `two_port()`
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug)]
+pub struct TwoPortResistance {
+    pub input: Ohms,
+    pub output: Ohms,
+}
+
+/// Small-signal resistance between the `[positive, negative]` nodes of `port`, at the operating
+/// point the solver is currently at.
+///
+/// Takes one time step from that point as usual, and another with a test current pushed into
+/// the port, and divides the difference in port voltage by the test current. The rest of the
+/// circuit stays as drawn. Since only the difference is kept, voltage sources act as shorts and
+/// current sources as opens, and anything still changing over the step cancels out. `dt` decides
+/// how reactive parts take part: over one step a capacitor looks like a dt/C resistor, and an
+/// inductor like an L/dt one. The solver itself isn't advanced.
+///
+/// Both steps take full Newton-Raphson updates, with the line search, whatever `cfg` says.
+///
+/// This doesn't just solve once against the matrix of the last stamp. The solver settles where
+/// the stamped equations are satisfied at the operating point they were stamped around, and for
+/// a junction that is not the same as the matrix being their slope there: a diode's stamp holds
+/// I_s·e^x/nV_T, but the current it settles to changes as (1 + 2x) times that. Solving against
+/// the stamp put the base of a common-emitter stage at about ten times its resistance.
+pub fn port_resistance(solver: &Solver, diagram: &PrimitiveDiagram, port: [usize; 2], dt: f64, cfg: &SolverConfig) -> Result<Ohms, SolverError> {
+    let cfg = SolverConfig {
+        mode: SolverMode::NewtonRaphson,
+        line_search: true,
+        nr_tolerance: NR_TOLERANCE,
+        ..*cfg
+    };
+    let map = solver.map();
+    let mut injection = vec![0.0; map.vector_size()];
+
+    // A current law reads (current in) - (current out) = b, so current pushed in from outside
+    // the circuit shows up as -b. The reference node has no current law, and needs none.
+    let [positive, negative] = port;
+    if let Some(idx) = map.param_map.current_laws().nth(positive) {
+        injection[idx] -= TEST_CURRENT;
+    }
+    if let Some(idx) = map.param_map.current_laws().nth(negative) {
+        injection[idx] += TEST_CURRENT;
+    }
+
+    let port_voltage = |external_params: Option<&[f64]>| -> Result<f64, SolverError> {
+        let mut solver = solver.clone();
+        solver.step(dt, diagram, &cfg, external_params)?;
+        let voltages = solver.state(diagram).voltages;
+        Ok(voltages[positive] - voltages[negative])
+    };
+
+    let baseline = port_voltage(None)?;
+    let driven = port_voltage(Some(&injection))?;
+
+    Ok(Ohms((driven - baseline) / TEST_CURRENT))
+}
+
+/// Input and output resistance of a two-port, each measured with `port_resistance()` while the
+/// other port is left as drawn
+pub fn two_port(solver: &Solver, diagram: &PrimitiveDiagram, input: [usize; 2], output: [usize; 2], dt: f64, cfg: &SolverConfig) -> Result<TwoPortResistance, SolverError> {
+    Ok(TwoPortResistance {
+        input: port_resistance(solver, diagram, input, dt, cfg)?,
+        output: port_resistance(solver, diagram, output, dt, cfg)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DiagramBuilder;
+
+    #[test]
+    fn divider_output_is_both_resistors_in_parallel() {
+        let (r1, r2) = (1e3, 3e3);
+        let mut builder = DiagramBuilder::new();
+        let supply = builder.node();
+        let output = builder.node();
+        builder.battery(DiagramBuilder::GROUND, supply, 10.0);
+        builder.resistor(supply, output, r1);
+        builder.resistor(output, DiagramBuilder::GROUND, r2);
+        let diagram = builder.build();
+        let ground = diagram.num_nodes - 1;
+
+        let cfg = SolverConfig::default();
+        let mut solver = Solver::new(&diagram);
+        solver.step(1e-3, &diagram, &cfg, None).unwrap();
+
+        // The battery is a short to the test current, which leaves R1 across R2
+        let Ohms(resistance) = port_resistance(&solver, &diagram, [output, ground], 1e-3, &cfg).unwrap();
+        let expected = r1 * r2 / (r1 + r2);
+        assert!((resistance - expected).abs() < 1e-6 * expected, "{resistance} Ω, expected {expected} Ω");
+
+        // Measured the other way around, the voltage and the current both change sign
+        let Ohms(reversed) = port_resistance(&solver, &diagram, [ground, output], 1e-3, &cfg).unwrap();
+        assert!((reversed - expected).abs() < 1e-6 * expected, "{reversed} Ω reversed, expected {expected} Ω");
+    }
+
+    #[test]
+    fn common_emitter_matches_textbook_estimates() {
+        // n·V_T of the solver's diode model, which also makes up the transistor junctions
+        let nvt = 2.0 * 8.617e-5 * (273.15 + 22.0);
+        let (r_c, r_b) = (4.7e3, 1e6);
+
+        let mut builder = DiagramBuilder::new();
+        let emitter = DiagramBuilder::GROUND;
+        let supply = builder.node();
+        let collector = builder.node();
+        let base = builder.node();
+        builder.battery(emitter, supply, 10.0);
+        builder.resistor(supply, collector, r_c);
+        builder.resistor(supply, base, r_b);
+        let transistor = builder.npn(collector, base, emitter, 100.0);
+        let diagram = builder.build();
+        let ground = diagram.num_nodes - 1;
+
+        // Long enough for the bias point to settle
+        let (dt, cfg) = (1e-3, SolverConfig { line_search: true, ..Default::default() });
+        let mut solver = Solver::new(&diagram);
+        for _ in 0..200 {
+            solver.step(dt, &diagram, &cfg, None).unwrap();
+        }
+
+        // Currents in [emitter, base, collector] order
+        let [i_e, i_b, i_c] = solver.state(&diagram).three_terminal_current[transistor].map(f64::abs);
+        let beta = i_c / i_b;
+        let r_e = nvt / i_e;
+        let measured = two_port(&solver, &diagram, [base, ground], [collector, ground], dt, &cfg).unwrap();
+
+        // R_B sits in parallel with the base, and R_C with the collector
+        let parallel = |a: f64, b: f64| a * b / (a + b);
+        let r_in = parallel((beta + 1.0) * r_e, r_b);
+        // The estimate uses the ideal junction slope, which the solver's junction model only
+        // approximates, so allow up to 20%
+        let Ohms(input) = measured.input;
+        assert!((input - r_in).abs() < 0.2 * r_in, "input {input} Ω, expected about {r_in} Ω (β {beta}, r_e {r_e} Ω)");
+        let Ohms(output) = measured.output;
+        assert!((output - r_c).abs() < 0.01 * r_c, "output {output} Ω, expected about {r_c} Ω");
+    }
+}
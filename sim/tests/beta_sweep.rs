@@ -0,0 +1,62 @@
+//! Drives the base of an NPN transistor with close to a fixed current, through 1 MΩ from a 5 V
+//! supply, and sweeps its beta.
+
+use cirmcut_sim::{
+    builder::DiagramBuilder,
+    solver::{Solver, SolverConfig},
+    stamp::SATURATION_CURRENT,
+};
+
+const R_B: f64 = 1e6;
+const STEPS: usize = 50;
+const DT: f64 = 1e-3;
+
+/// Base and collector current, with the collector held at the supply voltage
+fn currents(beta: f64) -> (f64, f64) {
+    let mut builder = DiagramBuilder::new();
+    let emitter = DiagramBuilder::GROUND;
+    let collector = builder.node();
+    let base = builder.node();
+    builder.battery(emitter, collector, 5.0);
+    builder.resistor(collector, base, R_B);
+    let transistor = builder.npn(collector, base, emitter, beta);
+    let diagram = builder.build();
+
+    // The default tolerance stops well short at high beta, where the base current is a tiny
+    // fraction of the collector current
+    let cfg = SolverConfig {
+        nr_tolerance: 1e-12,
+        max_nr_iters: 500,
+        line_search: true,
+        ..Default::default()
+    };
+    let mut solver = Solver::new(&diagram);
+    for _ in 0..STEPS {
+        solver
+            .step(DT, &diagram, &cfg, None)
+            .unwrap_or_else(|e| panic!("β = {beta}: {e}"));
+    }
+
+    // Currents in [emitter, base, collector] order
+    let [_, i_b, i_c] = solver.state(&diagram).three_terminal_current[transistor];
+    (i_b.abs(), i_c.abs())
+}
+
+#[test]
+fn collector_current_follows_beta() {
+    let mut last = 0.0;
+    for beta in [10.0, 50.0, 100.0, 200.0, 500.0] {
+        let (i_b, i_c) = currents(beta);
+        assert!(i_c > last, "β = {beta}: I_C = {i_c:e} A, no more than {last:e} A at the last β");
+        last = i_c;
+
+        // Besides beta times the base current, the reverse-biased collector junction leaks its
+        // saturation current, which the transistor amplifies like any other base current.
+        // That's a few percent at these base currents of a few µA.
+        let expected = beta * i_b + (beta + 1.0) * SATURATION_CURRENT;
+        assert!(
+            (i_c - expected).abs() < 1e-6 * expected,
+            "β = {beta}: I_C = {i_c:e} A, expected {expected:e} A with I_B = {i_b:e} A"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Replays the circuits in fixtures/ and checks that the solver still produces the outputs
+//! recorded in them, to catch numerical changes when refactoring the stamps or the solver.
+//!
+//! cargo test --test golden
+//!
+//! After a deliberate change in behavior, rewrite the fixtures from the current solver with:
+//!
+//! RECORD_GOLDEN=1 cargo test --test golden
+
+use std::path::PathBuf;
+
+use cirmcut_sim::{
+    builder::DiagramBuilder,
+    solver::{Solver, SolverConfig},
+    PrimitiveDiagram, SimOutputs,
+};
+
+/// Steps run between recorded outputs
+const STRIDE: usize = 50;
+
+/// Outputs may differ by this much relative to their size, or absolutely below 1
+const TOLERANCE: f64 = 1e-6;
+
+/// A diagram, how to step it, and the outputs it gave every `STRIDE` steps
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Fixture {
+    diagram: PrimitiveDiagram,
+    cfg: SolverConfig,
+    dt: f64,
+    outputs: Vec<SimOutputs>,
+}
+
+/// Circuits with recorded fixtures: name, diagram, solver settings, time step, and number of
+/// outputs
+fn circuits() -> Vec<(&'static str, PrimitiveDiagram, SolverConfig, f64, usize)> {
+    vec![("colpitts", colpitts(), colpitts_cfg(), 1e-4, 40)]
+}
+
+/// The bundled Colpitts oscillator (src/colpitts2.ckt). Oscillates at about 80 Hz, so the
+/// recording covers some 16 cycles.
+fn colpitts() -> PrimitiveDiagram {
+    let mut builder = DiagramBuilder::new();
+    let emitter = DiagramBuilder::GROUND;
+    let supply = builder.node();
+    let collector = builder.node();
+    let tank = builder.node();
+    let base = builder.node();
+
+    builder.battery(emitter, supply, 5.0);
+    builder.resistor(supply, collector, 100.0);
+    builder.resistor(collector, tank, 1000.0);
+    builder.capacitor(emitter, tank, 100e-6);
+    builder.capacitor(emitter, base, 100e-6);
+    builder.inductor(tank, base, 0.08);
+    builder.npn(collector, base, emitter, 100.0);
+
+    builder.build()
+}
+
+/// Settings saved with the bundled Colpitts oscillator
+fn colpitts_cfg() -> SolverConfig {
+    SolverConfig {
+        max_nr_iters: 200,
+        nr_tolerance: 1e-9,
+        adaptive_step_size: false,
+        ..Default::default()
+    }
+}
+
+fn path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join(format!("{name}.ron"))
+}
+
+/// Runs the diagram, keeping the outputs after every `STRIDE` steps
+fn run(
+    diagram: &PrimitiveDiagram,
+    cfg: &SolverConfig,
+    dt: f64,
+    count: usize,
+) -> Result<Vec<SimOutputs>, String> {
+    let mut solver = Solver::new(diagram);
+    let mut outputs = vec![];
+    for _ in 0..count {
+        for _ in 0..STRIDE {
+            solver
+                .step(dt, diagram, cfg, None)
+                .map_err(|e| e.to_string())?;
+        }
+        outputs.push(solver.state(diagram));
+    }
+    Ok(outputs)
+}
+
+fn record() -> Result<(), String> {
+    for (name, diagram, cfg, dt, count) in circuits() {
+        let outputs = run(&diagram, &cfg, dt, count)?;
+        let fixture = Fixture {
+            diagram,
+            cfg,
+            dt,
+            outputs,
+        };
+
+        let text =
+            ron::ser::to_string_pretty(&fixture, Default::default()).map_err(|e| e.to_string())?;
+        let path = path(name);
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| e.to_string())?;
+        std::fs::write(&path, text).map_err(|e| e.to_string())?;
+        println!("Recorded {}", path.display());
+    }
+    Ok(())
+}
+
+fn check() -> Result<(), String> {
+    for (name, ..) in circuits() {
+        let path = path(name);
+        let text =
+            std::fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let fixture: Fixture =
+            ron::from_str(&text).map_err(|e| format!("{}: {e}", path.display()))?;
+
+        let outputs = run(
+            &fixture.diagram,
+            &fixture.cfg,
+            fixture.dt,
+            fixture.outputs.len(),
+        )?;
+        for (idx, (expected, actual)) in fixture.outputs.iter().zip(&outputs).enumerate() {
+            let step = (idx + 1) * STRIDE;
+            compare(&expected.voltages, &actual.voltages)
+                .map_err(|i| format!("{name}: voltage of node {i} differs after step {step}"))?;
+            compare(&expected.two_terminal_current, &actual.two_terminal_current).map_err(|i| {
+                format!("{name}: current of two-terminal #{i} differs after step {step}")
+            })?;
+            compare(
+                expected.three_terminal_current.as_flattened(),
+                actual.three_terminal_current.as_flattened(),
+            )
+            .map_err(|i| {
+                format!(
+                    "{name}: current of three-terminal #{} differs after step {step}",
+                    i / 3
+                )
+            })?;
+        }
+        println!("{name}: {} outputs match", outputs.len());
+    }
+    Ok(())
+}
+
+/// Index of the first value outside the tolerance
+fn compare(expected: &[f64], actual: &[f64]) -> Result<(), usize> {
+    if expected.len() != actual.len() {
+        return Err(expected.len().min(actual.len()));
+    }
+    match expected.iter().zip(actual).position(|(e, a)| {
+        let diff = (e - a).abs();
+        diff.is_nan() || diff > TOLERANCE * e.abs().max(1.0)
+    }) {
+        Some(idx) => Err(idx),
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn fixtures_match() {
+    let result = if std::env::var_os("RECORD_GOLDEN").is_some() {
+        record()
+    } else {
+        check()
+    };
+    if let Err(e) = result {
+        panic!("{e}");
+    }
+}
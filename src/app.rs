@@ -2,88 +2,346 @@ use std::{
     collections::HashMap,
     ffi::OsStr,
     fs::File,
+    io::ErrorKind,
     path::{Path, PathBuf},
 };
 
 use cirmcut_sim::{
-    solver::{Solver, SolverConfig, SolverMode},
+    monte_carlo::MonteCarloConfig,
+    solver::{ColumnOrdering, Solver, SolverConfig, SolverMode},
     stamp::stamp,
-    PrimitiveDiagram, SimOutputs, ThreeTerminalComponent, TwoTerminalComponent,
+    GroundKind, IntegrationMethod, PrimitiveDiagram, SimOutputs, ThreeTerminalComponent,
+    TwoTerminalComponent,
 };
 use egui::{
-    Color32, DragValue, Key, Layout, Pos2, Rect, RichText, ScrollArea, Ui, Vec2, ViewportCommand,
+    Color32, DragPanButtons, DragValue, Key, Layout, Pos2, Rect, RichText, ScrollArea, Sense,
+    Stroke, Ui, Vec2, ViewportCommand,
 };
 
+#[cfg(all(feature = "audio", not(target_arch = "wasm32")))]
+use crate::audio::AudioOutput;
 use crate::circuit_widget::{
-    draw_grid, draw_twoterminal_component, draw_twoterminal_component_no_value, egui_to_cellpos,
-    show_add_component_buttons, Diagram, DiagramEditor, DiagramState, DiagramWireState,
-    SelectionType, VisualizationOptions,
+    diagram_to_svg, draw_grid, draw_legend, draw_twoterminal_component,
+    draw_twoterminal_component_no_value, edit_ground, format_value, metric_prefix,
+    show_add_component_buttons, CellPos, ColorScheme, ComponentDefaults, Diagram, DiagramEditor,
+    DiagramState, DiagramWireState, EditorMode, SelectionType, ValueFormat, VisualizationMode,
+    VisualizationOptions, DEFAULT_SERIES_RESISTANCE, DEFAULT_TOLERANCE, SIGNIFICANT_FIGURES,
 };
+use crate::diff::{diff, Change, ChangeKind};
+use crate::measurement::MeasurementWindow;
+use crate::netlist::{parse_netlist, write_netlist};
+use crate::worker::{MonteCarloJob, SimWorker, StepJob, SETTLED_TOLERANCE};
+
+/// Upper limit on time steps per batch sent to the solver thread, so that a slow circuit can't
+/// snowball
+const MAX_STEPS_PER_BATCH: usize = 100;
+
+/// Upper limit on trials times steps of a Monte Carlo run. A run can't be stopped once started,
+/// and on wasm it blocks the UI until it's done.
+const MAX_MONTE_CARLO_STEPS: usize = 10_000_000;
+
+/// Range offered by the time step control, in seconds
+const DT_RANGE: std::ops::RangeInclusive<f64> = 1e-12..=1.0;
+
+/// Time step of new circuits, in seconds
+const DEFAULT_DT: f64 = 5e-3;
+
+/// Frames per second assumed when converting to the speed setting. Before it existed, the
+/// simulation took one step per frame.
+const NOMINAL_FRAME_RATE: f64 = 60.0;
+
+/// Circuits still changing after this many time steps are reported as not settling
+const SETTLE_STEP_LIMIT: f64 = 10_000.0;
+
+/// Circuits offered by File > Examples, as (name, RON). The first one is opened on first launch.
+const EXAMPLES: &[(&str, &str)] = &[
+    ("Colpitts oscillator", include_str!("colpitts2.ckt")),
+    ("RC filter", include_str!("../circuits/rc_filter.ckt")),
+    (
+        "LC oscillator",
+        include_str!("../circuits/lc_oscillator.ckt"),
+    ),
+    (
+        "Half-wave rectifier",
+        include_str!("../circuits/half_wave_rectifier.ckt"),
+    ),
+    (
+        "Common-emitter amplifier",
+        include_str!("../circuits/common_emitter_amp.ckt"),
+    ),
+];
+
+/// Opens or closes all switches at once
+const TOGGLE_SWITCHES_SHORTCUT: Key = Key::P;
+
+/// Held down to pan the canvas by dragging with the primary button, for mice without a middle one
+const PAN_KEY: Key = Key::Space;
+
+/// Range of on-screen sizes of a grid cell, in points, when zooming
+const MIN_CELL_PIXELS: f32 = 10.0;
+const MAX_CELL_PIXELS: f32 = 300.0;
+
+/// Furthest the view reaches from the origin, and its largest size, in scene points
+const MAX_VIEW_EXTENT: f32 = 1e8;
+
+/// Moves the selection by one cell. Cells count down the screen, as in egui.
+const NUDGE_KEYS: [(Key, (i32, i32)); 4] = [
+    (Key::ArrowLeft, (-1, 0)),
+    (Key::ArrowRight, (1, 0)),
+    (Key::ArrowUp, (0, -1)),
+    (Key::ArrowDown, (0, 1)),
+];
+
+/// Steps the values of the selection up or down, see `DiagramEditor::step_values()`. Plus is
+/// shifted equals on many layouts, so both count.
+const VALUE_STEP_KEYS: [(Key, i32); 3] = [(Key::Plus, 1), (Key::Equals, 1), (Key::Minus, -1)];
+
+/// Ways to line up the selection, see `align()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Alignment {
+    Left,
+    Right,
+    Top,
+    Bottom,
+    DistributeHorizontally,
+    DistributeVertically,
+}
+
+/// Alignments offered by Edit > Align, as (alignment, label)
+const ALIGNMENTS: [(Alignment, &str); 6] = [
+    (Alignment::Left, "Align left"),
+    (Alignment::Right, "Align right"),
+    (Alignment::Top, "Align top"),
+    (Alignment::Bottom, "Align bottom"),
+    (Alignment::DistributeHorizontally, "Distribute horizontally"),
+    (Alignment::DistributeVertically, "Distribute vertically"),
+];
 
 /// (capitalized/shift, key, component)
 const TWO_TERMINAL_SHORTCUTS: [(bool, Key, TwoTerminalComponent); 8] = [
     (false, Key::W, TwoTerminalComponent::Wire),
-    (true, Key::L, TwoTerminalComponent::Inductor(1.0, None)),
+    (
+        true,
+        Key::L,
+        TwoTerminalComponent::Inductor(1.0, None, None, None, DEFAULT_SERIES_RESISTANCE),
+    ),
     (false, Key::R, TwoTerminalComponent::Resistor(1000.0)),
-    (false, Key::C, TwoTerminalComponent::Capacitor(1000.0)),
+    (
+        false,
+        Key::C,
+        TwoTerminalComponent::Capacitor(1000.0, None, None, DEFAULT_SERIES_RESISTANCE),
+    ),
     (false, Key::D, TwoTerminalComponent::Diode),
     (false, Key::S, TwoTerminalComponent::Switch(false)),
-    (false, Key::V, TwoTerminalComponent::Battery(5.0)),
+    (false, Key::V, TwoTerminalComponent::Battery(5.0, None)),
     (false, Key::A, TwoTerminalComponent::CurrentSource(10e-3)),
 ];
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct CircuitApp {
-    view_rect: Rect,
-    editor: DiagramEditor,
+    /// Open circuits, one per tab. Never empty
+    #[serde(default = "default_tabs")]
+    tabs: Vec<CircuitTab>,
+    #[serde(default)]
+    active_tab: usize,
+
     debug_draw: bool,
-    current_path: Option<PathBuf>,
     show_matrix: bool,
+    /// Only offered while `debug_draw` is on
+    #[serde(default)]
+    show_sparsity: bool,
+    #[serde(default)]
+    show_info: bool,
+    #[serde(default)]
+    show_monte_carlo: bool,
+    #[serde(default)]
+    show_defaults: bool,
+    #[cfg(all(feature = "audio", not(target_arch = "wasm32")))]
+    #[serde(default)]
+    show_audio: bool,
     show_componentlist: bool,
     show_shortcut_list: bool,
+    #[serde(default)]
+    show_minimap: bool,
 
-    current_file: CircuitFile,
     vis_opt: VisualizationOptions,
+    /// Values of newly added components
+    #[serde(default)]
+    component_defaults: ComponentDefaults,
+
+    #[cfg(all(feature = "audio", not(target_arch = "wasm32")))]
+    #[serde(default)]
+    audio: AudioOutput,
 
+    #[serde(skip)]
+    palette_filter: String,
+}
+
+/// A single open circuit, with its own simulation and editor state
+#[derive(serde::Deserialize, serde::Serialize)]
+struct CircuitTab {
+    view_rect: Rect,
+    editor: DiagramEditor,
+    current_path: Option<PathBuf>,
+    current_file: CircuitFile,
+
+    /// Latest state of the simulation, as returned by `worker`
     #[serde(skip)]
     sim: Option<Solver>,
+    #[serde(skip)]
+    worker: SimWorker,
+    /// The single-step button was pressed while paused, and the step hasn't been started yet
+    #[serde(skip)]
+    step_queued: bool,
 
     #[serde(skip)]
     error: Option<String>,
 
+    /// Why the last pasted netlist couldn't be read
+    #[serde(skip)]
+    paste_error: Option<String>,
+
+    /// Which cell the last copied netlist used as ground, if the circuit didn't have one
+    #[serde(skip)]
+    netlist_ground: Option<CellPos>,
+
+    /// Where File > Import into current places the origin of the imported circuit
+    #[serde(skip)]
+    import_offset: CellPos,
+
+    /// Name of the file picked in File > Compare with, and how this circuit differs from it
+    #[serde(skip)]
+    comparison: Option<(String, Vec<Change>)>,
+
+    /// Time step actually used by the last step, if it had to be reduced to converge
+    #[serde(skip)]
+    reduced_dt: Option<f64>,
+
+    /// The last batch of steps ran out of time, so the simulation runs slower than real time
+    #[serde(skip)]
+    behind: bool,
+
+    /// Simulated time owed to the simulation by the wall clock, in seconds
+    #[serde(skip)]
+    step_accumulator: f64,
+
+    /// Recent voltages and currents, for the mean and RMS readouts
+    #[serde(default)]
+    measurements: MeasurementWindow,
+
+    /// Named solver states, see `Solver::snapshot()`
+    #[serde(default)]
+    snapshots: HashMap<String, Vec<f64>>,
+    #[serde(skip)]
+    snapshot_name: String,
+    /// Name typed in for a new variable
+    #[serde(skip)]
+    variable_name: String,
+
+    #[serde(default)]
+    monte_carlo: MonteCarloSettings,
+    /// Output voltage of each trial of the last Monte Carlo run
+    #[serde(skip)]
+    monte_carlo_results: Option<Result<Vec<f64>, String>>,
+    /// Runs the Monte Carlo analysis off the UI thread
+    #[serde(skip)]
+    monte_carlo_worker: SimWorker<MonteCarloJob>,
+
     paused: bool,
+    /// Pause once the circuit has settled, see `SETTLED_TOLERANCE`
+    #[serde(default)]
+    pause_when_settled: bool,
+}
+
+/// Inputs of the Monte Carlo window
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+struct MonteCarloSettings {
+    /// Port whose voltage is collected
+    port: String,
+    /// Fraction, for two-terminal components without a tolerance of their own
+    tolerance: f64,
+    trials: usize,
+    steps: usize,
+    seed: u64,
+}
+
+impl Default for MonteCarloSettings {
+    fn default() -> Self {
+        Self {
+            port: String::new(),
+            tolerance: DEFAULT_TOLERANCE,
+            trials: 100,
+            steps: 100,
+            seed: 0,
+        }
+    }
 }
 
+/// Version of the circuit file format written by this build. Bump it whenever older files would
+/// stop parsing, and upgrade them in `migrate()`.
+const FILE_VERSION: u32 = 1;
+
 #[derive(serde::Deserialize, serde::Serialize)]
 struct CircuitFile {
+    /// Format version, see `FILE_VERSION`. Files from before versioning read as 0.
+    #[serde(default)]
+    version: u32,
     diagram: Diagram,
     cfg: SolverConfig,
     dt: f64,
+    /// Simulated seconds per real second
+    #[serde(default = "default_speed")]
+    speed: f64,
+    /// Part of the diagram that was in view when saved, in cells. Files without one open zoomed
+    /// to fit.
+    #[serde(default)]
+    view: Option<Rect>,
+    /// Named values shared by the components bound to them, see `Diagram::apply_variables()`
+    #[serde(default)]
+    variables: HashMap<String, f64>,
 }
 
 impl Default for CircuitApp {
     fn default() -> Self {
         Self {
+            tabs: default_tabs(),
+            active_tab: 0,
             show_matrix: false,
+            show_sparsity: false,
+            show_info: false,
+            show_monte_carlo: false,
+            show_defaults: false,
+            #[cfg(all(feature = "audio", not(target_arch = "wasm32")))]
+            show_audio: false,
+            #[cfg(all(feature = "audio", not(target_arch = "wasm32")))]
+            audio: AudioOutput::default(),
             vis_opt: VisualizationOptions::default(),
-            error: None,
-            sim: None,
-            editor: DiagramEditor::new(),
-            current_file: ron::from_str(include_str!("colpitts2.ckt")).unwrap_or_default(),
-            paused: false,
-            view_rect: Rect::from_center_size(Pos2::ZERO, Vec2::splat(1000.0)),
+            component_defaults: ComponentDefaults::default(),
+            palette_filter: String::new(),
             debug_draw: false,
-            current_path: None,
             show_componentlist: true,
             show_shortcut_list: true,
+            show_minimap: false,
         }
     }
 }
 
+impl Default for CircuitTab {
+    fn default() -> Self {
+        Self::new(ron::from_str(EXAMPLES[0].1).unwrap_or_default())
+    }
+}
+
 impl CircuitApp {
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
         if let Some(storage) = cc.storage {
-            return eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+            let mut inst: Self = eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+            if inst.tabs.is_empty() {
+                inst.tabs = default_tabs();
+            }
+            inst.active_tab = inst.active_tab.min(inst.tabs.len() - 1);
+            return inst;
         }
 
         let inst = Self::default();
@@ -92,14 +350,100 @@ impl CircuitApp {
         inst
     }
 
+    fn tab(&self) -> &CircuitTab {
+        &self.tabs[self.active_tab]
+    }
+
+    fn update_title(&self, ctx: &egui::Context) {
+        let tab = self.tab();
+        if let Some(path) = tab.current_path.as_ref().and_then(|file| file.to_str()) {
+            ctx.send_viewport_cmd(ViewportCommand::Title(format!("Circuit {path}")));
+        }
+    }
+
+    /// Shows one selectable label per tab, plus buttons to close tabs and open new ones
+    fn show_tab_bar(&mut self, ui: &mut Ui) {
+        let mut close_idx = None;
+
+        ui.horizontal(|ui| {
+            for (idx, tab) in self.tabs.iter().enumerate() {
+                if ui
+                    .selectable_label(idx == self.active_tab, tab.title())
+                    .clicked()
+                {
+                    self.active_tab = idx;
+                }
+                if self.tabs.len() > 1 && ui.small_button("x").on_hover_text("Close").clicked() {
+                    close_idx = Some(idx);
+                }
+                ui.separator();
+            }
+
+            if ui.button("+").on_hover_text("New tab").clicked() {
+                self.new_tab(CircuitTab::new(CircuitFile::default()));
+            }
+        });
+
+        if let Some(idx) = close_idx {
+            self.tabs.remove(idx);
+            if self.active_tab > idx || self.active_tab == self.tabs.len() {
+                self.active_tab -= 1;
+            }
+        }
+    }
+
+    fn new_tab(&mut self, tab: CircuitTab) {
+        self.tabs.push(tab);
+        self.active_tab = self.tabs.len() - 1;
+    }
+}
+
+impl CircuitTab {
+    fn new(current_file: CircuitFile) -> Self {
+        Self {
+            view_rect: default_view_rect(),
+            editor: DiagramEditor::new(),
+            current_path: None,
+            current_file,
+            sim: None,
+            worker: SimWorker::default(),
+            step_queued: false,
+            error: None,
+            paste_error: None,
+            netlist_ground: None,
+            import_offset: (0, 0),
+            comparison: None,
+            reduced_dt: None,
+            behind: false,
+            step_accumulator: 0.0,
+            measurements: MeasurementWindow::default(),
+            snapshots: HashMap::new(),
+            snapshot_name: String::new(),
+            variable_name: String::new(),
+            monte_carlo: MonteCarloSettings::default(),
+            monte_carlo_results: None,
+            monte_carlo_worker: SimWorker::default(),
+            paused: false,
+            pause_when_settled: false,
+        }
+    }
+
+    fn title(&self) -> String {
+        self.current_path
+            .as_ref()
+            .and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "Untitled".into())
+    }
+
     fn state(&self) -> Option<DiagramState> {
         self.sim.as_ref().map(|sim| {
             let diag = self.current_file.diagram.to_primitive_diagram();
-            DiagramState::new(&sim.state(&diag.primitive), &diag.primitive)
+            diag.state(&sim.state(&diag.primitive))
         })
     }
 
-    fn save_file(&mut self, ctx: &egui::Context) {
+    fn save_file(&mut self, vis_opt: &VisualizationOptions) {
         #[cfg(not(target_arch = "wasm32"))]
         {
             let maybe_path = match &self.current_path {
@@ -114,14 +458,17 @@ impl CircuitApp {
                     path.set_extension("ckt");
                 }
 
+                self.current_file.version = FILE_VERSION;
+                self.current_file.view = Some(Rect::from_min_max(
+                    self.view_rect.min / vis_opt.cell_size,
+                    self.view_rect.max / vis_opt.cell_size,
+                ));
                 write_file(&self.current_file, &path);
             }
-
-            self.update_title(ctx);
         }
     }
 
-    fn open_file(&mut self, ctx: &egui::Context) {
+    fn open_file(&mut self, vis_opt: &VisualizationOptions) {
         //self.save_file(ctx);
 
         #[cfg(not(target_arch = "wasm32"))]
@@ -134,21 +481,210 @@ impl CircuitApp {
             };
 
             if let Some(path) = maybe_path {
-                if let Some(data) = read_file(&path) {
-                    self.current_file = data;
+                match read_file(&path) {
+                    Ok(data) => {
+                        self.current_file = data;
+                        self.restore_view(vis_opt);
+                        self.editor.reset_selection();
+                        self.sim = None;
+                        self.worker.invalidate();
+                        self.error = None;
+                    }
+                    Err(e) => {
+                        // Pause, or the next successful step clears the message
+                        self.error = Some(e);
+                        self.paused = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds the circuit from a .ckt file to this one, moved by `import_offset`. Node identity is
+    /// positional, so the imported circuit stays separate unless its terminals land on ours.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn import_file(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("CKT", &["ckt"])
+            .pick_file()
+        else {
+            return;
+        };
+
+        match read_file(&path) {
+            Ok(data) => {
+                self.current_file
+                    .diagram
+                    .paste(data.diagram, self.import_offset);
+                self.sim = None;
+                self.worker.invalidate();
+                self.error = None;
+            }
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    /// Lists how this circuit differs from the one in a .ckt file, see `diff()`
+    #[cfg(not(target_arch = "wasm32"))]
+    fn compare_file(&mut self, format: ValueFormat) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("CKT", &["ckt"])
+            .pick_file()
+        else {
+            return;
+        };
+
+        match read_file(&path) {
+            Ok(data) => {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let changes = diff(&data.diagram, &self.current_file.diagram, format);
+                self.comparison = Some((name, changes));
+            }
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn export_svg(&mut self, vis_opt: &VisualizationOptions, monochrome: bool) {
+        let Some(state) = self.state() else {
+            return;
+        };
+
+        let svg = diagram_to_svg(&self.current_file.diagram, &state, vis_opt, monochrome);
+
+        if let Some(mut path) = rfd::FileDialog::new()
+            .add_filter("SVG", &["svg"])
+            .save_file()
+        {
+            if path.extension() != Some(OsStr::new("svg")) {
+                path.set_extension("svg");
+            }
+
+            if let Err(e) = std::fs::write(&path, svg) {
+                eprintln!("{e}");
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Replaces the circuit with one read from a JSON file with the same layout as a .ckt file
+    #[cfg(not(target_arch = "wasm32"))]
+    fn import_json(&mut self, vis_opt: &VisualizationOptions) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("JSON", &["json"])
+            .pick_file()
+        else {
+            return;
+        };
+
+        let result = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()));
+
+        match result {
+            Ok(file) => {
+                self.current_file = file;
+                self.restore_view(vis_opt);
+                self.editor.reset_selection();
+                self.sim = None;
+                self.worker.invalidate();
+                self.error = None;
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                self.error = Some(format!("Failed to import JSON: {e}"));
+            }
+        }
+    }
+
+    /// Copies the circuit to the clipboard as a SPICE netlist, see `write_netlist()`. If it had to
+    /// pick a ground, a reference ground is added on that cell, which doesn't change the
+    /// simulation but shows what node 0 is.
+    fn copy_netlist(&mut self, ctx: &egui::Context) {
+        match write_netlist(&self.current_file.diagram) {
+            Ok(netlist) => {
+                ctx.copy_text(netlist.text);
+                if let Some(pos) = netlist.chosen_ground {
+                    self.current_file
+                        .diagram
+                        .grounds
+                        .push((pos, GroundKind::Reference));
                     self.sim = None;
                 }
+                self.netlist_ground = netlist.chosen_ground;
             }
+            Err(e) => self.error = Some(format!("Failed to export the netlist: {e}")),
+        }
+    }
+
+    /// Writes the circuit as JSON. RON stays the native format; this is for other tools.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn export_json(&mut self) {
+        // JSON has no NaN or infinity, and serde_json would quietly write them as null
+        if let Some(name) = find_non_finite(&self.current_file) {
+            self.error = Some(format!("Can't export JSON: {name} is not a finite number"));
+            return;
+        }
+
+        let Some(mut path) = rfd::FileDialog::new()
+            .add_filter("JSON", &["json"])
+            .save_file()
+        else {
+            return;
+        };
+        if path.extension() != Some(OsStr::new("json")) {
+            path.set_extension("json");
+        }
+
+        let result = serde_json::to_string_pretty(&self.current_file)
+            .map_err(|e| e.to_string())
+            .and_then(|json| std::fs::write(&path, json).map_err(|e| e.to_string()));
 
-            self.update_title(ctx);
+        if let Err(e) = result {
+            eprintln!("{e}");
+            self.error = Some(format!("Failed to export JSON: {e}"));
         }
     }
 
-    fn update_title(&self, ctx: &egui::Context) {
-        if let Some(path) = self.current_path.as_ref().and_then(|file| file.to_str()) {
-            ctx.send_viewport_cmd(ViewportCommand::Title(format!("Circuit {path}")));
+    /// Shows the part of the diagram saved with the file, or all of it if none was
+    fn restore_view(&mut self, vis_opt: &VisualizationOptions) {
+        match self.current_file.view {
+            Some(view) => {
+                self.view_rect = Rect::from_min_max(
+                    view.min * vis_opt.cell_size,
+                    view.max * vis_opt.cell_size,
+                )
+            }
+            None => self.zoom_to_fit(vis_opt),
         }
     }
+
+    /// Fits the view to the bounding box of every component in the diagram
+    fn zoom_to_fit(&mut self, vis_opt: &VisualizationOptions) {
+        let diagram = &self.current_file.diagram;
+        let points: Vec<Pos2> = diagram
+            .two_terminal
+            .iter()
+            .flat_map(|(positions, _)| positions.iter().copied())
+            .chain(
+                diagram
+                    .three_terminal
+                    .iter()
+                    .flat_map(|(positions, _)| positions.iter().copied()),
+            )
+            .chain(diagram.ports.iter().map(|(pos, _)| *pos))
+            .map(|pos| vis_opt.cellpos_to_egui(pos))
+            .collect();
+
+        self.view_rect = if points.is_empty() {
+            default_view_rect()
+        } else {
+            Rect::from_points(&points).expand(vis_opt.cell_size)
+        };
+    }
 }
 
 impl eframe::App for CircuitApp {
@@ -163,40 +699,162 @@ impl eframe::App for CircuitApp {
             egui::menu::bar(ui, |ui| {
                 ui.menu_button("File", |ui| {
                     if ui.button("New").clicked() {
-                        self.current_file = CircuitFile::default();
-                        self.sim = None;
+                        self.new_tab(CircuitTab::new(CircuitFile::default()));
                     }
                     ui.separator();
                     #[cfg(not(target_arch = "wasm32"))]
                     {
                         if ui.button("Open").clicked() {
-                            self.open_file(ui.ctx());
+                            self.tabs[self.active_tab].open_file(&self.vis_opt);
+                            self.update_title(ui.ctx());
                         }
                         if ui.button("Save").clicked() {
-                            self.save_file(ui.ctx());
+                            self.tabs[self.active_tab].save_file(&self.vis_opt);
+                            self.update_title(ui.ctx());
+                        }
+                        ui.menu_button("Import into current", |ui| {
+                            let tab = &mut self.tabs[self.active_tab];
+                            ui.horizontal(|ui| {
+                                ui.label("Offset: ");
+                                ui.add(DragValue::new(&mut tab.import_offset.0).prefix("x: "));
+                                ui.add(DragValue::new(&mut tab.import_offset.1).prefix("y: "));
+                            });
+                            if ui
+                                .button("Choose file...")
+                                .on_hover_text("Adds a circuit file to this one, moved by the offset")
+                                .clicked()
+                            {
+                                tab.import_file();
+                            }
+                        });
+                        if ui
+                            .button("Compare with...")
+                            .on_hover_text("List what differs between another circuit file and this one")
+                            .clicked()
+                        {
+                            self.tabs[self.active_tab].compare_file(self.vis_opt.value_format);
+                        }
+                        ui.separator();
+                        if ui.button("Import JSON...").clicked() {
+                            self.tabs[self.active_tab].import_json(&self.vis_opt);
+                        }
+                        if ui.button("Export JSON...").clicked() {
+                            self.tabs[self.active_tab].export_json();
+                        }
+                        ui.separator();
+                        if ui.button("Export SVG...").clicked() {
+                            self.tabs[self.active_tab].export_svg(&self.vis_opt, false);
+                        }
+                        if ui.button("Export SVG (black on white)...").clicked() {
+                            self.tabs[self.active_tab].export_svg(&self.vis_opt, true);
                         }
                         ui.separator();
                     }
 
-                    if ui.button("Load Example circuit").clicked() {
-                        self.current_file = Self::default().current_file;
-                        self.sim = None;
-                    }
+                    ui.menu_button("Examples", |ui| {
+                        for (name, text) in EXAMPLES {
+                            if ui.button(*name).clicked() {
+                                self.new_tab(CircuitTab::new(ron::from_str(text).unwrap_or_default()));
+                                self.tabs[self.active_tab].restore_view(&self.vis_opt);
+                            }
+                        }
+                    });
                     egui::widgets::global_theme_preference_buttons(ui);
                 });
 
+                ui.menu_button("Edit", |ui| {
+                    if ui
+                        .button("Paste netlist")
+                        .on_hover_text("Add a SPICE netlist from the clipboard. Ctrl+V on the canvas does the same.")
+                        .clicked()
+                    {
+                        ui.ctx().send_viewport_cmd(ViewportCommand::RequestPaste);
+                    }
+                    if ui
+                        .button("Copy as netlist")
+                        .on_hover_text("Copy the circuit to the clipboard as a SPICE netlist")
+                        .clicked()
+                    {
+                        self.tabs[self.active_tab].copy_netlist(ui.ctx());
+                    }
+                    ui.separator();
+                    if ui
+                        .button("Lock all")
+                        .on_hover_text("Stop every component from being dragged or nudged")
+                        .clicked()
+                    {
+                        self.tabs[self.active_tab].current_file.diagram.set_all_locked(true);
+                    }
+                    if ui.button("Unlock all").clicked() {
+                        self.tabs[self.active_tab].current_file.diagram.set_all_locked(false);
+                    }
+                    ui.separator();
+                    let tab = &mut self.tabs[self.active_tab];
+                    let selection = tab.editor.selection(&tab.current_file.diagram);
+                    ui.add_enabled_ui(selection.len() >= 2, |ui| {
+                        ui.menu_button("Align", |ui| {
+                            for (alignment, label) in ALIGNMENTS {
+                                if ui.button(label).clicked()
+                                    && align(&mut tab.current_file.diagram, &selection, alignment)
+                                {
+                                    tab.sim = None;
+                                }
+                            }
+                        });
+                    });
+                });
+
                 ui.menu_button("View", |ui| {
                     egui::Grid::new("viewgrid").show(ui, |ui| {
                         ui.label("Show matrix");
                         ui.checkbox(&mut self.show_matrix, "On");
                         ui.end_row();
 
+                        ui.label("Show info");
+                        ui.checkbox(&mut self.show_info, "On");
+                        ui.end_row();
+
+                        ui.label("Show Monte Carlo");
+                        ui.checkbox(&mut self.show_monte_carlo, "On");
+                        ui.end_row();
+
+                        ui.label("Show default values");
+                        ui.checkbox(&mut self.show_defaults, "On");
+                        ui.end_row();
+
+                        #[cfg(all(feature = "audio", not(target_arch = "wasm32")))]
+                        {
+                            ui.label("Show audio");
+                            ui.checkbox(&mut self.show_audio, "On");
+                            ui.end_row();
+                        }
+
                         ui.label("Show component list");
                         ui.checkbox(&mut self.show_componentlist, "On");
                         ui.end_row();
 
-                        if ui.button("Reset viewbox").clicked() {
-                            self.view_rect = Rect::ZERO;
+                        ui.label("Show minimap")
+                            .on_hover_text("Overview of the whole circuit. Click it to look there.");
+                        ui.checkbox(&mut self.show_minimap, "On");
+                        ui.end_row();
+
+                        ui.label("Debug draw")
+                            .on_hover_text("Hitboxes, and the direction currents are counted in");
+                        ui.checkbox(&mut self.debug_draw, "On");
+                        ui.end_row();
+
+                        if self.debug_draw {
+                            ui.label("Show sparsity pattern")
+                                .on_hover_text("Where each component stamps into the matrix");
+                            ui.checkbox(&mut self.show_sparsity, "On");
+                            ui.end_row();
+                        }
+
+                        if ui.button("Zoom to fit").clicked() {
+                            self.tabs[self.active_tab].zoom_to_fit(&self.vis_opt);
+                        }
+                        if ui.button("Recenter").clicked() {
+                            self.tabs[self.active_tab].view_rect = default_view_rect();
                         }
                         ui.end_row();
                     });
@@ -209,12 +867,45 @@ impl eframe::App for CircuitApp {
                     );
                 });
             });
+
+            ui.separator();
+            self.show_tab_bar(ui);
         });
 
-        let mut rebuild_sim = self.sim.is_none();
+        let tab = &mut self.tabs[self.active_tab];
+        tab.finish_steps();
+
+        if let Some(pos) = tab.current_file.diagram.implicit_reference() {
+            egui::TopBottomPanel::top("no_ground").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.colored_label(
+                        Color32::YELLOW,
+                        format!(
+                            "No ground: voltages are measured from ({}, {}), chosen arbitrarily.",
+                            pos.0, pos.1
+                        ),
+                    );
+                    if ui
+                        .button("Add ground there")
+                        .on_hover_text("Or place a Ground from the component list yourself")
+                        .clicked()
+                    {
+                        tab.editor.new_ground(&mut tab.current_file.diagram, pos);
+                        tab.sim = None;
+                        tab.worker.invalidate();
+                    }
+                });
+            });
+        }
+
+        let mut rebuild_sim = tab.sim.is_none();
+        rebuild_sim |= tab
+            .current_file
+            .diagram
+            .apply_variables(&tab.current_file.variables);
 
         // TODO: Cache this?
-        let state = self.state();
+        let state = tab.state();
 
         let mut single_step = false;
 
@@ -223,68 +914,213 @@ impl eframe::App for CircuitApp {
                 ui.strong("Simulation");
 
                 ui.horizontal(|ui| {
-                    egui_simpletabs::play_pause_button(ui, &mut self.paused);
+                    egui_simpletabs::play_pause_button(ui, &mut tab.paused);
                     single_step |= egui_simpletabs::single_step_button(ui).clicked();
                     rebuild_sim |= egui_simpletabs::reset_step_button(ui).clicked();
                 });
 
+                let format = self.vis_opt.value_format;
+                let time = tab.sim.as_ref().map(|sim| sim.time()).unwrap_or(0.0);
                 ui.horizontal(|ui| {
-                    ui.label("Δt: ");
-                    ui.add(egui_simpletabs::edit_metric_f64(
-                        &mut self.current_file.dt,
-                        "s",
-                    ));
+                    ui.label(format!("t = {}", format_value(time, 's', format)));
+                    if let Some(sim) = &tab.sim {
+                        if sim.is_steady(SETTLED_TOLERANCE) {
+                            ui.colored_label(Color32::GREEN, "Settled");
+                        } else if time > SETTLE_STEP_LIMIT * tab.current_file.dt {
+                            ui.colored_label(
+                                Color32::YELLOW,
+                                "Not settling (possibly oscillating)",
+                            );
+                        } else if time > 0.0 {
+                            ui.weak("Settling...");
+                        }
+                    }
                 });
+                ui.checkbox(&mut tab.pause_when_settled, "Pause when settled");
+
+                ui.add(
+                    egui::Slider::new(&mut tab.current_file.speed, 1e-6..=10.0)
+                        .logarithmic(true)
+                        .text("Speed"),
+                )
+                .on_hover_text("Simulated seconds per real second");
+
+                ui.add(
+                    egui::Slider::new(&mut tab.current_file.dt, DT_RANGE)
+                        .logarithmic(true)
+                        .custom_formatter(|v, _| metric_prefix(v, 's', SIGNIFICANT_FIGURES))
+                        .custom_parser(|text| parse_metric_prefix(text, 's'))
+                        .text("Δt"),
+                )
+                .on_hover_text("Type a value such as 10ns or 1e-6");
 
-                if let Some(error) = &self.error {
+                ui.add(
+                    egui::Slider::new(&mut tab.measurements.length, 1e-9..=10.0)
+                        .logarithmic(true)
+                        .custom_formatter(|v, _| metric_prefix(v, 's', SIGNIFICANT_FIGURES))
+                        .custom_parser(|text| parse_metric_prefix(text, 's'))
+                        .text("Averaging window"),
+                )
+                .on_hover_text("Span of simulated time used for the mean and RMS readouts");
+
+                if let Some(error) = &tab.error {
                     ui.label(RichText::new(error).color(Color32::RED));
                 }
 
+                if let Some(dt) = tab.reduced_dt {
+                    ui.label(
+                        RichText::new(format!(
+                            "Δt reduced to {} to converge",
+                            format_value(dt, 's', format)
+                        ))
+                        .color(Color32::YELLOW),
+                    );
+                }
+
+                if tab.behind && !tab.paused {
+                    ui.label(
+                        RichText::new("Running slower than real time").color(Color32::YELLOW),
+                    )
+                    .on_hover_text(
+                        "The circuit takes longer to simulate than the time it covers. \
+                        A larger Δt or a lower speed helps.",
+                    );
+                }
+
+                if ui
+                    .button("Toggle all switches")
+                    .on_hover_text("Opens every switch if any is closed, otherwise closes them all")
+                    .clicked()
+                {
+                    tab.current_file.diagram.toggle_all_switches();
+                }
+
+                ui.collapsing("Snapshots", |ui| {
+                    tab.show_snapshots(ui);
+                });
+
+                ui.collapsing("Variables", |ui| {
+                    tab.show_variables(ui);
+                });
+
                 ui.collapsing("Advanced", |ui| {
                     ui.add(
-                        DragValue::new(&mut self.current_file.cfg.max_nr_iters)
+                        DragValue::new(&mut tab.current_file.cfg.max_nr_iters)
                             .prefix("Max NR iters: "),
                     );
                     ui.horizontal(|ui| {
                         ui.add(
-                            DragValue::new(&mut self.current_file.cfg.nr_step_size)
+                            DragValue::new(&mut tab.current_file.cfg.nr_step_size)
                                 .speed(1e-6)
                                 .prefix("Initial NR step size: "),
                         );
-                        ui.checkbox(&mut self.current_file.cfg.adaptive_step_size, "Adaptive");
+                        ui.checkbox(&mut tab.current_file.cfg.adaptive_step_size, "Adaptive");
                     });
+                    ui.checkbox(&mut tab.current_file.cfg.line_search, "Line search")
+                        .on_hover_text(
+                            "Take full NR steps, halved until they reduce the residual. \
+                            Ignores the step size above.",
+                        );
 
                     ui.add(
-                        DragValue::new(&mut self.current_file.cfg.nr_tolerance)
+                        DragValue::new(&mut tab.current_file.cfg.nr_tolerance)
                             .speed(1e-6)
                             .prefix("NR tolerance: "),
                     );
                     ui.add(
-                        DragValue::new(&mut self.current_file.cfg.dx_soln_tolerance)
+                        DragValue::new(&mut tab.current_file.cfg.dx_soln_tolerance)
                             .speed(1e-6)
                             .prefix("Matrix solve tol: "),
                     );
 
                     ui.horizontal(|ui| {
                         ui.selectable_value(
-                            &mut self.current_file.cfg.mode,
+                            &mut tab.current_file.cfg.mode,
                             SolverMode::NewtonRaphson,
                             "Newton-Raphson",
                         );
                         ui.selectable_value(
-                            &mut self.current_file.cfg.mode,
+                            &mut tab.current_file.cfg.mode,
                             SolverMode::Linear,
                             "Linear",
                         );
                     });
 
+                    let ordering = &mut tab.current_file.cfg.ordering;
+                    egui::ComboBox::from_label("Ordering")
+                        .selected_text(ordering.name())
+                        .show_ui(ui, |ui| {
+                            for option in ColumnOrdering::ALL {
+                                ui.selectable_value(ordering, option, option.name());
+                            }
+                        })
+                        .response
+                        .on_hover_text(
+                            "Elimination order of the matrix solve. The AMD orderings \
+                            can be much faster on large circuits.",
+                        );
+
+                    let integration = &mut tab.current_file.cfg.integration;
+                    egui::ComboBox::from_label("Integration")
+                        .selected_text(integration.name())
+                        .show_ui(ui, |ui| {
+                            for option in IntegrationMethod::ALL {
+                                ui.selectable_value(integration, option, option.name());
+                            }
+                        })
+                        .response
+                        .on_hover_text(
+                            "How capacitors and inductors are stepped, unless they choose \
+                            their own. Backward Euler damps, so oscillators die down; \
+                            trapezoidal keeps them going but can ring after sudden changes.",
+                        );
+
+                    if let Some(sim) = &tab.sim {
+                        ui.label("NR error of the last step");
+                        show_residual_plot(
+                            ui,
+                            sim.last_residuals(),
+                            tab.current_file.cfg.nr_tolerance,
+                        );
+                    }
+
                     if ui.button("Default cfg").clicked() {
-                        self.current_file.cfg = Default::default();
+                        tab.current_file.cfg = Default::default();
                     }
                 });
 
                 ui.separator();
                 ui.strong("Visualization");
+                ui.horizontal(|ui| {
+                    ui.label("Color by: ");
+                    for (mode, name) in [
+                        (VisualizationMode::Voltage, "Voltage"),
+                        (VisualizationMode::Current, "Current"),
+                        (VisualizationMode::Power, "Power"),
+                    ] {
+                        ui.selectable_value(&mut self.vis_opt.mode, mode, name);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Colors: ");
+                    for (scheme, name) in [
+                        (ColorScheme::RedGreen, "Red/green"),
+                        (ColorScheme::BlueYellow, "Blue/yellow"),
+                        (ColorScheme::Viridis, "Viridis"),
+                    ] {
+                        ui.selectable_value(&mut self.vis_opt.color_scheme, scheme, name);
+                    }
+                });
+                let value_format = &mut self.vis_opt.value_format;
+                egui::ComboBox::from_label("Values")
+                    .selected_text(value_format.name())
+                    .show_ui(ui, |ui| {
+                        for option in ValueFormat::ALL {
+                            ui.selectable_value(value_format, option, option.name());
+                        }
+                    })
+                    .response
+                    .on_hover_text("How values are written in labels and readouts");
                 ui.add(
                     egui_simpletabs::edit_metric_f64(&mut self.vis_opt.voltage_scale, "V")
                         .prefix("Voltage scale: ")
@@ -295,6 +1131,60 @@ impl eframe::App for CircuitApp {
                         .prefix("Current scale: ")
                         .speed(1e-2),
                 );
+                ui.horizontal(|ui| {
+                    ui.add(
+                        DragValue::new(&mut self.vis_opt.cell_size)
+                            .range(10.0..=1000.0)
+                            .prefix("Grid size: "),
+                    );
+                    ui.checkbox(&mut self.vis_opt.snap_to_grid, "Snap");
+                });
+                ui.collapsing("Grid style", |ui| {
+                    let grid = &mut self.vis_opt.grid;
+                    ui.horizontal(|ui| {
+                        ui.label("Dots: ");
+                        ui.color_edit_button_srgba(&mut grid.dot_color);
+                        ui.add(
+                            DragValue::new(&mut grid.dot_radius)
+                                .range(0.0..=10.0)
+                                .speed(0.05)
+                                .prefix("Radius: "),
+                        );
+                    });
+                    ui.horizontal(|ui| {
+                        let mut custom = grid.background.is_some();
+                        ui.checkbox(&mut custom, "Background: ");
+                        let mut color = grid.background.unwrap_or(Color32::BLACK);
+                        ui.add_enabled_ui(custom, |ui| ui.color_edit_button_srgba(&mut color));
+                        grid.background = custom.then_some(color);
+                    });
+                    ui.add(
+                        DragValue::new(&mut grid.min_spacing)
+                            .range(1.0..=100.0)
+                            .prefix("Min. spacing: ")
+                            .suffix(" pt"),
+                    )
+                    .on_hover_text("Zoomed out further, only every second, fourth, ... dot is drawn");
+                    ui.checkbox(&mut grid.hide_when_dense, "Hide instead when zoomed out");
+                });
+                ui.checkbox(
+                    &mut self.vis_opt.auto_connect,
+                    "Auto-connect adjacent terminals",
+                )
+                .on_hover_text("After a drag, wire loose terminals to neighbors one cell away");
+                ui.checkbox(&mut self.vis_opt.current_width, "Wire width by current")
+                    .on_hover_text("Thicker wires carry more current, up to the current scale");
+                ui.horizontal(|ui| {
+                    let mut warn = self.vis_opt.max_fan_in.is_some();
+                    ui.checkbox(&mut warn, "Warn above");
+                    let mut max = self.vis_opt.max_fan_in.unwrap_or(4);
+                    ui.add_enabled(
+                        warn,
+                        DragValue::new(&mut max).range(2..=100).suffix(" terminals"),
+                    )
+                    .on_hover_text("Highlights cells joining more terminals than this");
+                    self.vis_opt.max_fan_in = warn.then_some(max);
+                });
                 if ui.button("Auto scale").clicked() {
                     if let Some(state) = &state {
                         let all_wires = state.two_terminal.iter().copied().flatten();
@@ -316,29 +1206,123 @@ impl eframe::App for CircuitApp {
         if let Some(state) = &state {
             egui::SidePanel::right("component").show(ctx, |ui| {
                 ui.strong("Component");
-                rebuild_sim |=
-                    self.editor
-                        .edit_component(ui, &mut self.current_file.diagram, state);
+                rebuild_sim |= tab.editor.edit_component(
+                    ui,
+                    &mut tab.current_file.diagram,
+                    state,
+                    &tab.measurements,
+                    self.vis_opt.value_format,
+                );
             });
         }
 
+        if self.show_info {
+            egui::Window::new("Info")
+                .open(&mut self.show_info)
+                .show(ctx, |ui| {
+                    let rich = tab.current_file.diagram.to_primitive_diagram();
+                    let stats = rich.primitive.stats();
+                    egui::Grid::new("info").show(ui, |ui| {
+                        ui.label("Nodes");
+                        ui.label(stats.nodes.to_string());
+                        ui.end_row();
+
+                        ui.label("Two-terminal components");
+                        ui.label(stats.two_terminal.to_string());
+                        ui.end_row();
+
+                        ui.label("Three-terminal components");
+                        ui.label(stats.three_terminal.to_string());
+                        ui.end_row();
+
+                        ui.label("Matrix size");
+                        ui.label(format!("{0} × {0}", stats.matrix_size));
+                        ui.end_row();
+
+                        // Should be zero at every node, so anything else points at a bug
+                        let worst = tab.sim.as_ref().and_then(|sim| {
+                            sim.state(&rich.primitive)
+                                .kcl_residuals(&rich.primitive)
+                                .into_iter()
+                                .enumerate()
+                                .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+                        });
+                        if let Some((node, residual)) = worst {
+                            let cell = rich.all_positions.iter().find(|(_, &idx)| idx == node);
+                            let place = match cell {
+                                Some(((x, y), _)) => format!("({x}, {y})"),
+                                None => format!("hidden node {node}"),
+                            };
+                            ui.label("Worst KCL error")
+                                .on_hover_text("Net current into a node, which should be zero");
+                            let residual = format_value(residual, 'A', self.vis_opt.value_format);
+                            ui.label(format!("{residual} at {place}"));
+                            ui.end_row();
+                        }
+                    });
+                    ui.weak("Counts include the hidden parts of grounds and crystals");
+                });
+        }
+
+        if tab.comparison.is_some() {
+            let mut open = true;
+            egui::Window::new("Differences")
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    tab.show_comparison(ui);
+                });
+            if !open {
+                tab.comparison = None;
+            }
+        }
+
+        if self.show_monte_carlo {
+            egui::Window::new("Monte Carlo")
+                .open(&mut self.show_monte_carlo)
+                .show(ctx, |ui| {
+                    tab.show_monte_carlo(ui, self.vis_opt.value_format);
+                });
+        }
+
+        if self.show_defaults {
+            egui::Window::new("Default values")
+                .open(&mut self.show_defaults)
+                .show(ctx, |ui| {
+                    ui.weak("Values given to newly added components");
+                    ScrollArea::vertical().show(ui, |ui| {
+                        self.component_defaults.edit(ui);
+                    });
+                });
+        }
+
+        #[cfg(all(feature = "audio", not(target_arch = "wasm32")))]
+        if self.show_audio {
+            egui::Window::new("Audio")
+                .open(&mut self.show_audio)
+                .show(ctx, |ui| {
+                    self.audio.show(ui, &tab.current_file.diagram);
+                });
+        }
+
         if self.show_matrix {
             egui::Window::new("Matrix").open(&mut self.show_matrix).show(ctx, |ui| {
                 ui.heading("Matrix");
-                if let Some(solver) = &self.sim {
-                    let diagram = self.current_file.diagram.to_primitive_diagram();
+                if let Some(solver) = &tab.sim {
+                    let primary = tab.editor.primary(&tab.current_file.diagram);
+                    let diagram = tab.current_file.diagram.to_primitive_diagram();
                     let mut selection = None;
-                    if let Some((idx, SelectionType::TwoTerminal)) = self.editor.selected {
+                    if let Some((idx, SelectionType::TwoTerminal)) = primary {
                         selection = Some(idx);
                     }
 
-                    if let Some((idx, SelectionType::ThreeTerminal)) = self.editor.selected {
+                    if let Some((idx, SelectionType::ThreeTerminal)) = primary {
                         selection = Some(idx + diagram.primitive.two_terminal.len());
                     }
 
                     show_parameter_matrix(
                         ui,
-                        self.current_file.dt,
+                        tab.current_file.dt,
+                        tab.current_file.cfg.integration,
                         solver,
                         &diagram.primitive,
                         selection,
@@ -347,11 +1331,41 @@ impl eframe::App for CircuitApp {
             });
         }
 
+        if self.debug_draw && self.show_sparsity {
+            egui::Window::new("Sparsity pattern")
+                .open(&mut self.show_sparsity)
+                .show(ctx, |ui| match &tab.sim {
+                    Some(solver) => {
+                        let diagram = tab.current_file.diagram.to_primitive_diagram().primitive;
+                        let rows = match tab.editor.primary(&tab.current_file.diagram) {
+                            Some((idx, SelectionType::TwoTerminal)) => idx..idx + 1,
+                            Some((idx, SelectionType::ThreeTerminal)) => {
+                                let first = diagram.two_terminal.len() + 2 * idx;
+                                first..first + 2
+                            }
+                            _ => 0..0,
+                        };
+                        show_sparsity_pattern(
+                            ui,
+                            tab.current_file.dt,
+                            tab.current_file.cfg.integration,
+                            solver,
+                            &diagram,
+                            rows,
+                        );
+                    }
+                    None => {
+                        ui.weak("No simulation yet");
+                    }
+                });
+        }
+
         if self.show_componentlist {
             egui::Window::new("Component list").open(&mut self.show_componentlist).show(ctx, |ui| {
                 ui.heading("Components");
                 egui::ScrollArea::both().show(ui, |ui| {
-                    show_component_list(ui, &mut self.current_file.diagram, &mut self.editor);
+                    rebuild_sim |=
+                        show_component_list(ui, &mut tab.current_file.diagram, &mut tab.editor);
                 });
             });
         }
@@ -367,25 +1381,157 @@ impl eframe::App for CircuitApp {
         egui::TopBottomPanel::bottom("buttons").show(ctx, |ui| {
             ScrollArea::horizontal().show(ui, |ui| {
                 ui.horizontal(|ui| {
+                    let drawing = matches!(tab.editor.mode, EditorMode::DrawWire { .. });
+                    if ui
+                        .selectable_label(drawing, "Draw wires")
+                        .on_hover_text("Drag between cells to draw a chain of wires")
+                        .clicked()
+                    {
+                        tab.editor.mode = if drawing {
+                            EditorMode::Select
+                        } else {
+                            EditorMode::DrawWire { start: None }
+                        };
+                    }
+                    ui.separator();
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.palette_filter)
+                            .hint_text("Search components")
+                            .desired_width(120.0),
+                    );
+                    ui.separator();
                     rebuild_sim |= show_add_component_buttons(
                         ui,
-                        self.view_rect.center(),
-                        &mut self.editor,
-                        &mut self.current_file.diagram,
+                        self.vis_opt.egui_to_cellpos(tab.view_rect.center()),
+                        &mut tab.editor,
+                        &mut tab.current_file.diagram,
+                        &self.palette_filter,
+                        &self.component_defaults,
                     );
+                    let stacked = tab.current_file.diagram.stacked_components();
+                    if !stacked.is_empty() {
+                        ui.separator();
+                        ui.colored_label(
+                            Color32::YELLOW,
+                            format!("{} stacked components", stacked.len()),
+                        )
+                        .on_hover_text("Components lying exactly on top of another one");
+                        let diagram = &tab.current_file.diagram;
+                        let ids: Vec<_> =
+                            stacked.iter().filter_map(|&sel| diagram.id(sel)).collect();
+                        if ui.button("Select").clicked() {
+                            tab.editor.reset_selection();
+                            tab.editor.multi_selected = ids.clone();
+                        }
+                        if ui
+                            .button("Clean up")
+                            .on_hover_text("Deletes all but the first component in each stack")
+                            .clicked()
+                        {
+                            tab.editor.reset_selection();
+                            tab.editor.multi_selected = ids;
+                            tab.editor.delete(&mut tab.current_file.diagram);
+                            rebuild_sim = true;
+                        }
+                    }
+                    let diagram = &tab.current_file.diagram;
+                    let collapsed = diagram.collapsed_components();
+                    if !collapsed.is_empty() {
+                        ui.separator();
+                        ui.colored_label(
+                            Color32::RED,
+                            format!("{} collapsed components", collapsed.len()),
+                        )
+                        .on_hover_text(
+                            "Components with two terminals on the same cell, which are left out of the simulation",
+                        );
+                        if ui.button("Select").clicked() {
+                            tab.editor.reset_selection();
+                            tab.editor.multi_selected =
+                                collapsed.iter().filter_map(|&sel| diagram.id(sel)).collect();
+                        }
+                    }
+                    for idx in diagram.shorted_batteries() {
+                        // Numbered among the batteries, counting from 1
+                        let number = diagram.two_terminal[..idx]
+                            .iter()
+                            .filter(|(_, c)| matches!(c, TwoTerminalComponent::Battery(..)))
+                            .count()
+                            + 1;
+                        ui.separator();
+                        ui.colored_label(Color32::RED, format!("Battery {number} is shorted"))
+                            .on_hover_text(
+                                "Its terminals are joined by nothing but wires and closed switches",
+                            );
+                        if ui.button("Select").clicked() {
+                            tab.editor.reset_selection();
+                            tab.editor.multi_selected = diagram
+                                .id((idx, SelectionType::TwoTerminal))
+                                .into_iter()
+                                .collect();
+                        }
+                    }
+                    if let Some(e) = &tab.paste_error {
+                        ui.separator();
+                        ui.colored_label(Color32::RED, format!("Paste failed: {e}"));
+                        if ui.small_button("x").clicked() {
+                            tab.paste_error = None;
+                        }
+                    }
+                    if let Some((x, y)) = tab.netlist_ground {
+                        ui.separator();
+                        ui.colored_label(
+                            Color32::YELLOW,
+                            format!("The netlist uses ({x}, {y}) as ground"),
+                        )
+                        .on_hover_text(
+                            "The circuit had no reference ground, so the netlist's node 0 is where the simulator measures voltages from. A ground was added there.",
+                        );
+                        if ui.small_button("x").clicked() {
+                            tab.netlist_ground = None;
+                        }
+                    }
                 });
             });
         });
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            egui::Frame::canvas(ui.style()).show(ui, |ui| {
-                let rect = self.view_rect;
-                let resp = egui::Scene::new().show(ui, &mut self.view_rect, |ui| {
-                    draw_grid(ui, rect, 1.0, Color32::DARK_GRAY);
+            let mut frame = egui::Frame::canvas(ui.style());
+            if let Some(background) = self.vis_opt.grid.background {
+                frame = frame.fill(background);
+            }
+            frame.show(ui, |ui| {
+                sanitize_view(&mut tab.view_rect, self.vis_opt.cell_size);
+
+                // Scrolling zooms about the cursor, rather than panning as it would in the scene
+                let canvas = ui.available_rect_before_wrap();
+                let zoom_range = MIN_CELL_PIXELS / self.vis_opt.cell_size
+                    ..=MAX_CELL_PIXELS / self.vis_opt.cell_size;
+                if ui.rect_contains_pointer(canvas) {
+                    let scroll = ui.input_mut(|r| std::mem::take(&mut r.smooth_scroll_delta));
+                    // The editor steps the value of a selected component with it instead
+                    let pointer = ui.input(|r| r.pointer.hover_pos());
+                    if let Some(pointer) = pointer.filter(|_| !tab.editor.scrolls_value()) {
+                        let factor = (scroll.x + scroll.y) / 200.0;
+                        zoom_view(&mut tab.view_rect, canvas, pointer, factor.exp(), &zoom_range);
+                    }
+                }
+
+                let mut pan_buttons = DragPanButtons::MIDDLE;
+                if !ui.ctx().wants_keyboard_input() && ui.input(|r| r.key_down(PAN_KEY)) {
+                    pan_buttons |= DragPanButtons::PRIMARY;
+                }
+
+                let rect = tab.view_rect;
+                let scene = egui::Scene::new()
+                    .zoom_range(zoom_range)
+                    .drag_pan_buttons(pan_buttons);
+                let resp = scene.show(ui, &mut tab.view_rect, |ui| {
+                    draw_grid(ui, rect, &self.vis_opt);
                     if let Some(state) = state {
-                        rebuild_sim |= self.editor.edit(
+                        rebuild_sim |= tab.editor.edit(
                             ui,
-                            &mut self.current_file.diagram,
+                            &mut tab.current_file.diagram,
                             &state,
                             self.debug_draw,
                             &self.vis_opt,
@@ -393,67 +1539,612 @@ impl eframe::App for CircuitApp {
                     }
                 });
 
+                // Drawn over the scene, in the bottom left corner of the canvas
+                egui::Area::new(ui.id().with("legend"))
+                    .fixed_pos(resp.response.rect.left_bottom() + Vec2::new(8.0, -8.0))
+                    .pivot(egui::Align2::LEFT_BOTTOM)
+                    .interactable(false)
+                    .show(ui.ctx(), |ui| {
+                        egui::Frame::popup(ui.style()).show(ui, |ui| {
+                            draw_legend(ui, &self.vis_opt);
+                        });
+                    });
+
+                if self.show_minimap {
+                    egui::Area::new(ui.id().with("minimap"))
+                        .fixed_pos(resp.response.rect.right_bottom() + Vec2::new(-8.0, -8.0))
+                        .pivot(egui::Align2::RIGHT_BOTTOM)
+                        .show(ui.ctx(), |ui| {
+                            egui::Frame::popup(ui.style()).show(ui, |ui| {
+                                show_minimap(
+                                    ui,
+                                    &tab.current_file.diagram,
+                                    &mut tab.view_rect,
+                                    &self.vis_opt,
+                                );
+                            });
+                        });
+                }
+
                 // Delete
                 if ui.input(|r| r.key_pressed(Key::Delete)) {
                     rebuild_sim = true;
-                    self.editor.delete(&mut self.current_file.diagram);
+                    tab.editor.delete(&mut tab.current_file.diagram);
+                }
+
+                // Nudge, unless the arrows are meant for a text field
+                if !ui.ctx().wants_keyboard_input() {
+                    for (key, offset) in NUDGE_KEYS {
+                        if ui.input(|r| r.key_pressed(key)) {
+                            rebuild_sim |= tab.editor.nudge(&mut tab.current_file.diagram, offset);
+                        }
+                    }
+                    // Values don't change the topology, so this doesn't need a rebuild
+                    for (key, steps) in VALUE_STEP_KEYS {
+                        if ui.input(|r| r.key_pressed(key) && !r.modifiers.command) {
+                            tab.editor.step_values(&mut tab.current_file.diagram, steps);
+                        }
+                    }
                 }
 
                 // Reset selection
                 if resp.response.clicked() || ui.input(|r| r.key_pressed(Key::Escape)) {
-                    self.editor.reset_selection();
+                    tab.editor.reset_selection();
                 }
 
                 // Shortcuts
                 if let Some(mouse_pos) = resp.response.hover_pos() {
                     for (shift, key, component) in TWO_TERMINAL_SHORTCUTS {
                         if ui.input(|r| r.key_pressed(key) && r.modifiers.shift == shift) {
-                            self.editor.new_twoterminal(
-                                &mut self.current_file.diagram,
-                                egui_to_cellpos(mouse_pos),
-                                component,
+                            tab.editor.new_twoterminal(
+                                &mut tab.current_file.diagram,
+                                self.vis_opt.egui_to_cellpos(mouse_pos),
+                                self.component_defaults.two_terminal(component),
                             );
                             rebuild_sim = true;
                         }
                     }
                 }
 
+                // Switches only change values, not the topology, so this doesn't need a rebuild
+                let toggle_switches = ui.input(|r| r.key_pressed(TOGGLE_SWITCHES_SHORTCUT));
+                if toggle_switches && resp.response.hovered() {
+                    tab.current_file.diagram.toggle_all_switches();
+                }
+
+                // Netlists pasted from the clipboard, unless they're meant for a text field
+                let pasted = ui.input(|r| {
+                    r.events.iter().find_map(|event| match event {
+                        egui::Event::Paste(text) => Some(text.clone()),
+                        _ => None,
+                    })
+                });
+                if let Some(text) = pasted.filter(|_| !ui.ctx().wants_keyboard_input()) {
+                    match parse_netlist(&text) {
+                        Ok(netlist) => {
+                            let pos = resp.response.hover_pos().unwrap_or(tab.view_rect.center());
+                            tab.current_file
+                                .diagram
+                                .paste(netlist, self.vis_opt.egui_to_cellpos(pos));
+                            tab.paste_error = None;
+                            rebuild_sim = true;
+                        }
+                        Err(e) => tab.paste_error = Some(e),
+                    }
+                }
             });
         });
 
         // Reset
         if rebuild_sim {
-            self.sim = Some(Solver::new(
-                &self.current_file.diagram.to_primitive_diagram().primitive,
+            tab.sim = Some(Solver::new(
+                &tab.current_file.diagram.to_primitive_diagram().primitive,
             ));
+            tab.worker.invalidate();
+            tab.measurements.clear();
+        }
+
+        #[cfg(all(feature = "audio", not(target_arch = "wasm32")))]
+        self.audio.update(
+            self.active_tab,
+            &tab.current_file.diagram,
+            &tab.current_file.cfg,
+            rebuild_sim,
+        );
+
+        // Only the active tab runs; the others stay frozen until selected again.
+        // While paused, only the single-step button advances the clock, by exactly one dt.
+        // Rebuilding the sim resets it to t = 0 without stepping.
+        if tab.paused {
+            tab.step_accumulator = 0.0;
+            tab.step_queued |= single_step;
+        } else {
+            // Run as many steps as real time allows, independent of frame rate
+            tab.step_accumulator += ctx.input(|r| r.unstable_dt) as f64 * tab.current_file.speed;
+            tab.step_queued = false;
         }
 
-        if !self.paused || rebuild_sim || single_step {
-            ctx.request_repaint();
-
-            if let Some(sim) = &mut self.sim {
-                //let start = std::time::Instant::now();
-                if let Err(e) = sim.step(
-                    self.current_file.dt,
-                    &self.current_file.diagram.to_primitive_diagram().primitive,
-                    &self.current_file.cfg,
-                    None,
-                ) {
-                    eprintln!("{}", e);
-                    self.error = Some(e);
-                    self.paused = true;
+        // Steps owed while the solver thread was busy go into the next batch, which uses the
+        // diagram as it is by then
+        if !tab.worker.is_busy() {
+            let steps = if tab.paused {
+                usize::from(std::mem::take(&mut tab.step_queued))
+            } else {
+                let dt = tab.current_file.dt;
+                let owed = (tab.step_accumulator / dt) as usize;
+                if owed > MAX_STEPS_PER_BATCH {
+                    // Can't keep up; drop the backlog rather than falling further behind
+                    tab.step_accumulator = 0.0;
+                    MAX_STEPS_PER_BATCH
                 } else {
-                    self.error = None;
+                    tab.step_accumulator -= owed as f64 * dt;
+                    owed
+                }
+            };
+
+            if steps > 0 {
+                tab.start_steps(steps);
+            }
+        }
+    }
+}
+
+impl CircuitTab {
+    /// Lists the named snapshots, with controls to capture, restore and delete them
+    fn show_snapshots(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut self.snapshot_name)
+                    .hint_text("Name")
+                    .desired_width(100.0),
+            );
+            let can_capture = self.sim.is_some() && !self.snapshot_name.is_empty();
+            if ui
+                .add_enabled(can_capture, egui::Button::new("Capture"))
+                .clicked()
+            {
+                if let Some(sim) = &self.sim {
+                    let name = std::mem::take(&mut self.snapshot_name);
+                    self.snapshots.insert(name, sim.snapshot());
+                }
+            }
+        });
+
+        let mut names: Vec<String> = self.snapshots.keys().cloned().collect();
+        names.sort();
+
+        for name in names {
+            ui.horizontal(|ui| {
+                ui.label(&name);
+                if ui.button("Restore").clicked() {
+                    if let Some(sim) = &mut self.sim {
+                        if let Err(e) = sim.restore(&self.snapshots[&name]) {
+                            eprintln!("{e}");
+                            self.error = Some(e);
+                        }
+                        // The running batch started from the state before the restore
+                        self.worker.invalidate();
+                    }
+                }
+                if ui.button("Delete").clicked() {
+                    self.snapshots.remove(&name);
+                }
+            });
+        }
+    }
+
+    /// Lists the variables with their values, with controls to add and delete them and to bind
+    /// the selected components to them. Values are applied to the components on the next frame.
+    fn show_variables(&mut self, ui: &mut Ui) {
+        let variables = &mut self.current_file.variables;
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut self.variable_name)
+                    .hint_text("Name")
+                    .desired_width(100.0),
+            );
+            let name = self.variable_name.trim();
+            let can_add = !name.is_empty() && !variables.contains_key(name);
+            if ui.add_enabled(can_add, egui::Button::new("Add")).clicked() {
+                variables.insert(name.to_string(), 1.0);
+                self.variable_name.clear();
+            }
+        });
+
+        let diagram = &mut self.current_file.diagram;
+        let selected: Vec<_> = self
+            .editor
+            .selection(diagram)
+            .into_iter()
+            .filter(|&(idx, ty)| {
+                ty == SelectionType::TwoTerminal && diagram.two_terminal[idx].1.value().is_some()
+            })
+            .filter_map(|sel| diagram.id(sel))
+            .collect();
+
+        let mut names: Vec<String> = variables.keys().cloned().collect();
+        names.sort();
+
+        for name in names {
+            ui.horizontal(|ui| {
+                ui.label(&name);
+                if let Some(value) = variables.get_mut(&name) {
+                    ui.add(egui_simpletabs::edit_metric_f64(value, ""));
+                }
+                let uses = diagram.bindings.values().filter(|n| **n == name).count();
+                ui.weak(format!("×{uses}"))
+                    .on_hover_text("Components set by this variable");
+                if ui
+                    .add_enabled(!selected.is_empty(), egui::Button::new("Bind"))
+                    .on_hover_text("Sets the value of the selected components from this variable")
+                    .clicked()
+                {
+                    for &id in &selected {
+                        diagram.bindings.insert(id, name.clone());
+                    }
+                }
+                if ui.button("Delete").clicked() {
+                    variables.remove(&name);
+                    diagram.bindings.retain(|_, n| *n != name);
+                }
+            });
+        }
+    }
+
+    /// The changes found by File > Compare with, colored by kind, with buttons to select the
+    /// components which are still in the circuit
+    fn show_comparison(&mut self, ui: &mut Ui) {
+        let Some((name, changes)) = &self.comparison else {
+            return;
+        };
+
+        ui.label(format!("Changes from {name} to this circuit"));
+        if changes.is_empty() {
+            ui.weak("No differences");
+            return;
+        }
+        if ui.button("Copy report").clicked() {
+            let report: Vec<String> = changes.iter().map(|change| change.to_string()).collect();
+            ui.ctx().copy_text(report.join("\n"));
+        }
+        ui.separator();
+
+        let diagram = &self.current_file.diagram;
+        ScrollArea::vertical().show(ui, |ui| {
+            for change in changes {
+                let color = match change.kind {
+                    ChangeKind::Added => Color32::from_rgb(0x40, 0xb0, 0x40),
+                    ChangeKind::Removed => ui.visuals().error_fg_color,
+                    ChangeKind::Changed => ui.visuals().warn_fg_color,
+                };
+                ui.horizontal(|ui| {
+                    ui.colored_label(color, change.to_string());
+                    let found = change.id.and_then(|id| diagram.find(id));
+                    if let Some(sel) = found {
+                        if ui.small_button("Select").clicked() {
+                            self.editor.select(diagram, sel);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// Settings for a Monte Carlo run, a button to start it and the distribution of its results
+    fn show_monte_carlo(&mut self, ui: &mut Ui, format: ValueFormat) {
+        if let Some(results) = self.monte_carlo_worker.poll() {
+            self.monte_carlo_results = Some(results);
+        }
+
+        let mut names: Vec<String> = self
+            .current_file
+            .diagram
+            .ports
+            .iter()
+            .map(|(_, name)| name.clone())
+            .collect();
+        names.sort();
+        names.dedup();
+        if names.is_empty() {
+            ui.weak("Add a port to the circuit to choose the output");
+            return;
+        }
+
+        let settings = &mut self.monte_carlo;
+        egui::ComboBox::from_label("Output port")
+            .selected_text(&settings.port)
+            .show_ui(ui, |ui| {
+                for name in names {
+                    ui.selectable_value(&mut settings.port, name.clone(), name);
+                }
+            });
+
+        let mut percent = settings.tolerance * 100.0;
+        ui.add(
+            DragValue::new(&mut percent)
+                .range(0.0..=100.0)
+                .prefix("Default tolerance: ±")
+                .suffix("%"),
+        )
+        .on_hover_text(
+            "For components without a tolerance of their own, set in the Component panel",
+        );
+        settings.tolerance = percent / 100.0;
+        ui.add(
+            DragValue::new(&mut settings.trials)
+                .range(1..=100_000)
+                .prefix("Trials: "),
+        );
+        ui.horizontal(|ui| {
+            ui.add(
+                DragValue::new(&mut settings.steps)
+                    .range(1..=(MAX_MONTE_CARLO_STEPS / settings.trials.max(1)).max(1))
+                    .prefix("Steps: "),
+            )
+            .on_hover_text(format!(
+                "Trials times steps is limited to {MAX_MONTE_CARLO_STEPS}"
+            ));
+            let duration = settings.steps as f64 * self.current_file.dt;
+            ui.weak(format!("= {}", format_value(duration, 's', format)));
+        });
+        ui.add(DragValue::new(&mut settings.seed).prefix("Seed: "));
+
+        let running = self.monte_carlo_worker.is_busy();
+        if ui.add_enabled(!running, egui::Button::new("Run")).clicked() {
+            let rich = self.current_file.diagram.to_primitive_diagram();
+            let node = rich
+                .ports
+                .get(&settings.port)
+                .and_then(|nodes| nodes.first());
+            match node {
+                None => {
+                    self.monte_carlo_results =
+                        Some(Err(format!("Port \"{}\" isn't connected", settings.port)));
+                }
+                Some(&node) => {
+                    let tolerances = self
+                        .current_file
+                        .diagram
+                        .tolerances(&rich, settings.tolerance);
+                    let mc = MonteCarloConfig {
+                        trials: settings.trials,
+                        seed: settings.seed,
+                        dt: self.current_file.dt,
+                        steps: settings
+                            .steps
+                            .min(MAX_MONTE_CARLO_STEPS / settings.trials.max(1)),
+                    };
+                    self.monte_carlo_worker.send(MonteCarloJob {
+                        diagram: rich.primitive,
+                        tolerances,
+                        output_node: node,
+                        mc,
+                        cfg: self.current_file.cfg,
+                    });
                 }
-                //println!("Time: {:.03} ms = {:.03} fps", start.elapsed().as_secs_f32() * 1000.0, 1.0 / (start.elapsed().as_secs_f32()));
             }
         }
+
+        if running {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label("Running trials");
+            });
+            // Nothing else may repaint while the simulation is paused
+            ui.ctx().request_repaint();
+            return;
+        }
+
+        match &self.monte_carlo_results {
+            Some(Ok(values)) if !values.is_empty() => {
+                let n = values.len() as f64;
+                let mean = values.iter().sum::<f64>() / n;
+                let std_dev = (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+                let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+                let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                ui.label(format!(
+                    "Mean {}, σ {}",
+                    format_value(mean, 'V', format),
+                    format_value(std_dev, 'V', format)
+                ));
+                ui.label(format!(
+                    "Range {} to {}",
+                    format_value(min, 'V', format),
+                    format_value(max, 'V', format)
+                ));
+                show_histogram(ui, values, min, max);
+            }
+            Some(Ok(_)) => (),
+            Some(Err(e)) => {
+                ui.colored_label(Color32::RED, e);
+            }
+            None => (),
+        }
+    }
+
+    /// Sends the next `steps` time steps to the solver thread
+    fn start_steps(&mut self, steps: usize) {
+        let Some(sim) = &self.sim else {
+            return;
+        };
+
+        self.worker.send(StepJob {
+            sim: sim.clone(),
+            diagram: self.current_file.diagram.to_primitive_diagram(),
+            cfg: self.current_file.cfg,
+            dt: self.current_file.dt,
+            steps,
+            pause_when_settled: self.pause_when_settled,
+        });
+    }
+
+    /// Takes the simulation state from the last batch of steps, once it's done
+    fn finish_steps(&mut self) {
+        let Some(result) = self.worker.poll() else {
+            return;
+        };
+
+        for (dt, state) in &result.states {
+            self.measurements.push(*dt, state);
+        }
+        if !result.states.is_empty() {
+            self.error = None;
+            self.reduced_dt = result.reduced_dt;
+        }
+        if let Some(e) = result.error {
+            self.error = Some(e);
+        }
+        if result.pause {
+            self.paused = true;
+        }
+        self.behind = result.over_budget;
+        self.sim = Some(result.sim);
+    }
+}
+
+fn default_tabs() -> Vec<CircuitTab> {
+    vec![CircuitTab::default()]
+}
+
+fn default_view_rect() -> Rect {
+    Rect::from_center_size(Pos2::ZERO, Vec2::splat(1000.0))
+}
+
+fn read_file(path: &Path) -> Result<CircuitFile, String> {
+    let name = path.display();
+    let text = std::fs::read_to_string(path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => format!("Failed to open {name}: file not found"),
+        ErrorKind::PermissionDenied => format!("Failed to open {name}: permission denied"),
+        _ => format!("Failed to open {name}: {e}"),
+    })?;
+
+    // Read the version on its own first, since a file from a newer build probably won't parse
+    let FileVersion { version } =
+        ron::from_str(&text).map_err(|e| format!("Failed to open {name}: invalid RON: {e}"))?;
+    if version > FILE_VERSION {
+        return Err(format!(
+            "Failed to open {name}: it was saved by a newer version of cirmcut \
+            (file format {version}, this build reads up to {FILE_VERSION})"
+        ));
+    }
+
+    migrate(&text, version).map_err(|e| format!("Failed to open {name}: invalid RON: {e}"))
+}
+
+#[derive(serde::Deserialize)]
+struct FileVersion {
+    #[serde(default)]
+    version: u32,
+}
+
+/// Only the speed of a circuit file, to tell whether it has one
+#[derive(serde::Deserialize)]
+struct SavedSpeed {
+    #[serde(default, deserialize_with = "deserialize_some")]
+    speed: Option<f64>,
+}
+
+fn deserialize_some<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<f64>, D::Error> {
+    <f64 as serde::Deserialize>::deserialize(d).map(Some)
+}
+
+/// Parses a circuit file of the given format version, upgrading it to `FILE_VERSION`
+fn migrate(text: &str, version: u32) -> ron::error::SpannedResult<CircuitFile> {
+    let mut file: CircuitFile = match version {
+        // Files from before the version field lack fields added since, such as the initial
+        // conditions of capacitors and inductors. All of those default when missing, so the
+        // version 1 layout reads them.
+        0 | FILE_VERSION => ron::from_str(text)?,
+        _ => unreachable!("read_file() rejects versions newer than FILE_VERSION"),
+    };
+
+    // Files saved before components had IDs
+    file.diagram.assign_ids();
+
+    // Files saved before the speed setting ran one step per frame, whatever their time step
+    if version == 0 {
+        let SavedSpeed { speed } = ron::from_str(text)?;
+        if speed.is_none() {
+            file.speed = file.dt * NOMINAL_FRAME_RATE;
+        }
     }
+
+    Ok(CircuitFile {
+        version: FILE_VERSION,
+        ..file
+    })
 }
 
-fn read_file(path: &Path) -> Option<CircuitFile> {
-    let file = File::open(path).ok()?;
-    ron::de::from_reader(file).ok()
+/// Names the first value in the file which is NaN or infinite
+#[cfg(not(target_arch = "wasm32"))]
+fn find_non_finite(file: &CircuitFile) -> Option<String> {
+    let cfg = &file.cfg;
+    let mut values: Vec<(String, f64)> = vec![
+        ("Δt".into(), file.dt),
+        ("Speed".into(), file.speed),
+        ("NR step size".into(), cfg.nr_step_size),
+        ("NR tolerance".into(), cfg.nr_tolerance),
+        ("Solution tolerance".into(), cfg.dx_soln_tolerance),
+    ];
+    values.extend(
+        file.variables
+            .iter()
+            .map(|(name, value)| (format!("Variable {name}"), *value)),
+    );
+
+    for (_, component) in &file.diagram.two_terminal {
+        let name = component.name().to_string();
+        match *component {
+            TwoTerminalComponent::Resistor(v)
+            | TwoTerminalComponent::CurrentSource(v)
+            | TwoTerminalComponent::CurrentControlledSource(v, _)
+            | TwoTerminalComponent::NoiseSource { amplitude: v, .. } => values.push((name, v)),
+            TwoTerminalComponent::Battery(v, limit) => {
+                values.push((name.clone(), v));
+                values.extend(limit.map(|limit| (format!("{name} current limit"), limit)));
+            }
+            TwoTerminalComponent::Inductor(v, _, ic, _, r)
+            | TwoTerminalComponent::Capacitor(v, ic, _, r) => {
+                values.push((name.clone(), v));
+                values.push((format!("{name} series resistance"), r));
+                values.extend(ic.map(|ic| (name, ic)));
+            }
+            TwoTerminalComponent::Crystal { ls, cs, rs, cp } => {
+                values.extend([ls, cs, rs, cp].map(|v| (name.clone(), v)));
+            }
+            TwoTerminalComponent::Wire
+            | TwoTerminalComponent::Diode
+            | TwoTerminalComponent::Switch(_)
+            | TwoTerminalComponent::CurrentProbe => (),
+        }
+    }
+
+    for (_, component) in &file.diagram.three_terminal {
+        match *component {
+            ThreeTerminalComponent::PTransistor(beta, c_be, c_bc, ar)
+            | ThreeTerminalComponent::NTransistor(beta, c_be, c_bc, ar) => {
+                values.push((component.name().into(), beta));
+                values.push((format!("{} Cbe", component.name()), c_be));
+                values.push((format!("{} Cbc", component.name()), c_bc));
+                values.push((format!("{} reverse alpha", component.name()), ar));
+            }
+            ThreeTerminalComponent::Potentiometer(resistance, position) => {
+                values.push((component.name().into(), resistance));
+                values.push((format!("{} wiper", component.name()), position));
+            }
+        }
+    }
+
+    for (_, kind) in &file.diagram.grounds {
+        if let GroundKind::Tied(Some(resistance)) = kind {
+            values.push(("Ground resistance".into(), *resistance));
+        }
+    }
+
+    values
+        .into_iter()
+        .find(|(_, value)| !value.is_finite())
+        .map(|(name, _)| name)
 }
 
 fn write_file(diagram: &CircuitFile, path: &Path) {
@@ -478,13 +2169,47 @@ fn write_file(diagram: &CircuitFile, path: &Path) {
 impl Default for CircuitFile {
     fn default() -> Self {
         Self {
+            version: FILE_VERSION,
             diagram: Diagram::default(),
-            dt: 5e-3,
+            dt: DEFAULT_DT,
             cfg: Default::default(),
+            speed: default_speed(),
+            view: None,
+            variables: HashMap::new(),
         }
     }
 }
 
+/// One default time step per frame
+fn default_speed() -> f64 {
+    DEFAULT_DT * NOMINAL_FRAME_RATE
+}
+
+/// Parses a number with an optional SI prefix and unit, such as "10ns", "4.7 µs" or "1e-3"
+fn parse_metric_prefix(text: &str, unit: char) -> Option<f64> {
+    let text = text.trim();
+    let text = text.strip_suffix(unit).unwrap_or(text).trim_end();
+
+    let Some(prefix) = text.chars().last().filter(|c| c.is_alphabetic()) else {
+        return text.parse().ok();
+    };
+
+    let scale = match prefix {
+        'p' => 1e-12,
+        'n' => 1e-9,
+        'u' | 'µ' => 1e-6,
+        'm' => 1e-3,
+        'k' => 1e3,
+        'M' => 1e6,
+        'G' => 1e9,
+        // Exponents such as "1e" aren't prefixes
+        _ => return text.parse().ok(),
+    };
+
+    let number = &text[..text.len() - prefix.len_utf8()];
+    Some(number.trim_end().parse::<f64>().ok()? * scale)
+}
+
 fn to_subscript(s: String) -> String {
     s.chars()
         .map(|c| {
@@ -518,6 +2243,7 @@ fn display_number(ui: &mut Ui, value: f64) {
 fn show_parameter_matrix(
     ui: &mut Ui,
     dt: f64,
+    integration: IntegrationMethod,
     sim: &Solver,
     diagram: &PrimitiveDiagram,
     selected_idx: Option<usize>,
@@ -525,6 +2251,7 @@ fn show_parameter_matrix(
     //let map: HashMap<usize, ()>;
     let (matrix, params) = stamp(
         dt,
+        integration,
         &sim.map,
         diagram,
         &sim.soln_vector,
@@ -637,7 +2364,124 @@ fn show_parameter_matrix(
     });
 }
 
-fn show_component_list(ui: &mut Ui, diagram: &mut Diagram, editor: &mut DiagramEditor) {
+/// Draws a dot for each entry stamped into the matrix, with the unknowns along the top and the
+/// equations down the side, grouped by kind. Hollow dots are entries stamped as zero. The
+/// component law rows in `selected` are highlighted.
+fn show_sparsity_pattern(
+    ui: &mut Ui,
+    dt: f64,
+    integration: IntegrationMethod,
+    sim: &Solver,
+    diagram: &PrimitiveDiagram,
+    selected: std::ops::Range<usize>,
+) {
+    const CELL: f32 = 10.0;
+    const MARGIN: f32 = 90.0;
+
+    let (matrix, _) = stamp(
+        dt,
+        integration,
+        &sim.map,
+        diagram,
+        &sim.soln_vector,
+        &sim.soln_vector,
+        None,
+    );
+    let map = &sim.map;
+    let size = map.vector_size();
+
+    let columns = [
+        ("I", map.state_map.currents()),
+        ("Vd", map.state_map.voltage_drops()),
+        ("V", map.state_map.voltages()),
+    ];
+    let rows = [
+        ("Component", map.param_map.components()),
+        ("Current law", map.param_map.current_laws()),
+        ("Voltage law", map.param_map.voltage_laws()),
+    ];
+
+    ScrollArea::both().show(ui, |ui| {
+        let (response, painter) = ui.allocate_painter(
+            Vec2::splat(MARGIN) + Vec2::splat(CELL * size as f32),
+            Sense::hover(),
+        );
+        let origin = response.rect.min + Vec2::splat(MARGIN);
+        let grid = Rect::from_min_size(origin, Vec2::splat(CELL * size as f32));
+        let text_color = ui.visuals().text_color();
+        let line = Stroke::new(1.0, ui.visuals().weak_text_color());
+
+        painter.rect_stroke(grid, 0.0, line, egui::StrokeKind::Outside);
+        let first = origin.y + CELL * selected.start as f32;
+        let last = origin.y + CELL * selected.end as f32;
+        painter.rect_filled(
+            Rect::from_x_y_ranges(grid.x_range(), first..=last),
+            0.0,
+            Color32::CYAN.gamma_multiply(0.2),
+        );
+
+        for (label, range) in columns {
+            let start = origin.x + CELL * range.start as f32;
+            let end = origin.x + CELL * range.end as f32;
+            painter.vline(start, grid.y_range(), line);
+            painter.text(
+                Pos2::new((start + end) / 2.0, origin.y - 4.0),
+                egui::Align2::CENTER_BOTTOM,
+                label,
+                Default::default(),
+                text_color,
+            );
+        }
+        for (label, range) in rows {
+            let start = origin.y + CELL * range.start as f32;
+            let end = origin.y + CELL * range.end as f32;
+            painter.hline(grid.x_range(), start, line);
+            painter.text(
+                Pos2::new(origin.x - 4.0, (start + end) / 2.0),
+                egui::Align2::RIGHT_CENTER,
+                label,
+                Default::default(),
+                text_color,
+            );
+        }
+
+        // Compressed columns
+        let mut entries = HashMap::new();
+        for col in 0..matrix.n {
+            for k in matrix.p[col] as usize..matrix.p[col + 1] as usize {
+                let row = matrix.i[k];
+                *entries.entry((row, col)).or_insert(0.0) += matrix.x[k];
+            }
+        }
+        for (&(row, col), &value) in &entries {
+            let center = origin + CELL * Vec2::new(col as f32 + 0.5, row as f32 + 0.5);
+            if value == 0.0 {
+                painter.circle_stroke(center, CELL / 3.0, Stroke::new(1.0, text_color));
+            } else {
+                painter.circle_filled(center, CELL / 3.0, text_color);
+            }
+        }
+
+        if let Some(pos) = response.hover_pos().filter(|pos| size > 0 && grid.contains(*pos)) {
+            let col = (((pos.x - origin.x) / CELL) as usize).min(size - 1);
+            let row = (((pos.y - origin.y) / CELL) as usize).min(size - 1);
+            let value = match entries.get(&(row, col)) {
+                Some(value) => format!("{value:.4e}"),
+                None => "nothing".into(),
+            };
+            response.on_hover_text_at_pointer(format!(
+                "Row {row}: {}\nColumn {col}: {}\nStamped: {value}",
+                map.describe_param(diagram, row),
+                map.describe_state(diagram, col),
+            ));
+        }
+    });
+}
+
+/// Returns true if the simulation needs rebuild
+fn show_component_list(ui: &mut Ui, diagram: &mut Diagram, editor: &mut DiagramEditor) -> bool {
+    let mut rebuild_sim = false;
+
     ui.heading("Two terminal");
     let mut del_idx = None;
     egui::Grid::new("twoterminal").striped(true).show(ui, |ui| {
@@ -652,13 +2496,15 @@ fn show_component_list(ui: &mut Ui, diagram: &mut Diagram, editor: &mut DiagramE
                 if ui.button("Delete").clicked() {
                     del_idx = Some(idx);
                 }
-                ui.selectable_value(&mut editor.selected, Some((idx, SelectionType::TwoTerminal)), "Select");
+                if let Some(&id) = diagram.ids.two_terminal.get(idx) {
+                    ui.selectable_value(&mut editor.selected, Some(id), "Select");
+                }
             });
             ui.end_row();
         }
     });
     if let Some(idx) = del_idx {
-        diagram.two_terminal.remove(idx);
+        diagram.remove((idx, SelectionType::TwoTerminal));
     }
 
     ui.heading("Three terminal");
@@ -675,13 +2521,15 @@ fn show_component_list(ui: &mut Ui, diagram: &mut Diagram, editor: &mut DiagramE
                 if ui.button("Delete").clicked() {
                     del_idx = Some(idx);
                 }
-                ui.selectable_value(&mut editor.selected, Some((idx, SelectionType::ThreeTerminal)), "Select");
+                if let Some(&id) = diagram.ids.three_terminal.get(idx) {
+                    ui.selectable_value(&mut editor.selected, Some(id), "Select");
+                }
             });
             ui.end_row();
         }
     });
     if let Some(idx) = del_idx {
-        diagram.three_terminal.remove(idx);
+        diagram.remove((idx, SelectionType::ThreeTerminal));
     }
 
 
@@ -699,16 +2547,283 @@ fn show_component_list(ui: &mut Ui, diagram: &mut Diagram, editor: &mut DiagramE
                 if ui.button("Delete").clicked() {
                     del_idx = Some(idx);
                 }
-                ui.selectable_value(&mut editor.selected, Some((idx, SelectionType::Port)), "Select");
+                if let Some(&id) = diagram.ids.ports.get(idx) {
+                    ui.selectable_value(&mut editor.selected, Some(id), "Select");
+                }
+            });
+            ui.end_row();
+        }
+    });
+    if let Some(idx) = del_idx {
+        diagram.remove((idx, SelectionType::Port));
+    }
+
+    ui.heading("Grounds");
+    let mut del_idx = None;
+    egui::Grid::new("grounds").striped(true).show(ui, |ui| {
+        ui.strong("Kind");
+        ui.strong("Location");
+        ui.strong("Controls");
+        ui.end_row();
+        for (idx, ((x, y), kind)) in diagram.grounds.iter_mut().enumerate() {
+            rebuild_sim |= edit_ground(ui, kind);
+            ui.horizontal(|ui| {
+                rebuild_sim |= ui.add(DragValue::new(x)).changed();
+                rebuild_sim |= ui.add(DragValue::new(y)).changed();
             });
+            if ui.button("Delete").clicked() {
+                del_idx = Some(idx);
+            }
             ui.end_row();
         }
     });
     if let Some(idx) = del_idx {
-        diagram.ports.remove(idx);
+        diagram.grounds.remove(idx);
+        rebuild_sim = true;
+    }
+    if ui.button("Add ground").clicked() {
+        editor.new_ground(diagram, (0, 0));
+        rebuild_sim = true;
     }
 
    //let mut del_idx = None;
+
+    rebuild_sim
+}
+
+/// Bar chart of how many of the values fall in each of a number of bins between min and max
+fn show_histogram(ui: &mut Ui, values: &[f64], min: f64, max: f64) {
+    const BINS: usize = 20;
+    let mut counts = [0usize; BINS];
+    let span = (max - min).max(f64::MIN_POSITIVE);
+    for value in values {
+        let bin = ((value - min) / span * BINS as f64) as usize;
+        counts[bin.min(BINS - 1)] += 1;
+    }
+    let tallest = counts.iter().copied().max().unwrap_or(1).max(1);
+
+    let size = Vec2::new(ui.available_width().min(250.0), 80.0);
+    let (rect, _) = ui.allocate_exact_size(size, Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, ui.visuals().extreme_bg_color);
+
+    let width = rect.width() / BINS as f32;
+    for (i, count) in counts.into_iter().enumerate() {
+        let height = count as f32 / tallest as f32 * rect.height();
+        let left = rect.left() + i as f32 * width;
+        let bar = Rect::from_min_max(
+            Pos2::new(left + 1.0, rect.bottom() - height),
+            Pos2::new(left + width - 1.0, rect.bottom()),
+        );
+        painter.rect_filled(bar, 0.0, Color32::LIGHT_BLUE);
+    }
+}
+
+/// Plots the error of each Newton-Raphson iteration on a log scale, along with the tolerance
+fn show_residual_plot(ui: &mut Ui, residuals: &[f64], tolerance: f64) {
+    let size = Vec2::new(ui.available_width().min(250.0), 80.0);
+    let (rect, resp) = ui.allocate_exact_size(size, Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, ui.visuals().extreme_bg_color);
+
+    let log = |value: f64| value.max(f64::MIN_POSITIVE).log10();
+    let logs: Vec<f64> = residuals.iter().map(|&err| log(err)).collect();
+    let (min, max) = logs
+        .iter()
+        .chain(&[log(tolerance)])
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &v| {
+            (min.min(v), max.max(v))
+        });
+    let span = (max - min).max(1.0);
+    let y = |v: f64| rect.bottom() - ((v - min) / span) as f32 * rect.height();
+    let x = |i: usize| rect.left() + i as f32 / (logs.len().max(2) - 1) as f32 * rect.width();
+
+    painter.hline(
+        rect.x_range(),
+        y(log(tolerance)),
+        Stroke::new(1.0, Color32::DARK_GREEN),
+    );
+    let points: Vec<Pos2> = logs
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| Pos2::new(x(i), y(v)))
+        .collect();
+    painter.line(points, Stroke::new(1.5, Color32::YELLOW));
+
+    resp.on_hover_text(format!(
+        "{} iterations, from 1e{max:.0} to 1e{min:.0}. The green line is the tolerance.",
+        residuals.len()
+    ));
+}
+
+/// Moves the selected components by whole cells so that their bounding boxes line up with the
+/// outermost one, or, to distribute them, so that their left (or top) edges are evenly spaced
+/// between the first and last. Orientations are kept. Locked components count towards the
+/// target but stay put. Returns true if anything moved.
+fn align(
+    diagram: &mut Diagram,
+    selection: &[(usize, SelectionType)],
+    alignment: Alignment,
+) -> bool {
+    let horizontal = matches!(
+        alignment,
+        Alignment::Left | Alignment::Right | Alignment::DistributeHorizontally
+    );
+    let coord = |(x, y): CellPos| if horizontal { x } else { y };
+
+    // (component, (min, max)) along the axis being aligned
+    let mut spans: Vec<((usize, SelectionType), (i32, i32))> = selection
+        .iter()
+        .filter_map(|&sel| {
+            let cells = diagram.cells(sel);
+            let min = cells.iter().map(|&pos| coord(pos)).min()?;
+            let max = cells.iter().map(|&pos| coord(pos)).max()?;
+            Some((sel, (min, max)))
+        })
+        .collect();
+
+    let offsets: Vec<i32> = match alignment {
+        Alignment::Left | Alignment::Top => {
+            let target = spans
+                .iter()
+                .map(|(_, (min, _))| *min)
+                .min()
+                .unwrap_or_default();
+            spans.iter().map(|(_, (min, _))| target - min).collect()
+        }
+        Alignment::Right | Alignment::Bottom => {
+            let target = spans
+                .iter()
+                .map(|(_, (_, max))| *max)
+                .max()
+                .unwrap_or_default();
+            spans.iter().map(|(_, (_, max))| target - max).collect()
+        }
+        Alignment::DistributeHorizontally | Alignment::DistributeVertically => {
+            spans.sort_by_key(|(_, (min, _))| *min);
+            let (Some(&(_, (first, _))), Some(&(_, (last, _)))) = (spans.first(), spans.last())
+            else {
+                return false;
+            };
+            let gaps = (spans.len() - 1).max(1) as f64;
+            spans
+                .iter()
+                .enumerate()
+                .map(|(i, (_, (min, _)))| {
+                    let target = first as f64 + (last - first) as f64 * i as f64 / gaps;
+                    target.round() as i32 - min
+                })
+                .collect()
+        }
+    };
+
+    let mut moved = false;
+    for ((sel, _), offset) in spans.into_iter().zip(offsets) {
+        if offset != 0 && !diagram.is_locked(sel) {
+            let offset = if horizontal { (offset, 0) } else { (0, offset) };
+            diagram.translate(sel, offset);
+            moved = true;
+        }
+    }
+    moved
+}
+
+/// Zooms `view`, the part of the scene shown on `canvas`, by `factor` about the point under
+/// `pointer`, keeping the scene's scale (screen points per scene point) within `zoom_range`
+fn zoom_view(
+    view: &mut Rect,
+    canvas: Rect,
+    pointer: Pos2,
+    factor: f32,
+    zoom_range: &std::ops::RangeInclusive<f32>,
+) {
+    if factor == 1.0 || !view.is_positive() || !canvas.is_positive() {
+        return;
+    }
+
+    // The scene fits the view into the canvas, centered
+    let scale = (canvas.width() / view.width()).min(canvas.height() / view.height());
+    let new_scale = (scale * factor).clamp(*zoom_range.start(), *zoom_range.end());
+    let factor = new_scale / scale;
+
+    let anchor = view.center() + (pointer - canvas.center()) / scale;
+    *view = Rect::from_center_size(
+        anchor + (view.center() - anchor) / factor,
+        view.size() / factor,
+    );
+}
+
+/// Resets a view with a non-finite corner, and keeps the size of any other between one cell and
+/// `MAX_VIEW_EXTENT`, and its center within `MAX_VIEW_EXTENT` of the origin. The scene can leave
+/// either behind after extreme zooming, and the grid can't be drawn for them.
+fn sanitize_view(view: &mut Rect, cell_size: f32) {
+    if !view.is_finite() {
+        *view = default_view_rect();
+        return;
+    }
+
+    let size = view
+        .size()
+        .clamp(Vec2::splat(cell_size), Vec2::splat(MAX_VIEW_EXTENT));
+    let center = view.center().clamp(
+        Pos2::new(-MAX_VIEW_EXTENT, -MAX_VIEW_EXTENT),
+        Pos2::new(MAX_VIEW_EXTENT, MAX_VIEW_EXTENT),
+    );
+    // Left alone otherwise, since rebuilding it every frame would drift
+    if size != view.size() || center != view.center() {
+        *view = Rect::from_center_size(center, size);
+    }
+}
+
+/// Size of the minimap, in points
+const MINIMAP_SIZE: Vec2 = Vec2::new(200.0, 150.0);
+
+/// Draws the whole diagram shrunk to fit the minimap, as bare lines between terminals, and
+/// outlines the part in view. Clicking or dragging on it centers the view there.
+fn show_minimap(ui: &mut Ui, diagram: &Diagram, view: &mut Rect, vis: &VisualizationOptions) {
+    let (rect, resp) = ui.allocate_exact_size(MINIMAP_SIZE, Sense::click_and_drag());
+
+    let segments: Vec<[Pos2; 2]> = diagram
+        .two_terminal
+        .iter()
+        .map(|(positions, _)| *positions)
+        .chain(
+            diagram
+                .three_terminal
+                .iter()
+                .flat_map(|([a, b, c], _)| [[*a, *b], [*b, *c]]),
+        )
+        .map(|segment| segment.map(|pos| vis.cellpos_to_egui(pos)))
+        .collect();
+
+    // Bounded by the diagram alone, so that it holds still while the view moves
+    let mut bounds = Rect::NOTHING;
+    for point in segments.iter().flatten() {
+        bounds.extend_with(*point);
+    }
+    if !bounds.is_finite() {
+        bounds = *view;
+    }
+    let bounds = bounds.expand(vis.cell_size);
+    let scale = (rect.width() / bounds.width()).min(rect.height() / bounds.height());
+    let to_map = |pos: Pos2| rect.center() + (pos - bounds.center()) * scale;
+
+    let painter = ui.painter_at(rect);
+    let stroke = Stroke::new(1.0, ui.visuals().text_color());
+    for [a, b] in segments {
+        painter.line_segment([to_map(a), to_map(b)], stroke);
+    }
+    painter.rect_stroke(
+        Rect::from_min_max(to_map(view.min), to_map(view.max)),
+        0.0,
+        Stroke::new(1.5, Color32::YELLOW),
+        egui::StrokeKind::Inside,
+    );
+
+    if let Some(pointer) = resp.interact_pointer_pos() {
+        let center = bounds.center() + (pointer - rect.center()) / scale;
+        *view = Rect::from_center_size(center, view.size());
+    }
 }
 
 fn show_shortcut_list(ui: &mut Ui) {
@@ -721,6 +2836,97 @@ fn show_shortcut_list(ui: &mut Ui) {
         };
         ui.label(format!("Press {key} to add a {}", component.name()));
     }
+    ui.label(format!(
+        "Press {} to open or close all switches",
+        TOGGLE_SWITCHES_SHORTCUT.symbol_or_name().to_lowercase()
+    ));
     ui.label("Press DELETE to delete the selected component");
-    ui.label("Press ESC to unselect the selected component");
+    ui.label("Press the arrow keys to move the selection by one cell");
+    ui.label("Press + or - to step the values of the selection up or down");
+    ui.label("Press ESC to unselect the selected component, or finish drawing wires");
+    ui.label("Shift-click to select several components");
+    ui.label("Press Ctrl+V to paste a SPICE netlist at the cursor");
+    ui.label(format!(
+        "Drag with the middle mouse button, or hold {} and drag, to pan",
+        PAN_KEY.symbol_or_name().to_uppercase()
+    ));
+    ui.label("Scroll to zoom in or out at the cursor");
+    ui.label("Scroll over a selected component to step its value up or down");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_1_file_round_trips() {
+        let text = "(version:1,diagram:(two_terminal:[(((0,0),(0,2)),Battery(5.0,None)),\
+            (((0,0),(2,0)),Capacitor(0.0001,Some(1.5),Some(Trapezoidal),0.01)),\
+            (((2,0),(2,2)),Inductor(0.01,Some(3),None,None,0.5)),\
+            (((0,2),(2,2)),Resistor(1000.0))],\
+            three_terminal:[(((4,0),(4,1),(4,2)),NTransistor(100.0,0.0,0.0,0.1))]),\
+            cfg:(max_nr_iters:200,nr_step_size:0.1,nr_tolerance:0.000001,dx_soln_tolerance:0.001,\
+            mode:Linear),dt:0.001,speed:0.5,variables:{\"R\":1000.0})";
+
+        let file = migrate(text, 1).unwrap();
+        assert_eq!(file.version, FILE_VERSION);
+        assert_eq!(
+            file.diagram.two_terminal[1].1,
+            TwoTerminalComponent::Capacitor(
+                1e-4,
+                Some(1.5),
+                Some(IntegrationMethod::Trapezoidal),
+                0.01
+            )
+        );
+        assert_eq!(
+            file.diagram.two_terminal[2].1,
+            TwoTerminalComponent::Inductor(0.01, Some(3), None, None, 0.5)
+        );
+        assert_eq!(file.cfg.mode, SolverMode::Linear);
+        assert_eq!(file.speed, 0.5);
+        assert_eq!(file.variables.get("R"), Some(&1000.0));
+
+        // Saving and opening again changes nothing
+        let saved = ron::to_string(&file).unwrap();
+        let reopened = migrate(&saved, FILE_VERSION).unwrap();
+        assert_eq!(ron::to_string(&reopened).unwrap(), saved);
+    }
+
+    #[test]
+    fn version_0_files_open() {
+        for text in [
+            include_str!("../circuits/colpitts.ckt"),
+            include_str!("../circuits/colpitts2.ckt"),
+            include_str!("../circuits/orientations.ckt"),
+        ]
+        .into_iter()
+        .chain(EXAMPLES.iter().map(|(_, text)| *text))
+        {
+            let FileVersion { version } = ron::from_str(text).unwrap();
+            let file = migrate(text, version).unwrap();
+            assert_eq!(file.version, FILE_VERSION);
+        }
+
+        // The layout from before initial conditions, series resistance and integration methods
+        let text = "(diagram:(two_terminal:[(((0,0),(0,2)),Capacitor(0.0001)),\
+            (((0,2),(2,2)),Inductor(0.01))],three_terminal:[]),\
+            cfg:(max_nr_iters:200,nr_step_size:0.1,nr_tolerance:0.000001,dx_soln_tolerance:0.001,\
+            mode:NewtonRaphson),dt:0.0001)";
+        let file = migrate(text, 0).unwrap();
+        assert_eq!(
+            file.diagram.two_terminal[0].1,
+            TwoTerminalComponent::Capacitor(1e-4, None, None, 0.0)
+        );
+        assert_eq!(
+            file.diagram.two_terminal[1].1,
+            TwoTerminalComponent::Inductor(0.01, None, None, None, 0.0)
+        );
+        // Runs at the pace of one step per frame, as it used to
+        assert_eq!(file.speed, 1e-4 * NOMINAL_FRAME_RATE);
+
+        // A speed saved before the version field is kept
+        let text = text.replace("dt:0.0001)", "dt:0.0001,speed:0.5)");
+        assert_eq!(migrate(&text, 0).unwrap().speed, 0.5);
+    }
 }
@@ -0,0 +1,303 @@
+//! Plays the voltage between two ports through the speakers, simulated at the audio sample rate
+
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TryRecvError};
+
+use cirmcut_sim::map::PrimitiveDiagramMapping;
+use cirmcut_sim::solver::{Solver, SolverConfig};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use egui::{Color32, Ui};
+
+use crate::circuit_widget::{Diagram, RichPrimitiveDiagram};
+
+/// Samples simulated ahead of playback. Larger buffers ride out slow steps, at the cost of
+/// edits taking longer to be heard.
+const BUFFER_SAMPLES: usize = 4096;
+
+/// Pole of the DC blocking filter. Closer to 1 passes lower frequencies.
+const DC_BLOCK_POLE: f32 = 0.995;
+
+/// Settings of the audio window, and the stream while it's playing
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct AudioOutput {
+    /// Port whose voltage is played
+    pub positive: String,
+    /// Port the voltage is measured against. Empty for ground.
+    pub negative: String,
+    /// Full scale per volt. Anything louder is clipped.
+    pub gain: f32,
+    /// Filters out the DC part of the signal, which would only push the speaker off center
+    pub block_dc: bool,
+
+    #[serde(skip)]
+    playing: bool,
+    #[serde(skip)]
+    playback: Option<Playback>,
+    #[serde(skip)]
+    error: Option<String>,
+    /// Tab and matrix size of the circuit last sent to the solver thread. The solver only fits
+    /// that circuit, so a change to either starts it again.
+    #[serde(skip)]
+    source: Option<(usize, usize)>,
+}
+
+/// A running stream, fed by its own solver thread
+struct Playback {
+    _stream: cpal::Stream,
+    sample_rate: u32,
+    updates: Sender<AudioUpdate>,
+    errors: Receiver<String>,
+}
+
+/// The circuit and settings as of the latest frame
+struct AudioUpdate {
+    diagram: RichPrimitiveDiagram,
+    cfg: SolverConfig,
+    /// Nodes the voltage is measured between, where None is ground
+    probe: (Option<usize>, Option<usize>),
+    gain: f32,
+    block_dc: bool,
+    /// Start again from `Solver::new()`, for after the topology changed
+    reset: bool,
+}
+
+impl Default for AudioOutput {
+    fn default() -> Self {
+        Self {
+            positive: String::new(),
+            negative: String::new(),
+            gain: 0.1,
+            block_dc: true,
+            playing: false,
+            playback: None,
+            error: None,
+            source: None,
+        }
+    }
+}
+
+impl AudioOutput {
+    pub fn show(&mut self, ui: &mut Ui, diagram: &Diagram) {
+        let mut names: Vec<String> = diagram.ports.iter().map(|(_, name)| name.clone()).collect();
+        names.sort();
+        names.dedup();
+        if names.is_empty() {
+            ui.weak("Add a port to the circuit to choose what to play");
+            return;
+        }
+
+        egui::ComboBox::from_label("Play")
+            .selected_text(&self.positive)
+            .show_ui(ui, |ui| {
+                for name in &names {
+                    ui.selectable_value(&mut self.positive, name.clone(), name);
+                }
+            });
+        let negative = match self.negative.as_str() {
+            "" => "Ground",
+            name => name,
+        };
+        egui::ComboBox::from_label("Against")
+            .selected_text(negative)
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.negative, String::new(), "Ground");
+                for name in &names {
+                    ui.selectable_value(&mut self.negative, name.clone(), name);
+                }
+            });
+
+        ui.add(
+            egui::Slider::new(&mut self.gain, 1e-3..=10.0)
+                .logarithmic(true)
+                .text("Gain"),
+        )
+        .on_hover_text("Full scale per volt. Anything louder is clipped.");
+        ui.checkbox(&mut self.block_dc, "Block DC");
+
+        let label = if self.playing { "Stop" } else { "Play" };
+        if ui
+            .add_enabled(!self.positive.is_empty(), egui::Button::new(label))
+            .clicked()
+        {
+            self.playing = !self.playing;
+        }
+
+        if let Some(playback) = &self.playback {
+            ui.weak(format!(
+                "Simulating at the sample rate, {} Hz",
+                playback.sample_rate
+            ));
+        }
+        if let Some(e) = &self.error {
+            ui.colored_label(Color32::RED, e);
+        }
+    }
+
+    /// Starts, stops or updates the stream with the circuit of tab `tab`. Call once per frame.
+    /// `reset` restarts the simulation, which is needed whenever the topology changes.
+    pub fn update(&mut self, tab: usize, diagram: &Diagram, cfg: &SolverConfig, reset: bool) {
+        if !self.playing {
+            self.playback = None;
+            return;
+        }
+
+        let diagram = diagram.to_primitive_diagram();
+        let source = (tab, PrimitiveDiagramMapping::new(&diagram.primitive).vector_size());
+        let reset = reset || self.source != Some(source);
+        self.source = Some(source);
+
+        let node = |name: &str| {
+            diagram
+                .ports
+                .get(name)
+                .and_then(|nodes| nodes.first().copied())
+        };
+        let update = AudioUpdate {
+            probe: (node(&self.positive), node(&self.negative)),
+            diagram,
+            cfg: *cfg,
+            gain: self.gain,
+            block_dc: self.block_dc,
+            reset,
+        };
+
+        match &self.playback {
+            // A send only fails once the solver thread has stopped, which it reports below
+            Some(playback) => {
+                let _ = playback.updates.send(update);
+            }
+            None => match start(update) {
+                Ok(playback) => {
+                    self.playback = Some(playback);
+                    self.error = None;
+                }
+                Err(e) => {
+                    self.error = Some(e);
+                    self.playing = false;
+                }
+            },
+        }
+
+        // The thread only hangs up without a message if it panicked
+        let error = self.playback.as_ref().and_then(|p| match p.errors.try_recv() {
+            Ok(e) => Some(e),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some("The audio simulation stopped".to_string()),
+        });
+        if let Some(e) = error {
+            self.error = Some(e);
+            self.playing = false;
+            self.playback = None;
+        }
+    }
+}
+
+fn start(first: AudioUpdate) -> Result<Playback, String> {
+    let device = cpal::default_host()
+        .default_output_device()
+        .ok_or("No audio output device")?;
+    let supported = device.default_output_config().map_err(|e| e.to_string())?;
+    let sample_rate = supported.sample_rate().0;
+    let config = supported.config();
+
+    let (sample_tx, sample_rx) = sync_channel(BUFFER_SAMPLES);
+    let stream = match supported.sample_format() {
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, sample_rx),
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, sample_rx),
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, sample_rx),
+        format => return Err(format!("Unsupported sample format {format:?}")),
+    }
+    .map_err(|e| e.to_string())?;
+    stream.play().map_err(|e| e.to_string())?;
+
+    let (updates, update_rx) = channel();
+    let (error_tx, errors) = channel();
+    let dt = 1.0 / sample_rate as f64;
+    std::thread::spawn(move || generate(dt, first, update_rx, sample_tx, error_tx));
+
+    Ok(Playback {
+        _stream: stream,
+        sample_rate,
+        updates,
+        errors,
+    })
+}
+
+fn build_stream<T: SizedSample + FromSample<f32>>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    samples: Receiver<f32>,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    let channels = config.channels as usize;
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            for frame in data.chunks_mut(channels) {
+                // Silence while the solver is behind, or once it has stopped
+                let sample = samples.try_recv().unwrap_or(0.0);
+                frame.fill(T::from_sample(sample));
+            }
+        },
+        |e| eprintln!("{e}"),
+        None,
+    )
+}
+
+/// Runs on its own thread, stepping the solver once per sample. Blocks while the buffer is full,
+/// so it runs in step with playback, and exits once the stream or the `AudioOutput` is dropped.
+fn generate(
+    dt: f64,
+    mut update: AudioUpdate,
+    updates: Receiver<AudioUpdate>,
+    samples: SyncSender<f32>,
+    errors: Sender<String>,
+) {
+    let mut sim = Solver::new(&update.diagram.primitive);
+    let (mut last_in, mut last_out) = (0.0, 0.0);
+
+    loop {
+        // Updates pile up while the buffer is full; only the latest one matters
+        loop {
+            match updates.try_recv() {
+                Ok(next) => {
+                    if next.reset {
+                        sim = Solver::new(&next.diagram.primitive);
+                    }
+                    update = next;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        let diagram = &update.diagram.primitive;
+        if let Err(e) = sim.step(dt, diagram, &update.cfg, None) {
+            let _ = errors.send(e.to_string());
+            return;
+        }
+
+        let voltages = sim.state(diagram).voltages;
+        let probe = |node: Option<usize>| node.and_then(|n| voltages.get(n).copied());
+        let mut value =
+            (probe(update.probe.0).unwrap_or(0.0) - probe(update.probe.1).unwrap_or(0.0)) as f32;
+
+        if update.block_dc {
+            let out = value - last_in + DC_BLOCK_POLE * last_out;
+            last_in = value;
+            last_out = out;
+            value = out;
+        }
+
+        // Clamp, so a large voltage can't blow out the speakers
+        let sample = if value.is_finite() {
+            (value * update.gain).clamp(-1.0, 1.0)
+        } else {
+            0.0
+        };
+
+        if samples.send(sample).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::{cell::RefCell, fmt::Write};
+
+use egui::{Align, Align2, Color32, Painter, Pos2, Rect, Shape, Stroke};
+
+/// The drawing primitives used by the component glyphs. Implemented both for the on-screen
+/// egui [`Painter`] and for [`SvgCanvas`], so each glyph's geometry is only written once.
+pub trait Canvas {
+    fn line_segment(&self, points: [Pos2; 2], stroke: Stroke);
+    fn convex_polygon(&self, points: Vec<Pos2>, fill: Color32, stroke: Stroke);
+    fn circle_filled(&self, center: Pos2, radius: f32, fill: Color32);
+    fn circle_stroke(&self, center: Pos2, radius: f32, stroke: Stroke);
+    fn rect_filled(&self, rect: Rect, fill: Color32);
+    fn text(&self, pos: Pos2, anchor: Align2, text: &str, color: Color32);
+    /// Animation time in seconds, or None for static output such as an exported image
+    fn time(&self) -> Option<f64>;
+}
+
+impl Canvas for Painter {
+    fn line_segment(&self, points: [Pos2; 2], stroke: Stroke) {
+        Painter::line_segment(self, points, stroke);
+    }
+
+    fn convex_polygon(&self, points: Vec<Pos2>, fill: Color32, stroke: Stroke) {
+        self.add(Shape::convex_polygon(points, fill, stroke));
+    }
+
+    fn circle_filled(&self, center: Pos2, radius: f32, fill: Color32) {
+        Painter::circle_filled(self, center, radius, fill);
+    }
+
+    fn circle_stroke(&self, center: Pos2, radius: f32, stroke: Stroke) {
+        Painter::circle_stroke(self, center, radius, stroke);
+    }
+
+    fn rect_filled(&self, rect: Rect, fill: Color32) {
+        Painter::rect_filled(self, rect, 0.0, fill);
+    }
+
+    fn text(&self, pos: Pos2, anchor: Align2, text: &str, color: Color32) {
+        Painter::text(self, pos, anchor, text, Default::default(), color);
+    }
+
+    fn time(&self) -> Option<f64> {
+        Some(self.ctx().input(|r| r.time))
+    }
+}
+
+/// Collects drawing primitives into an SVG document
+pub struct SvgCanvas {
+    /// Draw everything black on a white background, instead of using the visualization colors
+    pub monochrome: bool,
+    body: RefCell<String>,
+    bounds: RefCell<Rect>,
+}
+
+impl SvgCanvas {
+    pub fn new(monochrome: bool) -> Self {
+        Self {
+            monochrome,
+            body: RefCell::new(String::new()),
+            bounds: RefCell::new(Rect::NOTHING),
+        }
+    }
+
+    /// Finishes the document, sized to fit everything drawn with the given margin
+    pub fn finish(self, margin: f32) -> String {
+        let bounds = self.bounds.into_inner();
+        let bounds = if bounds.is_positive() {
+            bounds.expand(margin)
+        } else {
+            Rect::from_min_size(Pos2::ZERO, egui::Vec2::splat(margin))
+        };
+
+        let background = if self.monochrome {
+            Color32::WHITE
+        } else {
+            Color32::from_gray(27)
+        };
+
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{} {} {} {}" width="{}" height="{}">"#,
+            bounds.min.x,
+            bounds.min.y,
+            bounds.width(),
+            bounds.height(),
+            bounds.width(),
+            bounds.height(),
+        );
+        let _ = writeln!(
+            svg,
+            r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{}"/>"#,
+            bounds.min.x,
+            bounds.min.y,
+            bounds.width(),
+            bounds.height(),
+            svg_color(background),
+        );
+        svg.push_str(&self.body.into_inner());
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    fn color(&self, color: Color32) -> String {
+        if color == Color32::TRANSPARENT {
+            "none".into()
+        } else if self.monochrome {
+            svg_color(Color32::BLACK)
+        } else {
+            svg_color(color)
+        }
+    }
+
+    fn include(&self, rect: Rect) {
+        let mut bounds = self.bounds.borrow_mut();
+        *bounds = bounds.union(rect);
+    }
+
+    fn push(&self, element: String) {
+        let mut body = self.body.borrow_mut();
+        body.push_str(&element);
+        body.push('\n');
+    }
+}
+
+impl Canvas for SvgCanvas {
+    fn line_segment(&self, [a, b]: [Pos2; 2], stroke: Stroke) {
+        self.include(Rect::from_two_pos(a, b).expand(stroke.width / 2.0));
+        self.push(format!(
+            r#"<line x1="{}" y1="{}" x2="{}" y2="{}" stroke="{}" stroke-width="{}" stroke-linecap="round"/>"#,
+            a.x,
+            a.y,
+            b.x,
+            b.y,
+            self.color(stroke.color),
+            stroke.width,
+        ));
+    }
+
+    fn convex_polygon(&self, points: Vec<Pos2>, fill: Color32, stroke: Stroke) {
+        self.include(Rect::from_points(&points));
+        let points: Vec<String> = points.iter().map(|p| format!("{},{}", p.x, p.y)).collect();
+        self.push(format!(
+            r#"<polygon points="{}" fill="{}" stroke="{}" stroke-width="{}"/>"#,
+            points.join(" "),
+            self.color(fill),
+            self.color(stroke.color),
+            stroke.width,
+        ));
+    }
+
+    fn circle_filled(&self, center: Pos2, radius: f32, fill: Color32) {
+        self.include(Rect::from_center_size(
+            center,
+            egui::Vec2::splat(radius * 2.0),
+        ));
+        self.push(format!(
+            r#"<circle cx="{}" cy="{}" r="{}" fill="{}"/>"#,
+            center.x,
+            center.y,
+            radius,
+            self.color(fill),
+        ));
+    }
+
+    fn circle_stroke(&self, center: Pos2, radius: f32, stroke: Stroke) {
+        self.include(Rect::from_center_size(
+            center,
+            egui::Vec2::splat(radius * 2.0),
+        ));
+        self.push(format!(
+            r#"<circle cx="{}" cy="{}" r="{}" fill="none" stroke="{}" stroke-width="{}"/>"#,
+            center.x,
+            center.y,
+            radius,
+            self.color(stroke.color),
+            stroke.width,
+        ));
+    }
+
+    fn rect_filled(&self, rect: Rect, fill: Color32) {
+        self.include(rect);
+        self.push(format!(
+            r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{}"/>"#,
+            rect.min.x,
+            rect.min.y,
+            rect.width(),
+            rect.height(),
+            self.color(fill),
+        ));
+    }
+
+    fn text(&self, pos: Pos2, anchor: Align2, text: &str, color: Color32) {
+        let text_anchor = match anchor.x() {
+            Align::Min => "start",
+            Align::Center => "middle",
+            Align::Max => "end",
+        };
+        let baseline = match anchor.y() {
+            Align::Min => "hanging",
+            Align::Center => "middle",
+            Align::Max => "auto",
+        };
+        self.include(Rect::from_center_size(pos, egui::Vec2::splat(14.0)));
+        self.push(format!(
+            r#"<text x="{}" y="{}" font-family="sans-serif" font-size="14" text-anchor="{}" dominant-baseline="{}" fill="{}">{}</text>"#,
+            pos.x,
+            pos.y,
+            text_anchor,
+            baseline,
+            self.color(color),
+            escape_xml(text),
+        ));
+    }
+
+    fn time(&self) -> Option<f64> {
+        None
+    }
+}
+
+fn svg_color(color: Color32) -> String {
+    let [r, g, b, a] = color.to_srgba_unmultiplied();
+    if a == 255 {
+        format!("rgb({r},{g},{b})")
+    } else {
+        format!("rgba({r},{g},{b},{:.3})", a as f32 / 255.0)
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -1,31 +1,271 @@
-use egui::{Color32, DragValue, Id, Painter, Pos2, Rect, Response, Sense, Shape, Stroke, Ui, Vec2};
-use egui_simpletabs::{edit_metric_f64, to_metric_prefix};
-use std::collections::HashMap;
-
-use cirmcut_sim::{PrimitiveDiagram, SimOutputs, ThreeTerminalComponent, TwoTerminalComponent};
+use egui::{Color32, DragValue, Id, Key, Pos2, Rect, Response, Sense, Slider, Stroke, Ui, Vec2};
+use egui_simpletabs::edit_metric_f64;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+
+use cirmcut_sim::{
+    stamp::LIMIT_SLACK, GroundKind, IntegrationMethod, PrimitiveDiagram, SimOutputs,
+    ThreeTerminalComponent, TwoTerminalComponent, DEFAULT_ALPHA_REVERSE,
+};
 
 pub type CellPos = (i32, i32);
 
+/// Identifies a component of a `Diagram` for as long as it exists. Unlike its index, this doesn't
+/// change when an earlier component is removed.
+pub type ComponentId = u64;
+
+use crate::canvas::{Canvas, SvgCanvas};
 use crate::components::{
-    draw_battery, draw_capacitor, draw_component_value, draw_current_source, draw_diode,
-    draw_inductor, draw_resistor, draw_switch, draw_transistor,
+    draw_battery, draw_capacitor, draw_component_value, draw_controlled_current_source,
+    draw_crystal, draw_current_probe, draw_current_source, draw_diode, draw_ground, draw_inductor,
+    draw_noise_source, draw_potentiometer, draw_reference_direction, draw_resistor, draw_switch,
+    draw_terminal_labels, draw_transistor, label_normal,
 };
+use crate::measurement::{MeasurementWindow, WindowAverages};
 
 pub const CELL_SIZE: f32 = 100.0;
 
+/// Smallest values accepted by the editor. Zero or negative values make the matrix singular or
+/// the circuit non-physical.
+const MIN_RESISTANCE: f64 = 1e-6;
+const MIN_CAPACITANCE: f64 = 1e-15;
+const MIN_INDUCTANCE: f64 = 1e-12;
+const MIN_CURRENT_LIMIT: f64 = 1e-9;
+/// Series resistance of new capacitors and inductors. Real parts have a little, and it damps
+/// the ringing of ideal LC loops.
+pub const DEFAULT_SERIES_RESISTANCE: f64 = 10e-3;
+/// Tolerance of a component when it's first given its own, and the Monte Carlo window's
+/// default for the rest: ±5%
+pub const DEFAULT_TOLERANCE: f64 = 0.05;
+/// Currents smaller than this fraction of `VisualizationOptions::current_scale` aren't animated.
+/// Their dots would barely move and only clutter the diagram.
+const MIN_ANIMATED_CURRENT: f64 = 1e-3;
+/// Wire stroke width, and its range when scaled by current
+const WIRE_WIDTH: f32 = 3.0;
+const MIN_WIRE_WIDTH: f32 = 1.5;
+const MAX_WIRE_WIDTH: f32 = 12.0;
+
+/// Cell coordinates from `egui_to_cellpos()` stay within this far of the origin, so that the
+/// differences between them still fit in an i32
+const MAX_CELL_COORD: f32 = (1 << 24) as f32;
+
+/// Color of the wires and terminals in the net under the pointer
+const NET_HIGHLIGHT: Color32 = Color32::from_rgb(0x40, 0xc0, 0xff);
+
 #[derive(Copy, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
 pub struct VisualizationOptions {
     /// Volts
     pub voltage_scale: f64,
     /// Amps
     pub current_scale: f64,
+    /// Display size of one grid cell. Components are stored in whole cells, so changing this only
+    /// rescales the drawing.
+    pub cell_size: f32,
+    /// Snap components to the grid while they are being dragged
+    pub snap_to_grid: bool,
+    /// After a drag, wire dangling terminals to terminals of other components one cell away
+    pub auto_connect: bool,
+    /// Quantity used to color wires
+    pub mode: VisualizationMode,
+    /// Colors used for negative and positive values
+    pub color_scheme: ColorScheme,
+    /// Highlight cells joining more than this many terminals, which are often an accidental short
+    pub max_fan_in: Option<u32>,
+    /// Draw wires thicker the more current they carry, reaching the widest at `current_scale`
+    pub current_width: bool,
+    pub grid: GridStyle,
+    /// How values are written out in labels and readouts
+    pub value_format: ValueFormat,
+}
+
+/// Look of the dot grid behind the diagram, see `draw_grid()`
+#[derive(Copy, Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct GridStyle {
+    pub dot_color: Color32,
+    pub dot_radius: f32,
+    /// Fill behind the grid, or None for the theme's
+    pub background: Option<Color32>,
+    /// Closest the dots get on screen, in points. Zoomed out past this, only every second,
+    /// fourth, ... dot is drawn.
+    pub min_spacing: f32,
+    /// Hide the grid instead of thinning it out
+    pub hide_when_dense: bool,
+}
+
+impl Default for GridStyle {
+    fn default() -> Self {
+        Self {
+            dot_color: Color32::DARK_GRAY,
+            dot_radius: 1.0,
+            background: None,
+            min_spacing: 8.0,
+            hide_when_dense: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum ColorScheme {
+    #[default]
+    RedGreen,
+    BlueYellow,
+    Viridis,
+}
+
+/// How values are written out in labels and readouts, see `format_value()`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum ValueFormat {
+    /// 4.7 kΩ
+    #[default]
+    SiPrefix,
+    /// 4.700e3 Ω
+    Scientific,
+    /// 4700 Ω
+    Raw,
+}
+
+impl ValueFormat {
+    pub const ALL: [Self; 3] = [Self::SiPrefix, Self::Scientific, Self::Raw];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::SiPrefix => "SI prefix",
+            Self::Scientific => "Scientific",
+            Self::Raw => "Raw",
+        }
+    }
+}
+
+/// Significant figures of values written with an SI prefix or in scientific notation
+pub const SIGNIFICANT_FIGURES: usize = 4;
+
+/// SI prefixes from 1e-24 to 1e24, a factor of 1000 apart
+const METRIC_PREFIXES: [&str; 17] = [
+    "y", "z", "a", "f", "p", "n", "µ", "m", "", "k", "M", "G", "T", "P", "E", "Z", "Y",
+];
+
+/// Writes out a value with its unit
+pub fn format_value(value: f64, unit: char, format: ValueFormat) -> String {
+    // Don't show -0 for values which only just rounded to zero
+    let value = if value == 0.0 { 0.0 } else { value };
+    match format {
+        ValueFormat::SiPrefix => metric_prefix(value, unit, SIGNIFICANT_FIGURES),
+        ValueFormat::Scientific => {
+            format!("{value:.*e} {unit}", SIGNIFICANT_FIGURES.saturating_sub(1))
+        }
+        ValueFormat::Raw => format!("{value} {unit}"),
+    }
+}
+
+/// Writes out a value with the SI prefix that leaves between 1 and 1000 in front of it, rounded
+/// to `significant_figures` without trailing zeros, e.g. 4.7 kΩ. Values beyond the prefixes are
+/// written in scientific notation instead.
+pub fn metric_prefix(value: f64, unit: char, significant_figures: usize) -> String {
+    let significant_figures = significant_figures.max(1);
+    if value == 0.0 {
+        return format!("0 {unit}");
+    }
+
+    // Round first, so that 999.96 becomes 1 k rather than 1000
+    let rounded: f64 = format!("{value:.*e}", significant_figures - 1)
+        .parse()
+        .unwrap_or(value);
+    let exponent = rounded.abs().log10().floor() as i32;
+    let group = exponent.div_euclid(3);
+    let Some(prefix) = usize::try_from(group + 8)
+        .ok()
+        .and_then(|idx| METRIC_PREFIXES.get(idx))
+    else {
+        return format!("{value:.*e} {unit}", significant_figures - 1);
+    };
+
+    let mantissa = rounded / 10_f64.powi(group * 3);
+    let decimals = (significant_figures as i32 - 1 - (exponent - group * 3)).max(0) as usize;
+    let mantissa = format!("{mantissa:.decimals$}");
+    let mantissa = if mantissa.contains('.') {
+        mantissa.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        &mantissa
+    };
+    format!("{mantissa} {prefix}{unit}")
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum VisualizationMode {
+    #[default]
+    Voltage,
+    Current,
+    Power,
 }
 
 #[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
 pub struct Diagram {
+    #[serde(default)]
     pub ports: Vec<(CellPos, String)>,
     pub two_terminal: Vec<([CellPos; 2], TwoTerminalComponent)>,
     pub three_terminal: Vec<([CellPos; 3], ThreeTerminalComponent)>,
+    #[serde(default)]
+    pub grounds: Vec<(CellPos, GroundKind)>,
+    /// Components left out of the simulation. Remove components with `remove()` to keep these
+    /// indices valid.
+    #[serde(default)]
+    pub disabled: Vec<(usize, SelectionType)>,
+    /// Components which can't be dragged or nudged, to protect a finished layout. Indices are
+    /// kept valid the same way as `disabled`.
+    #[serde(default)]
+    pub locked: Vec<(usize, SelectionType)>,
+    /// Stable IDs of the components, for references which have to survive removals
+    #[serde(default)]
+    pub ids: ComponentIds,
+    /// Named variables setting the value of two-terminal components, by component ID. See
+    /// `apply_variables()`.
+    #[serde(default)]
+    pub bindings: BTreeMap<ComponentId, String>,
+    /// Component whose current each current-controlled source follows, by the IDs of both
+    #[serde(default)]
+    pub sensed: BTreeMap<ComponentId, ComponentId>,
+    /// How far Monte Carlo analysis varies the value of two-terminal components, as a fraction
+    /// (0.05 for ±5%), by component ID. See `tolerances()`.
+    #[serde(default)]
+    pub tolerances: BTreeMap<ComponentId, f64>,
+}
+
+/// IDs of the components of a `Diagram`, in the same order as its lists. Removing components
+/// with `Diagram::remove()` keeps these in step. Components pushed onto the lists directly get
+/// theirs from `Diagram::assign_ids()`.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct ComponentIds {
+    pub ports: Vec<ComponentId>,
+    pub two_terminal: Vec<ComponentId>,
+    pub three_terminal: Vec<ComponentId>,
+    /// Next ID to hand out. IDs are never reused within a diagram.
+    next: ComponentId,
+    /// Random number given to the diagram along with its first IDs, and kept by its copies.
+    /// Diagrams drawn separately hand out the same IDs, so IDs only identify the same component
+    /// across diagrams with the same origin. 0 until `Diagram::assign_ids()` sets it, as in
+    /// files saved before there was one.
+    #[serde(default)]
+    pub origin: u64,
+}
+
+impl ComponentIds {
+    pub fn list(&self, ty: SelectionType) -> &[ComponentId] {
+        match ty {
+            SelectionType::Port => &self.ports,
+            SelectionType::TwoTerminal => &self.two_terminal,
+            SelectionType::ThreeTerminal => &self.three_terminal,
+        }
+    }
+
+    fn list_mut(&mut self, ty: SelectionType) -> &mut Vec<ComponentId> {
+        match ty {
+            SelectionType::Port => &mut self.ports,
+            SelectionType::TwoTerminal => &mut self.two_terminal,
+            SelectionType::ThreeTerminal => &mut self.three_terminal,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
@@ -56,58 +296,688 @@ impl Default for DiagramWireState {
     }
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Copy, Clone, PartialEq, Eq)]
+#[derive(serde::Deserialize, serde::Serialize, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum SelectionType {
     Port,
     TwoTerminal,
     ThreeTerminal,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
-pub struct DiagramEditor {
-    pub selected: Option<(usize, SelectionType)>,
+/// What dragging in the diagram does
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EditorMode {
+    /// Select and move components
+    #[default]
+    Select,
+    /// Draw chains of wires, starting from `start`
+    DrawWire { start: Option<CellPos> },
 }
 
-pub fn cellpos_to_egui((x, y): CellPos) -> Pos2 {
-    Pos2::new(x as f32, y as f32) * CELL_SIZE
+/// Random, nonzero value for `ComponentIds::origin`
+fn new_origin() -> u64 {
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    // RandomState alone repeats from one run to the next on wasm
+    if let Ok(since_epoch) = web_time::SystemTime::now().duration_since(web_time::UNIX_EPOCH) {
+        hasher.write_u128(since_epoch.as_nanos());
+    }
+    hasher.finish().max(1)
 }
 
-pub fn cellpos_to_egui_vec((x, y): CellPos) -> Vec2 {
-    Vec2::new(x as f32, y as f32) * CELL_SIZE
+/// Nets from `Diagram::wire_nets()`, and the wires they were computed from
+struct NetCache {
+    wires: Vec<[CellPos; 2]>,
+    nets: HashMap<CellPos, CellPos>,
 }
 
-pub fn egui_to_cellpos(pos: Pos2) -> CellPos {
-    (
-        (pos.x / CELL_SIZE).round() as i32,
-        (pos.y / CELL_SIZE).round() as i32,
-    )
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct DiagramEditor {
+    /// Primary selection. Saved under a new name, since this used to be an index.
+    #[serde(default, rename = "selected_id")]
+    pub selected: Option<ComponentId>,
+    /// Components added to the selection with shift-click, in addition to `selected`
+    #[serde(default, rename = "multi_selected_ids")]
+    pub multi_selected: Vec<ComponentId>,
+    #[serde(skip)]
+    pub mode: EditorMode,
+    /// See `net_cells()`
+    #[serde(skip)]
+    net_cache: Option<NetCache>,
+    /// Whether the pointer was over a selected component with a value in the last frame, see
+    /// `scrolls_value()`
+    #[serde(skip)]
+    scrolling_value: bool,
+    /// Scrolling not yet turned into steps of the value, in points
+    #[serde(skip)]
+    value_scroll: f32,
+}
+
+impl VisualizationOptions {
+    pub fn cellpos_to_egui(&self, (x, y): CellPos) -> Pos2 {
+        Pos2::new(x as f32, y as f32) * self.cell_size
+    }
+
+    pub fn cellpos_to_egui_vec(&self, (x, y): CellPos) -> Vec2 {
+        Vec2::new(x as f32, y as f32) * self.cell_size
+    }
+
+    /// Nearest cell, clamped to `MAX_CELL_COORD`. Positions which aren't finite go to the origin.
+    pub fn egui_to_cellpos(&self, pos: Pos2) -> CellPos {
+        let coord = |v: f32| to_cell_coord((v / self.cell_size).round());
+        (coord(pos.x), coord(pos.y))
+    }
+
+    pub fn egui_to_cellvec(&self, v: Vec2) -> CellPos {
+        let coord = |v: f32| to_cell_coord((v / self.cell_size).trunc());
+        (coord(v.x), coord(v.y))
+    }
+
+    /// Rounds a drag offset to whole cells, if snapping is enabled
+    pub fn snap(&self, offset: Vec2) -> Vec2 {
+        if self.snap_to_grid {
+            (offset / self.cell_size).round() * self.cell_size
+        } else {
+            offset
+        }
+    }
 }
 
-pub fn egui_to_cellvec(v: Vec2) -> CellPos {
-    ((v.x / CELL_SIZE) as i32, (v.y / CELL_SIZE) as i32)
+fn to_cell_coord(cells: f32) -> i32 {
+    if cells.is_finite() {
+        cells.clamp(-MAX_CELL_COORD, MAX_CELL_COORD) as i32
+    } else {
+        0
+    }
 }
 
 impl Diagram {
-    pub fn junctions(&self) -> Vec<CellPos> {
-        let mut junctions = HashMap::<CellPos, u32>::new();
+    /// Cell the simulator measures voltages from when no reference ground is connected to
+    /// anything, in which case it picks one. None if there is a connected reference ground, or
+    /// nothing to simulate.
+    pub fn implicit_reference(&self) -> Option<CellPos> {
+        let rich = self.to_primitive_diagram();
+        if rich
+            .primitive
+            .grounds
+            .iter()
+            .any(|(_, kind)| *kind == GroundKind::Reference)
+        {
+            return None;
+        }
+
+        let reference = rich.primitive.num_nodes.checked_sub(1)?;
+        rich.all_positions
+            .iter()
+            .find(|(_, idx)| **idx == reference)
+            .map(|(pos, _)| *pos)
+    }
+
+    /// Adds everything in `other`, moved so that its origin lands on `at`. A pasted reference
+    /// ground is tied to the existing one, if there is one.
+    pub fn paste(&mut self, other: Diagram, at: CellPos) {
+        let offset = |(x, y): CellPos| (x + at.0, y + at.1);
+        let has_reference = self
+            .grounds
+            .iter()
+            .any(|(_, kind)| *kind == GroundKind::Reference);
+
+        let (n_ports, n_two, n_three) = (
+            self.ports.len(),
+            self.two_terminal.len(),
+            self.three_terminal.len(),
+        );
+        let renumber = |&(idx, ty): &(usize, SelectionType)| match ty {
+            SelectionType::Port => (idx + n_ports, ty),
+            SelectionType::TwoTerminal => (idx + n_two, ty),
+            SelectionType::ThreeTerminal => (idx + n_three, ty),
+        };
+        self.disabled.extend(other.disabled.iter().map(renumber));
+        self.locked.extend(other.locked.iter().map(renumber));
+        // Bindings by index for now, since the pasted components get new IDs below
+        let bindings: Vec<(usize, String)> = other
+            .ids
+            .two_terminal
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, id)| Some((idx + n_two, other.bindings.get(id)?.clone())))
+            .collect();
+        let tolerances: Vec<(usize, f64)> = other
+            .ids
+            .two_terminal
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, id)| Some((idx + n_two, *other.tolerances.get(id)?)))
+            .collect();
+        let position = |id: &ComponentId| other.ids.two_terminal.iter().position(|i| i == id);
+        let sensed: Vec<(usize, usize)> = other
+            .sensed
+            .iter()
+            .filter_map(|(source, sensed)| {
+                Some((position(source)? + n_two, position(sensed)? + n_two))
+            })
+            .collect();
+
+        self.ports.extend(
+            other
+                .ports
+                .into_iter()
+                .map(|(pos, name)| (offset(pos), name)),
+        );
+        self.two_terminal.extend(
+            other
+                .two_terminal
+                .into_iter()
+                .map(|(pos, component)| (pos.map(offset), component)),
+        );
+        self.three_terminal.extend(
+            other
+                .three_terminal
+                .into_iter()
+                .map(|(pos, component)| (pos.map(offset), component)),
+        );
+        self.grounds
+            .extend(other.grounds.into_iter().map(|(pos, kind)| match kind {
+                GroundKind::Reference if has_reference => (offset(pos), GroundKind::Tied(None)),
+                kind => (offset(pos), kind),
+            }));
+
+        // The pasted IDs may clash with ours, so hand out new ones
+        self.assign_ids();
+        for (idx, name) in bindings {
+            if let Some(id) = self.id((idx, SelectionType::TwoTerminal)) {
+                self.bindings.insert(id, name);
+            }
+        }
+        for (idx, tolerance) in tolerances {
+            if let Some(id) = self.id((idx, SelectionType::TwoTerminal)) {
+                self.tolerances.insert(id, tolerance);
+            }
+        }
+        for (source, sensed) in sensed {
+            let source = self.id((source, SelectionType::TwoTerminal));
+            let sensed = self.id((sensed, SelectionType::TwoTerminal));
+            if let (Some(source), Some(sensed)) = (source, sensed) {
+                self.sensed.insert(source, sensed);
+            }
+        }
+    }
+
+    /// Removes a component, renumbering the disabled and locked components after it
+    pub fn remove(&mut self, (idx, ty): (usize, SelectionType)) {
+        let ids = self.ids.list_mut(ty);
+        if idx < ids.len() {
+            let id = ids.remove(idx);
+            if ty == SelectionType::TwoTerminal {
+                self.bindings.remove(&id);
+                self.tolerances.remove(&id);
+                self.sensed
+                    .retain(|&source, &mut sensed| source != id && sensed != id);
+            }
+        }
+
+        match ty {
+            SelectionType::Port => {
+                self.ports.remove(idx);
+            }
+            SelectionType::TwoTerminal => {
+                self.two_terminal.remove(idx);
+            }
+            SelectionType::ThreeTerminal => {
+                self.three_terminal.remove(idx);
+            }
+        }
+
+        for list in [&mut self.disabled, &mut self.locked] {
+            list.retain(|&sel| sel != (idx, ty));
+            for (other, other_ty) in list {
+                if *other_ty == ty && *other > idx {
+                    *other -= 1;
+                }
+            }
+        }
+    }
+
+    /// Gives new IDs to components which don't have one yet, e.g. those pushed onto the lists
+    /// directly or loaded from a file saved before components had IDs
+    pub fn assign_ids(&mut self) {
+        if self.ids.origin == 0 {
+            self.ids.origin = new_origin();
+        }
+
+        let lens = [
+            (SelectionType::Port, self.ports.len()),
+            (SelectionType::TwoTerminal, self.two_terminal.len()),
+            (SelectionType::ThreeTerminal, self.three_terminal.len()),
+        ];
+        for (ty, len) in lens {
+            let ids = &mut self.ids;
+            while ids.list(ty).len() < len {
+                let id = ids.next;
+                ids.next += 1;
+                ids.list_mut(ty).push(id);
+            }
+            ids.list_mut(ty).truncate(len);
+        }
+    }
+
+    /// ID of the component at an index, if it exists and has one
+    pub fn id(&self, (idx, ty): (usize, SelectionType)) -> Option<ComponentId> {
+        self.ids.list(ty).get(idx).copied()
+    }
+
+    /// Current index of a component, or None if it has been removed
+    pub fn find(&self, id: ComponentId) -> Option<(usize, SelectionType)> {
+        [
+            SelectionType::Port,
+            SelectionType::TwoTerminal,
+            SelectionType::ThreeTerminal,
+        ]
+        .into_iter()
+        .find_map(|ty| {
+            let idx = self.ids.list(ty).iter().position(|&other| other == id)?;
+            Some((idx, ty))
+        })
+    }
+
+    pub fn is_enabled(&self, sel: (usize, SelectionType)) -> bool {
+        !self.disabled.contains(&sel)
+    }
+
+    pub fn set_enabled(&mut self, sel: (usize, SelectionType), enabled: bool) {
+        self.disabled.retain(|&other| other != sel);
+        if !enabled {
+            self.disabled.push(sel);
+        }
+    }
+
+    /// Cells of a component's terminals, or none if it doesn't exist
+    pub fn cells(&self, (idx, ty): (usize, SelectionType)) -> Vec<CellPos> {
+        match ty {
+            SelectionType::Port => self.ports.get(idx).map(|(p, _)| vec![*p]),
+            SelectionType::TwoTerminal => self.two_terminal.get(idx).map(|(p, _)| p.to_vec()),
+            SelectionType::ThreeTerminal => self.three_terminal.get(idx).map(|(p, _)| p.to_vec()),
+        }
+        .unwrap_or_default()
+    }
+
+    /// Moves all of a component's terminals by `(dx, dy)` cells, whether or not it is locked
+    pub fn translate(&mut self, (idx, ty): (usize, SelectionType), (dx, dy): CellPos) {
+        let offset = |(x, y): CellPos| (x + dx, y + dy);
+        match ty {
+            SelectionType::Port => {
+                if let Some((pos, _)) = self.ports.get_mut(idx) {
+                    *pos = offset(*pos);
+                }
+            }
+            SelectionType::TwoTerminal => {
+                if let Some((positions, _)) = self.two_terminal.get_mut(idx) {
+                    *positions = positions.map(offset);
+                }
+            }
+            SelectionType::ThreeTerminal => {
+                if let Some((positions, _)) = self.three_terminal.get_mut(idx) {
+                    *positions = positions.map(offset);
+                }
+            }
+        }
+    }
+
+    pub fn is_locked(&self, sel: (usize, SelectionType)) -> bool {
+        self.locked.contains(&sel)
+    }
+
+    pub fn set_locked(&mut self, sel: (usize, SelectionType), locked: bool) {
+        self.locked.retain(|&other| other != sel);
+        if locked {
+            self.locked.push(sel);
+        }
+    }
+
+    /// Locks or unlocks every component
+    pub fn set_all_locked(&mut self, locked: bool) {
+        self.locked.clear();
+        if locked {
+            let ports = (0..self.ports.len()).map(|idx| (idx, SelectionType::Port));
+            let two = (0..self.two_terminal.len()).map(|idx| (idx, SelectionType::TwoTerminal));
+            let three =
+                (0..self.three_terminal.len()).map(|idx| (idx, SelectionType::ThreeTerminal));
+            self.locked.extend(ports.chain(two).chain(three));
+        }
+    }
+
+    /// Opens every switch if any of them is closed, otherwise closes them all
+    pub fn toggle_all_switches(&mut self) {
+        let any_closed = self
+            .two_terminal
+            .iter()
+            .any(|(_, component)| matches!(component, TwoTerminalComponent::Switch(false)));
+        for (_, component) in &mut self.two_terminal {
+            if let TwoTerminalComponent::Switch(is_open) = component {
+                *is_open = any_closed;
+            }
+        }
+    }
+
+    /// Number of component terminals in each occupied cell
+    pub fn terminal_counts(&self) -> HashMap<CellPos, u32> {
+        let mut counts = HashMap::<CellPos, u32>::new();
         for (positions, _) in &self.two_terminal {
             for &pos in positions {
-                *junctions.entry(pos).or_default() += 1;
+                *counts.entry(pos).or_default() += 1;
             }
         }
         for (positions, _) in &self.three_terminal {
             for &pos in positions {
-                *junctions.entry(pos).or_default() += 1;
+                *counts.entry(pos).or_default() += 1;
             }
         }
-        junctions
+        counts
+    }
+
+    /// Cells where the circuit branches, which get a junction dot. Following the usual schematic
+    /// convention, that takes three or more terminals; two components meeting end to end are
+    /// simply in series.
+    pub fn junctions(&self) -> Vec<CellPos> {
+        self.terminal_counts()
             .into_iter()
-            .filter_map(|(pos, count)| (count > 1).then_some(pos))
+            .filter_map(|(pos, count)| (count > 2).then_some(pos))
             .collect()
     }
 
+    /// Components lying exactly on top of an earlier one with the same terminal cells, in either
+    /// order. These are drawn as one, so they're usually left over from pasting by accident.
+    pub fn stacked_components(&self) -> Vec<(usize, SelectionType)> {
+        let mut stacked = vec![];
+
+        let mut seen = HashSet::new();
+        for (idx, ([a, b], _)) in self.two_terminal.iter().enumerate() {
+            if !seen.insert([*a.min(b), *a.max(b)]) {
+                stacked.push((idx, SelectionType::TwoTerminal));
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for (idx, (positions, _)) in self.three_terminal.iter().enumerate() {
+            if !seen.insert(*positions) {
+                stacked.push((idx, SelectionType::ThreeTerminal));
+            }
+        }
+
+        stacked
+    }
+
+    /// Components with two terminals on the same cell. Both terminals would be the same node, which
+    /// leaves the component's own equation degenerate, so `to_primitive_diagram()` leaves these
+    /// out like disabled ones.
+    pub fn collapsed_components(&self) -> Vec<(usize, SelectionType)> {
+        let two_terminal = self
+            .two_terminal
+            .iter()
+            .enumerate()
+            .filter(|(_, ([a, b], _))| a == b)
+            .map(|(idx, _)| (idx, SelectionType::TwoTerminal));
+
+        let three_terminal = self
+            .three_terminal
+            .iter()
+            .enumerate()
+            .filter(|(_, ([a, b, c], _))| a == b || b == c || a == c)
+            .map(|(idx, _)| (idx, SelectionType::ThreeTerminal));
+
+        two_terminal.chain(three_terminal).collect()
+    }
+
+    /// Adds a wire from each unconnected terminal of the given component to a terminal of another
+    /// component in a cell beside, above or below it, if there is one. Returns true if any wires
+    /// were added.
+    pub fn connect_adjacent_terminals(&mut self, (idx, ty): (usize, SelectionType)) -> bool {
+        let own: Vec<CellPos> = match ty {
+            SelectionType::TwoTerminal => self.two_terminal.get(idx).map(|(pos, _)| pos.to_vec()),
+            SelectionType::ThreeTerminal => {
+                self.three_terminal.get(idx).map(|(pos, _)| pos.to_vec())
+            }
+            SelectionType::Port => None,
+        }
+        .unwrap_or_default();
+
+        let mut others: Vec<CellPos> = vec![];
+        for (i, (positions, _)) in self.two_terminal.iter().enumerate() {
+            if (i, SelectionType::TwoTerminal) != (idx, ty) {
+                others.extend(positions);
+            }
+        }
+        for (i, (positions, _)) in self.three_terminal.iter().enumerate() {
+            if (i, SelectionType::ThreeTerminal) != (idx, ty) {
+                others.extend(positions);
+            }
+        }
+        others.extend(self.ports.iter().map(|(pos, _)| *pos));
+        others.sort();
+        others.dedup();
+
+        let mut added = false;
+        for (x, y) in own {
+            if others.contains(&(x, y)) {
+                continue;
+            }
+
+            // Not diagonally, which would make a diagonal wire
+            let neighbor = others
+                .iter()
+                .copied()
+                .find(|&(ox, oy)| (ox - x).abs() + (oy - y).abs() == 1);
+
+            if let Some(neighbor) = neighbor {
+                self.two_terminal
+                    .push(([(x, y), neighbor], TwoTerminalComponent::Wire));
+                added = true;
+            }
+        }
+
+        added
+    }
+
+    /// Maps each cell touched by a wire to a representative cell of the net the wires join it to.
+    /// Cells not listed here are only connected to themselves.
+    pub fn wire_nets(&self) -> HashMap<CellPos, CellPos> {
+        self.union_cells(
+            |_, component| matches!(component, TwoTerminalComponent::Wire),
+            &[],
+        )
+    }
+
+    /// Every net of the diagram, with the terminals in it, ordered by their first cell. Cells
+    /// joined by enabled wires are in the same net, and every cell with a terminal is in one.
+    /// Disabled wires don't conduct, as in the simulation. Wires belong to the net of their cells
+    /// but aren't listed among its terminals.
+    pub fn nets(&self) -> Vec<Net> {
+        let joined = self.union_cells(
+            |idx, component| {
+                matches!(component, TwoTerminalComponent::Wire)
+                    && self.is_enabled((idx, SelectionType::TwoTerminal))
+            },
+            &[],
+        );
+
+        let mut nets: HashMap<CellPos, Net> = HashMap::new();
+        let mut add = |pos: CellPos, terminal: Option<((usize, SelectionType), usize)>| {
+            let root = joined.get(&pos).copied().unwrap_or(pos);
+            let net = nets.entry(root).or_default();
+            net.cells.push(pos);
+            net.terminals.extend(terminal);
+        };
+        for (idx, (positions, component)) in self.two_terminal.iter().enumerate() {
+            let is_wire = matches!(component, TwoTerminalComponent::Wire);
+            for (terminal, &pos) in positions.iter().enumerate() {
+                add(
+                    pos,
+                    (!is_wire).then_some(((idx, SelectionType::TwoTerminal), terminal)),
+                );
+            }
+        }
+        for (idx, (positions, _)) in self.three_terminal.iter().enumerate() {
+            for (terminal, &pos) in positions.iter().enumerate() {
+                add(pos, Some(((idx, SelectionType::ThreeTerminal), terminal)));
+            }
+        }
+
+        let mut nets: Vec<Net> = nets
+            .into_values()
+            .map(|mut net| {
+                net.cells.sort_unstable();
+                net.cells.dedup();
+                net
+            })
+            .collect();
+        nets.sort_by_key(|net| net.cells[0]);
+        nets
+    }
+
+    /// Indices of enabled batteries whose terminals are joined by nothing but wires, closed
+    /// switches and current probes, or ideal ground ties. The solver can't hold a voltage across
+    /// zero resistance, so these make the matrix singular.
+    pub fn shorted_batteries(&self) -> Vec<usize> {
+        let grounds: Vec<CellPos> = self
+            .grounds
+            .iter()
+            .filter(|(_, kind)| matches!(kind, GroundKind::Reference | GroundKind::Tied(None)))
+            .map(|(pos, _)| *pos)
+            .collect();
+        let nets = self.union_cells(
+            |idx, component| {
+                self.is_enabled((idx, SelectionType::TwoTerminal))
+                    && matches!(
+                        component,
+                        TwoTerminalComponent::Wire
+                            | TwoTerminalComponent::Switch(false)
+                            | TwoTerminalComponent::CurrentProbe
+                    )
+            },
+            &grounds,
+        );
+        let net = |pos: CellPos| nets.get(&pos).copied().unwrap_or(pos);
+
+        self.two_terminal
+            .iter()
+            .enumerate()
+            .filter(|&(idx, ([begin, end], component))| {
+                matches!(component, TwoTerminalComponent::Battery(..))
+                    && self.is_enabled((idx, SelectionType::TwoTerminal))
+                    && net(*begin) == net(*end)
+            })
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Partitions cells into nets joined by the two-terminal components for which `joins` holds,
+    /// given their index, and by `tied`, which all go in one net. Maps each cell touched by any
+    /// of those to a representative cell of its net.
+    fn union_cells(
+        &self,
+        joins: impl Fn(usize, TwoTerminalComponent) -> bool,
+        tied: &[CellPos],
+    ) -> HashMap<CellPos, CellPos> {
+        fn find(parents: &mut HashMap<CellPos, CellPos>, pos: CellPos) -> CellPos {
+            let parent = *parents.entry(pos).or_insert(pos);
+            if parent == pos {
+                return pos;
+            }
+            let root = find(parents, parent);
+            parents.insert(pos, root);
+            root
+        }
+
+        let mut parents = HashMap::new();
+        let edges = self
+            .two_terminal
+            .iter()
+            .enumerate()
+            .filter(|&(idx, (_, component))| joins(idx, *component))
+            .map(|(_, (positions, _))| *positions)
+            .chain(tied.windows(2).map(|pair| [pair[0], pair[1]]));
+        for [a, b] in edges {
+            let a = find(&mut parents, a);
+            let b = find(&mut parents, b);
+            parents.insert(a, b);
+        }
+
+        let cells: Vec<CellPos> = parents.keys().copied().collect();
+        cells
+            .into_iter()
+            .map(|pos| (pos, find(&mut parents, pos)))
+            .collect()
+    }
+
+    /// Sets the value of every component bound to a variable to that variable's value, leaving
+    /// those bound to a variable which doesn't exist as they are. Values below what the editor
+    /// accepts for the component are raised to its minimum, since a variable can be bound to
+    /// several kinds at once. Returns true if any changed.
+    pub fn apply_variables(&mut self, variables: &HashMap<String, f64>) -> bool {
+        let mut changed = false;
+        for (idx, (_, component)) in self.two_terminal.iter_mut().enumerate() {
+            let Some(&value) = self
+                .ids
+                .two_terminal
+                .get(idx)
+                .and_then(|id| self.bindings.get(id))
+                .and_then(|name| variables.get(name))
+            else {
+                continue;
+            };
+            let value = match min_value(*component) {
+                Some(min) if value.is_nan() || value < min => min,
+                _ => value,
+            };
+            let bound = component.with_value(value);
+            changed |= bound != *component;
+            *component = bound;
+        }
+        changed
+    }
+
+    /// Tolerance of each two-terminal component of `rich`, which was made from this diagram, as
+    /// `monte_carlo()` takes them. Components without their own get `default`, and the parts a
+    /// crystal is expanded into get the crystal's. Resistors tying grounds to the reference
+    /// aren't components of the diagram, and keep their exact values.
+    pub fn tolerances(&self, rich: &RichPrimitiveDiagram, default: f64) -> Vec<f64> {
+        let tolerance = |idx: usize| {
+            self.id((idx, SelectionType::TwoTerminal))
+                .and_then(|id| self.tolerances.get(&id).copied())
+                .unwrap_or(default)
+        };
+
+        let mut tolerances: Vec<f64> = (0..rich.primitive.two_terminal.len())
+            .map(|idx| {
+                if idx < self.two_terminal.len() {
+                    tolerance(idx)
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        // Each crystal's four parts were added at the end, in the same order as `crystals`
+        let first_part = tolerances.len() - rich.crystals.len() * 4;
+        for (n, &(idx, _)) in rich.crystals.iter().enumerate() {
+            let start = first_part + n * 4;
+            tolerances[start..start + 4].fill(tolerance(idx));
+        }
+        tolerances
+    }
+
+    /// Index of the two-terminal component sensed by the current-controlled source at `idx`
+    fn sensed_index(&self, idx: usize) -> Option<usize> {
+        let id = self.id((idx, SelectionType::TwoTerminal))?;
+        match self.find(*self.sensed.get(&id)?)? {
+            (sensed, SelectionType::TwoTerminal) => Some(sensed),
+            _ => None,
+        }
+    }
+
     pub fn to_primitive_diagram(&self) -> RichPrimitiveDiagram {
-        let mut all_positions: HashMap<CellPos, usize> = HashMap::new();
+        // Nodes are numbered in the order their cells first appear in the component lists, never
+        // by hash order, so the same diagram always gets the same numbering and the same implicit
+        // ground (the last node) when there is no reference ground
+        let mut all_positions: BTreeMap<CellPos, usize> = BTreeMap::new();
 
         for (positions, _) in &self.two_terminal {
             for pos in positions {
@@ -127,25 +997,151 @@ impl Diagram {
             }
         }
 
-        let two_terminal = self
+        // The simulator treats the last node as 0 V, so the reference ground has to go there
+        let reference = self
+            .grounds
+            .iter()
+            .find(|(_, kind)| *kind == GroundKind::Reference)
+            .and_then(|(pos, _)| all_positions.get(pos).copied());
+        if let Some(reference) = reference {
+            let last = all_positions.len() - 1;
+            for idx in all_positions.values_mut() {
+                if *idx == reference {
+                    *idx = last;
+                } else if *idx == last {
+                    *idx = reference;
+                }
+            }
+        }
+
+        // Disabled components become open switches, which keeps the indices lined up. So do
+        // collapsed ones.
+        let collapsed = self.collapsed_components();
+        let mut two_terminal: Vec<_> = self
             .two_terminal
             .iter()
-            .map(|(positions, component)| (positions.map(|pos| all_positions[&pos]), *component))
+            .enumerate()
+            .map(|(idx, (positions, component))| {
+                let sel = (idx, SelectionType::TwoTerminal);
+                let component = match *component {
+                    _ if !self.is_enabled(sel) || collapsed.contains(&sel) => {
+                        TwoTerminalComponent::Switch(true)
+                    }
+                    TwoTerminalComponent::CurrentControlledSource(gain, _) => {
+                        TwoTerminalComponent::CurrentControlledSource(gain, self.sensed_index(idx))
+                    }
+                    component => component,
+                };
+                (positions.map(|pos| all_positions[&pos]), component)
+            })
             .collect();
 
-        let three_terminal = self
+        let mut three_terminal: Vec<_> = self
             .three_terminal
             .iter()
             .map(|(positions, component)| (positions.map(|pos| all_positions[&pos]), *component))
             .collect();
 
+        // Grounds on cells without any components aren't connected to anything. The solver is
+        // told about a reference left out that way, rather than finding no reference at all.
+        let unconnected_reference = self
+            .grounds
+            .iter()
+            .any(|(pos, kind)| *kind == GroundKind::Reference && !all_positions.contains_key(pos));
+        let mut grounds: Vec<(usize, GroundKind)> = self
+            .grounds
+            .iter()
+            .filter_map(|(pos, kind)| Some((*all_positions.get(pos)?, *kind)))
+            .collect();
+
+        // Tie the other grounds to the reference. These come after the diagram's own components,
+        // so that indices into the diagram stay valid for the primitive diagram.
+        if reference.is_some() {
+            let last = all_positions.len() - 1;
+            for &(node, kind) in &grounds {
+                let GroundKind::Tied(resistance) = kind else {
+                    continue;
+                };
+                if node == last {
+                    continue;
+                }
+
+                let component = match resistance {
+                    Some(resistance) => TwoTerminalComponent::Resistor(resistance),
+                    None => TwoTerminalComponent::Wire,
+                };
+                two_terminal.push(([node, last], component));
+            }
+        }
+
+        // Crystals are replaced by a wire into their equivalent circuit, which is built on hidden
+        // nodes. These go just before the last node, which has to stay last as the 0 V reference.
+        let num_crystals = two_terminal
+            .iter()
+            .filter(|(_, component)| matches!(component, TwoTerminalComponent::Crystal { .. }))
+            .count();
+        let mut num_nodes = all_positions.len();
+        let mut crystals = vec![];
+        if num_crystals > 0 {
+            let last = num_nodes - 1;
+            num_nodes += num_crystals * 3;
+            let relabel = |idx: &mut usize| {
+                if *idx == last {
+                    *idx = num_nodes - 1;
+                }
+            };
+
+            all_positions.values_mut().for_each(relabel);
+            two_terminal
+                .iter_mut()
+                .for_each(|(nodes, _)| nodes.iter_mut().for_each(relabel));
+            three_terminal
+                .iter_mut()
+                .for_each(|(nodes, _)| nodes.iter_mut().for_each(relabel));
+            grounds.iter_mut().for_each(|(node, _)| relabel(node));
+
+            let mut next_hidden = last;
+            for idx in 0..two_terminal.len() {
+                let ([begin, end], TwoTerminalComponent::Crystal { ls, cs, rs, cp }) =
+                    two_terminal[idx]
+                else {
+                    continue;
+                };
+                let [a, b, c] = [next_hidden, next_hidden + 1, next_hidden + 2];
+                next_hidden += 3;
+
+                two_terminal[idx] = ([begin, a], TwoTerminalComponent::Wire);
+                two_terminal.extend([
+                    (
+                        [a, b],
+                        TwoTerminalComponent::Inductor(ls, None, None, None, 0.0),
+                    ),
+                    ([b, c], TwoTerminalComponent::Capacitor(cs, None, None, 0.0)),
+                    ([c, end], TwoTerminalComponent::Resistor(rs)),
+                    (
+                        [a, end],
+                        TwoTerminalComponent::Capacitor(cp, None, None, 0.0),
+                    ),
+                ]);
+                crystals.push((idx, end));
+            }
+        }
+
         let primitive = PrimitiveDiagram {
-            num_nodes: all_positions.len(),
+            num_nodes,
             two_terminal,
             three_terminal,
+            grounds,
+            unconnected_reference,
+            disabled_three_terminal: self
+                .disabled
+                .iter()
+                .chain(&collapsed)
+                .filter_map(|&(idx, ty)| (ty == SelectionType::ThreeTerminal).then_some(idx))
+                .collect(),
         };
 
-        let mut ports: HashMap<String, Vec<usize>> = HashMap::default();
+        let mut ports: BTreeMap<String, Vec<usize>> = BTreeMap::default();
         for (pos, name) in self.ports.iter() {
             if let Some(pos) = all_positions.get(&pos) {
                 ports.entry(name.clone()).or_default().push(*pos);
@@ -156,64 +1152,263 @@ impl Diagram {
             primitive,
             all_positions,
             ports,
+            crystals,
         }
     }
 }
 
+/// Cells at the same voltage, and the component terminals on them, see `Diagram::nets()`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Net {
+    /// In ascending order
+    pub cells: Vec<CellPos>,
+    /// (component, terminal) for each terminal in the net, where the terminal indexes the
+    /// component's positions
+    pub terminals: Vec<((usize, SelectionType), usize)>,
+}
+
 pub struct RichPrimitiveDiagram {
     pub primitive: PrimitiveDiagram,
-    pub all_positions: HashMap<CellPos, usize>,
-    pub ports: HashMap<String, Vec<usize>>,
+    pub all_positions: BTreeMap<CellPos, usize>,
+    pub ports: BTreeMap<String, Vec<usize>>,
+    /// (component index, end node) of each crystal. In the primitive diagram the component is
+    /// the wire leading into the crystal's equivalent circuit.
+    pub crystals: Vec<(usize, usize)>,
+}
+
+impl RichPrimitiveDiagram {
+    /// Like `DiagramState::new`, but shows crystals with the voltage across the whole crystal
+    /// rather than across the wire standing in for it
+    pub fn state(&self, output: &SimOutputs) -> DiagramState {
+        let mut state = DiagramState::new(output, &self.primitive);
+        for &(idx, end) in &self.crystals {
+            state.two_terminal[idx][1].voltage = output.voltages[end];
+        }
+        state
+    }
 }
 
-pub fn draw_grid(ui: &mut egui::Ui, rect: Rect, radius: f32, color: Color32) {
-    let (min_x, min_y) = egui_to_cellpos(rect.min.floor());
-    let (max_x, max_y) = egui_to_cellpos(rect.max.ceil());
+/// Draws a dot on each cell in `rect`, styled by `vis.grid`. When zoomed out, only every
+/// second, fourth, ... cell gets one, keeping them `min_spacing` apart on screen and their count
+/// bounded.
+pub fn draw_grid(ui: &mut egui::Ui, rect: Rect, vis: &VisualizationOptions) {
+    /// Most dots drawn in one frame
+    const MAX_DOTS: i64 = 100_000;
+
+    let style = vis.grid;
+    let (min_x, min_y) = vis.egui_to_cellpos(rect.min.floor());
+    let (max_x, max_y) = vis.egui_to_cellpos(rect.max.ceil());
+    let cells = (max_x - min_x + 1) as i64 * (max_y - min_y + 1) as i64;
+
+    // Size of a cell on screen, since the scene may be zoomed
+    let zoom = ui
+        .ctx()
+        .layer_transform_to_global(ui.layer_id())
+        .map_or(1.0, |transform| transform.scaling);
+    let spacing = vis.cell_size * zoom;
+
+    let mut stride: i32 = 1;
+    while (spacing * (stride as f32) < style.min_spacing
+        || cells / (stride as i64).pow(2) > MAX_DOTS)
+        && stride < 1 << 20
+    {
+        stride *= 2;
+    }
+    if stride > 1 && style.hide_when_dense {
+        return;
+    }
 
     let painter = ui.painter();
+    let first = |min: i32| min.div_euclid(stride) * stride;
+    for y in (first(min_y)..=max_y).step_by(stride as usize) {
+        for x in (first(min_x)..=max_x).step_by(stride as usize) {
+            painter.circle_filled(
+                vis.cellpos_to_egui((x, y)),
+                style.dot_radius,
+                style.dot_color,
+            );
+        }
+    }
+}
 
-    // Draw visible circuit elements
-    let mut n = 0;
-    const MAX_N: i32 = 100_000;
-    'outer: for y in min_y..=max_y {
-        for x in min_x..=max_x {
-            n += 1;
-            if n > MAX_N {
-                break 'outer;
-            }
+/// Explains the wire colors and the current animation: a gradient of the color scheme labeled with
+/// the values at either end, and the current at which the dots move one step per second
+pub fn draw_legend(ui: &mut Ui, vis: &VisualizationOptions) {
+    let (quantity, scale, unit) = match vis.mode {
+        VisualizationMode::Voltage => ("Voltage", vis.voltage_scale, 'V'),
+        VisualizationMode::Current => ("Current", vis.current_scale, 'A'),
+        VisualizationMode::Power => ("Power", vis.voltage_scale * vis.current_scale, 'W'),
+    };
+    ui.small(quantity);
 
-            painter.circle_filled(cellpos_to_egui((x, y)), radius, color);
-        }
+    let text_height = 14.0;
+    let (rect, _) = ui.allocate_exact_size(Vec2::new(150.0, 10.0 + text_height), Sense::hover());
+    let bar = rect.with_max_y(rect.top() + 10.0);
+    let painter = ui.painter();
+
+    const STEPS: usize = 30;
+    let width = bar.width() / STEPS as f32;
+    for i in 0..STEPS {
+        let value = (i as f64 + 0.5) / STEPS as f64 * 2.0 - 1.0;
+        let left = bar.left() + i as f32 * width;
+        let cell = Rect::from_x_y_ranges(left..=left + width, bar.y_range());
+        painter.rect_filled(cell, 0.0, voltage_color(value, vis.color_scheme));
     }
-    if n > MAX_N {
-        eprintln!("WARNING: zoomed out too far!");
+
+    let font = egui::FontId::proportional(11.0);
+    let color = ui.visuals().text_color();
+    for (anchor, pos, value) in [
+        (egui::Align2::LEFT_TOP, bar.left_bottom(), -scale),
+        (egui::Align2::CENTER_TOP, bar.center_bottom(), 0.0),
+        (egui::Align2::RIGHT_TOP, bar.right_bottom(), scale),
+    ] {
+        let text = format_value(value, unit, vis.value_format);
+        painter.text(pos, anchor, text, font.clone(), color);
     }
+
+    ui.small(format!(
+        "Dots move a step per second at {}",
+        format_value(vis.current_scale, 'A', vis.value_format)
+    ));
 }
 
 impl DiagramEditor {
     pub fn new() -> Self {
-        Self { selected: None }
+        Self {
+            selected: None,
+            multi_selected: vec![],
+            mode: EditorMode::Select,
+            net_cache: None,
+            scrolling_value: false,
+            value_scroll: 0.0,
+        }
+    }
+
+    /// Whether scrolling steps the value of the selected component under the pointer, rather
+    /// than zooming. Decided from the last frame, like hovering in egui.
+    pub fn scrolls_value(&self) -> bool {
+        self.scrolling_value
+    }
+
+    /// Cells joined to `pos` by wires, including `pos`. The nets are only recomputed when the
+    /// wires have changed since the last call.
+    fn net_cells(&mut self, diagram: &Diagram, pos: CellPos) -> HashSet<CellPos> {
+        let wires: Vec<[CellPos; 2]> = diagram
+            .two_terminal
+            .iter()
+            .filter(|(_, component)| matches!(component, TwoTerminalComponent::Wire))
+            .map(|(positions, _)| *positions)
+            .collect();
+
+        let nets = match &mut self.net_cache {
+            Some(cache) if cache.wires == wires => &cache.nets,
+            cache => {
+                let nets = diagram.wire_nets();
+                &cache.insert(NetCache { wires, nets }).nets
+            }
+        };
+
+        let root = nets.get(&pos).copied().unwrap_or(pos);
+        nets.iter()
+            .filter(|(_, net)| **net == root)
+            .map(|(cell, _)| *cell)
+            .chain([pos])
+            .collect()
+    }
+
+    /// All selected components which still exist, starting with the primary selection
+    pub fn selection(&self, diagram: &Diagram) -> Vec<(usize, SelectionType)> {
+        self.selected
+            .into_iter()
+            .chain(self.multi_selected.iter().copied())
+            .filter_map(|id| diagram.find(id))
+            .collect()
+    }
+
+    /// The primary selection, if it still exists
+    pub fn primary(&self, diagram: &Diagram) -> Option<(usize, SelectionType)> {
+        self.selected.and_then(|id| diagram.find(id))
+    }
+
+    /// Selects only the given component
+    pub fn select(&mut self, diagram: &Diagram, sel: (usize, SelectionType)) {
+        self.reset_selection();
+        self.selected = diagram.id(sel);
+    }
+
+    /// Adds or removes a component from the selection, as with shift-click
+    pub fn toggle_selected(&mut self, diagram: &Diagram, sel: (usize, SelectionType)) {
+        let Some(sel) = diagram.id(sel) else {
+            return;
+        };
+        if self.selected == Some(sel) {
+            self.selected = self.multi_selected.pop();
+        } else if let Some(i) = self.multi_selected.iter().position(|s| *s == sel) {
+            self.multi_selected.remove(i);
+        } else if self.selected.is_none() {
+            self.selected = Some(sel);
+        } else {
+            self.multi_selected.push(sel);
+        }
     }
 
     pub fn delete(&mut self, diagram: &mut Diagram) {
-        if let Some((idx, ty)) = self.selected.take() {
-            match ty {
-                SelectionType::Port => {
-                    diagram.ports.remove(idx);
-                }
-                SelectionType::TwoTerminal => {
-                    diagram.two_terminal.remove(idx);
-                }
-                SelectionType::ThreeTerminal => {
-                    diagram.three_terminal.remove(idx);
-                }
+        let mut selection = self.selection(diagram);
+        self.reset_selection();
+
+        // Remove from the back so that the remaining indices stay valid
+        selection.sort_by_key(|&(idx, _)| std::cmp::Reverse(idx));
+
+        for sel in selection {
+            diagram.remove(sel);
+        }
+    }
+
+    /// Moves every selected component by `(dx, dy)` cells, except locked ones. Returns true if
+    /// anything moved.
+    pub fn nudge(&self, diagram: &mut Diagram, (dx, dy): CellPos) -> bool {
+        let mut selection = self.selection(diagram);
+        selection.retain(|&sel| !diagram.is_locked(sel));
+
+        for &sel in &selection {
+            diagram.translate(sel, (dx, dy));
+        }
+
+        !selection.is_empty()
+    }
+
+    /// Steps the value of every selected two-terminal component up or down by `steps`, see
+    /// `step_value()`. Components bound to a variable keep theirs. Returns true if anything
+    /// changed.
+    pub fn step_values(&self, diagram: &mut Diagram, steps: i32) -> bool {
+        let mut changed = false;
+        for sel in self.selection(diagram) {
+            if let (idx, SelectionType::TwoTerminal) = sel {
+                changed |= step_unbound_value(diagram, idx, steps);
             }
         }
+        changed
+    }
+
+    /// Adds a ground, which becomes the reference if there isn't one yet
+    pub fn new_ground(&mut self, diagram: &mut Diagram, pos: CellPos) {
+        let has_reference = diagram
+            .grounds
+            .iter()
+            .any(|(_, kind)| *kind == GroundKind::Reference);
+        let kind = if has_reference {
+            GroundKind::Tied(None)
+        } else {
+            GroundKind::Reference
+        };
+        diagram.grounds.push((pos, kind));
     }
 
     pub fn new_port(&mut self, diagram: &mut Diagram, pos: CellPos, component: String) {
-        self.selected = Some((diagram.ports.len(), SelectionType::Port));
+        let sel = (diagram.ports.len(), SelectionType::Port);
         diagram.ports.push((pos, component));
+        diagram.assign_ids();
+        self.select(diagram, sel);
     }
 
     pub fn new_threeterminal(
@@ -223,10 +1418,11 @@ impl DiagramEditor {
         component: ThreeTerminalComponent,
     ) {
         let (x, y) = pos;
-        self.selected = Some((diagram.two_terminal.len(), SelectionType::ThreeTerminal));
-        diagram
-            .three_terminal
-            .push(([pos, (x + 1, y + 1), (x + 1, y)], component));
+        let sel = (diagram.three_terminal.len(), SelectionType::ThreeTerminal);
+        let cells = footprint(component).map(|(dx, dy)| (x + dx, y + dy));
+        diagram.three_terminal.push((cells, component));
+        diagram.assign_ids();
+        self.select(diagram, sel);
     }
 
     pub fn new_twoterminal(
@@ -236,12 +1432,15 @@ impl DiagramEditor {
         component: TwoTerminalComponent,
     ) {
         let (x, y) = pos;
-        self.selected = Some((diagram.two_terminal.len(), SelectionType::TwoTerminal));
+        let sel = (diagram.two_terminal.len(), SelectionType::TwoTerminal);
         diagram.two_terminal.push(([pos, (x + 1, y)], component));
+        diagram.assign_ids();
+        self.select(diagram, sel);
     }
 
     pub fn reset_selection(&mut self) {
         self.selected = None;
+        self.multi_selected.clear();
     }
 
     pub fn edit(
@@ -252,12 +1451,16 @@ impl DiagramEditor {
         debug_draw: bool,
         vis: &VisualizationOptions,
     ) -> bool {
+        diagram.assign_ids();
+        let selection = self.selection(diagram);
+
         let mut port_responses = vec![];
         let mut two_body_responses = vec![];
         let mut three_body_responses = vec![];
 
         let mut destructive_change = false;
         let mut new_selection = None;
+        let mut moved = vec![];
 
         for (idx, (pos, comp)) in diagram.ports.iter_mut().enumerate() {
             let ret = interact_with_port_body(
@@ -265,9 +1468,14 @@ impl DiagramEditor {
                 comp,
                 pos,
                 Id::new("body").with(idx),
-                self.selected == Some((idx, SelectionType::Port)),
-                &mut destructive_change,
+                selection.contains(&(idx, SelectionType::Port)),
+                diagram.locked.contains(&(idx, SelectionType::Port)),
+                vis,
             );
+            // Moved to where the drag ended
+            if ret.drag_stopped() {
+                destructive_change = true;
+            }
             if ret.clicked() {
                 new_selection = Some((idx, SelectionType::Port));
             }
@@ -279,7 +1487,8 @@ impl DiagramEditor {
                 ui,
                 *pos,
                 Id::new("body").with(idx),
-                self.selected == Some((idx, SelectionType::TwoTerminal)),
+                selection.contains(&(idx, SelectionType::TwoTerminal)),
+                vis,
             );
             if ret.clicked() {
                 new_selection = Some((idx, SelectionType::TwoTerminal));
@@ -292,7 +1501,7 @@ impl DiagramEditor {
                 ui,
                 *pos,
                 Id::new("threebody").with(idx),
-                self.selected == Some((idx, SelectionType::ThreeTerminal)),
+                selection.contains(&(idx, SelectionType::ThreeTerminal)),
                 vis,
             );
             if ret.clicked() {
@@ -301,23 +1510,51 @@ impl DiagramEditor {
             three_body_responses.push(ret);
         }
 
+        // Scrolling over a selected component steps its value. The app only stops zooming
+        // once this has been seen for a frame, so the first frame goes to neither.
+        let value_hovered = (0..two_body_responses.len()).find(|&idx| {
+            two_body_responses[idx].hovered()
+                && selection.contains(&(idx, SelectionType::TwoTerminal))
+                && diagram.two_terminal[idx].1.value().is_some()
+                && !is_bound(diagram, idx)
+        });
+        match value_hovered {
+            Some(idx) if self.scrolling_value => {
+                let scroll = ui.input(|r| r.raw_scroll_delta);
+                self.value_scroll += scroll.x + scroll.y;
+                let steps = (self.value_scroll / VALUE_SCROLL_STEP).trunc();
+                self.value_scroll -= steps * VALUE_SCROLL_STEP;
+                if steps != 0.0 {
+                    step_unbound_value(diagram, idx, steps as i32);
+                }
+            }
+            _ => self.value_scroll = 0.0,
+        }
+        self.scrolling_value = value_hovered.is_some();
+
+        let hovered_wire = two_body_responses
+            .iter()
+            .position(|resp| resp.hovered())
+            .filter(|&idx| matches!(diagram.two_terminal[idx].1, TwoTerminalComponent::Wire));
+
+        let terminal_counts = diagram.terminal_counts();
         for (idx, ((resp, (pos, comp)), wires)) in two_body_responses
             .drain(..)
             .zip(diagram.two_terminal.iter_mut())
             .zip(state.two_terminal.iter())
             .enumerate()
         {
-            if interact_with_twoterminal(
-                ui,
-                pos,
-                comp,
-                *wires,
-                resp,
-                self.selected == Some((idx, SelectionType::TwoTerminal)),
+            let flip_label = label_flipped(&terminal_counts, *pos);
+            let interaction = Interaction {
+                body_resp: resp,
+                selected: selection.contains(&(idx, SelectionType::TwoTerminal)),
+                locked: diagram.locked.contains(&(idx, SelectionType::TwoTerminal)),
                 debug_draw,
                 vis,
-            ) {
+            };
+            if interact_with_twoterminal(ui, pos, comp, *wires, flip_label, interaction) {
                 destructive_change = true;
+                moved.push((idx, SelectionType::TwoTerminal));
             }
         }
 
@@ -327,26 +1564,171 @@ impl DiagramEditor {
             .zip(state.three_terminal.iter())
             .enumerate()
         {
-            if interact_with_threeterminal(
-                ui,
-                pos,
-                *comp,
-                *wires,
-                resp,
-                self.selected == Some((idx, SelectionType::ThreeTerminal)),
+            let interaction = Interaction {
+                body_resp: resp,
+                selected: selection.contains(&(idx, SelectionType::ThreeTerminal)),
+                locked: diagram
+                    .locked
+                    .contains(&(idx, SelectionType::ThreeTerminal)),
+                debug_draw,
                 vis,
-            ) {
+            };
+            if interact_with_threeterminal(ui, pos, *comp, *wires, interaction) {
                 destructive_change = true;
+                moved.push((idx, SelectionType::ThreeTerminal));
+            }
+        }
+
+        // Trace the net under the pointer, to tell apart wires that only look connected
+        if let Some(idx) = hovered_wire {
+            let cells = self.net_cells(diagram, diagram.two_terminal[idx].0[0]);
+            for (positions, component) in &diagram.two_terminal {
+                let is_wire = matches!(component, TwoTerminalComponent::Wire);
+                if is_wire && cells.contains(&positions[0]) {
+                    ui.painter().line_segment(
+                        positions.map(|pos| vis.cellpos_to_egui(pos)),
+                        Stroke::new(5.0, NET_HIGHLIGHT),
+                    );
+                }
+            }
+            for pos in terminal_counts.keys().filter(|pos| cells.contains(pos)) {
+                ui.painter().circle_stroke(
+                    vis.cellpos_to_egui(*pos),
+                    8.0,
+                    Stroke::new(2.0, NET_HIGHLIGHT),
+                );
+            }
+        }
+
+        if vis.auto_connect {
+            for component in moved {
+                diagram.connect_adjacent_terminals(component);
             }
         }
 
         if let Some(sel) = new_selection {
-            self.selected = Some(sel);
+            if ui.input(|r| r.modifiers.shift) {
+                self.toggle_selected(diagram, sel);
+            } else {
+                self.select(diagram, sel);
+            }
+        }
+
+        if let EditorMode::DrawWire { start } = &mut self.mode {
+            destructive_change |= draw_wires(ui, diagram, start, vis);
+        } else {
+            show_node_voltages(ui, diagram, state, vis);
         }
 
         for junction in diagram.junctions() {
             ui.painter()
-                .circle_filled(cellpos_to_egui(junction), 5.0, Color32::LIGHT_GRAY);
+                .circle_filled(vis.cellpos_to_egui(junction), 5.0, Color32::LIGHT_GRAY);
+        }
+
+        for &(pos, kind) in &diagram.grounds {
+            draw_ground(ui.painter(), vis.cellpos_to_egui(pos), kind, vis);
+        }
+
+        // Fade out disabled components, and outline them with dashes
+        for &(idx, ty) in &diagram.disabled {
+            let positions = diagram.cells((idx, ty));
+            if ty == SelectionType::Port || positions.is_empty() {
+                continue;
+            }
+
+            let points: Vec<Pos2> = positions.iter().map(|&p| vis.cellpos_to_egui(p)).collect();
+            let rect = Rect::from_points(&points).expand(vis.cell_size / 4.0);
+            ui.painter()
+                .rect_filled(rect, 0.0, Color32::from_black_alpha(160));
+            let outline = [
+                rect.left_top(),
+                rect.right_top(),
+                rect.right_bottom(),
+                rect.left_bottom(),
+                rect.left_top(),
+            ];
+            ui.painter().extend(egui::Shape::dashed_line(
+                &outline,
+                Stroke::new(1.0, Color32::GRAY),
+                6.0,
+                4.0,
+            ));
+        }
+
+        for &sel in &diagram.locked {
+            let points: Vec<Pos2> = diagram
+                .cells(sel)
+                .into_iter()
+                .map(|p| vis.cellpos_to_egui(p))
+                .collect();
+            if points.is_empty() {
+                continue;
+            }
+            let rect = Rect::from_points(&points).expand(vis.cell_size / 4.0);
+            ui.painter().text(
+                rect.right_top(),
+                egui::Align2::CENTER_CENTER,
+                "🔒",
+                egui::FontId::proportional(12.0),
+                Color32::GRAY,
+            );
+        }
+
+        for idx in diagram.shorted_batteries() {
+            let [begin, end] = diagram.two_terminal[idx].0.map(|p| vis.cellpos_to_egui(p));
+            let rect = Rect::from_two_pos(begin, end).expand(vis.cell_size / 4.0);
+            ui.painter().rect_stroke(
+                rect,
+                4.0,
+                Stroke::new(2., Color32::RED),
+                egui::StrokeKind::Outside,
+            );
+            ui.painter().text(
+                rect.center_bottom(),
+                egui::Align2::CENTER_TOP,
+                "Shorted",
+                Default::default(),
+                Color32::RED,
+            );
+        }
+
+        for sel in diagram.collapsed_components() {
+            let points: Vec<Pos2> = diagram
+                .cells(sel)
+                .into_iter()
+                .map(|p| vis.cellpos_to_egui(p))
+                .collect();
+            let rect = Rect::from_points(&points).expand(vis.cell_size / 4.0);
+            ui.painter().rect_stroke(
+                rect,
+                4.0,
+                Stroke::new(2., Color32::RED),
+                egui::StrokeKind::Outside,
+            );
+            ui.painter().text(
+                rect.center_bottom(),
+                egui::Align2::CENTER_TOP,
+                "Collapsed",
+                Default::default(),
+                Color32::RED,
+            );
+        }
+
+        if let Some(max) = vis.max_fan_in {
+            for (pos, count) in diagram.terminal_counts() {
+                if count > max {
+                    let pos = vis.cellpos_to_egui(pos);
+                    ui.painter()
+                        .circle_stroke(pos, 15.0, Stroke::new(2., Color32::YELLOW));
+                    ui.painter().text(
+                        pos + Vec2::splat(15.0),
+                        egui::Align2::LEFT_TOP,
+                        format!("{count} terminals"),
+                        Default::default(),
+                        Color32::YELLOW,
+                    );
+                }
+            }
         }
 
         destructive_change
@@ -358,8 +1740,14 @@ impl DiagramEditor {
         ui: &mut Ui,
         diagram: &mut Diagram,
         state: &DiagramState,
+        measurements: &MeasurementWindow,
+        format: ValueFormat,
     ) -> bool {
-        if let Some((idx, ty)) = self.selected {
+        if self.combine_resistors(ui, diagram, format) {
+            return true;
+        }
+
+        if let Some((idx, ty)) = self.primary(diagram) {
             match ty {
                 SelectionType::Port => {
                     if let Some((_, component)) = diagram.ports.get_mut(idx) {
@@ -367,24 +1755,113 @@ impl DiagramEditor {
                     }
                 }
                 SelectionType::ThreeTerminal => {
-                    if let Some((_, component)) = diagram.three_terminal.get_mut(idx) {
+                    if let Some((positions, component)) = diagram.three_terminal.get_mut(idx) {
                         edit_threeterminal_component(ui, component, state.three_terminal[idx]);
+
+                        let terminals: Vec<String> = component
+                            .terminal_labels()
+                            .iter()
+                            .zip(positions.iter())
+                            .map(|(label, (x, y))| format!("{label} ({x}, {y})"))
+                            .collect();
+                        ui.weak(terminals.join(", "));
+
+                        if ui
+                            .button("Rotate ⟳")
+                            .on_hover_text(
+                                "Turns the component a quarter turn about its middle terminal",
+                            )
+                            .clicked()
+                        {
+                            let (cx, cy) = positions[1];
+                            for (x, y) in positions.iter_mut() {
+                                (*x, *y) = (cx - (*y - cy), cy + (*x - cx));
+                            }
+                            return true;
+                        }
+
+                        if mirror_buttons(ui, positions) {
+                            return true;
+                        }
                     }
                 }
                 SelectionType::TwoTerminal => {
                     if let Some((terminals, component)) = diagram.two_terminal.get_mut(idx) {
-                        edit_twoterminal_component(ui, component, state.two_terminal[idx]);
+                        edit_twoterminal_component(
+                            ui,
+                            component,
+                            state.two_terminal[idx],
+                            measurements.averages(idx),
+                            format,
+                        );
 
                         if ui.button("Flip").clicked() {
                             terminals.swap(0, 1);
                             return true;
                         }
+
+                        if mirror_buttons(ui, terminals) {
+                            return true;
+                        }
                     } else {
                         eprintln!("Warning: Couldn't find {idx} in diagram");
                     }
                 }
             }
 
+            let binding = diagram
+                .id((idx, ty))
+                .filter(|_| ty == SelectionType::TwoTerminal)
+                .and_then(|id| Some((id, diagram.bindings.get(&id)?)));
+            if let Some((id, name)) = binding {
+                let mut unbind = false;
+                ui.horizontal(|ui| {
+                    ui.label(format!("Value set by variable {name}"))
+                        .on_hover_text("Change it in the Variables window");
+                    unbind = ui.button("Unbind").clicked();
+                });
+                if unbind {
+                    diagram.bindings.remove(&id);
+                }
+            }
+
+            let is_controlled = matches!(
+                diagram.two_terminal.get(idx),
+                Some((_, TwoTerminalComponent::CurrentControlledSource(..)))
+            );
+            if ty == SelectionType::TwoTerminal && is_controlled && edit_sensed(ui, diagram, idx) {
+                return true;
+            }
+
+            let has_value = diagram
+                .two_terminal
+                .get(idx)
+                .is_some_and(|(_, component)| component.value().is_some());
+            if ty == SelectionType::TwoTerminal && has_value {
+                edit_tolerance(ui, diagram, idx);
+            }
+
+            if ty != SelectionType::Port {
+                let mut enabled = diagram.is_enabled((idx, ty));
+                if ui
+                    .checkbox(&mut enabled, "Simulate")
+                    .on_hover_text("Leave unchecked to take the component out of the circuit")
+                    .changed()
+                {
+                    diagram.set_enabled((idx, ty), enabled);
+                    return true;
+                }
+            }
+
+            let mut locked = diagram.is_locked((idx, ty));
+            if ui
+                .checkbox(&mut locked, "🔒 Locked")
+                .on_hover_text("Stops the component from being dragged or nudged")
+                .changed()
+            {
+                diagram.set_locked((idx, ty), locked);
+            }
+
             if ui.button("Delete").clicked() {
                 self.delete(diagram);
                 return true;
@@ -397,6 +1874,191 @@ impl DiagramEditor {
     }
 }
 
+/// Cells of a newly placed three-terminal component relative to where it's placed, in terminal
+/// order. Transistors point their base left, with the collector above and the emitter below.
+fn footprint(component: ThreeTerminalComponent) -> [CellPos; 3] {
+    match component {
+        ThreeTerminalComponent::PTransistor(..) | ThreeTerminalComponent::NTransistor(..) => {
+            [(1, 1), (0, 0), (1, -1)]
+        }
+        ThreeTerminalComponent::Potentiometer(..) => [(0, 0), (1, 1), (2, 0)],
+    }
+}
+
+/// Buttons reflecting the cells of a component across the vertical or horizontal line through the
+/// middle of their bounding box. Terminals keep their roles, so the symbol is drawn mirrored.
+/// Returns true if the component was mirrored.
+fn mirror_buttons<const N: usize>(ui: &mut Ui, positions: &mut [CellPos; N]) -> bool {
+    let mut mirrored = false;
+    ui.horizontal(|ui| {
+        if ui.button("Mirror ↔").clicked() {
+            *positions = mirror(*positions, Axis::X);
+            mirrored = true;
+        }
+        if ui.button("Mirror ↕").clicked() {
+            *positions = mirror(*positions, Axis::Y);
+            mirrored = true;
+        }
+    });
+    mirrored
+}
+
+/// Coordinate flipped by `mirror()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Axis {
+    X,
+    Y,
+}
+
+/// Flips the terminals along one axis, within the box they already span
+fn mirror<const N: usize>(mut positions: [CellPos; N], axis: Axis) -> [CellPos; N] {
+    let coord = |(x, y): CellPos| match axis {
+        Axis::X => x,
+        Axis::Y => y,
+    };
+    let (min, max) = bounds(positions.iter().map(|&pos| coord(pos)));
+    for (x, y) in &mut positions {
+        match axis {
+            Axis::X => *x = min + max - *x,
+            Axis::Y => *y = min + max - *y,
+        }
+    }
+    positions
+}
+
+fn bounds(values: impl Iterator<Item = i32> + Clone) -> (i32, i32) {
+    let min = values.clone().min().unwrap_or_default();
+    let max = values.max().unwrap_or_default();
+    (min, max)
+}
+
+impl DiagramEditor {
+    /// Offers to replace exactly two selected resistors with their series or parallel equivalent.
+    /// Returns true if the diagram was changed.
+    fn combine_resistors(
+        &mut self,
+        ui: &mut Ui,
+        diagram: &mut Diagram,
+        format: ValueFormat,
+    ) -> bool {
+        let selection = self.selection(diagram);
+        let [(a, SelectionType::TwoTerminal), (b, SelectionType::TwoTerminal)] = selection[..]
+        else {
+            return false;
+        };
+        let (
+            Some(&(pos_a, TwoTerminalComponent::Resistor(r_a))),
+            Some(&(pos_b, TwoTerminalComponent::Resistor(r_b))),
+        ) = (diagram.two_terminal.get(a), diagram.two_terminal.get(b))
+        else {
+            return false;
+        };
+
+        let wire_nets = diagram.wire_nets();
+        let net = |pos: CellPos| wire_nets.get(&pos).copied().unwrap_or(pos);
+
+        // Number of non-wire terminals connected to the given net
+        let n_terminals = |target: CellPos| {
+            let two = diagram
+                .two_terminal
+                .iter()
+                .filter(|(_, comp)| !matches!(comp, TwoTerminalComponent::Wire))
+                .flat_map(|(positions, _)| positions.iter());
+            let three = diagram
+                .three_terminal
+                .iter()
+                .flat_map(|(positions, _)| positions.iter());
+            let ports = diagram.ports.iter().map(|(pos, _)| pos);
+            two.chain(three)
+                .chain(ports)
+                .filter(|&&pos| net(pos) == target)
+                .count()
+        };
+
+        // In series if exactly one net is shared, and nothing else connects to it. The combined
+        // resistor spans the other two ends, so they have to be distinct cells in a row or
+        // column, or it would come out diagonal or with no length.
+        let shared: Vec<(usize, usize)> = (0..2)
+            .flat_map(|i| (0..2).map(move |j| (i, j)))
+            .filter(|&(i, j)| net(pos_a[i]) == net(pos_b[j]))
+            .collect();
+        let in_line = |(x0, y0): CellPos, (x1, y1): CellPos| (x0 == x1) != (y0 == y1);
+        let series = match shared[..] {
+            [(i, j)] if n_terminals(net(pos_a[i])) == 2 => {
+                let ends = [pos_a[1 - i], pos_b[1 - j]];
+                in_line(ends[0], ends[1]).then_some((ends, net(pos_a[i])))
+            }
+            _ => None,
+        };
+
+        // In parallel if both ends share nets
+        let parallel = net(pos_a[0]) != net(pos_a[1])
+            && ((net(pos_a[0]) == net(pos_b[0]) && net(pos_a[1]) == net(pos_b[1]))
+                || (net(pos_a[0]) == net(pos_b[1]) && net(pos_a[1]) == net(pos_b[0])));
+
+        let series_value = r_a + r_b;
+        let parallel_value = r_a * r_b / (r_a + r_b);
+
+        let mut combine_series = false;
+        let mut combine_parallel = false;
+
+        ui.strong("Combine resistors");
+        ui.horizontal(|ui| {
+            combine_series = ui
+                .add_enabled(series.is_some(), egui::Button::new("Series"))
+                .on_disabled_hover_text(
+                    "Resistors must share one node, with nothing else on it, and their other ends \
+                    must line up",
+                )
+                .clicked();
+            ui.label(format_value(series_value, 'Ω', format));
+        });
+        ui.horizontal(|ui| {
+            combine_parallel = ui
+                .add_enabled(parallel, egui::Button::new("Parallel"))
+                .on_disabled_hover_text("Resistors must connect the same two nodes")
+                .clicked();
+            ui.label(format_value(parallel_value, 'Ω', format));
+        });
+        ui.separator();
+
+        let kept = diagram.id((a, SelectionType::TwoTerminal));
+        let mut removals = vec![b];
+        if combine_series {
+            let Some((ends, middle)) = series else {
+                return false;
+            };
+            diagram.two_terminal[a] = (ends, TwoTerminalComponent::Resistor(series_value));
+
+            // Wires on the middle node would be left floating
+            removals.extend(
+                diagram
+                    .two_terminal
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (positions, comp))| {
+                        matches!(comp, TwoTerminalComponent::Wire) && net(positions[0]) == middle
+                    })
+                    .map(|(idx, _)| idx),
+            );
+        } else if combine_parallel {
+            diagram.two_terminal[a].1 = TwoTerminalComponent::Resistor(parallel_value);
+        } else {
+            return false;
+        }
+
+        removals.sort_by_key(|&idx| std::cmp::Reverse(idx));
+        for &idx in &removals {
+            diagram.remove((idx, SelectionType::TwoTerminal));
+        }
+
+        self.reset_selection();
+        self.selected = kept;
+
+        true
+    }
+}
+
 // TODO: The following code sucks.
 fn interact_with_port_body(
     ui: &mut Ui,
@@ -404,16 +2066,23 @@ fn interact_with_port_body(
     pos: &mut CellPos,
     id: Id,
     selected: bool,
-    destructive_change: &mut bool,
+    locked: bool,
+    vis: &VisualizationOptions,
 ) -> egui::Response {
-    let begin = cellpos_to_egui(*pos);
+    let begin = vis.cellpos_to_egui(*pos);
 
     let handle_hitbox_size = 50.0;
     let begin_hitbox = Rect::from_center_size(begin, Vec2::splat(handle_hitbox_size));
 
     let mut begin_offset = Vec2::ZERO;
 
-    let begin_resp = ui.interact(begin_hitbox, id.with("begin"), Sense::click_and_drag());
+    // Still clickable while locked, so it can be selected and unlocked again
+    let sense = if locked {
+        Sense::click()
+    } else {
+        Sense::click_and_drag()
+    };
+    let begin_resp = ui.interact(begin_hitbox, id.with("begin"), sense);
 
     let interact_pos = begin_resp.interact_pointer_pos();
 
@@ -427,15 +2096,14 @@ fn interact_with_port_body(
 
     let interact_delta = interact_begin_pos
         .zip(interact_pos)
-        .map(|(start, stop)| stop - start);
+        .map(|(start, stop)| vis.snap(stop - start));
 
     if begin_resp.dragged() || begin_resp.drag_stopped() {
         begin_offset = interact_delta.unwrap_or(Vec2::ZERO);
     }
 
     if begin_resp.drag_stopped() {
-        *pos = egui_to_cellpos(begin + begin_offset);
-        *destructive_change = true;
+        *pos = vis.egui_to_cellpos(begin + begin_offset);
     }
 
     if selected {
@@ -457,25 +2125,214 @@ fn interact_with_port_body(
     begin_resp
 }
 
+/// Shows the voltage of the node under the pointer as a tooltip
+fn show_node_voltages(
+    ui: &mut Ui,
+    diagram: &Diagram,
+    state: &DiagramState,
+    vis: &VisualizationOptions,
+) {
+    let mut node_voltages: HashMap<CellPos, f64> = HashMap::new();
+    for ((positions, _), wires) in diagram.two_terminal.iter().zip(&state.two_terminal) {
+        for (pos, wire) in positions.iter().zip(wires) {
+            node_voltages.insert(*pos, wire.voltage);
+        }
+    }
+    for ((positions, _), wires) in diagram.three_terminal.iter().zip(&state.three_terminal) {
+        for (pos, wire) in positions.iter().zip(wires) {
+            node_voltages.insert(*pos, wire.voltage);
+        }
+    }
+
+    for (pos, voltage) in node_voltages {
+        let rect = Rect::from_center_size(vis.cellpos_to_egui(pos), Vec2::splat(20.0));
+        ui.interact(rect, Id::new("node_voltage").with(pos), Sense::hover())
+            .on_hover_text(format_value(voltage, 'V', vis.value_format));
+    }
+}
+
+/// Rubber-band wire drawing. Dragging between two cells (or clicking each of them) adds a wire,
+/// and the next one continues from where the last one ended. Clicking the end cell again or
+/// pressing escape finishes the chain. Returns true if a wire was added.
+fn draw_wires(
+    ui: &mut Ui,
+    diagram: &mut Diagram,
+    start: &mut Option<CellPos>,
+    vis: &VisualizationOptions,
+) -> bool {
+    // Created after the components, so it takes priority over them
+    let resp = ui.interact(
+        ui.clip_rect(),
+        Id::new("draw_wire"),
+        Sense::click_and_drag(),
+    );
+
+    if ui.input(|r| r.key_pressed(Key::Escape)) {
+        *start = None;
+    }
+
+    let Some(pointer) = resp.interact_pointer_pos().or(resp.hover_pos()) else {
+        return false;
+    };
+    let cell = vis.egui_to_cellpos(pointer);
+
+    if resp.drag_started() && start.is_none() {
+        let origin = ui.input(|r| r.pointer.press_origin()).unwrap_or(pointer);
+        *start = Some(vis.egui_to_cellpos(origin));
+    }
+
+    let mut added = false;
+    if resp.clicked() || resp.drag_stopped() {
+        match *start {
+            Some(begin) if begin != cell => {
+                diagram
+                    .two_terminal
+                    .push(([begin, cell], TwoTerminalComponent::Wire));
+                *start = Some(cell);
+                added = true;
+            }
+            Some(_) => *start = None,
+            None => *start = Some(cell),
+        }
+    }
+
+    let stroke = Stroke::new(3.0, Color32::LIGHT_GRAY);
+    if let Some(begin) = *start {
+        ui.painter().line_segment(
+            [vis.cellpos_to_egui(begin), vis.cellpos_to_egui(cell)],
+            stroke,
+        );
+    }
+    ui.painter()
+        .circle_stroke(vis.cellpos_to_egui(cell), 5.0, stroke);
+
+    added
+}
+
+/// Smallest value the editor accepts for the value returned by `value()`, for components which
+/// only make sense with a positive one
+fn min_value(component: TwoTerminalComponent) -> Option<f64> {
+    match component {
+        TwoTerminalComponent::Resistor(_) => Some(MIN_RESISTANCE),
+        TwoTerminalComponent::Capacitor(..) => Some(MIN_CAPACITANCE),
+        TwoTerminalComponent::Inductor(..) | TwoTerminalComponent::Crystal { .. } => {
+            Some(MIN_INDUCTANCE)
+        }
+        _ => None,
+    }
+}
+
+/// Preferred values for resistors, per decade
+const E12: [f64; 12] = [1.0, 1.2, 1.5, 1.8, 2.2, 2.7, 3.3, 3.9, 4.7, 5.6, 6.8, 8.2];
+
+/// Scrolling over a selected component which steps its value once, in points. One line of a
+/// mouse wheel on native.
+const VALUE_SCROLL_STEP: f32 = 40.0;
+
+/// A component's value moved `steps` steps up, or down for negative steps. Resistors move
+/// through the E12 series, and other components double or halve. Resistances which aren't
+/// positive have no step, and are left alone.
+fn step_value(component: TwoTerminalComponent, steps: i32) -> TwoTerminalComponent {
+    let Some(mut value) = component.value() else {
+        return component;
+    };
+
+    if let TwoTerminalComponent::Resistor(_) = component {
+        if !(value.is_finite() && value > 0.0) {
+            return component;
+        }
+        for _ in 0..steps.unsigned_abs() {
+            value = e12_neighbor(value, steps > 0);
+        }
+    } else {
+        value *= 2f64.powi(steps);
+    }
+
+    component.with_value(value)
+}
+
+/// The next E12 value above `value`, or below it. Values between two of the series snap to
+/// the nearest one in that direction.
+fn e12_neighbor(value: f64, up: bool) -> f64 {
+    let decade = 10f64.powf(value.log10().floor());
+    // Values within rounding error of one of the series count as that one
+    let margin = value * 1e-6;
+    let candidates = [decade / 10.0, decade, decade * 10.0]
+        .into_iter()
+        .flat_map(|decade| E12.map(|mantissa| mantissa * decade));
+    if up {
+        candidates
+            .filter(|&v| v > value + margin)
+            .fold(f64::INFINITY, f64::min)
+    } else {
+        candidates
+            .filter(|&v| v < value - margin)
+            .fold(f64::NEG_INFINITY, f64::max)
+    }
+}
+
+/// Applies `step_value()` to a two-terminal component, unless it's bound to a variable, which
+/// would overwrite it. Returns true if the value changed.
+fn step_unbound_value(diagram: &mut Diagram, idx: usize, steps: i32) -> bool {
+    if is_bound(diagram, idx) {
+        return false;
+    }
+    let component = &mut diagram.two_terminal[idx].1;
+    let stepped = step_value(*component, steps);
+    let changed = stepped != *component;
+    *component = stepped;
+    changed
+}
+
+/// Whether a two-terminal component takes its value from a variable
+fn is_bound(diagram: &Diagram, idx: usize) -> bool {
+    diagram
+        .id((idx, SelectionType::TwoTerminal))
+        .is_some_and(|id| diagram.bindings.contains_key(&id))
+}
+
+/// Width of the clickable strip along a two-terminal component's body
+const BODY_HITBOX_WIDTH: f32 = 20.0;
+
 fn interact_with_twoterminal_body(
     ui: &mut Ui,
     pos: [CellPos; 2],
     id: Id,
     selected: bool,
+    vis: &VisualizationOptions,
 ) -> egui::Response {
-    let begin = cellpos_to_egui(pos[0]);
-    let end = cellpos_to_egui(pos[1]);
-    let body_rect = Rect::from_points(&[begin, end]);
+    let begin = vis.cellpos_to_egui(pos[0]);
+    let end = vis.cellpos_to_egui(pos[1]);
 
     let horiz = pos[0].1 == pos[1].1;
     let vert = pos[0].0 == pos[1].0;
-    let body_hitbox = if horiz == vert {
-        body_rect
-    } else {
-        body_rect.expand(10.0)
-    };
+    if horiz || vert {
+        let body_hitbox = Rect::from_points(&[begin, end]).expand(BODY_HITBOX_WIDTH / 2.0);
+        return ui.interact(body_hitbox, id, Sense::click_and_drag());
+    }
 
-    ui.interact(body_hitbox, id, Sense::click_and_drag())
+    // A rectangle around a diagonal would cover its whole bounding box, and steal clicks from
+    // the components inside it. Follow the line with small squares instead.
+    let steps = ((end - begin).length() / (BODY_HITBOX_WIDTH / 2.0)).ceil() as usize;
+    (0..=steps)
+        .map(|i| {
+            let center = begin.lerp(end, i as f32 / steps as f32);
+            let hitbox = Rect::from_center_size(center, Vec2::splat(BODY_HITBOX_WIDTH));
+            ui.interact(hitbox, id.with(i), Sense::click_and_drag())
+        })
+        .reduce(|a, b| a.union(b))
+        .expect("at least one hitbox")
+}
+
+/// What `interact_with_twoterminal()` and `interact_with_threeterminal()` need to know about a
+/// component besides its position and value
+struct Interaction<'a> {
+    /// From `interact_with_twoterminal_body()` or `interact_with_threeterminal_body()`
+    body_resp: Response,
+    selected: bool,
+    locked: bool,
+    debug_draw: bool,
+    vis: &'a VisualizationOptions,
 }
 
 fn interact_with_twoterminal(
@@ -483,14 +2340,19 @@ fn interact_with_twoterminal(
     pos: &mut [CellPos; 2],
     component: &mut TwoTerminalComponent,
     wires: [DiagramWireState; 2],
-    body_resp: Response,
-    selected: bool,
-    debug_draw: bool,
-    vis: &VisualizationOptions,
+    flip_label: bool,
+    interaction: Interaction<'_>,
 ) -> bool {
+    let Interaction {
+        body_resp,
+        selected,
+        locked,
+        debug_draw,
+        vis,
+    } = interaction;
     let id = Id::new("twoterminal");
-    let begin = cellpos_to_egui(pos[0]);
-    let end = cellpos_to_egui(pos[1]);
+    let begin = vis.cellpos_to_egui(pos[0]);
+    let end = vis.cellpos_to_egui(pos[1]);
 
     let handle_hitbox_size = 50.0;
     let begin_hitbox = Rect::from_center_size(begin, Vec2::splat(handle_hitbox_size));
@@ -501,7 +2363,8 @@ fn interact_with_twoterminal(
 
     let mut destructive_change = false;
 
-    if selected {
+    // Locked components get no handles, and ignore drags of their body
+    if selected && !locked {
         let end_resp = ui.interact(end_hitbox, id.with("end"), Sense::click_and_drag());
         let begin_resp = ui.interact(begin_hitbox, id.with("begin"), Sense::click_and_drag());
 
@@ -520,7 +2383,7 @@ fn interact_with_twoterminal(
 
         let interact_delta = interact_begin_pos
             .zip(interact_pos)
-            .map(|(start, stop)| stop - start);
+            .map(|(start, stop)| vis.snap(stop - start));
 
         if body_resp.dragged() || body_resp.drag_stopped() {
             begin_offset = interact_delta.unwrap_or(Vec2::ZERO);
@@ -532,8 +2395,8 @@ fn interact_with_twoterminal(
         }
 
         if body_resp.drag_stopped() || begin_resp.drag_stopped() || end_resp.drag_stopped() {
-            pos[0] = egui_to_cellpos(begin + begin_offset);
-            pos[1] = egui_to_cellpos(end + end_offset);
+            pos[0] = vis.egui_to_cellpos(begin + begin_offset);
+            pos[1] = vis.egui_to_cellpos(end + end_offset);
             destructive_change = true;
         }
 
@@ -590,12 +2453,36 @@ fn interact_with_twoterminal(
         wires,
         *component,
         selected,
+        flip_label,
         vis,
     );
 
+    if debug_draw {
+        draw_reference_direction(
+            ui.painter(),
+            begin + begin_offset,
+            end + end_offset,
+            "+I",
+            vis,
+        );
+    }
+
     destructive_change
 }
 
+/// Whether the value label of a component with terminals at `pos` should go on the other side
+/// than usual, because the usual side has terminals right next to it and the other side doesn't
+fn label_flipped(terminal_counts: &HashMap<CellPos, u32>, pos: [CellPos; 2]) -> bool {
+    let [begin, end] = pos.map(|(x, y)| Pos2::new(x as f32, y as f32));
+    let blocked = |normal: Vec2| {
+        let (dx, dy) = (normal.x.round() as i32, normal.y.round() as i32);
+        pos.iter()
+            .any(|&(x, y)| terminal_counts.contains_key(&(x + dx, y + dy)))
+    };
+    let normal = label_normal(begin, end, false);
+    blocked(normal) && !blocked(-normal)
+}
+
 fn interact_with_threeterminal_body(
     ui: &mut Ui,
     pos: [CellPos; 3],
@@ -603,9 +2490,9 @@ fn interact_with_threeterminal_body(
     selected: bool,
     vis: &VisualizationOptions,
 ) -> egui::Response {
-    let a = cellpos_to_egui(pos[0]);
-    let b = cellpos_to_egui(pos[1]);
-    let c = cellpos_to_egui(pos[2]);
+    let a = vis.cellpos_to_egui(pos[0]);
+    let b = vis.cellpos_to_egui(pos[1]);
+    let c = vis.cellpos_to_egui(pos[2]);
     let body_rect = Rect::from_points(&[a, b, c]);
 
     let body_hitbox = if body_rect.area() == 0.0 {
@@ -622,14 +2509,19 @@ fn interact_with_threeterminal(
     pos: &mut [CellPos; 3],
     component: ThreeTerminalComponent,
     wires: [DiagramWireState; 3],
-    body_resp: Response,
-    selected: bool,
-    vis: &VisualizationOptions,
+    interaction: Interaction<'_>,
 ) -> bool {
+    let Interaction {
+        body_resp,
+        selected,
+        locked,
+        debug_draw,
+        vis,
+    } = interaction;
     let id = Id::new("threeterminal");
-    let a = cellpos_to_egui(pos[0]);
-    let b = cellpos_to_egui(pos[1]);
-    let c = cellpos_to_egui(pos[2]);
+    let a = vis.cellpos_to_egui(pos[0]);
+    let b = vis.cellpos_to_egui(pos[1]);
+    let c = vis.cellpos_to_egui(pos[2]);
 
     let handle_hitbox_size = 50.0;
     let a_hitbox = Rect::from_center_size(a, Vec2::splat(handle_hitbox_size));
@@ -642,7 +2534,7 @@ fn interact_with_threeterminal(
 
     let mut destructive_change = false;
 
-    if selected {
+    if selected && !locked {
         let a_resp = ui.interact(a_hitbox, id.with("a"), Sense::click_and_drag());
         let b_resp = ui.interact(b_hitbox, id.with("b"), Sense::click_and_drag());
         let c_resp = ui.interact(c_hitbox, id.with("c"), Sense::click_and_drag());
@@ -667,7 +2559,7 @@ fn interact_with_threeterminal(
 
         let interact_delta = interact_begin_pos
             .zip(interact_pos)
-            .map(|(start, stop)| stop - start);
+            .map(|(start, stop)| vis.snap(stop - start));
 
         if body_resp.dragged() || body_resp.drag_stopped() {
             a_offset = interact_delta.unwrap_or(Vec2::ZERO);
@@ -686,9 +2578,9 @@ fn interact_with_threeterminal(
             || b_resp.drag_stopped()
             || c_resp.drag_stopped()
         {
-            pos[0] = egui_to_cellpos(a + a_offset);
-            pos[1] = egui_to_cellpos(b + b_offset);
-            pos[2] = egui_to_cellpos(c + c_offset);
+            pos[0] = vis.egui_to_cellpos(a + a_offset);
+            pos[1] = vis.egui_to_cellpos(b + b_offset);
+            pos[2] = vis.egui_to_cellpos(c + c_offset);
             destructive_change = true;
             ui.memory_mut(|mem| mem.data.remove::<Pos2>(id));
         }
@@ -706,6 +2598,12 @@ fn interact_with_threeterminal(
 
     draw_threeterminal_component(ui.painter(), [a, b, c], wires, component, selected, vis);
 
+    // The two branches stamped for every three-terminal component, see stamp_dynamic()
+    if debug_draw {
+        draw_reference_direction(ui.painter(), b, a, "ab", vis);
+        draw_reference_direction(ui.painter(), c, b, "bc", vis);
+    }
+
     destructive_change
 }
 
@@ -722,13 +2620,33 @@ impl DiagramWireState {
         if selected {
             Color32::from_rgb(0x00, 0xff, 0xff)
         } else {
-            voltage_color(self.voltage / vis.voltage_scale)
+            let value = match vis.mode {
+                VisualizationMode::Voltage => self.voltage / vis.voltage_scale,
+                VisualizationMode::Current => self.current / vis.current_scale,
+                VisualizationMode::Power => {
+                    self.voltage * self.current / (vis.voltage_scale * vis.current_scale)
+                }
+            };
+            voltage_color(value, vis.color_scheme)
+        }
+    }
+
+    /// Stroke width, see `VisualizationOptions::current_width`
+    pub fn width(&self, vis: &VisualizationOptions) -> f32 {
+        if vis.current_width {
+            let fraction = (self.current.abs() / vis.current_scale) as f32;
+            // clamp() would pass a NaN through, so draw those as thin as no current
+            let fraction = if fraction.is_nan() { 0.0 } else { fraction };
+            (MIN_WIRE_WIDTH + (MAX_WIRE_WIDTH - MIN_WIRE_WIDTH) * fraction)
+                .clamp(MIN_WIRE_WIDTH, MAX_WIRE_WIDTH)
+        } else {
+            WIRE_WIDTH
         }
     }
 
     pub fn wire(
         &self,
-        painter: &Painter,
+        painter: &dyn Canvas,
         a: Pos2,
         b: Pos2,
         selected: bool,
@@ -740,7 +2658,7 @@ impl DiagramWireState {
 
     pub fn arrow(
         &self,
-        painter: &Painter,
+        painter: &dyn Canvas,
         a: Pos2,
         b: Pos2,
         selected: bool,
@@ -757,64 +2675,69 @@ impl DiagramWireState {
 
     pub fn line_segment(
         &self,
-        painter: &Painter,
+        painter: &dyn Canvas,
         a: Pos2,
         b: Pos2,
         selected: bool,
         vis: &VisualizationOptions,
     ) {
-        painter.line_segment([a, b], Stroke::new(3., self.color(selected, vis)));
+        painter.line_segment(
+            [a, b],
+            Stroke::new(self.width(vis), self.color(selected, vis)),
+        );
     }
 
     pub fn arrow_segment(
         &self,
-        painter: &Painter,
+        painter: &dyn Canvas,
         a: Pos2,
         b: Pos2,
         selected: bool,
         vis: &VisualizationOptions,
     ) {
-        painter.line_segment([a, b], Stroke::new(3., self.color(selected, vis)));
+        painter.line_segment(
+            [a, b],
+            Stroke::new(self.width(vis), self.color(selected, vis)),
+        );
 
         let y = (b - a).normalized();
         let x = y.rot90();
 
-        let vp = (y + x / 3.0) * CELL_SIZE * 0.15;
-        let vm = (y - x / 3.0) * CELL_SIZE * 0.15;
+        let vp = (y + x / 3.0) * vis.cell_size * 0.15;
+        let vm = (y - x / 3.0) * vis.cell_size * 0.15;
 
-        painter.add(Shape::convex_polygon(
+        painter.convex_polygon(
             vec![a, a + vp, a + vm],
             self.color(selected, vis),
             Stroke::NONE,
-        ));
+        );
         //painter.arrow(a, b - a, Stroke::new(3., self.color(selected)));
     }
 
-    pub fn current(&self, painter: &Painter, a: Pos2, b: Pos2, vis: &VisualizationOptions) {
-        if self.current == 0.0 {
+    pub fn current(&self, painter: &dyn Canvas, a: Pos2, b: Pos2, vis: &VisualizationOptions) {
+        if self.current.abs() < vis.current_scale * MIN_ANIMATED_CURRENT {
             return;
         }
 
-        let spacing = CELL_SIZE / 5.0;
+        // The diagram is drawn in scene coordinates, so sizes relative to the cell follow the zoom
+        let spacing = vis.cell_size / 5.0;
+        let radius = vis.cell_size / 40.0;
 
         let n = ((b - a).length() / spacing) as usize;
         let n = n.max(1);
 
-        let time = painter
-            .ctx()
-            .input(|r| r.time * self.current.abs() as f64 / vis.current_scale)
-            .fract() as f32;
-
-        let rect_size = 5.0;
+        // Static output (e.g. an exported image) has nothing to animate
+        let Some(time) = painter.time() else {
+            return;
+        };
+        let time = (time * self.current.abs() / vis.current_scale).fract() as f32;
 
         for i in 0..n {
             let mut t = (i as f32 + time) / n as f32;
             if self.current < 0.0 {
                 t = 1.0 - t
             }
-            let pos = a.lerp(b, t);
-            let rect = Rect::from_center_size(pos, Vec2::splat(rect_size));
-            painter.rect_filled(rect, 0.0, Color32::YELLOW);
+            painter.circle_filled(a.lerp(b, t), radius, Color32::YELLOW);
         }
     }
 
@@ -827,20 +2750,41 @@ impl DiagramWireState {
     }
 }
 
-fn voltage_color(voltage: f64) -> Color32 {
+fn voltage_color(voltage: f64, scheme: ColorScheme) -> Color32 {
     let v = voltage.clamp(-1.0, 1.0);
 
     let neutral = Color32::DARK_GRAY;
 
+    let (negative, positive) = match scheme {
+        ColorScheme::RedGreen => (Color32::RED, Color32::GREEN),
+        ColorScheme::BlueYellow => (Color32::from_rgb(0x20, 0x60, 0xff), Color32::YELLOW),
+        ColorScheme::Viridis => return viridis((v as f32 + 1.0) / 2.0),
+    };
+
     if v > 0.0 {
-        neutral.lerp_to_gamma(Color32::GREEN, v as f32)
+        neutral.lerp_to_gamma(positive, v as f32)
     } else {
-        neutral.lerp_to_gamma(Color32::RED, -v as f32)
+        neutral.lerp_to_gamma(negative, -v as f32)
     }
 }
 
+/// Piecewise-linear approximation of the viridis colormap, for t in 0..=1
+fn viridis(t: f32) -> Color32 {
+    const STOPS: [Color32; 5] = [
+        Color32::from_rgb(0x44, 0x01, 0x54),
+        Color32::from_rgb(0x3b, 0x52, 0x8b),
+        Color32::from_rgb(0x21, 0x91, 0x8c),
+        Color32::from_rgb(0x5e, 0xc9, 0x62),
+        Color32::from_rgb(0xfd, 0xe7, 0x25),
+    ];
+
+    let x = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let idx = (x as usize).min(STOPS.len() - 2);
+    STOPS[idx].lerp_to_gamma(STOPS[idx + 1], x - idx as f32)
+}
+
 fn draw_threeterminal_component(
-    painter: &Painter,
+    painter: &dyn Canvas,
     pos: [Pos2; 3],
     wires: [DiagramWireState; 3],
     component: ThreeTerminalComponent,
@@ -848,17 +2792,24 @@ fn draw_threeterminal_component(
     vis: &VisualizationOptions,
 ) {
     match component {
-        ThreeTerminalComponent::PTransistor(_) => {
+        ThreeTerminalComponent::PTransistor(..) => {
             draw_transistor(painter, pos, wires, selected, true, vis)
         }
-        ThreeTerminalComponent::NTransistor(_) => {
+        ThreeTerminalComponent::NTransistor(..) => {
             draw_transistor(painter, pos, wires, selected, false, vis)
         }
+        ThreeTerminalComponent::Potentiometer(..) => {
+            draw_potentiometer(painter, pos, wires, selected, vis)
+        }
+    }
+
+    if selected {
+        draw_terminal_labels(painter, pos, component.terminal_labels(), vis);
     }
 }
 
 pub fn draw_twoterminal_component_no_value(
-    painter: &Painter,
+    painter: &dyn Canvas,
     pos: [Pos2; 2],
     wires: [DiagramWireState; 2],
     component: TwoTerminalComponent,
@@ -868,29 +2819,42 @@ pub fn draw_twoterminal_component_no_value(
     match component {
         TwoTerminalComponent::Wire => wires[0].wire(painter, pos[0], pos[1], selected, vis),
         TwoTerminalComponent::Resistor(_) => draw_resistor(painter, pos, wires, selected, vis),
-        TwoTerminalComponent::Inductor(_, _) => draw_inductor(painter, pos, wires, selected, vis),
-        TwoTerminalComponent::Capacitor(_) => draw_capacitor(painter, pos, wires, selected, vis),
+        TwoTerminalComponent::Inductor(..) => draw_inductor(painter, pos, wires, selected, vis),
+        TwoTerminalComponent::Capacitor(capacitance, ..) => {
+            draw_capacitor(painter, pos, wires, selected, capacitance, vis)
+        }
         TwoTerminalComponent::Diode => draw_diode(painter, pos, wires, selected, vis),
-        TwoTerminalComponent::Battery(_) => draw_battery(painter, pos, wires, selected, vis),
+        TwoTerminalComponent::Battery(..) => draw_battery(painter, pos, wires, selected, vis),
         TwoTerminalComponent::Switch(is_open) => {
             draw_switch(painter, pos, wires, selected, is_open, vis)
         }
-        TwoTerminalComponent::CurrentSource(_) => {
-            draw_current_source(painter, pos, wires, selected, vis)
+        TwoTerminalComponent::CurrentSource(i) => {
+            draw_current_source(painter, pos, wires, selected, i, vis)
+        }
+        TwoTerminalComponent::CurrentControlledSource(gain, _) => {
+            draw_controlled_current_source(painter, pos, wires, selected, gain, vis)
+        }
+        TwoTerminalComponent::CurrentProbe => {
+            draw_current_probe(painter, pos, wires, selected, vis)
+        }
+        TwoTerminalComponent::Crystal { .. } => draw_crystal(painter, pos, wires, selected, vis),
+        TwoTerminalComponent::NoiseSource { .. } => {
+            draw_noise_source(painter, pos, wires, selected, vis)
         }
     }
 }
 
 pub fn draw_twoterminal_component(
-    painter: &Painter,
+    painter: &dyn Canvas,
     pos: [Pos2; 2],
     wires: [DiagramWireState; 2],
     component: TwoTerminalComponent,
     selected: bool,
+    flip_label: bool,
     vis: &VisualizationOptions,
 ) {
     draw_twoterminal_component_no_value(painter, pos, wires, component, selected, vis);
-    draw_component_value(painter, pos, component);
+    draw_component_value(painter, pos, component, flip_label, vis);
 }
 
 impl DiagramState {
@@ -910,8 +2874,139 @@ impl DiagramState {
     }
 }
 
-fn edit_transistor(ui: &mut Ui, beta: &mut f64) -> Response {
-    ui.add(DragValue::new(beta).speed(1e-2).prefix("Beta: "))
+/// Picks the component whose current the current-controlled source at `idx` follows. Returns
+/// true if it changed.
+fn edit_sensed(ui: &mut Ui, diagram: &mut Diagram, idx: usize) -> bool {
+    let Some(id) = diagram.id((idx, SelectionType::TwoTerminal)) else {
+        return false;
+    };
+    let old = diagram.sensed.get(&id).copied();
+    let mut sensed = old;
+
+    let label = |other: usize| {
+        let (pos, component) = &diagram.two_terminal[other];
+        format!("{} at {pos:?}", component.name())
+    };
+    let selected_text = match sensed.and_then(|sensed| diagram.find(sensed)) {
+        Some((other, SelectionType::TwoTerminal)) => label(other),
+        _ => "Nothing".into(),
+    };
+    egui::ComboBox::from_label("Senses")
+        .selected_text(selected_text)
+        .show_ui(ui, |ui| {
+            ui.selectable_value(&mut sensed, None, "Nothing");
+            for (other, &other_id) in diagram.ids.two_terminal.iter().enumerate() {
+                if other != idx {
+                    ui.selectable_value(&mut sensed, Some(other_id), label(other));
+                }
+            }
+        })
+        .response
+        .on_hover_text("The source pushes the current through this component, times the gain");
+
+    if sensed == old {
+        return false;
+    }
+    match sensed {
+        Some(sensed) => diagram.sensed.insert(id, sensed),
+        None => diagram.sensed.remove(&id),
+    };
+    true
+}
+
+/// Edits the Monte Carlo tolerance of the two-terminal component at `idx`. This doesn't change
+/// the simulation, so there's nothing to return.
+fn edit_tolerance(ui: &mut Ui, diagram: &mut Diagram, idx: usize) {
+    let Some(id) = diagram.id((idx, SelectionType::TwoTerminal)) else {
+        return;
+    };
+    let mut tolerance = diagram.tolerances.get(&id).copied();
+
+    ui.horizontal(|ui| {
+        let mut own = tolerance.is_some();
+        ui.checkbox(&mut own, "Tolerance")
+            .on_hover_text("Without one, Monte Carlo analysis uses the default from its window");
+        tolerance = match (own, tolerance) {
+            (true, None) => Some(DEFAULT_TOLERANCE),
+            (true, tolerance) => tolerance,
+            (false, _) => None,
+        };
+        if let Some(tolerance) = &mut tolerance {
+            let mut percent = *tolerance * 100.0;
+            ui.add(
+                DragValue::new(&mut percent)
+                    .range(0.0..=100.0)
+                    .prefix("±")
+                    .suffix("%"),
+            );
+            *tolerance = percent / 100.0;
+        }
+    });
+
+    match tolerance {
+        Some(tolerance) => diagram.tolerances.insert(id, tolerance),
+        None => diagram.tolerances.remove(&id),
+    };
+}
+
+fn edit_transistor(
+    ui: &mut Ui,
+    beta: &mut f64,
+    c_be: &mut f64,
+    c_bc: &mut f64,
+    ar: &mut f64,
+) -> Response {
+    let resp = ui.add(DragValue::new(beta).speed(1e-2).prefix("Beta: "));
+    if beta.is_nan() || *beta < 0.0 {
+        *beta = 0.0;
+    }
+    ui.add(
+        DragValue::new(ar)
+            .range(0.0..=0.999)
+            .speed(1e-3)
+            .prefix("Reverse alpha: "),
+    )
+    .on_hover_text(
+        "Fraction of the current that crosses over when collector and emitter swap roles",
+    );
+    for (label, value) in [("Cbe: ", c_be), ("Cbc: ", c_bc)] {
+        ui.horizontal(|ui| {
+            ui.label(label);
+            ui.add(edit_metric_f64(value, "F"))
+                .on_hover_text("Junction capacitance, which slows down switching. 0 for none.");
+        });
+        if value.is_nan() || *value < 0.0 {
+            *value = 0.0;
+        }
+    }
+    resp
+}
+
+/// Returns true if the ground was changed
+pub fn edit_ground(ui: &mut Ui, kind: &mut GroundKind) -> bool {
+    let old = *kind;
+    ui.horizontal(|ui| {
+        ui.selectable_value(kind, GroundKind::Reference, "Reference");
+        if ui
+            .selectable_label(matches!(kind, GroundKind::Tied(_)), "Tied")
+            .clicked()
+        {
+            if let GroundKind::Reference = kind {
+                *kind = GroundKind::Tied(None);
+            }
+        }
+
+        if let GroundKind::Tied(resistance) = kind {
+            let mut direct = resistance.is_none();
+            if ui.checkbox(&mut direct, "Direct").changed() {
+                *resistance = (!direct).then_some(1.0);
+            }
+            if let Some(resistance) = resistance {
+                edit_positive_f64(ui, resistance, 'Ω', MIN_RESISTANCE);
+            }
+        }
+    });
+    *kind != old
 }
 
 fn edit_port(ui: &mut Ui, component: &mut String) {
@@ -928,22 +3023,80 @@ fn edit_threeterminal_component(
     wires: [DiagramWireState; 3],
 ) {
     ui.strong(component.name());
+    edit_threeterminal_value(ui, component);
+}
+
+/// Edits the values of a three-terminal component, without any readouts
+fn edit_threeterminal_value(ui: &mut Ui, component: &mut ThreeTerminalComponent) -> Response {
     match component {
-        ThreeTerminalComponent::PTransistor(beta) => edit_transistor(ui, beta),
-        ThreeTerminalComponent::NTransistor(beta) => edit_transistor(ui, beta),
-    };
+        ThreeTerminalComponent::PTransistor(beta, c_be, c_bc, ar)
+        | ThreeTerminalComponent::NTransistor(beta, c_be, c_bc, ar) => {
+            edit_transistor(ui, beta, c_be, c_bc, ar)
+        }
+        ThreeTerminalComponent::Potentiometer(resistance, position) => {
+            edit_positive_f64(ui, resistance, 'Ω', MIN_RESISTANCE);
+            ui.add(Slider::new(position, 0.0..=1.0).text("Wiper"))
+        }
+    }
 }
 
 fn edit_twoterminal_component(
     ui: &mut Ui,
     component: &mut TwoTerminalComponent,
     wires: [DiagramWireState; 2],
+    averages: Option<WindowAverages>,
+    format: ValueFormat,
 ) {
     ui.strong(component.name());
+    edit_twoterminal_value(ui, component, wires[0].current);
+
+    let voltage = wires[1].voltage - wires[0].voltage;
+    ui.label(format!("Vd: {}", format_value(voltage, 'V', format)));
+    let current = wires[0].current;
+    ui.label(format!("I: {}", format_value(current, 'A', format)))
+        .on_hover_text("Positive from the first terminal to the second, see View > Debug draw");
+    ui.weak(format!(
+        "P: {}",
+        format_value(voltage * current, 'W', format)
+    ));
+
+    match averages {
+        Some(avg) => {
+            ui.label(format!(
+                "Vd mean: {}, RMS: {}",
+                format_value(avg.mean_voltage, 'V', format),
+                format_value(avg.rms_voltage, 'V', format)
+            ));
+            ui.label(format!(
+                "I mean: {}, RMS: {}",
+                format_value(avg.mean_current, 'A', format),
+                format_value(avg.rms_current, 'A', format)
+            ));
+        }
+        None => {
+            ui.weak("Mean and RMS: accumulating...");
+        }
+    }
+}
+
+/// Edits the values of a two-terminal component, without any readouts. `current` is only used
+/// to show when a battery is current limited.
+fn edit_twoterminal_value(
+    ui: &mut Ui,
+    component: &mut TwoTerminalComponent,
+    current: f64,
+) -> Response {
     match component {
-        TwoTerminalComponent::Battery(v) => ui.add(edit_metric_f64(v, "V")),
-        TwoTerminalComponent::Inductor(i, maybe_coreid) => {
-            ui.add(edit_metric_f64(i, "H"));
+        TwoTerminalComponent::Battery(v, limit) => {
+            let resp = ui.add(edit_metric_f64(v, "V"));
+            edit_current_limit(ui, limit, current);
+            resp
+        }
+        TwoTerminalComponent::Inductor(i, maybe_coreid, ic, method, r) => {
+            edit_positive_f64(ui, i, 'H', MIN_INDUCTANCE);
+            edit_series_resistance(ui, r, "Resistance of the winding. 0 for none.");
+            edit_initial_condition(ui, ic, "A");
+            edit_integration(ui, method);
             let mut has_core = maybe_coreid.is_some();
             if ui.checkbox(&mut has_core, "Transformer").changed() {
                 *maybe_coreid = has_core.then(|| 0);
@@ -957,19 +3110,129 @@ fn edit_twoterminal_component(
             })
             .response
         }
-        TwoTerminalComponent::Capacitor(c) => ui.add(edit_metric_f64(c, "F")),
-        TwoTerminalComponent::Resistor(r) => ui.add(edit_metric_f64(r, "Ω")),
+        TwoTerminalComponent::Capacitor(c, ic, method, esr) => {
+            edit_series_resistance(ui, esr, "Equivalent series resistance. 0 for none.");
+            edit_initial_condition(ui, ic, "V");
+            edit_integration(ui, method);
+            edit_positive_f64(ui, c, 'F', MIN_CAPACITANCE)
+        }
+        TwoTerminalComponent::Resistor(r) => edit_positive_f64(ui, r, 'Ω', MIN_RESISTANCE),
         TwoTerminalComponent::Wire => ui.response(),
         TwoTerminalComponent::Diode => ui.response(),
         TwoTerminalComponent::Switch(is_open) => ui.checkbox(is_open, "Switch open"),
         TwoTerminalComponent::CurrentSource(i) => ui.add(edit_metric_f64(i, "A")),
-    };
+        TwoTerminalComponent::CurrentControlledSource(gain, _) => {
+            ui.add(DragValue::new(gain).speed(1e-2).prefix("Gain: "))
+        }
+        TwoTerminalComponent::CurrentProbe => ui.response(),
+        TwoTerminalComponent::Crystal { ls, cs, rs, cp } => {
+            for (label, value, unit, min) in [
+                ("Ls: ", ls, 'H', MIN_INDUCTANCE),
+                ("Cs: ", cs, 'F', MIN_CAPACITANCE),
+                ("Rs: ", rs, 'Ω', MIN_RESISTANCE),
+                ("Cp: ", cp, 'F', MIN_CAPACITANCE),
+            ] {
+                ui.horizontal(|ui| {
+                    ui.label(label);
+                    edit_positive_f64(ui, value, unit, min);
+                });
+            }
+            ui.response()
+        }
+        TwoTerminalComponent::NoiseSource { amplitude, seed } => {
+            ui.horizontal(|ui| {
+                ui.label("Seed: ");
+                ui.add(DragValue::new(seed))
+                    .on_hover_text("The same seed draws the same noise after a reset");
+            });
+            ui.add(edit_metric_f64(amplitude, "V")).on_hover_text(
+                "RMS voltage. A new value is drawn every Δt, so Δt sets the bandwidth.",
+            )
+        }
+    }
+}
 
-    let voltage = wires[1].voltage - wires[0].voltage;
-    ui.label(format!("Vd: {}", to_metric_prefix(voltage, 'V')));
-    let current = wires[0].current;
-    ui.label(format!("I: {}", to_metric_prefix(current, 'A')));
-    ui.weak(format!("P: {}", to_metric_prefix(voltage * current, 'W')));
+/// Edits the optional initial voltage or current of a component, which is applied on reset
+fn edit_initial_condition(ui: &mut Ui, ic: &mut Option<f64>, unit: &str) {
+    ui.horizontal(|ui| {
+        let mut enabled = ic.is_some();
+        if ui
+            .checkbox(&mut enabled, "Initial condition")
+            .on_hover_text("Applied when the simulation is reset")
+            .changed()
+        {
+            *ic = enabled.then_some(0.0);
+        }
+        if let Some(value) = ic {
+            ui.add(edit_metric_f64(value, unit));
+        }
+    });
+}
+
+/// Edits the integration method of a capacitor or inductor, None following the solver's
+fn edit_integration(ui: &mut Ui, method: &mut Option<IntegrationMethod>) {
+    let name = |method: Option<IntegrationMethod>| method.map_or("Solver default", |m| m.name());
+    egui::ComboBox::from_label("Integration")
+        .selected_text(name(*method))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(method, None, name(None));
+            for option in IntegrationMethod::ALL {
+                ui.selectable_value(method, Some(option), name(Some(option)));
+            }
+        })
+        .response
+        .on_hover_text(
+            "Trapezoidal keeps LC circuits ringing at the right amplitude. Backward Euler damps \
+            them, which can calm a part that rings numerically after a sudden change.",
+        );
+}
+
+/// Edits a battery's optional current limit, and says when the battery is held at it
+fn edit_current_limit(ui: &mut Ui, limit: &mut Option<f64>, current: f64) {
+    ui.horizontal(|ui| {
+        let mut enabled = limit.is_some();
+        if ui
+            .checkbox(&mut enabled, "Current limit")
+            .on_hover_text("Past this current, the voltage sags instead")
+            .changed()
+        {
+            *limit = enabled.then_some(1.0);
+        }
+        if let Some(value) = limit {
+            edit_positive_f64(ui, value, 'A', MIN_CURRENT_LIMIT);
+        }
+    });
+    if limit.is_some_and(|limit| current.abs() >= limit * (1.0 - LIMIT_SLACK)) {
+        ui.colored_label(Color32::YELLOW, "Current limited");
+    }
+}
+
+/// Edits a value which must stay above `min`, clamping it and warning while it sits at the limit
+fn edit_series_resistance(ui: &mut Ui, r: &mut f64, hover: &str) {
+    ui.horizontal(|ui| {
+        ui.label("Series R: ");
+        ui.add(edit_metric_f64(r, "Ω")).on_hover_text(hover);
+    });
+    if r.is_nan() || *r < 0.0 {
+        *r = 0.0;
+    }
+}
+
+fn edit_positive_f64(ui: &mut Ui, value: &mut f64, unit: char, min: f64) -> Response {
+    let resp = ui.add(edit_metric_f64(value, &unit.to_string()));
+    if value.is_nan() || *value < min {
+        *value = min;
+    }
+    if *value == min {
+        ui.colored_label(
+            Color32::YELLOW,
+            format!(
+                "Clamped to minimum of {}",
+                metric_prefix(min, unit, SIGNIFICANT_FIGURES)
+            ),
+        );
+    }
+    resp
 }
 
 impl Default for VisualizationOptions {
@@ -977,6 +3240,15 @@ impl Default for VisualizationOptions {
         Self {
             voltage_scale: 5.0,
             current_scale: 5.0,
+            cell_size: CELL_SIZE,
+            snap_to_grid: true,
+            auto_connect: false,
+            mode: VisualizationMode::default(),
+            color_scheme: ColorScheme::default(),
+            max_fan_in: Some(4),
+            current_width: false,
+            grid: GridStyle::default(),
+            value_format: ValueFormat::default(),
         }
     }
 }
@@ -1010,80 +3282,299 @@ impl DiagramState {
     }
 }
 
+/// Renders the diagram as an SVG document, using the same glyphs as the editor
+pub fn diagram_to_svg(
+    diagram: &Diagram,
+    state: &DiagramState,
+    vis: &VisualizationOptions,
+    monochrome: bool,
+) -> String {
+    let canvas = SvgCanvas::new(monochrome);
+
+    let terminal_counts = diagram.terminal_counts();
+    for ((pos, comp), wires) in diagram.two_terminal.iter().zip(&state.two_terminal) {
+        let flip_label = label_flipped(&terminal_counts, *pos);
+        let pos = pos.map(|p| vis.cellpos_to_egui(p));
+        draw_twoterminal_component(&canvas, pos, *wires, *comp, false, flip_label, vis);
+    }
+
+    for ((pos, comp), wires) in diagram.three_terminal.iter().zip(&state.three_terminal) {
+        let pos = pos.map(|p| vis.cellpos_to_egui(p));
+        draw_threeterminal_component(&canvas, pos, *wires, *comp, false, vis);
+    }
+
+    for junction in diagram.junctions() {
+        canvas.circle_filled(vis.cellpos_to_egui(junction), 5.0, Color32::LIGHT_GRAY);
+    }
+
+    for &(pos, kind) in &diagram.grounds {
+        draw_ground(&canvas, vis.cellpos_to_egui(pos), kind, vis);
+    }
+
+    for (pos, name) in &diagram.ports {
+        let pos = vis.cellpos_to_egui(*pos);
+        canvas.circle_stroke(pos, 10.0, Stroke::new(1., Color32::ORANGE));
+        canvas.text(pos, egui::Align2::RIGHT_TOP, name, Color32::ORANGE);
+    }
+
+    canvas.finish(vis.cell_size / 2.0)
+}
+
 fn draw_handle(ui: &mut Ui, pos: Pos2) {
     ui.painter()
         .circle_stroke(pos, 25.0, Stroke::new(1., Color32::WHITE));
 }
 
-/// Returns true if the simulation needs rebuild
-pub fn show_add_component_buttons(ui: &mut Ui, add_pos: Pos2, editor: &mut DiagramEditor, diagram: &mut Diagram) -> bool {
-    let mut rebuild_sim = false;
+/// An entry in the component palette
+#[derive(Clone, Copy, Debug)]
+pub enum PaletteItem {
+    TwoTerminal(TwoTerminalComponent),
+    ThreeTerminal(ThreeTerminalComponent),
+    Ground,
+}
 
-    let pos = egui_to_cellpos(add_pos);
-
-    let two_terminal_components = [
-        TwoTerminalComponent::Wire,
-        TwoTerminalComponent::Resistor(1000.0),
-        TwoTerminalComponent::Inductor(1.0, None),
-        TwoTerminalComponent::Capacitor(10e-6),
-        TwoTerminalComponent::Diode,
-        TwoTerminalComponent::Battery(5.0),
-        TwoTerminalComponent::Switch(true),
-        TwoTerminalComponent::CurrentSource(0.1),
-    ];
+impl PaletteItem {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::TwoTerminal(component) => component.name(),
+            Self::ThreeTerminal(component) => component.name(),
+            Self::Ground => "Ground",
+        }
+    }
+}
 
-    let vis_opt = VisualizationOptions::default();
+/// Values given to newly added components, in place of the ones built into the palette and
+/// shortcuts. Keyed by component name, so that each kind has one default.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ComponentDefaults {
+    two_terminal: BTreeMap<String, TwoTerminalComponent>,
+    three_terminal: BTreeMap<String, ThreeTerminalComponent>,
+}
 
-    for component in two_terminal_components {
-        let resp = ui
-            .push_id(component.name(), |ui| {
-                two_terminal_component_button(ui, component, &vis_opt)
-                    .on_hover_text(format!("Add {}", component.name()))
-            })
-        .inner;
+impl ComponentDefaults {
+    /// `component`, with the user's default values if they set any
+    pub fn two_terminal(&self, component: TwoTerminalComponent) -> TwoTerminalComponent {
+        self.two_terminal
+            .get(component.name())
+            .copied()
+            .unwrap_or(component)
+    }
 
-        if resp.clicked() {
-            rebuild_sim = true;
-            editor.new_twoterminal(
-                diagram,
-                pos,
-                component,
-            );
+    /// `component`, with the user's default values if they set any
+    pub fn three_terminal(&self, component: ThreeTerminalComponent) -> ThreeTerminalComponent {
+        self.three_terminal
+            .get(component.name())
+            .copied()
+            .unwrap_or(component)
+    }
+
+    pub fn apply(&self, item: PaletteItem) -> PaletteItem {
+        match item {
+            PaletteItem::TwoTerminal(component) => {
+                PaletteItem::TwoTerminal(self.two_terminal(component))
+            }
+            PaletteItem::ThreeTerminal(component) => {
+                PaletteItem::ThreeTerminal(self.three_terminal(component))
+            }
+            PaletteItem::Ground => PaletteItem::Ground,
         }
     }
 
-    if ui.button("PNP").clicked() {
-        rebuild_sim = true;
-        editor.new_threeterminal(
-            diagram,
-            pos,
-            ThreeTerminalComponent::PTransistor(100.0),
-        );
+    /// Editor for the default value of every component in the palette which has any
+    pub fn edit(&mut self, ui: &mut Ui) {
+        for item in component_palette().into_iter().flat_map(|(_, items)| items) {
+            match self.apply(item) {
+                PaletteItem::TwoTerminal(mut component) => {
+                    if matches!(
+                        component,
+                        TwoTerminalComponent::Wire
+                            | TwoTerminalComponent::Diode
+                            | TwoTerminalComponent::CurrentProbe
+                    ) {
+                        continue;
+                    }
+                    let name = component.name();
+                    ui.push_id(name, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.strong(name);
+                            if self.two_terminal.contains_key(name)
+                                && ui.small_button("Reset").clicked()
+                            {
+                                self.two_terminal.remove(name);
+                            }
+                        });
+                        let old = component;
+                        edit_twoterminal_value(ui, &mut component, 0.0);
+                        if component != old {
+                            self.two_terminal.insert(name.into(), component);
+                        }
+                    });
+                }
+                PaletteItem::ThreeTerminal(mut component) => {
+                    let name = component.name();
+                    ui.push_id(name, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.strong(name);
+                            if self.three_terminal.contains_key(name)
+                                && ui.small_button("Reset").clicked()
+                            {
+                                self.three_terminal.remove(name);
+                            }
+                        });
+                        let old = component;
+                        edit_threeterminal_value(ui, &mut component);
+                        if component != old {
+                            self.three_terminal.insert(name.into(), component);
+                        }
+                    });
+                }
+                PaletteItem::Ground => continue,
+            }
+            ui.separator();
+        }
     }
-    if ui.button("NPN").clicked() {
-        rebuild_sim = true;
-        editor.new_threeterminal(
-            diagram,
-            pos,
-            ThreeTerminalComponent::NTransistor(100.0),
-        );
+}
+
+/// Components offered by the palette, grouped by category
+pub fn component_palette() -> Vec<(&'static str, Vec<PaletteItem>)> {
+    use PaletteItem::*;
+    vec![
+        (
+            "Passives",
+            vec![
+                TwoTerminal(TwoTerminalComponent::Wire),
+                TwoTerminal(TwoTerminalComponent::Resistor(1000.0)),
+                ThreeTerminal(ThreeTerminalComponent::Potentiometer(10e3, 0.5)),
+                TwoTerminal(TwoTerminalComponent::Inductor(
+                    1.0,
+                    None,
+                    None,
+                    None,
+                    DEFAULT_SERIES_RESISTANCE,
+                )),
+                TwoTerminal(TwoTerminalComponent::Capacitor(
+                    10e-6,
+                    None,
+                    None,
+                    DEFAULT_SERIES_RESISTANCE,
+                )),
+                TwoTerminal(TwoTerminalComponent::Crystal {
+                    ls: 10e-3,
+                    cs: 25e-15,
+                    rs: 10.0,
+                    cp: 5e-12,
+                }),
+            ],
+        ),
+        (
+            "Sources",
+            vec![
+                TwoTerminal(TwoTerminalComponent::Battery(5.0, None)),
+                TwoTerminal(TwoTerminalComponent::CurrentSource(0.1)),
+                TwoTerminal(TwoTerminalComponent::CurrentControlledSource(1.0, None)),
+                TwoTerminal(TwoTerminalComponent::NoiseSource {
+                    amplitude: 0.1,
+                    seed: 0,
+                }),
+                Ground,
+            ],
+        ),
+        (
+            "Semiconductors",
+            vec![
+                TwoTerminal(TwoTerminalComponent::Diode),
+                ThreeTerminal(ThreeTerminalComponent::PTransistor(
+                    100.0,
+                    10e-12,
+                    2e-12,
+                    DEFAULT_ALPHA_REVERSE,
+                )),
+                ThreeTerminal(ThreeTerminalComponent::NTransistor(
+                    100.0,
+                    10e-12,
+                    2e-12,
+                    DEFAULT_ALPHA_REVERSE,
+                )),
+            ],
+        ),
+        (
+            "Switches",
+            vec![TwoTerminal(TwoTerminalComponent::Switch(true))],
+        ),
+        (
+            "Measurement",
+            vec![TwoTerminal(TwoTerminalComponent::CurrentProbe)],
+        ),
+    ]
+}
+
+/// Shows the component palette, limited to components whose name contains `filter`.
+/// Returns true if the simulation needs rebuild
+pub fn show_add_component_buttons(
+    ui: &mut Ui,
+    pos: CellPos,
+    editor: &mut DiagramEditor,
+    diagram: &mut Diagram,
+    filter: &str,
+    defaults: &ComponentDefaults,
+) -> bool {
+    let mut rebuild_sim = false;
+
+    let filter = filter.to_lowercase();
+    let vis_opt = VisualizationOptions::default();
+
+    for (category, items) in component_palette() {
+        let items: Vec<PaletteItem> = items
+            .into_iter()
+            .filter(|item| item.name().to_lowercase().contains(&filter))
+            .map(|item| defaults.apply(item))
+            .collect();
+
+        if items.is_empty() {
+            continue;
+        }
+
+        ui.vertical(|ui| {
+            ui.weak(category);
+            ui.horizontal(|ui| {
+                for item in items {
+                    match item {
+                        PaletteItem::TwoTerminal(component) => {
+                            let resp = ui
+                                .push_id(component.name(), |ui| {
+                                    two_terminal_component_button(ui, component, &vis_opt)
+                                        .on_hover_text(format!("Add {}", component.name()))
+                                })
+                                .inner;
+
+                            if resp.clicked() {
+                                rebuild_sim = true;
+                                editor.new_twoterminal(diagram, pos, component);
+                            }
+                        }
+                        PaletteItem::ThreeTerminal(component) => {
+                            let resp = ui
+                                .button(component.name())
+                                .on_hover_text(format!("Add {}", component.name()));
+
+                            if resp.clicked() {
+                                rebuild_sim = true;
+                                editor.new_threeterminal(diagram, pos, component);
+                            }
+                        }
+                        PaletteItem::Ground => {
+                            if ui.button("Ground").on_hover_text("Add Ground").clicked() {
+                                rebuild_sim = true;
+                                editor.new_ground(diagram, pos);
+                            }
+                        }
+                    }
+                }
+            });
+        });
+        ui.separator();
     }
-    /*
-       if ui.button("Port").clicked() {
-       rebuild_sim = true;
-       editor.new_port(
-       &mut current_file.diagram,
-       pos,
-       "New port".into(),
-       );
-       }
-       */
-    /*
-       if ui.button("Delete").clicked() {
-       editor.delete();
-       }
-       ui.checkbox(&mut debug_draw, "Debug draw");
-       */
 
     rebuild_sim
 }
@@ -1141,3 +3632,552 @@ pub fn two_terminal_component_button(
 
     resp
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cirmcut_sim::solver::{Solver, SolverConfig, SolverMode};
+
+    /// Battery feeding a resistor loop and a transistor, laid out on the grid
+    fn sample_diagram() -> Diagram {
+        Diagram {
+            two_terminal: vec![
+                ([(0, 0), (0, 2)], TwoTerminalComponent::Battery(5.0, None)),
+                ([(0, 0), (2, 0)], TwoTerminalComponent::Resistor(1e3)),
+                ([(2, 0), (2, 2)], TwoTerminalComponent::Resistor(2e3)),
+                ([(0, 2), (2, 2)], TwoTerminalComponent::Wire),
+            ],
+            three_terminal: vec![(
+                [(2, 0), (3, 0), (2, 2)],
+                ThreeTerminalComponent::NTransistor(100.0, 0.0, 0.0, DEFAULT_ALPHA_REVERSE),
+            )],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn node_numbering_is_reproducible() {
+        let diagram = sample_diagram();
+        let first = diagram.to_primitive_diagram();
+
+        // Numbered by first appearance, so the implicit ground is the last new cell
+        let expected: BTreeMap<CellPos, usize> = [
+            ((0, 0), 0),
+            ((0, 2), 1),
+            ((2, 0), 2),
+            ((2, 2), 3),
+            ((3, 0), 4),
+        ]
+        .into();
+        assert_eq!(first.all_positions, expected);
+
+        // Same numbering every time, including after saving and reopening
+        let reopened: Diagram = ron::from_str(&ron::to_string(&diagram).unwrap()).unwrap();
+        for again in [
+            diagram.to_primitive_diagram(),
+            reopened.to_primitive_diagram(),
+        ] {
+            assert_eq!(again.all_positions, first.all_positions);
+            assert_eq!(
+                format!("{:?}", again.primitive),
+                format!("{:?}", first.primitive)
+            );
+        }
+    }
+
+    /// Runs one frame of `add_contents` in a central panel, with `events` as the input
+    fn run_frame(
+        ctx: &egui::Context,
+        events: Vec<egui::Event>,
+        add_contents: &mut impl FnMut(&mut Ui),
+    ) -> egui::FullOutput {
+        let input = egui::RawInput {
+            events,
+            ..Default::default()
+        };
+        ctx.run(input, |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| add_contents(ui));
+        })
+    }
+
+    /// Where the first text containing `text` was drawn
+    fn find_text(shapes: &[egui::epaint::ClippedShape], text: &str) -> Option<Rect> {
+        fn find(shape: &egui::Shape, text: &str) -> Option<Rect> {
+            match shape {
+                egui::Shape::Text(shape) if shape.galley.text().contains(text) => {
+                    Some(shape.visual_bounding_rect())
+                }
+                egui::Shape::Vec(shapes) => shapes.iter().find_map(|shape| find(shape, text)),
+                _ => None,
+            }
+        }
+        shapes.iter().find_map(|clipped| find(&clipped.shape, text))
+    }
+
+    /// Runs `add_contents` for a frame to find the first text containing `text`, then for two
+    /// more, clicking on that text
+    fn click_text(add_contents: &mut impl FnMut(&mut Ui), text: &str) {
+        let ctx = egui::Context::default();
+        let output = run_frame(&ctx, vec![], add_contents);
+        let pos = find_text(&output.shapes, text)
+            .unwrap_or_else(|| panic!("{text} isn't shown"))
+            .center();
+        let press = |pressed| egui::Event::PointerButton {
+            pos,
+            button: egui::PointerButton::Primary,
+            pressed,
+            modifiers: egui::Modifiers::NONE,
+        };
+        run_frame(
+            &ctx,
+            vec![egui::Event::PointerMoved(pos), press(true)],
+            add_contents,
+        );
+        run_frame(&ctx, vec![press(false)], add_contents);
+    }
+
+    #[test]
+    fn new_threeterminal_is_selected_and_edited() {
+        let mut diagram = sample_diagram();
+        let mut editor = DiagramEditor::new();
+        let transistor = ThreeTerminalComponent::PTransistor(50.0, 0.0, 0.0, DEFAULT_ALPHA_REVERSE);
+        editor.new_threeterminal(&mut diagram, (10, 10), transistor);
+
+        let idx = diagram.three_terminal.len() - 1;
+        assert_eq!(
+            editor.primary(&diagram),
+            Some((idx, SelectionType::ThreeTerminal))
+        );
+
+        // Click the rotate button, which only the three-terminal editor has
+        let before = diagram.clone();
+        let state = DiagramState::default_from_diagram(&diagram);
+        let measurements = MeasurementWindow::default();
+        let mut changed = false;
+        let mut edit = |ui: &mut Ui| {
+            changed |= editor.edit_component(
+                ui,
+                &mut diagram,
+                &state,
+                &measurements,
+                ValueFormat::default(),
+            );
+        };
+        click_text(&mut edit, "Rotate");
+
+        assert!(changed);
+        assert_eq!(diagram.two_terminal, before.two_terminal);
+        assert_ne!(diagram.three_terminal[idx].0, before.three_terminal[idx].0);
+        assert_eq!(diagram.three_terminal[idx].1, transistor);
+        // Turned about the middle terminal
+        assert_eq!(
+            diagram.three_terminal[idx].0[1],
+            before.three_terminal[idx].0[1]
+        );
+    }
+
+    #[test]
+    fn junctions_only_where_circuit_branches() {
+        // Two resistors in series meet without a dot
+        let series = Diagram {
+            two_terminal: vec![
+                ([(0, 0), (1, 0)], TwoTerminalComponent::Resistor(1e3)),
+                ([(1, 0), (2, 0)], TwoTerminalComponent::Resistor(1e3)),
+            ],
+            ..Default::default()
+        };
+        assert!(series.junctions().is_empty());
+
+        // A third one makes a tee
+        let mut tee = series.clone();
+        tee.two_terminal
+            .push(([(1, 0), (1, 1)], TwoTerminalComponent::Resistor(1e3)));
+        assert_eq!(tee.junctions(), [(1, 0)]);
+
+        // Three-terminal components count too, so the transistor adds dots to the loop where
+        // it joins the resistors, but not to the corners
+        let mut junctions = sample_diagram().junctions();
+        junctions.sort();
+        assert_eq!(junctions, [(2, 0), (2, 2)]);
+    }
+
+    #[test]
+    fn metric_prefix_edges() {
+        let format = |value| metric_prefix(value, 'V', SIGNIFICANT_FIGURES);
+        assert_eq!(format(999.9), "999.9 V");
+        // Rounds up into the next prefix instead of showing 1000
+        assert_eq!(format(999.96), "1 kV");
+        assert_eq!(format(1000.0), "1 kV");
+        assert_eq!(format(4700.0), "4.7 kV");
+        assert_eq!(format(0.001), "1 mV");
+        assert_eq!(format(0.0), "0 V");
+        // Smaller than the smallest prefix, yocto
+        assert_eq!(format(1e-25), "1.000e-25 V");
+        assert_eq!(format(-999.9), "-999.9 V");
+        assert_eq!(format(-1000.0), "-1 kV");
+        assert_eq!(format(-0.001), "-1 mV");
+        assert_eq!(format(-1e-25), "-1.000e-25 V");
+    }
+
+    #[test]
+    fn zero_length_resistor_is_left_out() {
+        let mut diagram = sample_diagram();
+        diagram
+            .two_terminal
+            .push(([(0, 0), (0, 0)], TwoTerminalComponent::Resistor(1.0)));
+        let idx = diagram.two_terminal.len() - 1;
+        assert_eq!(
+            diagram.collapsed_components(),
+            [(idx, SelectionType::TwoTerminal)]
+        );
+
+        // Stands in as an open switch, which keeps the indices lined up but adds no resistance
+        let rich = diagram.to_primitive_diagram();
+        assert!(matches!(
+            rich.primitive.two_terminal[idx].1,
+            TwoTerminalComponent::Switch(true)
+        ));
+
+        let solve = |primitive: &PrimitiveDiagram| {
+            let cfg = SolverConfig {
+                mode: SolverMode::Linear,
+                ..Default::default()
+            };
+            let mut solver = Solver::new(primitive);
+            solver.step(1e-3, primitive, &cfg, None).unwrap();
+            solver.state(primitive)
+        };
+        let with = solve(&rich.primitive);
+        let without = solve(&sample_diagram().to_primitive_diagram().primitive);
+        assert_eq!(with.two_terminal_current[idx], 0.0);
+        assert_eq!(with.voltages, without.voltages);
+        assert_eq!(
+            with.two_terminal_current[..idx],
+            without.two_terminal_current[..]
+        );
+    }
+
+    #[test]
+    fn nets_partition_cells_and_terminals() {
+        // A battery driving two resistors in parallel, with a third resistor hanging off a
+        // disabled wire
+        let diagram = Diagram {
+            two_terminal: vec![
+                // 0: Battery on the left
+                ([(0, 4), (0, 0)], TwoTerminalComponent::Battery(5.0, None)),
+                // 1-2: Top rail
+                ([(0, 0), (4, 0)], TwoTerminalComponent::Wire),
+                ([(4, 0), (8, 0)], TwoTerminalComponent::Wire),
+                // 3-4: Bottom rail
+                ([(0, 4), (4, 4)], TwoTerminalComponent::Wire),
+                ([(4, 4), (8, 4)], TwoTerminalComponent::Wire),
+                // 5-6: The branches
+                ([(4, 0), (4, 4)], TwoTerminalComponent::Resistor(1e3)),
+                ([(8, 0), (8, 4)], TwoTerminalComponent::Resistor(2e3)),
+                // 7: Disabled, so it joins nothing
+                ([(8, 4), (12, 4)], TwoTerminalComponent::Wire),
+                // 8: Only touches the disabled wire
+                ([(12, 4), (12, 8)], TwoTerminalComponent::Resistor(3e3)),
+            ],
+            disabled: vec![(7, SelectionType::TwoTerminal)],
+            ..Default::default()
+        };
+
+        let two = |idx| (idx, SelectionType::TwoTerminal);
+        let expected = [
+            Net {
+                cells: vec![(0, 0), (4, 0), (8, 0)],
+                terminals: vec![(two(0), 1), (two(5), 0), (two(6), 0)],
+            },
+            Net {
+                cells: vec![(0, 4), (4, 4), (8, 4)],
+                terminals: vec![(two(0), 0), (two(5), 1), (two(6), 1)],
+            },
+            Net {
+                cells: vec![(12, 4)],
+                terminals: vec![(two(8), 0)],
+            },
+            Net {
+                cells: vec![(12, 8)],
+                terminals: vec![(two(8), 1)],
+            },
+        ];
+        assert_eq!(diagram.nets(), expected);
+    }
+
+    #[test]
+    fn wire_state_draws() {
+        let states = [
+            DiagramWireState::ZERO,
+            DiagramWireState {
+                voltage: 2.5,
+                current: -1e-3,
+            },
+            DiagramWireState {
+                voltage: -1e300,
+                current: 1e300,
+            },
+            DiagramWireState {
+                voltage: f64::NAN,
+                current: f64::INFINITY,
+            },
+        ];
+        let (a, b) = (Pos2::new(0.0, 0.0), Pos2::new(0.0, CELL_SIZE));
+
+        let draw = |painter: &dyn Canvas| {
+            for mode in [
+                VisualizationMode::Voltage,
+                VisualizationMode::Current,
+                VisualizationMode::Power,
+            ] {
+                for color_scheme in [
+                    ColorScheme::RedGreen,
+                    ColorScheme::BlueYellow,
+                    ColorScheme::Viridis,
+                ] {
+                    for current_width in [false, true] {
+                        let vis = VisualizationOptions {
+                            mode,
+                            color_scheme,
+                            current_width,
+                            ..Default::default()
+                        };
+                        for state in states {
+                            for selected in [false, true] {
+                                state.wire(painter, a, b, selected, &vis);
+                                state.arrow(painter, a, b, selected, true, &vis);
+                                // Zero length
+                                state.wire(painter, a, a, selected, &vis);
+                            }
+                        }
+                    }
+                }
+            }
+        };
+
+        let ctx = egui::Context::default();
+        let _ = ctx.run(egui::RawInput::default(), |ctx| {
+            draw(&ctx.layer_painter(egui::LayerId::background()));
+        });
+        let svg = SvgCanvas::new(false);
+        draw(&svg);
+        svg.finish(1.0);
+    }
+
+    #[test]
+    fn adjacent_terminals_connect_orthogonally() {
+        let mut diagram = Diagram {
+            two_terminal: vec![
+                ([(0, 0), (2, 0)], TwoTerminalComponent::Resistor(1e3)),
+                // Diagonal from the resistor's start, and first in sorted order
+                ([(-1, -1), (-1, -3)], TwoTerminalComponent::Resistor(1e3)),
+                // Below the resistor's start
+                ([(0, 1), (0, 3)], TwoTerminalComponent::Resistor(1e3)),
+            ],
+            ..Default::default()
+        };
+        assert!(diagram.connect_adjacent_terminals((0, SelectionType::TwoTerminal)));
+        assert_eq!(
+            diagram.two_terminal[3..],
+            [([(0, 0), (0, 1)], TwoTerminalComponent::Wire)]
+        );
+
+        // Nothing left beside it
+        assert!(!diagram.connect_adjacent_terminals((1, SelectionType::TwoTerminal)));
+    }
+
+    #[test]
+    fn series_resistors_combine_only_in_line() {
+        for (far_end, combined) in [
+            ((4, 0), Some([(0, 0), (4, 0)])),
+            // Would make a diagonal resistor
+            ((2, 2), None),
+            // Would fold back onto the first one's start
+            ((0, 0), None),
+        ] {
+            let mut diagram = Diagram {
+                two_terminal: vec![
+                    ([(0, 0), (2, 0)], TwoTerminalComponent::Resistor(1e3)),
+                    ([(2, 0), far_end], TwoTerminalComponent::Resistor(2e3)),
+                ],
+                ..Default::default()
+            };
+            diagram.assign_ids();
+            let before = diagram.clone();
+
+            let mut editor = DiagramEditor::new();
+            editor.select(&diagram, (0, SelectionType::TwoTerminal));
+            editor.toggle_selected(&diagram, (1, SelectionType::TwoTerminal));
+            let state = DiagramState::default_from_diagram(&diagram);
+            let measurements = MeasurementWindow::default();
+            click_text(
+                &mut |ui| {
+                    editor.edit_component(
+                        ui,
+                        &mut diagram,
+                        &state,
+                        &measurements,
+                        ValueFormat::default(),
+                    );
+                },
+                "Series",
+            );
+
+            match combined {
+                Some(ends) => assert_eq!(
+                    diagram.two_terminal,
+                    [(ends, TwoTerminalComponent::Resistor(3e3))]
+                ),
+                None => assert_eq!(diagram.two_terminal, before.two_terminal),
+            }
+        }
+    }
+
+    #[test]
+    fn unconnected_reference_ground_is_reported() {
+        let mut diagram = sample_diagram();
+        diagram.grounds = vec![
+            ((20, 20), GroundKind::Reference),
+            ((0, 2), GroundKind::Tied(None)),
+        ];
+        let primitive = diagram.to_primitive_diagram().primitive;
+
+        let mut solver = Solver::new(&primitive);
+        let err = solver
+            .step(1e-3, &primitive, &SolverConfig::default(), None)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The reference ground isn't connected to anything"
+        );
+    }
+
+    #[test]
+    fn variables_keep_passives_positive() {
+        let mut diagram = Diagram {
+            two_terminal: vec![
+                ([(0, 0), (0, 1)], TwoTerminalComponent::Resistor(1e3)),
+                (
+                    [(1, 0), (1, 1)],
+                    TwoTerminalComponent::Capacitor(1e-6, None, None, 0.0),
+                ),
+                ([(2, 0), (2, 1)], TwoTerminalComponent::Battery(5.0, None)),
+            ],
+            ..Default::default()
+        };
+        diagram.assign_ids();
+        for idx in 0..3 {
+            let id = diagram.id((idx, SelectionType::TwoTerminal)).unwrap();
+            diagram.bindings.insert(id, "x".into());
+        }
+
+        // A source may go negative, but a resistor or capacitor may not
+        let variables = HashMap::from([("x".to_string(), -1.0)]);
+        assert!(diagram.apply_variables(&variables));
+        let values: Vec<f64> = diagram
+            .two_terminal
+            .iter()
+            .map(|(_, component)| component.value().unwrap())
+            .collect();
+        assert_eq!(values, [MIN_RESISTANCE, MIN_CAPACITANCE, -1.0]);
+
+        let variables = HashMap::from([("x".to_string(), f64::NAN)]);
+        diagram.apply_variables(&variables);
+        assert_eq!(diagram.two_terminal[0].1.value(), Some(MIN_RESISTANCE));
+    }
+
+    #[test]
+    fn removing_sensed_component_stops_mirror() {
+        // A battery across two resistors, one of them sensed, and a mirror into a load
+        let mut diagram = Diagram {
+            two_terminal: vec![
+                ([(0, 0), (0, 2)], TwoTerminalComponent::Battery(5.0, None)),
+                ([(0, 0), (0, 2)], TwoTerminalComponent::Resistor(1e3)),
+                ([(0, 0), (0, 2)], TwoTerminalComponent::Resistor(500.0)),
+                (
+                    [(0, 2), (1, 0)],
+                    TwoTerminalComponent::CurrentControlledSource(1.0, None),
+                ),
+                ([(1, 0), (0, 2)], TwoTerminalComponent::Resistor(1e3)),
+            ],
+            grounds: vec![((0, 2), GroundKind::Reference)],
+            ..Default::default()
+        };
+        diagram.assign_ids();
+        let source = diagram.id((3, SelectionType::TwoTerminal)).unwrap();
+        let sensed = diagram.id((1, SelectionType::TwoTerminal)).unwrap();
+        diagram.sensed.insert(source, sensed);
+
+        let load_current = |diagram: &Diagram| {
+            let primitive = diagram.to_primitive_diagram().primitive;
+            let mut solver = Solver::new(&primitive);
+            let cfg = SolverConfig {
+                mode: SolverMode::Linear,
+                ..Default::default()
+            };
+            solver.step(1e-3, &primitive, &cfg, None).unwrap();
+            let currents = solver.state(&primitive).two_terminal_current;
+            *currents.last().unwrap()
+        };
+        assert!((load_current(&diagram).abs() - 5e-3).abs() < 1e-12);
+
+        // The 500 Ω resistor slides into the sensed one's index, but the source mustn't follow it
+        diagram.remove((1, SelectionType::TwoTerminal));
+        assert!(diagram.sensed.is_empty());
+        assert_eq!(load_current(&diagram), 0.0);
+    }
+
+    #[test]
+    fn mirroring_twice_restores_three_terminal() {
+        // Collector, base and emitter of a transistor
+        let positions = [(2, 0), (3, 1), (2, 2)];
+        assert_eq!(mirror(positions, Axis::X), [(3, 0), (2, 1), (3, 2)]);
+        assert_eq!(mirror(positions, Axis::Y), [(2, 2), (3, 1), (2, 0)]);
+        for axis in [Axis::X, Axis::Y] {
+            assert_eq!(mirror(mirror(positions, axis), axis), positions);
+        }
+    }
+
+    #[test]
+    fn tolerances_follow_components() {
+        let mut diagram = Diagram {
+            two_terminal: vec![
+                ([(0, 0), (0, 2)], TwoTerminalComponent::Battery(5.0, None)),
+                ([(0, 0), (2, 0)], TwoTerminalComponent::Resistor(1e3)),
+                (
+                    [(2, 0), (2, 2)],
+                    TwoTerminalComponent::Crystal {
+                        ls: 1e-3,
+                        cs: 1e-12,
+                        rs: 10.0,
+                        cp: 1e-12,
+                    },
+                ),
+                ([(0, 2), (2, 2)], TwoTerminalComponent::Wire),
+            ],
+            grounds: vec![
+                ((0, 2), GroundKind::Reference),
+                ((2, 0), GroundKind::Tied(Some(1e6))),
+            ],
+            ..Default::default()
+        };
+        diagram.assign_ids();
+        let id = |diagram: &Diagram, idx| diagram.id((idx, SelectionType::TwoTerminal)).unwrap();
+        diagram.tolerances.insert(id(&diagram, 1), 0.01);
+        diagram.tolerances.insert(id(&diagram, 2), 0.2);
+
+        // The diagram's own components, the tie to the reference, then the crystal's four parts
+        let rich = diagram.to_primitive_diagram();
+        assert_eq!(
+            diagram.tolerances(&rich, 0.05),
+            [0.05, 0.01, 0.2, 0.05, 0.0, 0.2, 0.2, 0.2, 0.2]
+        );
+
+        // Pasted copies keep theirs, and removed components take theirs with them
+        let copy = diagram.clone();
+        diagram.paste(copy, (10, 0));
+        assert_eq!(diagram.tolerances.get(&id(&diagram, 5)), Some(&0.01));
+        diagram.remove((1, SelectionType::TwoTerminal));
+        assert_eq!(diagram.tolerances.len(), 3);
+    }
+}
@@ -1,13 +1,15 @@
 use std::f32::consts::{PI, TAU};
 
-use cirmcut_sim::TwoTerminalComponent;
-use egui::{Align2, Color32, Painter, Pos2, Shape, Stroke, Vec2};
-use egui_simpletabs::to_metric_prefix;
+use cirmcut_sim::{GroundKind, TwoTerminalComponent};
+use egui::{Align2, Color32, Pos2, Stroke, Vec2};
 
-use crate::circuit_widget::{DiagramWireState, VisualizationOptions, CELL_SIZE};
+use crate::{
+    canvas::Canvas,
+    circuit_widget::{format_value, DiagramWireState, ValueFormat, VisualizationOptions},
+};
 
 pub fn draw_transistor(
-    painter: &Painter,
+    painter: &dyn Canvas,
     pos: [Pos2; 3],
     wires: [DiagramWireState; 3],
     selected: bool,
@@ -17,7 +19,8 @@ pub fn draw_transistor(
     let [emitter_in, base_in, collector_in] = pos;
     let [emitter_wire, base_wire, collector_wire] = wires;
 
-    let orient = (base_in - (emitter_in + collector_in.to_vec2()) / 2.0).normalized() * CELL_SIZE;
+    let orient =
+        (base_in - (emitter_in + collector_in.to_vec2()) / 2.0).normalized() * vis.cell_size;
     let center = (emitter_in + base_in.to_vec2() + collector_in.to_vec2()) / 3.0;
 
     let orient_x = orient.rot90();
@@ -41,7 +44,13 @@ pub fn draw_transistor(
 
     let conn_radius = 0.10;
 
-    let ty_orient = if p_type { -orient_x } else { orient_x };
+    // Draw the emitter on the side its terminal is on, so that its lead doesn't cross the
+    // collector's. The arrow tells the two types apart.
+    let ty_orient = if (emitter_in - center).dot(orient_x) < 0.0 {
+        -orient_x
+    } else {
+        orient_x
+    };
     let emitter_input_tap = center + (ty_orient) * 0.25;
     let collector_input_tap = center + (-ty_orient) * 0.25;
 
@@ -68,7 +77,7 @@ pub fn draw_transistor(
 }
 
 pub fn draw_resistor(
-    painter: &Painter,
+    painter: &dyn Canvas,
     pos: [Pos2; 2],
     wires: [DiagramWireState; 2],
     selected: bool,
@@ -77,9 +86,9 @@ pub fn draw_resistor(
     let [begin, end] = pos;
     let [begin_wire, end_wire] = wires;
 
-    let (begin_segment, end_segment, y) = center_cell_segment(begin, end, CELL_SIZE);
+    let (begin_segment, end_segment, y) = center_cell_segment(begin, end, vis.cell_size);
 
-    let y = y * CELL_SIZE;
+    let y = y * vis.cell_size;
     let x = y.rot90();
 
     begin_wire.line_segment(painter, begin, begin_segment, selected, vis);
@@ -112,6 +121,88 @@ pub fn draw_resistor(
     begin_wire.current(painter, begin, end, vis);
 }
 
+/// Resistor between the `a` and `c` terminals, with an arrow from the wiper (`b`) pointing at it
+pub fn draw_potentiometer(
+    painter: &dyn Canvas,
+    pos: [Pos2; 3],
+    wires: [DiagramWireState; 3],
+    selected: bool,
+    vis: &VisualizationOptions,
+) {
+    let [a, wiper, c] = pos;
+    let [a_wire, wiper_wire, c_wire] = wires;
+
+    draw_resistor(painter, [a, c], [a_wire, c_wire], selected, vis);
+
+    // Stop just short of the zigzag, on the wiper's side of it
+    let middle = a + (c - a) / 2.0;
+    let across = (c - a).normalized().rot90();
+    let across = if across.dot(wiper - middle) < 0.0 {
+        -across
+    } else {
+        across
+    };
+    let tip = middle + across * vis.cell_size * 0.15;
+
+    wiper_wire.arrow(painter, tip, wiper, selected, true, vis);
+}
+
+/// Faint arrow beside the line from `from` to `to`, pointing the way a positive current is
+/// counted, with `label` at its middle. Shown with the debug drawing.
+pub fn draw_reference_direction(
+    painter: &dyn Canvas,
+    from: Pos2,
+    to: Pos2,
+    label: &str,
+    vis: &VisualizationOptions,
+) {
+    let color = Color32::from_white_alpha(96);
+    let along = (to - from).normalized();
+    let across = along.rot90() * vis.cell_size * 0.2;
+
+    let start = from + (to - from) * 0.25 + across;
+    let tip = from + (to - from) * 0.75 + across;
+    painter.line_segment([start, tip], Stroke::new(1.0, color));
+
+    let head = along * vis.cell_size * 0.08;
+    painter.convex_polygon(
+        vec![
+            tip,
+            tip - head + head.rot90() / 2.0,
+            tip - head - head.rot90() / 2.0,
+        ],
+        color,
+        Stroke::NONE,
+    );
+
+    painter.text(
+        from + (to - from) * 0.5 + across * 1.6,
+        Align2::CENTER_CENTER,
+        label,
+        color,
+    );
+}
+
+/// Small labels next to each terminal of a three-terminal component, so it's clear which way
+/// round it's wired
+pub fn draw_terminal_labels(
+    painter: &dyn Canvas,
+    pos: [Pos2; 3],
+    labels: [&str; 3],
+    vis: &VisualizationOptions,
+) {
+    let center = (pos[0] + pos[1].to_vec2() + pos[2].to_vec2()) / 3.0;
+    for (pos, label) in pos.into_iter().zip(labels) {
+        let outward = (pos - center).normalized();
+        painter.text(
+            pos + outward * vis.cell_size * 0.2,
+            Align2::CENTER_CENTER,
+            label,
+            Color32::LIGHT_GRAY,
+        );
+    }
+}
+
 fn center_cell_segment(a: Pos2, b: Pos2, len: f32) -> (Pos2, Pos2, Vec2) {
     let diff = b - a;
     let remain = (diff.length() - len).max(0.0);
@@ -121,7 +212,7 @@ fn center_cell_segment(a: Pos2, b: Pos2, len: f32) -> (Pos2, Pos2, Vec2) {
 }
 
 pub fn draw_inductor(
-    painter: &Painter,
+    painter: &dyn Canvas,
     pos: [Pos2; 2],
     wires: [DiagramWireState; 2],
     selected: bool,
@@ -130,14 +221,18 @@ pub fn draw_inductor(
     let [begin, end] = pos;
     let [begin_wire, end_wire] = wires;
 
-    let (begin_segment, end_segment, y) = center_cell_segment(begin, end, CELL_SIZE);
+    let (begin_segment, end_segment, y) = center_cell_segment(begin, end, vis.cell_size);
 
-    let y = y * CELL_SIZE;
+    let y = y * vis.cell_size;
     let x = y.rot90();
 
     begin_wire.line_segment(painter, begin, begin_segment, selected, vis);
     end_wire.line_segment(painter, end_segment, end, selected, vis);
 
+    // Thicken the coil with current, to hint at the field strength
+    let field = (begin_wire.current.abs() / vis.current_scale).min(1.0) as f32;
+    let width = 3.0 * (1.0 + field);
+
     let steps = 100;
 
     let mut last = begin_segment;
@@ -154,9 +249,10 @@ pub fn draw_inductor(
         let yf = (((t.cos() - 1.0) * k.cos()) + t * a) / (TAU * n_loops as f32 * a);
 
         let new_pos = begin_segment + x * xf + y * yf;
-        begin_wire
+        let color = begin_wire
             .lerp_voltage(&end_wire, f as f64)
-            .line_segment(painter, last, new_pos, selected, vis);
+            .color(selected, vis);
+        painter.line_segment([last, new_pos], Stroke::new(width, color));
 
         last = new_pos;
     }
@@ -165,7 +261,7 @@ pub fn draw_inductor(
 }
 
 fn draw_capacitorlike(
-    painter: &Painter,
+    painter: &dyn Canvas,
     pos: [Pos2; 2],
     wires: [DiagramWireState; 2],
     selected: bool,
@@ -176,10 +272,10 @@ fn draw_capacitorlike(
     let [begin, end] = pos;
     let [begin_wire, end_wire] = wires;
 
-    let sep = 0.1 * CELL_SIZE;
+    let sep = 0.1 * vis.cell_size;
     let (begin_segment, end_segment, y) = center_cell_segment(begin, end, sep);
 
-    let y = y * CELL_SIZE;
+    let y = y * vis.cell_size;
     let x = y.rot90();
 
     begin_wire.line_segment(painter, begin, begin_segment, selected, vis);
@@ -205,18 +301,44 @@ fn draw_capacitorlike(
 }
 
 pub fn draw_capacitor(
-    painter: &Painter,
+    painter: &dyn Canvas,
     pos: [Pos2; 2],
     wires: [DiagramWireState; 2],
     selected: bool,
+    capacitance: f64,
     vis: &VisualizationOptions,
 ) {
     let radius = 0.2;
+
+    // Fill the gap between the plates in proportion to the stored charge, relative to the
+    // charge held at the voltage scale
+    let [begin, end] = pos;
+    let [begin_wire, end_wire] = wires;
+    let charge = capacitance * (end_wire.voltage - begin_wire.voltage);
+    let full_charge = capacitance * vis.voltage_scale;
+    let fill = (charge / full_charge).abs().min(1.0) as f32;
+
+    if fill > 0.0 {
+        let (begin_segment, end_segment, y) = center_cell_segment(begin, end, 0.1 * vis.cell_size);
+        let x = y.rot90() * vis.cell_size * radius * fill;
+        let charged = if charge > 0.0 { end_wire } else { begin_wire };
+        painter.convex_polygon(
+            vec![
+                begin_segment - x,
+                begin_segment + x,
+                end_segment + x,
+                end_segment - x,
+            ],
+            charged.color(selected, vis).gamma_multiply(0.4),
+            Stroke::NONE,
+        );
+    }
+
     draw_capacitorlike(painter, pos, wires, selected, radius, radius, vis);
 }
 
 pub fn draw_battery(
-    painter: &Painter,
+    painter: &dyn Canvas,
     pos: [Pos2; 2],
     wires: [DiagramWireState; 2],
     selected: bool,
@@ -225,8 +347,64 @@ pub fn draw_battery(
     draw_capacitorlike(painter, pos, wires, selected, 0.1, 0.2, vis);
 }
 
+pub fn draw_crystal(
+    painter: &dyn Canvas,
+    pos: [Pos2; 2],
+    wires: [DiagramWireState; 2],
+    selected: bool,
+    vis: &VisualizationOptions,
+) {
+    let [begin, end] = pos;
+    let [begin_wire, end_wire] = wires;
+
+    let sep = 0.3 * vis.cell_size;
+    let (begin_segment, end_segment, y) = center_cell_segment(begin, end, sep);
+
+    let y = y * vis.cell_size;
+    let x = y.rot90();
+
+    begin_wire.line_segment(painter, begin, begin_segment, selected, vis);
+    end_wire.line_segment(painter, end_segment, end, selected, vis);
+
+    let plate = 0.25;
+    begin_wire.line_segment(
+        painter,
+        begin_segment - x * plate,
+        begin_segment + x * plate,
+        selected,
+        vis,
+    );
+    end_wire.line_segment(
+        painter,
+        end_segment - x * plate,
+        end_segment + x * plate,
+        selected,
+        vis,
+    );
+
+    // The quartz, floating between the plates
+    let mid = begin_wire.lerp_voltage(&end_wire, 0.5);
+    let corners = [
+        begin_segment + y * 0.07 - x * 0.17,
+        begin_segment + y * 0.07 + x * 0.17,
+        end_segment - y * 0.07 + x * 0.17,
+        end_segment - y * 0.07 - x * 0.17,
+    ];
+    for i in 0..corners.len() {
+        mid.line_segment(
+            painter,
+            corners[i],
+            corners[(i + 1) % corners.len()],
+            selected,
+            vis,
+        );
+    }
+
+    begin_wire.current(painter, begin, end, vis);
+}
+
 pub fn draw_diode(
-    painter: &Painter,
+    painter: &dyn Canvas,
     pos: [Pos2; 2],
     wires: [DiagramWireState; 2],
     selected: bool,
@@ -237,10 +415,10 @@ pub fn draw_diode(
 
     let size = 0.2;
 
-    let sep = size * 2.0 * CELL_SIZE;
+    let sep = size * 2.0 * vis.cell_size;
     let (begin_segment, end_segment, y) = center_cell_segment(begin, end, sep);
 
-    let y = y * CELL_SIZE;
+    let y = y * vis.cell_size;
     let x = y.rot90();
 
     begin_wire.line_segment(painter, begin, begin_segment, selected, vis);
@@ -256,7 +434,7 @@ pub fn draw_diode(
         vis,
     );
 
-    painter.add(Shape::convex_polygon(
+    painter.convex_polygon(
         vec![
             end_segment,
             begin_segment + x * plate_radius,
@@ -264,13 +442,13 @@ pub fn draw_diode(
         ],
         begin_wire.color(selected, vis),
         Stroke::NONE,
-    ));
+    );
 
     begin_wire.current(painter, begin, end, vis);
 }
 
 pub fn draw_switch(
-    painter: &Painter,
+    painter: &dyn Canvas,
     pos: [Pos2; 2],
     wires: [DiagramWireState; 2],
     selected: bool,
@@ -280,9 +458,9 @@ pub fn draw_switch(
     let [begin, end] = pos;
     let [begin_wire, end_wire] = wires;
 
-    let (begin_segment, end_segment, y) = center_cell_segment(begin, end, CELL_SIZE);
+    let (begin_segment, end_segment, y) = center_cell_segment(begin, end, vis.cell_size);
 
-    let y = y * CELL_SIZE;
+    let y = y * vis.cell_size;
     let x = y.rot90();
 
     begin_wire.line_segment(painter, begin, begin_segment, selected, vis);
@@ -300,64 +478,245 @@ pub fn draw_switch(
     begin_wire.current(painter, begin, end, vis);
 }
 
+/// The arrow follows the sign of `current`: towards the end terminal if positive, towards the
+/// beginning if negative, and absent if zero
 pub fn draw_current_source(
-    painter: &Painter,
+    painter: &dyn Canvas,
+    pos: [Pos2; 2],
+    wires: [DiagramWireState; 2],
+    selected: bool,
+    current: f64,
+    vis: &VisualizationOptions,
+) {
+    let [begin, end] = pos;
+    let [begin_wire, end_wire] = wires;
+
+    let r = 0.25 * vis.cell_size;
+    let (begin_segment, end_segment, y) = center_cell_segment(begin, end, r * 2.0);
+
+    let center = begin_segment.lerp(end_segment, 0.5);
+
+    painter.circle_stroke(center, r, Stroke::new(1.0, Color32::DARK_GRAY));
+
+    begin_wire.line_segment(painter, begin, begin_segment, selected, vis);
+    end_wire.line_segment(painter, end_segment, end, selected, vis);
+
+    let (arrow_begin, arrow_end, _) = center_cell_segment(begin, end, r * 1.5);
+    if current > 0.0 {
+        DiagramWireState::default().arrow_segment(painter, arrow_end, arrow_begin, selected, vis);
+    } else if current < 0.0 {
+        DiagramWireState::default().arrow_segment(painter, arrow_begin, arrow_end, selected, vis);
+    }
+
+    begin_wire.current(painter, begin, end, vis);
+}
+
+/// A circle, as independent sources are drawn, with a zigzag across it for the noise
+pub fn draw_noise_source(
+    painter: &dyn Canvas,
+    pos: [Pos2; 2],
+    wires: [DiagramWireState; 2],
+    selected: bool,
+    vis: &VisualizationOptions,
+) {
+    let [begin, end] = pos;
+    let [begin_wire, end_wire] = wires;
+
+    let r = 0.25 * vis.cell_size;
+    let (begin_segment, end_segment, y) = center_cell_segment(begin, end, r * 2.0);
+
+    let center = begin_segment.lerp(end_segment, 0.5);
+    let stroke = Stroke::new(1.0, Color32::DARK_GRAY);
+    painter.circle_stroke(center, r, stroke);
+
+    let n = 6;
+    let x = y.rot90() * r * 0.4;
+    let zigzag: Vec<Pos2> = (0..=n)
+        .map(|i| {
+            let along = y * r * 1.2 * (i as f32 / n as f32 - 0.5);
+            let across = if i == 0 || i == n {
+                Vec2::ZERO
+            } else if i % 2 == 0 {
+                -x
+            } else {
+                x
+            };
+            center + along + across
+        })
+        .collect();
+    for pair in zigzag.windows(2) {
+        painter.line_segment([pair[0], pair[1]], stroke);
+    }
+
+    begin_wire.line_segment(painter, begin, begin_segment, selected, vis);
+    end_wire.line_segment(painter, end_segment, end, selected, vis);
+
+    begin_wire.current(painter, begin, end, vis);
+}
+
+/// A diamond, as dependent sources are drawn, with an arrow the way the current goes for a
+/// positive sensed current
+pub fn draw_controlled_current_source(
+    painter: &dyn Canvas,
     pos: [Pos2; 2],
     wires: [DiagramWireState; 2],
     selected: bool,
+    gain: f64,
     vis: &VisualizationOptions,
 ) {
     let [begin, end] = pos;
     let [begin_wire, end_wire] = wires;
 
-    let r = 0.25 * CELL_SIZE;
+    let r = 0.25 * vis.cell_size;
     let (begin_segment, end_segment, y) = center_cell_segment(begin, end, r * 2.0);
 
+    let center = begin_segment.lerp(end_segment, 0.5);
+    let x = y.rot90() * r;
+    let corners = [begin_segment, center + x, end_segment, center - x];
+    for i in 0..corners.len() {
+        painter.line_segment(
+            [corners[i], corners[(i + 1) % corners.len()]],
+            Stroke::new(1.0, Color32::DARK_GRAY),
+        );
+    }
+
+    begin_wire.line_segment(painter, begin, begin_segment, selected, vis);
+    end_wire.line_segment(painter, end_segment, end, selected, vis);
+
+    let (arrow_begin, arrow_end, _) = center_cell_segment(begin, end, r * 1.5);
+    if gain > 0.0 {
+        DiagramWireState::default().arrow_segment(painter, arrow_end, arrow_begin, selected, vis);
+    } else if gain < 0.0 {
+        DiagramWireState::default().arrow_segment(painter, arrow_begin, arrow_end, selected, vis);
+    }
+
+    begin_wire.current(painter, begin, end, vis);
+}
+
+pub fn draw_current_probe(
+    painter: &dyn Canvas,
+    pos: [Pos2; 2],
+    wires: [DiagramWireState; 2],
+    selected: bool,
+    vis: &VisualizationOptions,
+) {
+    let [begin, end] = pos;
+    let [begin_wire, end_wire] = wires;
+
+    let r = 0.2 * vis.cell_size;
+    let (begin_segment, end_segment, _) = center_cell_segment(begin, end, r * 2.0);
+
     let center = begin_segment.lerp(end_segment, 0.5);
 
     painter.circle_stroke(center, r, Stroke::new(1.0, Color32::DARK_GRAY));
+    painter.text(center, Align2::CENTER_CENTER, "A", Color32::LIGHT_GRAY);
 
     begin_wire.line_segment(painter, begin, begin_segment, selected, vis);
     end_wire.line_segment(painter, end_segment, end, selected, vis);
 
-    let (arrow_begin, arrow_end, y) = center_cell_segment(begin, end, r * 1.5);
-    DiagramWireState::default().arrow_segment(painter, arrow_end, arrow_begin, selected, vis);
+    // Reading, where other components show their value
+    let reading = format_value(begin_wire.current, 'A', vis.value_format);
+    let x = label_normal(begin, end, false) * vis.cell_size;
+    painter.text(
+        center + x * 0.35,
+        Align2::CENTER_CENTER,
+        &reading,
+        Color32::WHITE,
+    );
 
     begin_wire.current(painter, begin, end, vis);
 }
 
-pub fn draw_component_value(painter: &Painter, pos: [Pos2; 2], component: TwoTerminalComponent) {
-    if let Some(text) = format_component_value(component) {
-        let diff = pos[1] - pos[0];
-        let y = diff.normalized() * CELL_SIZE;
-        let x = y.rot90();
+pub fn draw_ground(painter: &dyn Canvas, pos: Pos2, kind: GroundKind, vis: &VisualizationOptions) {
+    let color = match kind {
+        GroundKind::Reference => Color32::LIGHT_GRAY,
+        GroundKind::Tied(_) => Color32::GRAY,
+    };
+    let stroke = Stroke::new(3.0, color);
+
+    let down = Vec2::Y * vis.cell_size;
+    let across = Vec2::X * vis.cell_size;
+
+    let top = pos + down * 0.2;
+    painter.line_segment([pos, top], stroke);
+    for (i, half_width) in [0.2, 0.13, 0.06].into_iter().enumerate() {
+        let y = top + down * 0.07 * i as f32;
+        painter.line_segment([y - across * half_width, y + across * half_width], stroke);
+    }
+
+    let label = match kind {
+        GroundKind::Reference => "0 V".to_string(),
+        GroundKind::Tied(None) => return,
+        GroundKind::Tied(Some(resistance)) => format_value(resistance, 'Ω', vis.value_format),
+    };
+    painter.text(pos + across * 0.25, Align2::LEFT_CENTER, &label, color);
+}
+
+/// Unit vector pointing from a two-terminal component to the side its value label goes on:
+/// above it, or to the right if it's vertical, or the opposite side if `flip` is set. This only
+/// depends on the component's orientation, so flipping its terminals doesn't move the label.
+pub fn label_normal(begin: Pos2, end: Pos2, flip: bool) -> Vec2 {
+    let normal = (end - begin).normalized().rot90();
+    let normal = if normal.y > 0.0 || (normal.y == 0.0 && normal.x < 0.0) {
+        -normal
+    } else {
+        normal
+    };
+    if flip {
+        -normal
+    } else {
+        normal
+    }
+}
+
+pub fn draw_component_value(
+    painter: &dyn Canvas,
+    pos: [Pos2; 2],
+    component: TwoTerminalComponent,
+    flip_label: bool,
+    vis: &VisualizationOptions,
+) {
+    if let Some(text) = format_component_value(component, vis.value_format) {
+        let x = label_normal(pos[0], pos[1], flip_label) * vis.cell_size;
 
         let midpt = (pos[0] + pos[1].to_vec2()) / 2.0;
 
         let pos = midpt + x * 0.35;
 
-        painter.text(
-            pos,
-            Align2::CENTER_CENTER,
-            text,
-            Default::default(),
-            Color32::WHITE,
-        );
+        painter.text(pos, Align2::CENTER_CENTER, &text, Color32::WHITE);
     }
 }
 
-fn format_component_value(component: TwoTerminalComponent) -> Option<String> {
+pub fn format_component_value(
+    component: TwoTerminalComponent,
+    format: ValueFormat,
+) -> Option<String> {
     match component {
-        TwoTerminalComponent::Battery(v) => Some(to_metric_prefix(v, 'V')),
-        TwoTerminalComponent::Capacitor(c) => Some(to_metric_prefix(c, 'F')),
-        TwoTerminalComponent::Inductor(i, maybe_core_id) => {
-            let mut prefix = to_metric_prefix(i, 'H');
+        TwoTerminalComponent::Battery(v, None) => Some(format_value(v, 'V', format)),
+        TwoTerminalComponent::Battery(v, Some(limit)) => Some(format!(
+            "{}, {} max",
+            format_value(v, 'V', format),
+            format_value(limit, 'A', format)
+        )),
+        TwoTerminalComponent::Capacitor(c, ..) => Some(format_value(c, 'F', format)),
+        TwoTerminalComponent::Inductor(i, maybe_core_id, ..) => {
+            let mut prefix = format_value(i, 'H', format);
             if let Some(id) = maybe_core_id {
                 prefix.push_str(&format!(" (Tf. {id})"));
             }
             Some(prefix)
         }
-        TwoTerminalComponent::Resistor(r) => Some(to_metric_prefix(r, 'Ω')),
+        TwoTerminalComponent::Resistor(r) => Some(format_value(r, 'Ω', format)),
+        // The arrow shows the direction
+        TwoTerminalComponent::CurrentSource(i) => Some(format_value(i.abs(), 'A', format)),
+        TwoTerminalComponent::CurrentControlledSource(gain, _) => Some(format!("×{}", gain.abs())),
+        TwoTerminalComponent::Crystal { ls, cs, .. } => {
+            let resonance = 1.0 / (TAU as f64 * (ls * cs).sqrt());
+            Some(format_value(resonance, 'H', format) + "z")
+        }
+        TwoTerminalComponent::NoiseSource { amplitude, .. } => {
+            Some(format_value(amplitude, 'V', format) + " RMS")
+        }
         _ => None,
     }
 }
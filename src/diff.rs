@@ -0,0 +1,342 @@
+use std::mem::discriminant;
+
+use cirmcut_sim::{GroundKind, ThreeTerminalComponent};
+
+use crate::{
+    circuit_widget::{format_value, CellPos, ComponentId, Diagram, SelectionType, ValueFormat},
+    components::format_component_value,
+};
+
+/// Whether a component only exists in the new diagram, only in the old one, or in both but
+/// differently
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// One line of the report from `diff()`
+#[derive(Clone, Debug)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub text: String,
+    /// The component in the new diagram, if it's still there
+    pub id: Option<ComponentId>,
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = match self.kind {
+            ChangeKind::Added => '+',
+            ChangeKind::Removed => '-',
+            ChangeKind::Changed => '~',
+        };
+        write!(f, "{sign} {}", self.text)
+    }
+}
+
+/// Compares two versions of a diagram, component by component.
+///
+/// When both are copies of the same file, components are paired by ID first, so that ones which
+/// were moved or edited are recognized. The rest, and all components of files drawn separately,
+/// are paired by position. Either way both have to be the same kind of component. Grounds have
+/// no IDs, and are only compared by position.
+pub fn diff(old: &Diagram, new: &Diagram, format: ValueFormat) -> Vec<Change> {
+    let mut changes = vec![];
+
+    for ty in [
+        SelectionType::Port,
+        SelectionType::TwoTerminal,
+        SelectionType::ThreeTerminal,
+    ] {
+        let pairs = pair(old, new, ty);
+
+        for (new_idx, old_idx) in pairs.iter().enumerate() {
+            let id = new.id((new_idx, ty));
+            match old_idx {
+                Some(old_idx) => {
+                    let details = differences(old, new, (*old_idx, ty), (new_idx, ty), format);
+                    if !details.is_empty() {
+                        changes.push(Change {
+                            kind: ChangeKind::Changed,
+                            text: format!(
+                                "{}: {}",
+                                describe(old, (*old_idx, ty), format),
+                                details.join(", ")
+                            ),
+                            id,
+                        });
+                    }
+                }
+                None => changes.push(Change {
+                    kind: ChangeKind::Added,
+                    text: describe(new, (new_idx, ty), format),
+                    id,
+                }),
+            }
+        }
+
+        for old_idx in 0..len(old, ty) {
+            if !pairs.contains(&Some(old_idx)) {
+                changes.push(Change {
+                    kind: ChangeKind::Removed,
+                    text: describe(old, (old_idx, ty), format),
+                    id: None,
+                });
+            }
+        }
+    }
+
+    for &(pos, kind) in &new.grounds {
+        if !old.grounds.contains(&(pos, kind)) {
+            changes.push(Change {
+                kind: ChangeKind::Added,
+                text: describe_ground(pos, kind, format),
+                id: None,
+            });
+        }
+    }
+    for &(pos, kind) in &old.grounds {
+        if !new.grounds.contains(&(pos, kind)) {
+            changes.push(Change {
+                kind: ChangeKind::Removed,
+                text: describe_ground(pos, kind, format),
+                id: None,
+            });
+        }
+    }
+
+    changes
+}
+
+/// For each component of the given type in `new`, the index of the same one in `old`
+fn pair(old: &Diagram, new: &Diagram, ty: SelectionType) -> Vec<Option<usize>> {
+    let mut pairs = vec![None; len(new, ty)];
+    let mut taken = vec![false; len(old, ty)];
+
+    // Every diagram numbers its components from 0, so IDs only mean something within copies of
+    // the same one
+    let same_origin = old.ids.origin != 0 && old.ids.origin == new.ids.origin;
+    for (new_idx, slot) in pairs.iter_mut().enumerate() {
+        let Some(id) = new.id((new_idx, ty)).filter(|_| same_origin) else {
+            continue;
+        };
+        let found = old.ids.list(ty).iter().position(|&other| other == id);
+        if let Some(old_idx) = found {
+            if !taken[old_idx] && same_kind(old, new, (old_idx, new_idx), ty) {
+                taken[old_idx] = true;
+                *slot = Some(old_idx);
+            }
+        }
+    }
+
+    for (new_idx, slot) in pairs.iter_mut().enumerate() {
+        if slot.is_some() {
+            continue;
+        }
+        let cells = new.cells((new_idx, ty));
+        let found = (0..len(old, ty)).find(|&old_idx| {
+            !taken[old_idx]
+                && old.cells((old_idx, ty)) == cells
+                && same_kind(old, new, (old_idx, new_idx), ty)
+        });
+        if let Some(old_idx) = found {
+            taken[old_idx] = true;
+            *slot = Some(old_idx);
+        }
+    }
+
+    pairs
+}
+
+fn len(diagram: &Diagram, ty: SelectionType) -> usize {
+    match ty {
+        SelectionType::Port => diagram.ports.len(),
+        SelectionType::TwoTerminal => diagram.two_terminal.len(),
+        SelectionType::ThreeTerminal => diagram.three_terminal.len(),
+    }
+}
+
+fn same_kind(
+    old: &Diagram,
+    new: &Diagram,
+    (old_idx, new_idx): (usize, usize),
+    ty: SelectionType,
+) -> bool {
+    match ty {
+        SelectionType::Port => true,
+        SelectionType::TwoTerminal => {
+            discriminant(&old.two_terminal[old_idx].1) == discriminant(&new.two_terminal[new_idx].1)
+        }
+        SelectionType::ThreeTerminal => {
+            discriminant(&old.three_terminal[old_idx].1)
+                == discriminant(&new.three_terminal[new_idx].1)
+        }
+    }
+}
+
+/// What differs between two paired components, as short phrases
+fn differences(
+    old: &Diagram,
+    new: &Diagram,
+    old_sel: (usize, SelectionType),
+    new_sel: (usize, SelectionType),
+    format: ValueFormat,
+) -> Vec<String> {
+    let mut details = vec![];
+
+    let same_value = match new_sel.1 {
+        SelectionType::Port => old.ports[old_sel.0].1 == new.ports[new_sel.0].1,
+        SelectionType::TwoTerminal => {
+            old.two_terminal[old_sel.0].1 == new.two_terminal[new_sel.0].1
+        }
+        SelectionType::ThreeTerminal => {
+            old.three_terminal[old_sel.0].1 == new.three_terminal[new_sel.0].1
+        }
+    };
+    if !same_value {
+        match (value(old, old_sel, format), value(new, new_sel, format)) {
+            (Some(before), Some(after)) if before != after => {
+                details.push(format!("{before} → {after}"))
+            }
+            // Something the summary doesn't show, like an initial condition
+            _ => details.push("settings changed".into()),
+        }
+    }
+
+    let cells = new.cells(new_sel);
+    if old.cells(old_sel) != cells {
+        details.push(format!("moved to {}", cell_list(&cells)));
+    }
+
+    match (old.is_enabled(old_sel), new.is_enabled(new_sel)) {
+        (true, false) => details.push("disabled".into()),
+        (false, true) => details.push("enabled".into()),
+        _ => (),
+    }
+
+    let old_binding = old.id(old_sel).and_then(|id| old.bindings.get(&id));
+    let new_binding = new.id(new_sel).and_then(|id| new.bindings.get(&id));
+    if old_binding != new_binding {
+        match new_binding {
+            Some(name) => details.push(format!("bound to {name}")),
+            None => details.push("unbound".into()),
+        }
+    }
+
+    let old_tolerance = old.id(old_sel).and_then(|id| old.tolerances.get(&id));
+    let new_tolerance = new.id(new_sel).and_then(|id| new.tolerances.get(&id));
+    if old_tolerance != new_tolerance {
+        match new_tolerance {
+            Some(tolerance) => details.push(format!("tolerance ±{}%", tolerance * 100.0)),
+            None => details.push("default tolerance".into()),
+        }
+    }
+
+    details
+}
+
+/// Kind, value and position of a component
+fn describe(diagram: &Diagram, sel: (usize, SelectionType), format: ValueFormat) -> String {
+    let name = match sel.1 {
+        SelectionType::Port => "Port",
+        SelectionType::TwoTerminal => diagram.two_terminal[sel.0].1.name(),
+        SelectionType::ThreeTerminal => diagram.three_terminal[sel.0].1.name(),
+    };
+    let cells = cell_list(&diagram.cells(sel));
+    match value(diagram, sel, format) {
+        Some(value) => format!("{name} {value} at {cells}"),
+        None => format!("{name} at {cells}"),
+    }
+}
+
+fn value(
+    diagram: &Diagram,
+    (idx, ty): (usize, SelectionType),
+    format: ValueFormat,
+) -> Option<String> {
+    match ty {
+        SelectionType::Port => Some(format!("\"{}\"", diagram.ports[idx].1)),
+        SelectionType::TwoTerminal => format_component_value(diagram.two_terminal[idx].1, format),
+        SelectionType::ThreeTerminal => Some(match diagram.three_terminal[idx].1 {
+            ThreeTerminalComponent::NTransistor(beta, ..)
+            | ThreeTerminalComponent::PTransistor(beta, ..) => format!("β = {beta}"),
+            ThreeTerminalComponent::Potentiometer(r, wiper) => {
+                format!("{} at {:.0}%", format_value(r, 'Ω', format), wiper * 100.0)
+            }
+        }),
+    }
+}
+
+fn describe_ground(pos: CellPos, kind: GroundKind, format: ValueFormat) -> String {
+    let kind = match kind {
+        GroundKind::Reference => "Reference ground".to_string(),
+        GroundKind::Tied(None) => "Tied ground".to_string(),
+        GroundKind::Tied(Some(r)) => {
+            format!("Tied ground through {}", format_value(r, 'Ω', format))
+        }
+    };
+    format!("{kind} at {}", cell_list(&[pos]))
+}
+
+fn cell_list(cells: &[CellPos]) -> String {
+    cells
+        .iter()
+        .map(|(x, y)| format!("({x}, {y})"))
+        .collect::<Vec<_>>()
+        .join("–")
+}
+
+#[cfg(test)]
+mod tests {
+    use cirmcut_sim::TwoTerminalComponent;
+
+    use super::*;
+
+    fn resistor_at(cells: [CellPos; 2], ohms: f64) -> Diagram {
+        let mut diagram = Diagram {
+            two_terminal: vec![(cells, TwoTerminalComponent::Resistor(ohms))],
+            ..Default::default()
+        };
+        diagram.assign_ids();
+        diagram
+    }
+
+    #[test]
+    fn copies_pair_by_id() {
+        let old = resistor_at([(0, 0), (0, 1)], 1e3);
+        let mut new = old.clone();
+        new.two_terminal[0] = ([(2, 0), (2, 1)], TwoTerminalComponent::Resistor(2e3));
+
+        let changes = diff(&old, &new, ValueFormat::Raw);
+        assert_eq!(changes.len(), 1, "{changes:?}");
+        assert_eq!(changes[0].kind, ChangeKind::Changed);
+        assert!(
+            changes[0].text.contains("moved to (2, 0)–(2, 1)"),
+            "{}",
+            changes[0].text
+        );
+        assert_eq!(changes[0].id, new.id((0, SelectionType::TwoTerminal)));
+    }
+
+    #[test]
+    fn separate_drawings_pair_by_position() {
+        // Both resistors get the first ID of their own diagram
+        let old = resistor_at([(0, 0), (0, 1)], 1e3);
+        let new = resistor_at([(2, 0), (2, 1)], 1e3);
+        assert_eq!(old.ids.two_terminal, new.ids.two_terminal);
+
+        let kinds: Vec<ChangeKind> = diff(&old, &new, ValueFormat::Raw)
+            .iter()
+            .map(|change| change.kind)
+            .collect();
+        assert_eq!(kinds, [ChangeKind::Added, ChangeKind::Removed]);
+
+        // Drawn in the same place, they are the same component
+        let moved = resistor_at([(0, 0), (0, 1)], 2e3);
+        let changes = diff(&old, &moved, ValueFormat::Raw);
+        assert_eq!(changes.len(), 1, "{changes:?}");
+        assert_eq!(changes[0].kind, ChangeKind::Changed);
+    }
+}
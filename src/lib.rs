@@ -3,6 +3,12 @@
 pub use cirmcut_sim;
 mod app;
 pub use app::CircuitApp;
-//mod camera;
+#[cfg(all(feature = "audio", not(target_arch = "wasm32")))]
+pub mod audio;
+pub mod canvas;
 pub mod circuit_widget;
 pub mod components;
+pub mod diff;
+pub mod measurement;
+pub mod netlist;
+pub mod worker;
@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+
+use crate::circuit_widget::DiagramState;
+
+/// Mean and RMS of a component's voltage drop and current over a `MeasurementWindow`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WindowAverages {
+    pub mean_voltage: f64,
+    pub rms_voltage: f64,
+    pub mean_current: f64,
+    pub rms_current: f64,
+}
+
+/// Rolling record of the voltage drop and current of each two-terminal component, covering the
+/// last `length` seconds of simulated time
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct MeasurementWindow {
+    /// Simulated seconds
+    pub length: f64,
+    /// (dt, voltage drop and current of each component) for each step, oldest first
+    #[serde(skip)]
+    samples: VecDeque<(f64, Vec<(f64, f64)>)>,
+    /// Total dt of `samples`
+    #[serde(skip)]
+    duration: f64,
+}
+
+impl Default for MeasurementWindow {
+    fn default() -> Self {
+        Self {
+            // One cycle of 50 Hz mains
+            length: 20e-3,
+            samples: VecDeque::new(),
+            duration: 0.0,
+        }
+    }
+}
+
+impl MeasurementWindow {
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.duration = 0.0;
+    }
+
+    /// Records the state after a step of `dt`, dropping samples which have left the window
+    pub fn push(&mut self, dt: f64, state: &DiagramState) {
+        let values = state
+            .two_terminal
+            .iter()
+            .map(|[begin, end]| (end.voltage - begin.voltage, begin.current))
+            .collect();
+        self.samples.push_back((dt, values));
+        self.duration += dt;
+
+        while let Some(&(oldest_dt, _)) = self.samples.front() {
+            if self.duration - oldest_dt < self.length {
+                break;
+            }
+            self.samples.pop_front();
+            self.duration -= oldest_dt;
+        }
+    }
+
+    /// Averages for the two-terminal component at `idx`, weighted by time step. None until the
+    /// samples cover the whole window.
+    pub fn averages(&self, idx: usize) -> Option<WindowAverages> {
+        if self.duration < self.length {
+            return None;
+        }
+
+        let mut sums = WindowAverages::default();
+        for (dt, values) in &self.samples {
+            let &(voltage, current) = values.get(idx)?;
+            sums.mean_voltage += voltage * dt;
+            sums.rms_voltage += voltage.powi(2) * dt;
+            sums.mean_current += current * dt;
+            sums.rms_current += current.powi(2) * dt;
+        }
+
+        Some(WindowAverages {
+            mean_voltage: sums.mean_voltage / self.duration,
+            rms_voltage: (sums.rms_voltage / self.duration).sqrt(),
+            mean_current: sums.mean_current / self.duration,
+            rms_current: (sums.rms_current / self.duration).sqrt(),
+        })
+    }
+}
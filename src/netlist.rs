@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+
+use cirmcut_sim::{GroundKind, ThreeTerminalComponent, TwoTerminalComponent};
+
+use crate::circuit_widget::{CellPos, Diagram, SelectionType};
+
+/// Parses the two-terminal elements of a SPICE netlist (R, L, C, V, I and D cards) into a
+/// diagram. Each node becomes a horizontal rail of wires, and the components stand between their
+/// rails from left to right in netlist order. Node 0 becomes the reference ground.
+///
+/// The first line is skipped if it isn't an element, since SPICE reserves it for the title.
+pub fn parse_netlist(text: &str) -> Result<Diagram, String> {
+    let mut elements: Vec<([&str; 2], TwoTerminalComponent)> = vec![];
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.is_empty() || line.starts_with(['*', '.']) {
+            continue;
+        }
+
+        match parse_element(line) {
+            Ok(element) => elements.push(element),
+            Err(_) if line_idx == 0 => continue,
+            Err(e) => return Err(format!("Line {}: {e}", line_idx + 1)),
+        }
+    }
+
+    if elements.is_empty() {
+        return Err("No components found in the netlist".into());
+    }
+
+    // One rail per node, two cells apart, with ground at the bottom
+    let mut nodes: Vec<&str> = vec![];
+    for &(terminals, _) in &elements {
+        for node in terminals {
+            if !is_ground(node) && !nodes.contains(&node) {
+                nodes.push(node);
+            }
+        }
+    }
+    let ground_row = nodes.len() as i32 * 2;
+    let row = |node: &str| match nodes.iter().position(|n| *n == node) {
+        Some(idx) => idx as i32 * 2,
+        None => ground_row,
+    };
+
+    let mut diagram = Diagram::default();
+    let mut rails: HashMap<i32, Vec<i32>> = HashMap::new();
+    for (idx, &([a, b], component)) in elements.iter().enumerate() {
+        let x = idx as i32 * 2;
+        let positions: [CellPos; 2] = [(x, row(a)), (x, row(b))];
+        for (x, y) in positions {
+            rails.entry(y).or_default().push(x);
+        }
+        diagram.two_terminal.push((positions, component));
+    }
+
+    for (y, mut xs) in rails {
+        xs.sort();
+        xs.dedup();
+        for pair in xs.windows(2) {
+            diagram
+                .two_terminal
+                .push(([(pair[0], y), (pair[1], y)], TwoTerminalComponent::Wire));
+        }
+        if y == ground_row {
+            diagram.grounds.push(((xs[0], y), GroundKind::Reference));
+        }
+    }
+
+    Ok(diagram)
+}
+
+/// A diagram written out by `write_netlist()`
+pub struct ExportedNetlist {
+    pub text: String,
+    /// Cell which became node 0 because the diagram has no connected reference ground
+    pub chosen_ground: Option<CellPos>,
+}
+
+/// Writes the enabled components of a diagram as a SPICE netlist. Cells joined by wires share a
+/// node, numbered from 1 in the order of `Diagram::nets()`, and the reference ground is node 0.
+/// Without a connected reference ground, SPICE would have nothing to measure from, so the cell
+/// the simulator picks in that case becomes node 0 instead, and is returned for the caller to
+/// point out. Components SPICE has no element for are left as comments.
+pub fn write_netlist(diagram: &Diagram) -> Result<ExportedNetlist, String> {
+    let nets = diagram.nets();
+    if nets.is_empty() {
+        return Err("The circuit has no components".into());
+    }
+
+    // Ideal ground ties only join the reference if there is one, as in the simulation
+    let grounded = |kinds: &[GroundKind]| -> Vec<CellPos> {
+        diagram
+            .grounds
+            .iter()
+            .filter(|(_, kind)| kinds.contains(kind))
+            .map(|(pos, _)| *pos)
+            .collect()
+    };
+    let mut chosen_ground = None;
+    let mut ground_cells = grounded(&[GroundKind::Reference]);
+    if nets
+        .iter()
+        .any(|net| net.cells.iter().any(|cell| ground_cells.contains(cell)))
+    {
+        ground_cells = grounded(&[GroundKind::Reference, GroundKind::Tied(None)]);
+    } else {
+        let cell = diagram
+            .implicit_reference()
+            .ok_or("The circuit has nothing to simulate")?;
+        chosen_ground = Some(cell);
+        ground_cells = vec![cell];
+    }
+
+    let mut nodes: HashMap<CellPos, String> = HashMap::new();
+    let mut last_node = 0;
+    for net in &nets {
+        let name = if net.cells.iter().any(|cell| ground_cells.contains(cell)) {
+            "0".to_string()
+        } else {
+            last_node += 1;
+            last_node.to_string()
+        };
+        for &cell in &net.cells {
+            nodes.insert(cell, name.clone());
+        }
+    }
+    // Every terminal is in a net
+    let node = |pos: CellPos| nodes[&pos].as_str();
+
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    let mut element = |letter: char| {
+        let count = counts.entry(letter).or_default();
+        *count += 1;
+        format!("{letter}{count}")
+    };
+
+    let mut lines = vec!["* Exported from cirmcut".to_string()];
+    let mut models = vec![];
+    let collapsed = diagram.collapsed_components();
+    let skip = |sel| !diagram.is_enabled(sel) || collapsed.contains(&sel);
+
+    for (idx, &([a, b], component)) in diagram.two_terminal.iter().enumerate() {
+        if skip((idx, SelectionType::TwoTerminal)) {
+            continue;
+        }
+        let (a, b) = (node(a), node(b));
+        match component {
+            TwoTerminalComponent::Wire | TwoTerminalComponent::Switch(true) => (),
+            TwoTerminalComponent::Resistor(r) => {
+                lines.push(format!("{} {a} {b} {r:e}", element('R')));
+            }
+            TwoTerminalComponent::Capacitor(c, ic, _, esr) => {
+                let ic = ic.map(|v| format!(" IC={v:e}")).unwrap_or_default();
+                lines.push(format!("{} {a} {b} {c:e}{ic}", element('C')));
+                if esr > 0.0 {
+                    lines.push(format!("* Series resistance of {esr:e} Ω not exported"));
+                }
+            }
+            TwoTerminalComponent::Inductor(l, core, ic, _, r) => {
+                let ic = ic.map(|i| format!(" IC={i:e}")).unwrap_or_default();
+                lines.push(format!("{} {a} {b} {l:e}{ic}", element('L')));
+                if r > 0.0 {
+                    lines.push(format!("* Series resistance of {r:e} Ω not exported"));
+                }
+                if let Some(core) = core {
+                    lines.push(format!("* Coupling through core {core} not exported"));
+                }
+            }
+            // A battery's end terminal is its positive one
+            TwoTerminalComponent::Battery(v, _) => {
+                lines.push(format!("{} {b} {a} {v:e}", element('V')));
+            }
+            TwoTerminalComponent::CurrentSource(i) => {
+                lines.push(format!("{} {a} {b} {i:e}", element('I')));
+            }
+            TwoTerminalComponent::Diode => {
+                let model = ".model D D".to_string();
+                if !models.contains(&model) {
+                    models.push(model);
+                }
+                lines.push(format!("{} {a} {b} D", element('D')));
+            }
+            // Zero-volt sources, which SPICE also measures current with
+            TwoTerminalComponent::Switch(false) | TwoTerminalComponent::CurrentProbe => {
+                lines.push(format!("{} {a} {b} 0", element('V')));
+            }
+            TwoTerminalComponent::CurrentControlledSource(..)
+            | TwoTerminalComponent::Crystal { .. }
+            | TwoTerminalComponent::NoiseSource { .. } => {
+                let name = component.name();
+                lines.push(format!("* {name} between {a} and {b} not exported"));
+            }
+        }
+    }
+
+    for (idx, &([a, b, c], component)) in diagram.three_terminal.iter().enumerate() {
+        if skip((idx, SelectionType::ThreeTerminal)) {
+            continue;
+        }
+        let (a, b, c) = (node(a), node(b), node(c));
+        match component {
+            ThreeTerminalComponent::NTransistor(beta, ..)
+            | ThreeTerminalComponent::PTransistor(beta, ..) => {
+                let name = element('Q');
+                let kind = match component {
+                    ThreeTerminalComponent::NTransistor(..) => "NPN",
+                    _ => "PNP",
+                };
+                models.push(format!(".model {name}_MODEL {kind}(BF={beta:e})"));
+                // Collector, base, emitter
+                lines.push(format!("{name} {c} {b} {a} {name}_MODEL"));
+            }
+            ThreeTerminalComponent::Potentiometer(r, wiper) => {
+                let wiper = wiper.clamp(0.0, 1.0);
+                lines.push(format!("{} {a} {b} {:e}", element('R'), r * wiper));
+                lines.push(format!("{} {b} {c} {:e}", element('R'), r * (1.0 - wiper)));
+            }
+        }
+    }
+
+    // Grounds tied through a resistance, which only apply with a reference ground
+    if chosen_ground.is_none() {
+        for &(pos, kind) in &diagram.grounds {
+            if let (GroundKind::Tied(Some(r)), Some(node)) = (kind, nodes.get(&pos)) {
+                lines.push(format!("{} {node} 0 {r:e}", element('R')));
+            }
+        }
+    }
+
+    lines.extend(models);
+    lines.push(".end".into());
+
+    Ok(ExportedNetlist {
+        text: lines.join("\n") + "\n",
+        chosen_ground,
+    })
+}
+
+fn is_ground(node: &str) -> bool {
+    node == "0" || node.eq_ignore_ascii_case("gnd")
+}
+
+/// Returns the nodes in the order the diagram expects them, and the component
+fn parse_element(line: &str) -> Result<([&str; 2], TwoTerminalComponent), String> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let [name, pos, neg, rest @ ..] = tokens.as_slice() else {
+        return Err(format!("Expected a name and two nodes in \"{line}\""));
+    };
+
+    let value = || -> Result<f64, String> {
+        // Sources may give their value as "DC 5"
+        let value = match rest {
+            [dc, value, ..] if dc.eq_ignore_ascii_case("dc") => value,
+            [value, ..] => value,
+            [] => return Err(format!("{name} has no value")),
+        };
+        parse_value(value).ok_or_else(|| format!("Can't read the value \"{value}\" of {name}"))
+    };
+
+    let first = name.chars().next().unwrap_or_default().to_ascii_uppercase();
+    Ok(match first {
+        'R' => ([*pos, *neg], TwoTerminalComponent::Resistor(value()?)),
+        'L' => (
+            [*pos, *neg],
+            TwoTerminalComponent::Inductor(value()?, None, None, None, 0.0),
+        ),
+        'C' => (
+            [*pos, *neg],
+            TwoTerminalComponent::Capacitor(value()?, None, None, 0.0),
+        ),
+        'D' => ([*pos, *neg], TwoTerminalComponent::Diode),
+        // A battery's end terminal is its positive one
+        'V' => ([*neg, *pos], TwoTerminalComponent::Battery(value()?, None)),
+        // SPICE current sources push current from the positive node to the negative one
+        'I' => ([*pos, *neg], TwoTerminalComponent::CurrentSource(value()?)),
+        _ => return Err(format!("Unsupported element {name}")),
+    })
+}
+
+/// Reads a SPICE number such as "4.7k", "10uF" or "1meg". Any unit after the scale factor is
+/// ignored, as in SPICE.
+fn parse_value(text: &str) -> Option<f64> {
+    let text = text.to_lowercase();
+    let mut split = 0;
+    for (idx, c) in text.char_indices() {
+        let exponent = c == 'e'
+            && text[idx + 1..]
+                .trim_start_matches(['+', '-'])
+                .starts_with(|c: char| c.is_ascii_digit());
+        let sign = (c == '+' || c == '-') && (idx == 0 || text[..idx].ends_with('e'));
+        if c.is_ascii_digit() || c == '.' || exponent || sign {
+            split = idx + c.len_utf8();
+        } else {
+            break;
+        }
+    }
+
+    let (number, suffix) = text.split_at(split);
+    let scale = if suffix.starts_with("meg") {
+        1e6
+    } else {
+        match suffix.chars().next() {
+            Some('t') => 1e12,
+            Some('g') => 1e9,
+            Some('k') => 1e3,
+            Some('m') => 1e-3,
+            Some('u') | Some('µ') => 1e-6,
+            Some('n') => 1e-9,
+            Some('p') => 1e-12,
+            Some('f') => 1e-15,
+            _ => 1.0,
+        }
+    };
+
+    number.parse::<f64>().ok().map(|value| value * scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_take_suffixes_and_exponents() {
+        for (text, expected) in [
+            ("4.7k", 4.7e3),
+            ("1meg", 1e6),
+            ("1MEG", 1e6),
+            ("2m", 2e-3),
+            ("2M", 2e-3),
+            ("10u", 10e-6),
+            ("10uF", 10e-6),
+            ("1e3", 1e3),
+            ("1E-3", 1e-3),
+            ("2.5e+2k", 250e3),
+            ("-5", -5.0),
+            ("3v", 3.0),
+        ] {
+            let value = parse_value(text).unwrap();
+            assert!(
+                (value - expected).abs() <= 1e-12 * expected.abs(),
+                "\"{text}\" read as {value}, expected {expected}"
+            );
+        }
+        assert_eq!(parse_value("k"), None);
+        assert_eq!(parse_value("abc"), None);
+    }
+
+    #[test]
+    fn title_is_skipped_and_bad_cards_are_located() {
+        let diagram = parse_netlist("Divider\nV1 1 0 DC 5\nR1 1 2 1k\nR2 2 0 1k\n.end\n").unwrap();
+        let components: Vec<_> = diagram
+            .two_terminal
+            .iter()
+            .map(|(_, component)| *component)
+            .filter(|component| *component != TwoTerminalComponent::Wire)
+            .collect();
+        assert_eq!(
+            components,
+            [
+                TwoTerminalComponent::Battery(5.0, None),
+                TwoTerminalComponent::Resistor(1e3),
+                TwoTerminalComponent::Resistor(1e3),
+            ]
+        );
+
+        // Only the first line may be a title, and a first line that is an element is kept
+        let diagram = parse_netlist("R1 1 0 1k\n").unwrap();
+        assert_eq!(diagram.two_terminal.len(), 1);
+        let err = parse_netlist("Title\nR1 1 0 1k\n* comment\nX1 1 0 foo\n").unwrap_err();
+        assert_eq!(err, "Line 4: Unsupported element X1");
+        let err = parse_netlist("Title\nR1 1 0 lots\n").unwrap_err();
+        assert_eq!(err, "Line 2: Can't read the value \"lots\" of R1");
+    }
+
+    #[test]
+    fn ungrounded_circuit_gets_chosen_ground() {
+        let mut diagram = Diagram {
+            two_terminal: vec![
+                ([(0, 0), (0, 2)], TwoTerminalComponent::Battery(5.0, None)),
+                ([(0, 0), (2, 0)], TwoTerminalComponent::Resistor(1e3)),
+                ([(2, 0), (2, 2)], TwoTerminalComponent::Resistor(2e3)),
+                ([(0, 2), (2, 2)], TwoTerminalComponent::Wire),
+            ],
+            ..Default::default()
+        };
+
+        // The simulator measures from the last cell, which the wire joins to the battery's
+        // positive end
+        let exported = write_netlist(&diagram).unwrap();
+        assert_eq!(exported.chosen_ground, Some((2, 2)));
+        assert_eq!(exported.chosen_ground, diagram.implicit_reference());
+        assert_eq!(
+            exported.text,
+            "* Exported from cirmcut\nV1 0 1 5e0\nR1 1 2 1e3\nR2 2 0 2e3\n.end\n"
+        );
+
+        // With a reference ground, that's node 0 instead
+        diagram.grounds = vec![((0, 0), GroundKind::Reference)];
+        let grounded = write_netlist(&diagram).unwrap();
+        assert_eq!(grounded.chosen_ground, None);
+        assert_eq!(
+            grounded.text,
+            "* Exported from cirmcut\nV1 1 0 5e0\nR1 0 2 1e3\nR2 2 1 2e3\n.end\n"
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! Runs the solver away from the UI thread, so that a slow circuit doesn't stall the editor
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::time::Duration;
+
+use web_time::Instant;
+
+use cirmcut_sim::monte_carlo::{monte_carlo, MonteCarloConfig};
+use cirmcut_sim::solver::{Solver, SolverConfig, SolverError};
+use cirmcut_sim::PrimitiveDiagram;
+
+use crate::circuit_widget::{DiagramState, RichPrimitiveDiagram};
+
+/// How many times a step that fails to converge is retried with half the time step
+const MAX_DT_HALVINGS: usize = 4;
+
+/// Largest change of any voltage or current in one step for the circuit to count as settled
+pub const SETTLED_TOLERANCE: f64 = 1e-6;
+
+/// Wall-clock time a batch may spend stepping before it stops short. On wasm the batch runs on
+/// the UI thread, so this keeps the editor responsive when the circuit is too heavy to simulate
+/// in real time.
+const STEP_BUDGET: Duration = Duration::from_millis(8);
+
+/// Work for the solver thread, along with everything needed to do it
+pub trait Job: Send + 'static {
+    type Output: Send + 'static;
+
+    fn run(self) -> Self::Output;
+}
+
+/// A batch of time steps, along with everything needed to run them
+pub struct StepJob {
+    /// Copy of the solver to advance
+    pub sim: Solver,
+    /// The diagram as it was when the batch was started
+    pub diagram: RichPrimitiveDiagram,
+    pub cfg: SolverConfig,
+    pub dt: f64,
+    pub steps: usize,
+    /// Stop early once the circuit has settled
+    pub pause_when_settled: bool,
+}
+
+pub struct StepResult {
+    pub sim: Solver,
+    /// (time step actually used, state after the step) for each step taken
+    pub states: Vec<(f64, DiagramState)>,
+    /// Time step used by the last step, if it had to be reduced to converge
+    pub reduced_dt: Option<f64>,
+    pub error: Option<String>,
+    /// The batch stopped early and the simulation should pause, on an error or once settled
+    pub pause: bool,
+    /// The batch ran out of `STEP_BUDGET` before taking all of its steps
+    pub over_budget: bool,
+}
+
+impl Job for StepJob {
+    type Output = StepResult;
+
+    fn run(self) -> StepResult {
+        let mut result = StepResult {
+            sim: self.sim,
+            states: vec![],
+            reduced_dt: None,
+            error: None,
+            pause: false,
+            over_budget: false,
+        };
+
+        if self.dt.is_nan() || self.dt <= 0.0 {
+            result.error = Some("Δt must be greater than zero".into());
+            result.pause = true;
+            return result;
+        }
+
+        let sim = &mut result.sim;
+        let diagram = &self.diagram.primitive;
+        let start = Instant::now();
+        'steps: for _ in 0..self.steps {
+            // The steps left over are dropped, as the app does when it can't keep up
+            if !result.states.is_empty() && start.elapsed() > STEP_BUDGET {
+                result.over_budget = true;
+                break;
+            }
+
+            // Convergence failures and blow-ups are often transient, so retry those with a
+            // smaller time step before giving up. Anything else won't be fixed by a smaller dt.
+            let mut dt = self.dt;
+            let mut halvings = 0;
+            loop {
+                match sim.step(dt, diagram, &self.cfg, None) {
+                    Ok(()) => {
+                        let state = self.diagram.state(&sim.state(diagram));
+                        result.states.push((dt, state));
+                        result.reduced_dt = (halvings > 0).then_some(dt);
+                        if self.pause_when_settled && sim.is_steady(SETTLED_TOLERANCE) {
+                            result.pause = true;
+                            break 'steps;
+                        }
+                        break;
+                    }
+                    Err(SolverError::NotConverged { .. } | SolverError::NonFinite(_))
+                        if halvings < MAX_DT_HALVINGS =>
+                    {
+                        dt /= 2.0;
+                        halvings += 1;
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        result.error = Some(e.to_string());
+                        result.pause = true;
+                        break 'steps;
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// A Monte Carlo analysis, see `monte_carlo()`
+pub struct MonteCarloJob {
+    pub diagram: PrimitiveDiagram,
+    pub tolerances: Vec<f64>,
+    pub output_node: usize,
+    pub mc: MonteCarloConfig,
+    pub cfg: SolverConfig,
+}
+
+impl Job for MonteCarloJob {
+    /// Output voltage of each trial
+    type Output = Result<Vec<f64>, String>;
+
+    fn run(self) -> Self::Output {
+        monte_carlo(
+            &self.diagram,
+            &self.tolerances,
+            self.output_node,
+            &self.mc,
+            &self.cfg,
+        )
+        .map_err(|e| e.to_string())
+    }
+}
+
+/// Jobs to the solver thread and their results, each tagged with the generation it was sent in
+#[cfg(not(target_arch = "wasm32"))]
+type Channels<J> = (Sender<(u64, J)>, Receiver<(u64, <J as Job>::Output)>);
+
+/// Runs jobs one at a time on a background thread, started by the first job. Results of jobs
+/// sent before the last `invalidate()` are dropped.
+///
+/// wasm has no threads, so there each job runs as soon as it's sent.
+pub struct SimWorker<J: Job = StepJob> {
+    #[cfg(not(target_arch = "wasm32"))]
+    channels: Option<Channels<J>>,
+    #[cfg(target_arch = "wasm32")]
+    finished: Option<(u64, J::Output)>,
+    generation: u64,
+    busy: bool,
+}
+
+impl<J: Job> Default for SimWorker<J> {
+    fn default() -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            channels: None,
+            #[cfg(target_arch = "wasm32")]
+            finished: None,
+            generation: 0,
+            busy: false,
+        }
+    }
+}
+
+impl<J: Job> SimWorker<J> {
+    /// Whether a job is still running. Only one runs at a time.
+    pub fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    /// Starts a job. Wait for the last one to finish first.
+    pub fn send(&mut self, job: J) {
+        debug_assert!(!self.busy, "a job is already running");
+        self.busy = true;
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let (jobs, _) = self.channels.get_or_insert_with(spawn_worker);
+            jobs.send((self.generation, job))
+                .expect("The solver thread has stopped");
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            self.finished = Some((self.generation, job.run()));
+        }
+    }
+
+    /// The result of the running job, once it has finished and is still wanted
+    pub fn poll(&mut self) -> Option<J::Output> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let finished = match self.channels.as_ref()?.1.try_recv() {
+            Ok(finished) => finished,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => panic!("The solver thread has stopped"),
+        };
+
+        #[cfg(target_arch = "wasm32")]
+        let finished = self.finished.take()?;
+
+        self.busy = false;
+        let (generation, result) = finished;
+        (generation == self.generation).then_some(result)
+    }
+
+    /// Drops the result of the running job, for when the simulation it was advancing has been
+    /// replaced
+    pub fn invalidate(&mut self) {
+        self.generation += 1;
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn_worker<J: Job>() -> Channels<J> {
+    let (job_tx, job_rx) = channel::<(u64, J)>();
+    let (result_tx, result_rx) = channel();
+
+    // Exits once the tab, and with it the sending half, is dropped
+    std::thread::spawn(move || {
+        for (generation, job) in job_rx {
+            if result_tx.send((generation, job.run())).is_err() {
+                break;
+            }
+        }
+    });
+
+    (job_tx, result_rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use cirmcut_sim::{GroundKind, TwoTerminalComponent};
+
+    use super::*;
+    use crate::circuit_widget::Diagram;
+
+    #[test]
+    fn retries_with_half_the_step_when_not_converging() {
+        // A capacitor discharging through a resistor. Damped Newton-Raphson needs more
+        // iterations the further a step moves the state, so more for a longer step.
+        let diagram = Diagram {
+            two_terminal: vec![
+                (
+                    [(0, 0), (0, 1)],
+                    TwoTerminalComponent::Capacitor(1e-6, Some(1.0), None, 0.0),
+                ),
+                ([(0, 0), (0, 1)], TwoTerminalComponent::Resistor(1e3)),
+            ],
+            grounds: vec![((0, 0), GroundKind::Reference)],
+            ..Default::default()
+        }
+        .to_primitive_diagram();
+        let primitive = &diagram.primitive;
+        let dt = 1e-4;
+
+        // Past the jump of the first step, which doesn't depend on dt
+        let mut sim = Solver::new(primitive);
+        sim.step(dt, primitive, &SolverConfig::default(), None)
+            .unwrap();
+        let iterations = |dt: f64| {
+            let mut sim = sim.clone();
+            sim.step(dt, primitive, &SolverConfig::default(), None)
+                .unwrap();
+            sim.last_residuals().len()
+        };
+        let (full, half) = (iterations(dt), iterations(dt / 2.0));
+        assert!(half < full, "{half} iterations for dt/2, {full} for dt");
+
+        // Enough iterations for half the step, but not for all of it
+        let result = StepJob {
+            sim: sim.clone(),
+            diagram,
+            cfg: SolverConfig {
+                max_nr_iters: half,
+                ..Default::default()
+            },
+            dt,
+            steps: 1,
+            pause_when_settled: false,
+        }
+        .run();
+        assert!(result.error.is_none(), "{:?}", result.error);
+        assert_eq!(result.reduced_dt, Some(dt / 2.0));
+        assert_eq!(result.states.len(), 1);
+        assert_eq!(result.sim.time(), sim.time() + dt / 2.0);
+    }
+}